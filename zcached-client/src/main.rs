@@ -8,9 +8,7 @@ use zcached::DB;
 fn main() {
     let db = DB::new();
     let key = "abc".to_string();
-    let mut lock = db.write().unwrap();
-    lock.insert(key.clone(), "value".to_string());
-    drop(lock);
+    db.insert(key.clone(), "value".into()).unwrap();
     let iterations = 100_000;
     let n_threads = 4;
     let join_handles: Vec<JoinHandle<_>> = (0..n_threads)
@@ -1,7 +1,11 @@
+use std::io::Read;
+use std::io::Write;
+use std::net::TcpStream;
 use std::thread;
 
 use criterion::criterion_group;
 use criterion::criterion_main;
+use criterion::BatchSize;
 use criterion::Criterion;
 use rand::distributions::Alphanumeric;
 use rand::distributions::DistString;
@@ -11,7 +15,13 @@ use rand::rngs::StdRng;
 use rand::Rng;
 use rand::SeedableRng;
 use zcached::Client;
+use zcached::Database;
+use zcached::RequestEncoder;
+use zcached::Response;
+use zcached::Serialize;
 use zcached::Server;
+use zcached::Value;
+use zcached::DB;
 
 fn get_key(c: &mut Criterion) {
     let host = "127.0.0.1";
@@ -31,6 +41,227 @@ fn get_key(c: &mut Criterion) {
     c.bench_function("get key", |b| b.iter(|| client.get("hello")));
 }
 
+fn pipelined_gets(c: &mut Criterion) {
+    let mut requests = RequestEncoder::new();
+    for _ in 0..1000 {
+        requests.get("hello").unwrap();
+    }
+    let response_len = Response::Get(Some("world".to_string())).serialize().len() * 1000;
+    let mut responses = vec![0; response_len];
+
+    let mut group = c.benchmark_group("pipeline 1000 gets");
+    for (name, coalesce_responses) in [("per-response writes", false), ("coalesced writes", true)] {
+        let host = "127.0.0.1";
+        let server = Server::builder()
+            .address(format!("{host}:0"))
+            .initial_buffer_size(requests.as_bytes().len())
+            .coalesce_responses(coalesce_responses)
+            .build()
+            .unwrap();
+        let port = server.port().unwrap();
+        thread::spawn(move || {
+            server.run();
+        });
+        Client::connect(format!("{host}:{port}"))
+            .set("hello", "world")
+            .unwrap();
+        let mut stream = TcpStream::connect(format!("{host}:{port}")).unwrap();
+        stream.set_nodelay(true).unwrap();
+
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                stream.write_all(requests.as_bytes()).unwrap();
+                stream.read_exact(&mut responses).unwrap();
+            })
+        });
+    }
+    group.finish();
+}
+
+fn pipelined_sets(c: &mut Criterion) {
+    let value = "v".repeat(64);
+    let mut requests = RequestEncoder::new();
+    for i in 0..10_000 {
+        requests.set(&format!("key-{}", i % 100), &value).unwrap();
+    }
+    let mut responses = vec![0; Response::Set.serialize().len() * 10_000];
+
+    let mut group = c.benchmark_group("pipeline 10000 sets");
+    for (name, read_batching) in [("fixed reads", false), ("batched reads", true)] {
+        let host = "127.0.0.1";
+        let server = Server::builder()
+            .address(format!("{host}:0"))
+            .initial_buffer_size(1024)
+            .max_buffer_size(1024 * 1024)
+            .read_batching(read_batching)
+            .build()
+            .unwrap();
+        let port = server.port().unwrap();
+        thread::spawn(move || {
+            server.run();
+        });
+        let stream = TcpStream::connect(format!("{host}:{port}")).unwrap();
+        stream.set_nodelay(true).unwrap();
+        let mut reader = stream.try_clone().unwrap();
+        let mut writer = stream;
+
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                // The responses are read concurrently, so that neither side blocks on a full socket buffer.
+                thread::scope(|scope| {
+                    scope.spawn(|| writer.write_all(requests.as_bytes()).unwrap());
+                    reader.read_exact(&mut responses).unwrap();
+                });
+            })
+        });
+    }
+    group.finish();
+}
+
+fn get_many_keys(c: &mut Criterion) {
+    let db = DB::new();
+    let (_, keys, values) = get_random_data();
+    for (key, value) in keys.iter().zip(&values) {
+        db.insert(key.clone(), value.as_str().into()).unwrap();
+    }
+    let batch: Vec<&str> = keys.iter().take(50).map(String::as_str).collect();
+
+    let mut group = c.benchmark_group("get 50 keys");
+    group.bench_function("single gets", |b| {
+        b.iter(|| {
+            batch
+                .iter()
+                .map(|key| db.get(key).unwrap())
+                .collect::<Vec<_>>()
+        })
+    });
+    group.bench_function("get_many", |b| b.iter(|| db.get_many(&batch).unwrap()));
+    group.bench_function("get_many_ref", |b| {
+        b.iter(|| {
+            let mut total_len = 0;
+            db.get_many_ref(&batch, &mut |value| {
+                if let Some(Value::Str(value)) = value {
+                    total_len += value.len();
+                }
+            })
+            .unwrap();
+            total_len
+        })
+    });
+    group.finish();
+}
+
+fn get_hot_key(c: &mut Criterion) {
+    let db = DB::new();
+    db.insert("hot".to_string(), "v".repeat(1024).into())
+        .unwrap();
+
+    let mut group = c.benchmark_group("get hot key");
+    group.bench_function("get", |b| b.iter(|| db.get("hot").unwrap()));
+    group.bench_function("get_arc", |b| b.iter(|| db.get_arc("hot").unwrap()));
+    group.finish();
+}
+
+fn bulk_load(c: &mut Criterion) {
+    let pairs: Vec<(String, String)> = (0..100_000)
+        .map(|i| (format!("key-{i}"), format!("value-{i}")))
+        .collect();
+
+    let mut group = c.benchmark_group("load 100k pairs");
+    group.sample_size(10);
+    group.bench_function("single inserts", |b| {
+        b.iter_batched(
+            || (DB::new(), pairs.clone()),
+            |(db, pairs)| {
+                for (key, value) in pairs {
+                    db.insert(key, value.into()).unwrap();
+                }
+                db
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("extend", |b| {
+        b.iter_batched(
+            || (DB::new(), pairs.clone()),
+            |(db, pairs)| {
+                db.extend(pairs).unwrap();
+                db
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+fn overwrite_key(c: &mut Criterion) {
+    let db = DB::new();
+    let value = "v".repeat(64);
+    db.insert("key".to_string(), value.as_str().into()).unwrap();
+
+    let mut group = c.benchmark_group("overwrite key");
+    group.bench_function("insert", |b| {
+        b.iter(|| db.insert("key".to_string(), value.as_str().into()).unwrap())
+    });
+    group.bench_function("insert_str", |b| {
+        b.iter(|| db.insert_str("key", &value).unwrap())
+    });
+    group.finish();
+}
+
+fn read_modify_writes(c: &mut Criterion) {
+    let keys: Vec<String> = (0..1000).map(|i| format!("key-{i}")).collect();
+    let existing = DB::new();
+    for key in &keys {
+        existing.insert(key.clone(), Value::Int(0)).unwrap();
+    }
+
+    let mut group = c.benchmark_group("1000 read-modify-writes");
+    group.bench_function("incr existing keys", |b| {
+        b.iter(|| {
+            for key in &keys {
+                existing.incr(key, 1).unwrap();
+            }
+        })
+    });
+    group.bench_function("incr new keys", |b| {
+        b.iter_batched(
+            DB::new,
+            |db| {
+                for key in &keys {
+                    db.incr(key, 1).unwrap();
+                }
+                db
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.bench_function("insert_if_absent new keys", |b| {
+        b.iter_batched(
+            || (DB::new(), keys.clone()),
+            |(db, keys)| {
+                for key in keys {
+                    db.insert_if_absent(key, Value::Int(1)).unwrap();
+                }
+                db
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.bench_function("insert_if_absent existing keys", |b| {
+        b.iter_batched(
+            || keys.clone(),
+            |keys| {
+                for key in keys {
+                    existing.insert_if_absent(key, Value::Int(1)).unwrap();
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
 #[derive(Debug)]
 enum RandomAccessClientSetup<'a> {
     Set { key: &'a str, value: &'a str },
@@ -79,6 +310,32 @@ fn get_random_data() -> (StdRng, Vec<String>, Vec<String>) {
     (rng, keys, values)
 }
 
+fn concurrent_incrs(c: &mut Criterion) {
+    let mut group = c.benchmark_group("10000 incrs per thread on distinct keys");
+    for threads in [1, 2, 4, 8] {
+        group.bench_function(format!("{threads} threads"), |b| {
+            b.iter_batched(
+                DB::new,
+                |db| {
+                    thread::scope(|scope| {
+                        for t in 0..threads {
+                            let db = &db;
+                            scope.spawn(move || {
+                                let key = format!("counter:{t}");
+                                for _ in 0..10_000 {
+                                    db.incr(&key, 1).unwrap();
+                                }
+                            });
+                        }
+                    });
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
 fn get_data_actions_and_distributions<'a>(
     rng: &mut StdRng,
     keys: &'a [String],
@@ -131,5 +388,17 @@ fn set_and_get_random_access(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, get_key, set_and_get_random_access,);
+criterion_group!(
+    benches,
+    get_key,
+    pipelined_gets,
+    pipelined_sets,
+    get_many_keys,
+    get_hot_key,
+    bulk_load,
+    overwrite_key,
+    set_and_get_random_access,
+    concurrent_incrs,
+    read_modify_writes,
+);
 criterion_main!(benches);
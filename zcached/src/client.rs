@@ -1,54 +1,496 @@
+use std::collections::hash_map::RandomState;
+use std::collections::VecDeque;
+use std::hash::BuildHasher;
+use std::io;
+use std::io::ErrorKind;
 use std::io::Read;
 use std::io::Write;
+use std::mem;
+use std::net::Shutdown;
+use std::net::SocketAddr;
 use std::net::TcpStream;
 use std::net::ToSocketAddrs;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
 
 use crate::error::ClientError;
+use crate::error::Error;
 use crate::error::Result;
 use crate::parse_response;
-use crate::serialize_request;
+use crate::serialization::read_element;
+use crate::serialization::Serialize;
+use crate::ErrorCode;
+use crate::GetModified;
+use crate::KeyMeta;
 use crate::Request;
+use crate::RequestEncoder;
 use crate::Response;
+use crate::ServerInfo;
 
-pub struct Client {
-    stream: TcpStream,
+/// A client sending requests over a stream `S`, by default a [`TcpStream`].
+pub struct Client<S = TcpStream> {
+    stream: S,
     init_buffer_size: usize,
     // The buffer can be resized as long as it is < max_buffer_size.
     // If the server sends too much data, we reject the response.
     max_buffer_size: usize,
+    keepalive: Option<Keepalive>,
+    // Shared with the keepalive thread, which reads from the same connection.
+    inbox: Arc<Mutex<Inbox>>,
+    // The address of the server, kept for reconnecting. `None` if created from a stream.
+    peer: Option<SocketAddr>,
+    reconnect_backoff: ReconnectBackoff,
 }
 
-impl Client {
-    pub fn connect<A: ToSocketAddrs>(addr: A) -> Self {
+/// A `ClientBuilder` can be used to create a `Client` with custom configuration.
+#[derive(Debug)]
+pub struct ClientBuilder<A> {
+    addr: Option<A>,
+    init_buffer_size: Option<usize>,
+    max_buffer_size: Option<usize>,
+    operation_timeout: Option<Duration>,
+    connect_timeout: Option<Duration>,
+    connect_stagger: Option<Duration>,
+    keepalive_interval: Option<Duration>,
+    batch_max_bytes: Option<usize>,
+    batch_max_delay: Option<Duration>,
+    reconnect_attempts: Option<u32>,
+    reconnect_backoff_base: Option<Duration>,
+    reconnect_backoff_cap: Option<Duration>,
+}
+
+impl<A> Default for ClientBuilder<A> {
+    fn default() -> Self {
         Self {
-            stream: TcpStream::connect(addr).unwrap(),
-            init_buffer_size: 4096,
-            max_buffer_size: 1024 * 1024,
+            addr: None,
+            init_buffer_size: None,
+            max_buffer_size: None,
+            operation_timeout: None,
+            connect_timeout: None,
+            connect_stagger: None,
+            keepalive_interval: None,
+            batch_max_bytes: None,
+            batch_max_delay: None,
+            reconnect_attempts: None,
+            reconnect_backoff_base: None,
+            reconnect_backoff_cap: None,
         }
     }
+}
+
+impl<A: ToSocketAddrs> ClientBuilder<A> {
+    /// Creates a new `ClientBuilder`
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the address of the server the `Client` connects to.
+    pub fn address(
+        mut self,
+        addr: A,
+    ) -> Self {
+        self.addr = Some(addr);
+        self
+    }
+
+    /// Sets the initial buffer size in bytes for receiving responses.
+    pub fn initial_buffer_size(
+        mut self,
+        init_buffer_size: usize,
+    ) -> Self {
+        self.init_buffer_size = Some(init_buffer_size);
+        self
+    }
+
+    /// Sets the maximum buffer size in bytes for receiving responses.
+    /// If the server sends more than this number of bytes, the response will be rejected.
+    pub fn max_buffer_size(
+        mut self,
+        max_buffer_size: usize,
+    ) -> Self {
+        self.max_buffer_size = Some(max_buffer_size);
+        self
+    }
+
+    /// Sets how long the `Client` waits for the server to respond before
+    /// failing an operation with [`ClientError::Timeout`].
+    /// By default, the `Client` waits indefinitely.
+    pub fn operation_timeout(
+        mut self,
+        operation_timeout: Duration,
+    ) -> Self {
+        self.operation_timeout = Some(operation_timeout);
+        self
+    }
+
+    /// Sets how long the `Client` tries to connect to the server before
+    /// failing with an [`io::Error`] of kind [`ErrorKind::TimedOut`].
+    /// The timeout covers all addresses the [`address`] resolves to together.
+    /// By default, only the operating system's timeout applies.
+    ///
+    /// [`address`]: ClientBuilder::address
+    pub fn connect_timeout(
+        mut self,
+        connect_timeout: Duration,
+    ) -> Self {
+        self.connect_timeout = Some(connect_timeout);
+        self
+    }
+
+    /// Sets how long a connection attempt to one of the addresses the [`address`] resolves to
+    /// is waited for before the next address is tried in parallel. Defaults to 250 milliseconds.
+    /// The first connection to succeed is used, so that a dual-stack host whose first address
+    /// is unreachable is connected to quickly anyway.
+    ///
+    /// [`address`]: ClientBuilder::address
+    pub fn connect_stagger(
+        mut self,
+        stagger: Duration,
+    ) -> Self {
+        self.connect_stagger = Some(stagger);
+        self
+    }
+
+    /// Sends a `Ping` from a background thread whenever the connection was idle for `interval`,
+    /// so that idle connections are not dropped by NATs or load balancers.
+    /// The `Pong`s are discarded and never interleave with the responses to the `Client`'s requests.
+    /// By default, no keepalive is sent.
+    pub fn keepalive_interval(
+        mut self,
+        interval: Duration,
+    ) -> Self {
+        self.keepalive_interval = Some(interval);
+        self
+    }
+
+    /// Sends the queued requests of a [`BatchingClient`] as soon as they take up at least
+    /// `max_bytes`. Only used by [`build_batching`].
+    ///
+    /// [`build_batching`]: ClientBuilder::build_batching
+    pub fn batch_max_bytes(
+        mut self,
+        max_bytes: usize,
+    ) -> Self {
+        self.batch_max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Sends the queued requests of a [`BatchingClient`] once the first of them was queued
+    /// at least `max_delay` ago. Only used by [`build_batching`].
+    ///
+    /// [`build_batching`]: ClientBuilder::build_batching
+    pub fn batch_max_delay(
+        mut self,
+        max_delay: Duration,
+    ) -> Self {
+        self.batch_max_delay = Some(max_delay);
+        self
+    }
+
+    /// Sets how often [`Client::reconnect`] tries to connect before it fails. Defaults to 1, i.e. no retries.
+    /// Before every retry, the `Client` waits a random delay of up to [`reconnect_backoff_base`]
+    /// doubled for every failed attempt, but at most [`reconnect_backoff_cap`], so that clients
+    /// reconnecting to a restarted server at the same time spread out their attempts.
+    ///
+    /// [`reconnect_backoff_base`]: ClientBuilder::reconnect_backoff_base
+    /// [`reconnect_backoff_cap`]: ClientBuilder::reconnect_backoff_cap
+    pub fn reconnect_attempts(
+        mut self,
+        attempts: u32,
+    ) -> Self {
+        self.reconnect_attempts = Some(attempts);
+        self
+    }
+
+    /// Sets the longest delay before the first retry of [`Client::reconnect`],
+    /// see [`reconnect_attempts`](ClientBuilder::reconnect_attempts). Defaults to 100 milliseconds.
+    pub fn reconnect_backoff_base(
+        mut self,
+        base: Duration,
+    ) -> Self {
+        self.reconnect_backoff_base = Some(base);
+        self
+    }
+
+    /// Sets the longest delay before any retry of [`Client::reconnect`],
+    /// see [`reconnect_attempts`](ClientBuilder::reconnect_attempts). Defaults to 10 seconds.
+    pub fn reconnect_backoff_cap(
+        mut self,
+        cap: Duration,
+    ) -> Self {
+        self.reconnect_backoff_cap = Some(cap);
+        self
+    }
+
+    /// Connects a [`BatchingClient`] from this `ClientBuilder`, sending its batches
+    /// automatically as configured with [`batch_max_bytes`] and [`batch_max_delay`].
+    ///
+    /// # Errors
+    /// See [`build`](ClientBuilder::build).
+    ///
+    /// [`batch_max_bytes`]: ClientBuilder::batch_max_bytes
+    /// [`batch_max_delay`]: ClientBuilder::batch_max_delay
+    pub fn build_batching(self) -> Result<BatchingClient> {
+        let max_bytes = self.batch_max_bytes;
+        let max_delay = self.batch_max_delay;
+        let mut client = BatchingClient::new(self.build()?);
+        client.max_bytes = max_bytes;
+        client.max_delay = max_delay;
+        Ok(client)
+    }
+
+    /// Connects a `Client` from this `ClientBuilder`.
+    ///
+    /// # Errors
+    /// If no [`address`] was set or the connection cannot be established then an error is returned.
+    ///
+    /// [`address`]: ClientBuilder::address
+    pub fn build(self) -> Result<Client> {
+        let Some(addr) = self.addr else {
+            return Err(ClientError::NoAddress.into());
+        };
+        let stagger = self.connect_stagger.unwrap_or(CONNECT_STAGGER);
+        let defaults = ReconnectBackoff::default();
+        let reconnect_backoff = ReconnectBackoff {
+            attempts: self.reconnect_attempts.unwrap_or(defaults.attempts),
+            base: self.reconnect_backoff_base.unwrap_or(defaults.base),
+            cap: self.reconnect_backoff_cap.unwrap_or(defaults.cap),
+        };
+        let stream = connect_racing(addr, stagger, self.connect_timeout)?;
+        stream.set_read_timeout(self.operation_timeout)?;
+        let init_buffer_size = self.init_buffer_size.unwrap_or(4096);
+        let inbox = Arc::default();
+        let keepalive = self
+            .keepalive_interval
+            .map(|interval| Keepalive::spawn(&stream, interval, init_buffer_size, &inbox))
+            .transpose()?;
+        Ok(Client {
+            peer: Some(stream.peer_addr()?),
+            stream,
+            init_buffer_size,
+            max_buffer_size: self.max_buffer_size.unwrap_or(1024 * 1024),
+            keepalive,
+            inbox,
+            reconnect_backoff,
+        })
+    }
+}
+
+impl Client {
+    /// Returns a `ClientBuilder` that can be used to build a `Client`.
+    pub fn builder<A: ToSocketAddrs>() -> ClientBuilder<A> {
+        ClientBuilder::new()
+    }
+
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Self {
+        Self::connect_with_max_buffer_size(addr, 1024 * 1024)
+    }
 
     pub fn connect_with_max_buffer_size<A: ToSocketAddrs>(
         addr: A,
         max_buffer_size: usize,
     ) -> Self {
+        let stream = connect_racing(addr, CONNECT_STAGGER, None).unwrap();
+        let peer = stream.peer_addr().ok();
+        Client {
+            peer,
+            ..Self::from_stream(stream).with_max_buffer_size(max_buffer_size)
+        }
+    }
+
+    /// Opens a new connection to the same server with the same buffer sizes, operation timeout, keepalive
+    /// and reconnect backoff.
+    /// Unlike [`TcpStream::try_clone`] the socket is not shared, so both clients can be used
+    /// from different threads without their responses interleaving.
+    ///
+    /// # Errors
+    /// If the connection cannot be established then an error is returned.
+    pub fn try_clone(&self) -> Result<Client> {
+        let peer = match self.peer {
+            Some(peer) => peer,
+            None => self.stream.peer_addr()?,
+        };
+        let stream = TcpStream::connect(peer)?;
+        stream.set_read_timeout(self.stream.read_timeout()?)?;
+        let inbox = Arc::default();
+        let keepalive = self
+            .keepalive
+            .as_ref()
+            .map(|keepalive| {
+                Keepalive::spawn(&stream, keepalive.interval, self.init_buffer_size, &inbox)
+            })
+            .transpose()?;
+        Ok(Client {
+            stream,
+            init_buffer_size: self.init_buffer_size,
+            max_buffer_size: self.max_buffer_size,
+            keepalive,
+            inbox,
+            peer: Some(peer),
+            reconnect_backoff: self.reconnect_backoff,
+        })
+    }
+
+    /// Closes the connection and opens a new one to the same server like [`try_clone`](Client::try_clone),
+    /// e.g. after a [`ClientError::Timeout`] when the response may still arrive later
+    /// and would be mistaken for the response to the next request.
+    /// Everything received but not read yet is discarded, including pushed invalidations,
+    /// and the connection's state on the server, e.g. the selected database or watched keys, is lost.
+    ///
+    /// Failed attempts are retried with a jittered exponential backoff as configured with
+    /// [`ClientBuilder::reconnect_attempts`], by default not at all.
+    ///
+    /// # Errors
+    /// If the new connection cannot be established within the configured attempts then the error
+    /// of the last attempt is returned and the old connection is kept.
+    pub fn reconnect(&mut self) -> Result<()> {
+        let ReconnectBackoff {
+            attempts,
+            base,
+            cap,
+        } = self.reconnect_backoff;
+        let random = RandomState::new();
+        let mut attempt = 0;
+        let client = loop {
+            match self.try_clone() {
+                Ok(client) => break client,
+                Err(e) if attempt + 1 >= attempts => return Err(e),
+                Err(_) => {
+                    let delay = backoff_delay(attempt, base, cap, &mut || random.hash_one(attempt));
+                    thread::sleep(delay);
+                    attempt += 1;
+                }
+            }
+        };
+        // The keepalive thread holds a clone of the socket, so dropping the stream would not close it.
+        // Fails only if the connection is closed already.
+        let _ = self.stream.shutdown(Shutdown::Both);
+        *self = client;
+        Ok(())
+    }
+}
+
+impl<S> Client<S>
+where
+    S: Read,
+    S: Write,
+{
+    /// Creates a `Client` that sends its requests over an already connected `stream`,
+    /// e.g. an in-memory pipe to a server in tests.
+    pub fn from_stream(stream: S) -> Self {
         Self {
-            stream: TcpStream::connect(addr).unwrap(),
+            stream,
             init_buffer_size: 4096,
-            max_buffer_size,
+            max_buffer_size: 1024 * 1024,
+            keepalive: None,
+            inbox: Arc::default(),
+            peer: None,
+            reconnect_backoff: ReconnectBackoff::default(),
         }
     }
 
+    fn with_max_buffer_size(
+        mut self,
+        max_buffer_size: usize,
+    ) -> Self {
+        self.max_buffer_size = max_buffer_size;
+        self
+    }
+
     pub fn get(
         &mut self,
         key: &str,
     ) -> Result<Response> {
         let request = Request::Get(key);
-        self.send_request(request);
-        receive_response(
-            &mut self.stream,
-            self.init_buffer_size,
-            self.max_buffer_size,
-        )
+        self.send_request(request)?;
+        self.receive(receive_response)
+    }
+
+    /// Gets the `key`'s value and writes it into `out` instead of allocating a new `String`.
+    /// `out` is cleared first so its capacity can be reused across calls.
+    /// Returns `true` if the key exists, otherwise `out` is left empty and `false` is returned.
+    pub fn get_into(
+        &mut self,
+        key: &str,
+        out: &mut String,
+    ) -> Result<bool> {
+        out.clear();
+        let request = Request::Get(key);
+        self.send_request(request)?;
+        self.receive(|stream, inbox, init_buffer_size, max_buffer_size| {
+            receive_with(
+                stream,
+                inbox,
+                init_buffer_size,
+                max_buffer_size,
+                |input, _| parse_get_into(input, out),
+            )
+        })
+    }
+
+    /// Gets the `key`'s value and copies it into `sink` in chunks as it arrives,
+    /// so that the value is neither buffered completely nor limited by the maximum buffer size.
+    /// Returns `true` if the key exists, otherwise nothing is written to `sink` and `false` is returned.
+    pub fn get_streaming(
+        &mut self,
+        key: &str,
+        sink: &mut impl Write,
+    ) -> Result<bool> {
+        let request = Request::Get(key);
+        self.send_request(request)?;
+        self.receive(|stream, inbox, init_buffer_size, max_buffer_size| {
+            receive_get_streaming(stream, inbox, init_buffer_size, max_buffer_size, sink)
+        })
+    }
+
+    /// Gets the `key`'s value together with its metadata, e.g. its remaining time to live.
+    /// Returns `None` if the key does not exist.
+    pub fn get_with_meta(
+        &mut self,
+        key: &str,
+    ) -> Result<Option<(String, KeyMeta)>> {
+        let request = Request::GetMeta(key);
+        self.send_request(request)?;
+        match self.receive(receive_response)? {
+            Response::GetMeta(value) => Ok(value),
+            _ => Err(ClientError::UnexpectedResponse.into()),
+        }
+    }
+
+    /// Gets the `key`'s value and, if the key exists, sets its expiry to `ttl` from now in one request.
+    /// Returns `None` if the key does not exist. The `ttl` is sent with millisecond precision.
+    pub fn get_touch(
+        &mut self,
+        key: &str,
+        ttl: Duration,
+    ) -> Result<Option<String>> {
+        let ttl_ms = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+        let request = Request::GetTouch { key, ttl_ms };
+        self.send_request(request)?;
+        match self.receive(receive_response)? {
+            Response::Get(value) => Ok(value),
+            _ => Err(ClientError::UnexpectedResponse.into()),
+        }
+    }
+
+    /// Gets the `key`'s value or `default` if the key does not exist.
+    /// As empty values are sent like missing keys, `default` is also returned for them.
+    pub fn get_or_default(
+        &mut self,
+        key: &str,
+        default: &str,
+    ) -> Result<String> {
+        match self.get(key)? {
+            Response::Get(value) => Ok(value.unwrap_or_else(|| default.to_string())),
+            _ => Err(ClientError::UnexpectedResponse.into()),
+        }
     }
 
     pub fn set(
@@ -57,12 +499,54 @@ impl Client {
         value: &str,
     ) -> Result<Response> {
         let request = Request::Set { key, value };
-        self.send_request(request);
-        receive_response(
-            &mut self.stream,
-            self.init_buffer_size,
-            self.max_buffer_size,
-        )
+        self.send_request(request)?;
+        self.receive(receive_response)
+    }
+
+    /// Sets the value of `key` to `new` only if its current value is `expected`,
+    /// which also matches a counter whose digits are `expected`.
+    /// Returns whether the value was set.
+    pub fn set_if_equal(
+        &mut self,
+        key: &str,
+        expected: &str,
+        new: &str,
+    ) -> Result<bool> {
+        let request = Request::Cas { key, expected, new };
+        self.send_request(request)?;
+        match self.receive(receive_response)? {
+            Response::Cas(swapped) => Ok(swapped),
+            _ => Err(ClientError::UnexpectedResponse.into()),
+        }
+    }
+
+    /// Sets the `value` for `key` only if `key` does not exist.
+    /// Returns whether the value was set.
+    pub fn set_if_absent(
+        &mut self,
+        key: &str,
+        value: &str,
+    ) -> Result<bool> {
+        let request = Request::SetNx { key, value };
+        self.send_request(request)?;
+        match self.receive(receive_response)? {
+            Response::SetNx(set) => Ok(set),
+            _ => Err(ClientError::UnexpectedResponse.into()),
+        }
+    }
+
+    /// Sets the `value` for `key` which expires after `ttl`.
+    /// The `ttl` is sent with millisecond precision.
+    pub fn set_ex(
+        &mut self,
+        key: &str,
+        value: &str,
+        ttl: Duration,
+    ) -> Result<Response> {
+        let ttl_ms = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+        let request = Request::SetEx { key, value, ttl_ms };
+        self.send_request(request)?;
+        self.receive(receive_response)
     }
 
     pub fn delete(
@@ -70,55 +554,1445 @@ impl Client {
         key: &str,
     ) -> Result<Response> {
         let request = Request::Delete(key);
-        self.send_request(request);
-        receive_response(
-            &mut self.stream,
-            self.init_buffer_size,
-            self.max_buffer_size,
-        )
+        self.send_request(request)?;
+        self.receive(receive_response)
+    }
+
+    /// Deletes `key` only if it was last written before `cutoff` according to the server's clock.
+    /// The `cutoff` is sent with millisecond precision.
+    /// Returns whether the key was deleted.
+    pub fn delete_if_older_than(
+        &mut self,
+        key: &str,
+        cutoff: SystemTime,
+    ) -> Result<bool> {
+        let cutoff_ms = cutoff.duration_since(UNIX_EPOCH).map_or(0, |since_epoch| {
+            u64::try_from(since_epoch.as_millis()).unwrap_or(u64::MAX)
+        });
+        let request = Request::DeleteIfOlderThan { key, cutoff_ms };
+        self.send_request(request)?;
+        match self.receive(receive_response)? {
+            Response::DeleteIfOlderThan(deleted) => Ok(deleted),
+            _ => Err(ClientError::UnexpectedResponse.into()),
+        }
+    }
+
+    /// Deletes `key` only if its current value is `expected`, so that a value another client
+    /// just refreshed is not deleted. A counter matches if its digits are `expected`.
+    /// Returns whether the key was deleted.
+    pub fn delete_if(
+        &mut self,
+        key: &str,
+        expected: &str,
+    ) -> Result<bool> {
+        let request = Request::DeleteIf { key, expected };
+        self.send_request(request)?;
+        match self.receive(receive_response)? {
+            Response::DeleteIf(deleted) => Ok(deleted),
+            _ => Err(ClientError::UnexpectedResponse.into()),
+        }
+    }
+
+    /// Gets the `key`'s value only if it was written after `since`, like HTTP's `If-Modified-Since`.
+    /// The value is not transferred if it was not modified.
+    /// `since` is sent with millisecond precision, so a write within the same millisecond counts as a modification.
+    pub fn get_if_modified_since(
+        &mut self,
+        key: &str,
+        since: SystemTime,
+    ) -> Result<GetModified> {
+        let since_ms = since.duration_since(UNIX_EPOCH).map_or(0, |since_epoch| {
+            u64::try_from(since_epoch.as_millis()).unwrap_or(u64::MAX)
+        });
+        let request = Request::GetIfModifiedSince { key, since_ms };
+        self.send_request(request)?;
+        match self.receive(receive_response)? {
+            Response::GetIfModifiedSince(modified) => Ok(modified),
+            _ => Err(ClientError::UnexpectedResponse.into()),
+        }
+    }
+
+    /// Gets the bytes of the value of `key` from `start` up to but excluding `end`,
+    /// or `None` if the key does not exist.
+    /// The offsets are byte offsets, see [`Database::get_range`](crate::Database::get_range).
+    /// Fails with [`ErrorCode::WrongType`](crate::ErrorCode::WrongType) if the range splits a character.
+    pub fn get_range(
+        &mut self,
+        key: &str,
+        start: i64,
+        end: i64,
+    ) -> Result<Option<String>> {
+        self.send_request(Request::GetRange { key, start, end })?;
+        match self.receive(receive_response)? {
+            Response::GetRange(range) => Ok(range),
+            _ => Err(ClientError::UnexpectedResponse.into()),
+        }
+    }
+
+    /// Gets the length of the value of `key` in bytes without fetching the value,
+    /// or `None` if the key does not exist.
+    pub fn strlen(
+        &mut self,
+        key: &str,
+    ) -> Result<Option<u64>> {
+        self.send_request(Request::StrLen(key))?;
+        match self.receive(receive_response)? {
+            Response::StrLen(len) => Ok(len),
+            _ => Err(ClientError::UnexpectedResponse.into()),
+        }
+    }
+
+    /// Overwrites the bytes of the value of `key` starting at byte `offset` with `value`,
+    /// padding the value with zero bytes if it is shorter than `offset`.
+    /// Returns the new length of the value in bytes.
+    pub fn set_range(
+        &mut self,
+        key: &str,
+        offset: u64,
+        value: &str,
+    ) -> Result<u64> {
+        self.send_request(Request::SetRange { key, offset, value })?;
+        match self.receive(receive_response)? {
+            Response::SetRange(len) => Ok(len),
+            _ => Err(ClientError::UnexpectedResponse.into()),
+        }
     }
 
     pub fn flush(&mut self) -> Result<Response> {
         let request = Request::Flush;
-        self.send_request(request);
-        receive_response(
+        self.send_request(request)?;
+        self.receive(receive_response)
+    }
+
+    /// Authenticates the connection with the server's admin password,
+    /// which is required for flushing if the server has one.
+    pub fn auth(
+        &mut self,
+        password: &str,
+    ) -> Result<Response> {
+        let request = Request::Auth(password);
+        self.send_request(request)?;
+        self.receive(receive_response)
+    }
+
+    /// Identifies the connection with `client_id`.
+    /// If the server allows only one connection per client id, a duplicate id is either rejected
+    /// with [`ErrorCode::DuplicateClientId`](crate::ErrorCode::DuplicateClientId)
+    /// or closes the other connection.
+    pub fn hello(
+        &mut self,
+        client_id: &str,
+    ) -> Result<Response> {
+        let request = Request::Hello(client_id);
+        self.send_request(request)?;
+        self.receive(receive_response)
+    }
+
+    /// Increments the integer value of `key` by `delta` and returns the new value.
+    /// A missing key is treated as `0`.
+    pub fn incr(
+        &mut self,
+        key: &str,
+        delta: i64,
+    ) -> Result<Response> {
+        let request = Request::Incr { key, delta };
+        self.send_request(request)?;
+        self.receive(receive_response)
+    }
+
+    /// Gets the values of all `keys` in one request.
+    pub fn get_many(
+        &mut self,
+        keys: &[&str],
+    ) -> Result<Response> {
+        let request = Request::MGet(keys.to_vec());
+        self.send_request(request)?;
+        self.receive(receive_response)
+    }
+
+    /// Sets all key value `pairs` in one request.
+    pub fn set_many(
+        &mut self,
+        pairs: &[(&str, &str)],
+    ) -> Result<Response> {
+        let request = Request::MSet(pairs.to_vec());
+        self.send_request(request)?;
+        self.receive(receive_response)
+    }
+
+    /// Atomically replaces the entire contents of the selected database with the key value `pairs`,
+    /// so other clients never see an empty or partially rebuilt database.
+    pub fn replace_all(
+        &mut self,
+        pairs: &[(&str, &str)],
+    ) -> Result<Response> {
+        let request = Request::ReplaceAll(pairs.to_vec());
+        self.send_request(request)?;
+        self.receive(receive_response)
+    }
+
+    /// Sends the `requests` as a transaction, which the server applies without requests
+    /// of other connections interleaving, and returns their responses in order.
+    /// Requests failing while being applied are answered with an error response, whereas
+    /// a request rejected while being queued discards the transaction and is returned as error.
+    pub fn multi_exec(
+        &mut self,
+        requests: &[Request],
+    ) -> Result<Vec<Response>> {
+        let mut bytes = Request::Multi.serialize();
+        for request in requests {
+            bytes.extend(request.try_serialize()?);
+        }
+        bytes.extend(Request::Exec.serialize());
+        for response in self.send_batch(&bytes, requests.len() + 2)? {
+            match response {
+                Response::Multi | Response::Queued => {}
+                Response::Exec(responses) => return Ok(responses),
+                Response::Error { code, message } => return Err(server_error(code, message)),
+                _ => return Err(ClientError::UnexpectedResponse.into()),
+            }
+        }
+        Err(ClientError::UnexpectedResponse.into())
+    }
+
+    /// Checks for each of the `keys` whether it exists in one request.
+    /// The result is in the order of `keys`.
+    pub fn exists_many(
+        &mut self,
+        keys: &[&str],
+    ) -> Result<Response> {
+        let request = Request::MExists(keys.to_vec());
+        self.send_request(request)?;
+        self.receive(receive_response)
+    }
+
+    /// Sets the expiry of all existing `keys` to `ttl` from now in one request.
+    /// The `ttl` is sent with millisecond precision.
+    pub fn touch_many(
+        &mut self,
+        keys: &[&str],
+        ttl: Duration,
+    ) -> Result<Response> {
+        let ttl_ms = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+        let request = Request::MTouch {
+            keys: keys.to_vec(),
+            ttl_ms,
+        };
+        self.send_request(request)?;
+        self.receive(receive_response)
+    }
+
+    /// Counts the keys starting with `prefix`, an empty `prefix` counts all keys.
+    /// The server iterates over all keys, so this takes time linear in the database size.
+    pub fn count_prefix(
+        &mut self,
+        prefix: &str,
+    ) -> Result<Response> {
+        let request = Request::CountPrefix(prefix);
+        self.send_request(request)?;
+        self.receive(receive_response)
+    }
+
+    /// Gets all keys starting with `prefix` and their values, sorted by key.
+    /// An empty `prefix` gets all keys.
+    /// The server iterates over all keys and sends all matches in one response,
+    /// so this takes time linear in the database size and is limited by the maximum buffer size.
+    pub fn get_prefix(
+        &mut self,
+        prefix: &str,
+    ) -> Result<Response> {
+        let request = Request::GetPrefix(prefix);
+        self.send_request(request)?;
+        self.receive(receive_response)
+    }
+
+    /// Requests information about the server, e.g. its version and uptime.
+    pub fn info(&mut self) -> Result<ServerInfo> {
+        let request = Request::Info;
+        self.send_request(request)?;
+        match self.receive(receive_response)? {
+            Response::Info(info) => Ok(info),
+            _ => Err(ClientError::UnexpectedResponse.into()),
+        }
+    }
+
+    /// Selects the database with the given `index` for all following requests.
+    pub fn select(
+        &mut self,
+        index: u32,
+    ) -> Result<Response> {
+        let request = Request::Select(index);
+        self.send_request(request)?;
+        self.receive(receive_response)
+    }
+
+    /// Clears all databases, not only the selected one.
+    pub fn flush_all(&mut self) -> Result<Response> {
+        let request = Request::FlushAll;
+        self.send_request(request)?;
+        self.receive(receive_response)
+    }
+
+    /// Requests up to `count` keys starting at `cursor`.
+    /// A scan starts with a cursor of `0` and is complete once the returned cursor is `0` again.
+    pub fn scan(
+        &mut self,
+        cursor: u64,
+        count: u32,
+    ) -> Result<Response> {
+        let request = Request::Scan { cursor, count };
+        self.send_request(request)?;
+        self.receive(receive_response)
+    }
+
+    /// Returns an iterator over all keys in the database.
+    /// The keys are fetched lazily page by page with [`scan`].
+    /// Every key is returned exactly once as long as the database is not modified concurrently.
+    /// The iterator stops after yielding the first error.
+    ///
+    /// [`scan`]: Client::scan
+    pub fn scan_iter(&mut self) -> impl Iterator<Item = Result<String>> + '_ {
+        ScanIter {
+            client: self,
+            cursor: 0,
+            keys: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    /// Sends the already serialized `bytes` as they are and receives the response.
+    /// The bytes are not validated, so they should contain exactly one complete request.
+    /// This is useful for replaying captured traffic or fuzzing the server.
+    /// See [`Serialize`](crate::Serialize) for serializing a [`Request`].
+    pub fn send_raw(
+        &mut self,
+        bytes: &[u8],
+    ) -> Result<Response> {
+        self.write_request(bytes)?;
+        self.receive(receive_response)
+    }
+
+    /// Watches the `keys` of the selected database: whenever another connection writes one of them,
+    /// the server pushes an invalidation for it, see [`next_invalidation`](Client::next_invalidation).
+    /// The keys stay watched until the connection is closed.
+    pub fn watch(
+        &mut self,
+        keys: &[&str],
+    ) -> Result<Response> {
+        let request = Request::Watch(keys.to_vec());
+        self.send_request(request)?;
+        self.receive(receive_response)
+    }
+
+    /// Returns the key of the next invalidation pushed for a watched key,
+    /// waiting for one if none was received yet.
+    /// Invalidations arriving while receiving responses are kept until they are taken.
+    pub fn next_invalidation(&mut self) -> Result<String> {
+        // Keeps the keepalive from reading the invalidation while waiting
+        if let Some(keepalive) = &self.keepalive {
+            keepalive.lock().awaiting_response = true;
+        }
+        self.receive(|stream, inbox, init_buffer_size, max_buffer_size| {
+            receive_with(
+                stream,
+                inbox,
+                init_buffer_size,
+                max_buffer_size,
+                |_, inbox| Ok(inbox.invalidations.pop_front().map(|key| (key, 0))),
+            )
+        })
+    }
+
+    /// Returns the keys of the invalidations received so far without waiting for more.
+    pub fn take_invalidations(&mut self) -> Vec<String> {
+        let mut inbox = self.inbox.lock().unwrap_or_else(|e| e.into_inner());
+        inbox.invalidations.drain(..).collect()
+    }
+
+    /// Sends a `Ping` and waits for the `Pong`.
+    pub fn ping(&mut self) -> Result<()> {
+        self.send_request(Request::Ping)?;
+        match self.receive(receive_response)? {
+            Response::Pong => Ok(()),
+            _ => Err(ClientError::UnexpectedResponse.into()),
+        }
+    }
+
+    fn send_request(
+        &mut self,
+        request: Request,
+    ) -> Result<()> {
+        let request_bytes = request.try_serialize()?;
+        if let Err(e) = self.write_request(&request_bytes) {
+            // The server may have rejected the request and closed the connection
+            // before it was written completely.
+            return match self.receive(receive_response) {
+                Err(Error::Client(ClientError::TooMuchData)) => {
+                    Err(ClientError::TooMuchData.into())
+                }
+                _ => Err(e.into()),
+            };
+        }
+        Ok(())
+    }
+
+    /// Sends the `count` already serialized requests in `bytes` at once and receives all responses.
+    /// Error responses are returned as they are, so that one failed request does not hide
+    /// the responses to the others.
+    fn send_batch(
+        &mut self,
+        bytes: &[u8],
+        count: usize,
+    ) -> Result<Vec<Response>> {
+        self.write_request(bytes)?;
+        self.receive(|stream, inbox, init_buffer_size, max_buffer_size| {
+            let mut responses = Vec::with_capacity(count);
+            let mut parsed = 0;
+            receive_with(
+                stream,
+                inbox,
+                init_buffer_size,
+                max_buffer_size,
+                |input, inbox| {
+                    while responses.len() < count {
+                        // Invalidations can also be pushed in between the responses
+                        parsed += inbox.take_pushed(&input[parsed..])?;
+                        let Some((response, len)) = parse_response(&input[parsed..])? else {
+                            return Ok(None);
+                        };
+                        responses.push(response);
+                        parsed += len;
+                    }
+                    Ok(Some((mem::take(&mut responses), parsed)))
+                },
+            )
+        })
+    }
+
+    /// Writes the serialized request to the stream.
+    /// With a keepalive this waits for a running keepalive ping to finish first
+    /// and marks the connection as busy until the response was received.
+    fn write_request(
+        &mut self,
+        bytes: &[u8],
+    ) -> io::Result<()> {
+        let _activity = match &self.keepalive {
+            Some(keepalive) => {
+                let mut activity = keepalive.lock();
+                activity.awaiting_response = true;
+                Some(activity)
+            }
+            None => None,
+        };
+        self.stream.write_all(bytes)?;
+        self.stream.flush()
+    }
+
+    /// Receives the response to the last request with `receive`.
+    /// With a keepalive the connection is marked as idle again afterwards, also if receiving fails.
+    fn receive<T>(
+        &mut self,
+        receive: impl FnOnce(&mut S, &mut Inbox, usize, usize) -> Result<T>,
+    ) -> Result<T> {
+        let mut inbox = self.inbox.lock().unwrap_or_else(|e| e.into_inner());
+        let result = receive(
             &mut self.stream,
+            &mut inbox,
             self.init_buffer_size,
             self.max_buffer_size,
-        )
+        );
+        drop(inbox);
+        if let Some(keepalive) = &self.keepalive {
+            let mut activity = keepalive.lock();
+            activity.awaiting_response = false;
+            activity.last_used = Instant::now();
+        }
+        result
     }
+}
 
-    fn send_request(
+/// A [`Client`] queuing writes and sending them as a single pipelined batch,
+/// so that a batch costs one round trip instead of one per request.
+///
+/// The batch is sent by [`flush`](BatchingClient::flush) or automatically when queuing a request
+/// if the limits set with [`ClientBuilder::batch_max_bytes`] or [`ClientBuilder::batch_max_delay`]
+/// are reached. The delay is only checked when queuing, so a batch is not sent while the client is idle.
+/// Requests still queued when the `BatchingClient` is dropped are discarded.
+pub struct BatchingClient<S = TcpStream> {
+    client: Client<S>,
+    requests: RequestEncoder,
+    queued: usize,
+    // When the first request of the current batch was queued.
+    batch_started_at: Option<Instant>,
+    max_bytes: Option<usize>,
+    max_delay: Option<Duration>,
+    // The responses to batches sent automatically since the last `flush`.
+    responses: Vec<Response>,
+}
+
+impl<S> BatchingClient<S>
+where
+    S: Read,
+    S: Write,
+{
+    /// Wraps `client`. Batches are only sent on [`flush`](BatchingClient::flush).
+    pub fn new(client: Client<S>) -> Self {
+        Self {
+            client,
+            requests: RequestEncoder::new(),
+            queued: 0,
+            batch_started_at: None,
+            max_bytes: None,
+            max_delay: None,
+            responses: Vec::new(),
+        }
+    }
+
+    /// Queues a `Set` request, sending the batch if it reached its limits.
+    ///
+    /// # Errors
+    /// If the `key` or `value` is too long to be encoded, an [`Error::Serialization`] is returned
+    /// and the request is not queued. Otherwise, only returns an error if the batch was sent
+    /// and sending or receiving the responses failed.
+    pub fn queue_set(
         &mut self,
-        request: Request,
-    ) {
-        let request_bytes = serialize_request(request);
-        self.stream.write_all(&request_bytes).unwrap();
-        self.stream.flush().unwrap();
+        key: &str,
+        value: &str,
+    ) -> Result<()> {
+        self.requests.set(key, value)?;
+        self.queued += 1;
+        let batch_started_at = *self.batch_started_at.get_or_insert_with(Instant::now);
+        let is_full = self
+            .max_bytes
+            .is_some_and(|max_bytes| self.requests.as_bytes().len() >= max_bytes);
+        let is_due = self
+            .max_delay
+            .is_some_and(|max_delay| batch_started_at.elapsed() >= max_delay);
+        if is_full || is_due {
+            self.send_batch()?;
+        }
+        Ok(())
+    }
+
+    /// Returns the number of requests waiting to be sent.
+    pub fn queued(&self) -> usize {
+        self.queued
+    }
+
+    /// Sends all queued requests and returns the responses to every request queued since the last
+    /// `flush`, including the ones sent automatically, in the order the requests were queued.
+    /// A request the server failed to apply is answered with a [`Response::Error`].
+    ///
+    /// Unlike [`Client::flush`] this does not send a `Flush` request.
+    pub fn flush(&mut self) -> Result<Vec<Response>> {
+        self.send_batch()?;
+        Ok(mem::take(&mut self.responses))
+    }
+
+    /// Returns the wrapped client. Queued requests are discarded.
+    pub fn into_inner(self) -> Client<S> {
+        self.client
+    }
+
+    fn send_batch(&mut self) -> Result<()> {
+        if self.queued == 0 {
+            return Ok(());
+        }
+        let count = mem::take(&mut self.queued);
+        self.batch_started_at = None;
+        let result = self.client.send_batch(self.requests.as_bytes(), count);
+        self.requests.clear();
+        self.responses.extend(result?);
+        Ok(())
+    }
+}
+
+/// The state shared between a [`Client`] and its keepalive thread.
+#[derive(Debug)]
+struct Activity {
+    // Set while a request of the client waits for its response.
+    awaiting_response: bool,
+    last_used: Instant,
+}
+
+/// What a [`Client`] read from its connection besides the responses to its requests.
+#[derive(Debug, Default)]
+struct Inbox {
+    // The bytes read past the last frame, i.e. the start of the next one.
+    unread: Vec<u8>,
+    // The keys of the invalidations pushed by the server that were not taken yet.
+    invalidations: VecDeque<String>,
+}
+
+impl Inbox {
+    /// Moves the complete invalidations at the start of `input` into the inbox
+    /// and returns the number of bytes they took up.
+    fn take_pushed(
+        &mut self,
+        input: &[u8],
+    ) -> Result<usize> {
+        let mut pushed = 0;
+        while input.get(pushed) == Some(&INVALIDATE_OPCODE) {
+            let Some((Response::Invalidate(key), len)) = parse_response(&input[pushed..])? else {
+                break;
+            };
+            self.invalidations.push_back(key);
+            pushed += len;
+        }
+        Ok(pushed)
+    }
+}
+
+/// The opcode of a [`Response::Invalidate`], which the server pushes without a request.
+const INVALIDATE_OPCODE: u8 = 32;
+
+/// Sends `Ping`s over a connection once it was idle for `interval`, see [`ClientBuilder::keepalive_interval`].
+#[derive(Debug)]
+struct Keepalive {
+    interval: Duration,
+    activity: Arc<Mutex<Activity>>,
+}
+
+impl Keepalive {
+    /// Spawns a thread sending the `Ping`s over a clone of `stream`.
+    /// The thread stops within `interval` once the returned `Keepalive` was dropped
+    /// or right away if the connection failed.
+    fn spawn(
+        stream: &TcpStream,
+        interval: Duration,
+        init_buffer_size: usize,
+        inbox: &Arc<Mutex<Inbox>>,
+    ) -> io::Result<Self> {
+        let mut stream = stream.try_clone()?;
+        let inbox = Arc::clone(inbox);
+        let activity = Arc::new(Mutex::new(Activity {
+            awaiting_response: false,
+            last_used: Instant::now(),
+        }));
+        let weak_activity = Arc::downgrade(&activity);
+        thread::spawn(move || {
+            let ping = Request::Ping.serialize();
+            let mut sleep_for = interval;
+            loop {
+                thread::sleep(sleep_for);
+                let Some(activity) = weak_activity.upgrade() else {
+                    return;
+                };
+                let mut activity = activity.lock().unwrap_or_else(|e| e.into_inner());
+                let idle_for = activity.last_used.elapsed();
+                if activity.awaiting_response || idle_for < interval {
+                    sleep_for = interval
+                        .saturating_sub(idle_for)
+                        .max(Duration::from_millis(1));
+                    continue;
+                }
+                // Holding the lock keeps the client from sending until the `Pong` was read.
+                let pong = stream.write_all(&ping).map_err(Error::from).and_then(|_| {
+                    let mut inbox = inbox.lock().unwrap_or_else(|e| e.into_inner());
+                    receive_response(&mut stream, &mut inbox, init_buffer_size, init_buffer_size)
+                });
+                if !matches!(pong, Ok(Response::Pong)) {
+                    return;
+                }
+                activity.last_used = Instant::now();
+                sleep_for = interval;
+            }
+        });
+        Ok(Self { interval, activity })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Activity> {
+        self.activity.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// The number of keys requested per page by [`ScanIter`].
+const SCAN_PAGE_SIZE: u32 = 128;
+
+/// An iterator over all keys in the database, see [`Client::scan_iter`].
+struct ScanIter<'a, S> {
+    client: &'a mut Client<S>,
+    cursor: u64,
+    keys: VecDeque<String>,
+    done: bool,
+}
+
+impl<S> Iterator for ScanIter<'_, S>
+where
+    S: Read,
+    S: Write,
+{
+    type Item = Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(key) = self.keys.pop_front() {
+                return Some(Ok(key));
+            }
+            if self.done {
+                return None;
+            }
+            match self.client.scan(self.cursor, SCAN_PAGE_SIZE) {
+                Ok(Response::Scan { cursor, keys }) => {
+                    self.cursor = cursor;
+                    self.done = cursor == 0;
+                    self.keys.extend(keys);
+                }
+                Ok(_) => {
+                    self.done = true;
+                    return Some(Err(ClientError::UnexpectedResponse.into()));
+                }
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
     }
 }
 
 fn receive_response<R: Read>(
     stream: &mut R,
+    inbox: &mut Inbox,
     init_buffer_size: usize,
     max_buffer_size: usize,
 ) -> Result<Response> {
-    let mut buffer = vec![0; init_buffer_size];
+    let response = receive_with(
+        stream,
+        inbox,
+        init_buffer_size,
+        max_buffer_size,
+        |input, _| Ok(parse_response(input)?),
+    )?;
+    match response {
+        Response::Error { code, message } => Err(server_error(code, message)),
+        response => Ok(response),
+    }
+}
+
+/// Maps an error response to the corresponding [`ClientError`].
+fn server_error(
+    code: ErrorCode,
+    message: Option<String>,
+) -> Error {
+    match code {
+        ErrorCode::TooMuchData => ClientError::TooMuchData.into(),
+        code => ClientError::Server { code, message }.into(),
+    }
+}
+
+/// Parses a `Get` response and copies the value into `out`.
+/// Returns `None` if the buffer does not contain the full response yet.
+fn parse_get_into(
+    input: &[u8],
+    out: &mut String,
+) -> Result<Option<(bool, usize)>> {
+    match input.first() {
+        None => Ok(None),
+        Some(1) => {
+            let mut cursor = 1;
+            let Some(value) = read_element(input, &mut cursor)? else {
+                return Ok(None);
+            };
+            // A missing value is sent with a length of 0
+            out.push_str(value);
+            Ok(Some((!value.is_empty(), cursor)))
+        }
+        Some(_) => match parse_response(input)? {
+            None => Ok(None),
+            Some((Response::Error { code, message }, _)) => Err(server_error(code, message)),
+            Some(_) => Err(ClientError::UnexpectedResponse.into()),
+        },
+    }
+}
+
+/// Reads a `Get` response from the `stream` and copies the value into `sink`.
+/// Only other responses, e.g. errors, are buffered up to `max_buffer_size`.
+fn receive_get_streaming<R: Read, W: Write + ?Sized>(
+    stream: &mut R,
+    inbox: &mut Inbox,
+    init_buffer_size: usize,
+    max_buffer_size: usize,
+    sink: &mut W,
+) -> Result<bool> {
+    // The start of the response may have been read already
+    let unread = mem::take(&mut inbox.unread);
+    let mut not_yet_read = unread.as_slice();
+    let mut stream = (&mut not_yet_read).chain(stream);
+    let result = copy_get_value(&mut stream, inbox, init_buffer_size, max_buffer_size, sink);
+    // Anything read ahead meanwhile came before what is left
+    inbox.unread.extend_from_slice(not_yet_read);
+    result
+}
+
+fn copy_get_value<R: Read, W: Write + ?Sized>(
+    stream: &mut R,
+    inbox: &mut Inbox,
+    init_buffer_size: usize,
+    max_buffer_size: usize,
+    sink: &mut W,
+) -> Result<bool> {
+    let mut opcode = [0; 1];
+    loop {
+        stream.read_exact(&mut opcode).map_err(read_error)?;
+        match opcode[0] {
+            1 => break,
+            // An invalidation pushed before the response
+            INVALIDATE_OPCODE => {
+                let mut len = [0; 4];
+                stream.read_exact(&mut len).map_err(read_error)?;
+                let len = u32::from_be_bytes(len) as usize;
+                if len > max_buffer_size {
+                    return Err(ClientError::TooMuchData.into());
+                }
+                let mut key = vec![0; len];
+                stream.read_exact(&mut key).map_err(read_error)?;
+                let key = String::from_utf8(key).map_err(|_| ClientError::UnexpectedResponse)?;
+                inbox.invalidations.push_back(key);
+            }
+            _ => {
+                let mut stream = opcode.chain(stream);
+                receive_response(&mut stream, inbox, init_buffer_size, max_buffer_size)?;
+                return Err(ClientError::UnexpectedResponse.into());
+            }
+        }
+    }
+    let mut len = [0; 4];
+    stream.read_exact(&mut len).map_err(read_error)?;
+    let len = u64::from(u32::from_be_bytes(len));
+    let copied = io::copy(&mut stream.take(len), sink).map_err(read_error)?;
+    if copied < len {
+        return Err(ClientError::ConnectionResetByPeer.into());
+    }
+    // A missing value is sent with a length of 0
+    Ok(len > 0)
+}
+
+/// Maps an error while reading a response to the corresponding [`ClientError`].
+fn read_error(e: io::Error) -> Error {
+    match e.kind() {
+        // A read timeout is reported as one of these depending on the platform
+        ErrorKind::WouldBlock | ErrorKind::TimedOut => ClientError::Timeout.into(),
+        ErrorKind::UnexpectedEof => ClientError::ConnectionResetByPeer.into(),
+        _ => e.into(),
+    }
+}
+
+/// How [`Client::reconnect`] retries, see [`ClientBuilder::reconnect_attempts`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct ReconnectBackoff {
+    // The number of attempts, including the first one.
+    attempts: u32,
+    base: Duration,
+    cap: Duration,
+}
+
+impl Default for ReconnectBackoff {
+    fn default() -> Self {
+        Self {
+            attempts: 1,
+            base: Duration::from_millis(100),
+            cap: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Returns the delay before the retry following the failed `attempt`, counted from 0, with "full jitter":
+/// a uniformly distributed delay between zero and `base * 2^attempt`, but at most `cap`.
+/// `random` returns uniformly distributed numbers.
+fn backoff_delay(
+    attempt: u32,
+    base: Duration,
+    cap: Duration,
+    random: &mut impl FnMut() -> u64,
+) -> Duration {
+    let ceiling = 2u32
+        .checked_pow(attempt)
+        .and_then(|factor| base.checked_mul(factor))
+        .map_or(cap, |ceiling| ceiling.min(cap));
+    // Delays beyond `u64::MAX` nanoseconds, i.e. centuries, are not worth distinguishing
+    let ceiling = ceiling.as_nanos().min(u64::MAX as u128 - 1);
+    // Scales the random number to `0..=ceiling` without the bias of a remainder
+    let nanos = (u128::from(random()) * (ceiling + 1)) >> 64;
+    Duration::from_nanos(nanos as u64)
+}
+
+/// How long a connection attempt is waited for before the next address is tried in parallel,
+/// as recommended by RFC 8305, see [`ClientBuilder::connect_stagger`].
+const CONNECT_STAGGER: Duration = Duration::from_millis(250);
+
+/// How long a single attempt racing other addresses is waited for at most, so that a losing
+/// attempt gives up its thread well before the operating system's timeout would.
+const RACING_ATTEMPT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connects to the first of the addresses `addr` resolves to that accepts the connection, like
+/// "Happy Eyeballs" (RFC 8305): each attempt starts `stagger` after the previous one or as soon
+/// as it failed, alternating between IPv6 and IPv4 addresses. Each attempt gives up after
+/// [`RACING_ATTEMPT_TIMEOUT`], and the connections of attempts succeeding after another one
+/// are closed right away. A single address is connected to directly, without a thread.
+///
+/// Fails with [`ErrorKind::TimedOut`] if no attempt succeeded within `timeout`,
+/// or with the error of the last attempt if all of them failed.
+fn connect_racing<A: ToSocketAddrs>(
+    addr: A,
+    stagger: Duration,
+    timeout: Option<Duration>,
+) -> io::Result<TcpStream> {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let addrs = interleave_families(addr.to_socket_addrs()?);
+    if let [addr] = addrs[..] {
+        return match timeout {
+            Some(timeout) => TcpStream::connect_timeout(&addr, timeout),
+            None => TcpStream::connect(addr),
+        };
+    }
+    let mut addrs = addrs.into_iter();
+    let (sender, receiver) = mpsc::channel();
+    let mut pending = 0;
+    let mut last_err = None;
+    // Every round starts with the next attempt, i.e. after the previous one failed or the stagger elapsed
+    loop {
+        let remaining = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        if remaining == Some(Duration::ZERO) {
+            return Err(io::Error::new(ErrorKind::TimedOut, "connecting timed out"));
+        }
+        if let Some(addr) = addrs.next() {
+            let sender = sender.clone();
+            let attempt_timeout = remaining.map_or(RACING_ATTEMPT_TIMEOUT, |remaining| {
+                remaining.min(RACING_ATTEMPT_TIMEOUT)
+            });
+            thread::spawn(move || {
+                let result = TcpStream::connect_timeout(&addr, attempt_timeout);
+                // The receiver is gone if another attempt succeeded first
+                let _ = sender.send(result);
+            });
+            pending += 1;
+        }
+        if pending == 0 {
+            return Err(last_err.unwrap_or_else(|| {
+                io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "could not resolve to any addresses",
+                )
+            }));
+        }
+        let mut wait = if addrs.as_slice().is_empty() {
+            Duration::MAX
+        } else {
+            stagger
+        };
+        if let Some(remaining) = remaining {
+            wait = wait.min(remaining);
+        }
+        match receiver.recv_timeout(wait) {
+            Ok(Ok(stream)) => return Ok(stream),
+            Ok(Err(e)) => {
+                pending -= 1;
+                last_err = Some(e);
+            }
+            // The stagger or the timeout elapsed, the sender is never disconnected
+            Err(_) => {}
+        }
+    }
+}
+
+/// Orders `addrs` alternating between IPv6 and IPv4, starting with the family of the first address,
+/// so that an unreachable family delays the connection by one stagger at most.
+fn interleave_families(addrs: impl Iterator<Item = SocketAddr>) -> Vec<SocketAddr> {
+    let addrs: Vec<SocketAddr> = addrs.collect();
+    let Some(first_is_ipv6) = addrs.first().map(SocketAddr::is_ipv6) else {
+        return addrs;
+    };
+    let mut ordered = Vec::with_capacity(addrs.len());
+    let (preferred, other): (Vec<SocketAddr>, Vec<SocketAddr>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == first_is_ipv6);
+    let mut other = other.into_iter();
+    for addr in preferred {
+        ordered.push(addr);
+        ordered.extend(other.next());
+    }
+    ordered.extend(other);
+    ordered
+}
+
+/// Reads from the `stream` until `parse` returns a value and the length of the frame it was parsed from,
+/// growing the buffer up to `max_buffer_size`.
+/// Invalidations pushed before the frame are moved into the `inbox`, as are the bytes read past it,
+/// so that the next call continues with them.
+fn receive_with<R: Read, T>(
+    stream: &mut R,
+    inbox: &mut Inbox,
+    init_buffer_size: usize,
+    max_buffer_size: usize,
+    mut parse: impl FnMut(&[u8], &mut Inbox) -> Result<Option<(T, usize)>>,
+) -> Result<T> {
+    let mut buffer = mem::take(&mut inbox.unread);
+    let mut cursor = buffer.len();
+    buffer.resize(cursor.max(init_buffer_size), 0);
     loop {
-        let bytes_read = stream.read(&mut buffer)?;
-        if let Some(response) = parse_response(&buffer)? {
-            return Ok(response);
+        let pushed = inbox.take_pushed(&buffer[..cursor])?;
+        buffer.copy_within(pushed..cursor, 0);
+        cursor -= pushed;
+        match parse(&buffer[..cursor], inbox) {
+            Ok(Some((parsed, len))) => {
+                inbox.unread = buffer[len..cursor].to_vec();
+                return Ok(parsed);
+            }
+            Ok(None) => {}
+            Err(e) => {
+                // Skip the frame the error was raised for, e.g. an unexpected response
+                if let Ok(Some((_, len))) = parse_response(&buffer[..cursor]) {
+                    inbox.unread = buffer[len..cursor].to_vec();
+                }
+                return Err(e);
+            }
+        }
+        if cursor == buffer.len() {
+            if buffer.len() >= max_buffer_size {
+                return Err(ClientError::TooMuchData.into());
+            }
+            buffer.resize(buffer.len() * 2, 0);
         }
+        let bytes_read = match stream.read(&mut buffer[cursor..]) {
+            Ok(bytes_read) => bytes_read,
+            // A read timeout is reported as one of these depending on the platform
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                return Err(ClientError::Timeout.into());
+            }
+            Err(e) => return Err(e.into()),
+        };
         if bytes_read == 0 {
             // Connection reset by peer:
             // No more bytes were read but we still could not parse the response
             return Err(ClientError::ConnectionResetByPeer.into());
         }
-        if buffer.len() == buffer.capacity() {
-            buffer.resize(buffer.capacity() * 2, 0);
+        cursor += bytes_read;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::io::Cursor;
+    use std::net::TcpListener;
+
+    use socket2::Domain;
+    use socket2::Socket;
+    use socket2::Type;
+
+    use super::*;
+    use crate::error::Error;
+    use crate::serialization::Serialize;
+    use crate::ErrorCode;
+
+    #[test]
+    fn test_error_response_is_surfaced_as_client_error() {
+        let bytes = Response::Error {
+            code: ErrorCode::InvalidRequest,
+            message: Some("cannot convert Utf8".to_string()),
         }
-        if buffer.len() >= max_buffer_size {
-            return Err(ClientError::TooMuchData.into());
+        .serialize();
+        let mut stream = Cursor::new(bytes);
+        let result = receive_response(&mut stream, &mut Inbox::default(), 4, 1024);
+        assert!(matches!(
+            result,
+            Err(Error::Client(ClientError::Server {
+                code: ErrorCode::InvalidRequest,
+                message: Some(message),
+            })) if message == "cannot convert Utf8"
+        ));
+    }
+
+    #[test]
+    fn test_get_streaming_copies_value_larger_than_buffer() {
+        let value = "a".repeat(100_000);
+        let mut stream = Cursor::new(Response::Get(Some(value.clone())).serialize());
+        let mut sink = Vec::new();
+        assert!(
+            receive_get_streaming(&mut stream, &mut Inbox::default(), 4, 16, &mut sink).unwrap()
+        );
+        assert_eq!(sink, value.as_bytes());
+
+        let mut stream = Cursor::new(Response::Get(None).serialize());
+        let mut sink = Vec::new();
+        assert!(
+            !receive_get_streaming(&mut stream, &mut Inbox::default(), 4, 16, &mut sink).unwrap()
+        );
+        assert!(sink.is_empty());
+    }
+
+    #[test]
+    fn test_get_streaming_surfaces_errors() {
+        let bytes = Response::Error {
+            code: ErrorCode::WrongType,
+            message: None,
         }
+        .serialize();
+        let result = receive_get_streaming(
+            &mut Cursor::new(bytes),
+            &mut Inbox::default(),
+            4,
+            1024,
+            &mut Vec::new(),
+        );
+        assert!(matches!(
+            result,
+            Err(Error::Client(ClientError::Server {
+                code: ErrorCode::WrongType,
+                ..
+            }))
+        ));
+
+        // The connection closes in the middle of the value
+        let mut bytes = Response::Get(Some("abc".to_string())).serialize();
+        bytes.pop();
+        let result = receive_get_streaming(
+            &mut Cursor::new(bytes),
+            &mut Inbox::default(),
+            4,
+            1024,
+            &mut Vec::new(),
+        );
+        assert!(matches!(
+            result,
+            Err(Error::Client(ClientError::ConnectionResetByPeer))
+        ));
+    }
+
+    #[test]
+    fn test_get_into_copies_split_value() {
+        let value = "a".repeat(100);
+        let bytes = Response::Get(Some(value.clone())).serialize();
+        let mut stream = Cursor::new(bytes);
+        let mut out = String::new();
+        let found = receive_with(&mut stream, &mut Inbox::default(), 4, 1024, |input, _| {
+            parse_get_into(input, &mut out)
+        })
+        .unwrap();
+        assert!(found);
+        assert_eq!(out, value);
+    }
+
+    #[test]
+    fn test_get_into_surfaces_error_response() {
+        let bytes = Response::Error {
+            code: ErrorCode::Internal,
+            message: None,
+        }
+        .serialize();
+        let mut stream = Cursor::new(bytes);
+        let mut out = String::new();
+        let result = receive_with(&mut stream, &mut Inbox::default(), 4, 1024, |input, _| {
+            parse_get_into(input, &mut out)
+        });
+        assert!(matches!(
+            result,
+            Err(Error::Client(ClientError::Server {
+                code: ErrorCode::Internal,
+                message: None,
+            }))
+        ));
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn test_response_split_across_reads_is_received() {
+        let value = "a".repeat(100);
+        let bytes = Response::Get(Some(value.clone())).serialize();
+        let mut stream = Cursor::new(bytes);
+        let response = receive_response(&mut stream, &mut Inbox::default(), 4, 1024).unwrap();
+        assert_eq!(response, Response::Get(Some(value)));
+    }
+
+    #[test]
+    fn test_pushed_invalidations_are_kept_apart_from_responses() {
+        let mut bytes = Response::Invalidate("a".to_string()).serialize();
+        bytes.extend(Response::Get(Some("x".to_string())).serialize());
+        bytes.extend(Response::Invalidate("b".to_string()).serialize());
+        bytes.extend(Response::Get(Some("yz".to_string())).serialize());
+        bytes.extend(Response::Invalidate("c".to_string()).serialize());
+        bytes.extend(Response::Pong.serialize());
+        let mut stream = Cursor::new(bytes);
+        let mut inbox = Inbox::default();
+
+        // Reads ahead past the first response
+        let response = receive_response(&mut stream, &mut inbox, 16, 1024).unwrap();
+        assert_eq!(response, Response::Get(Some("x".to_string())));
+        assert_eq!(inbox.invalidations, ["a"]);
+
+        let mut sink = Vec::new();
+        assert!(receive_get_streaming(&mut stream, &mut inbox, 4, 1024, &mut sink).unwrap());
+        assert_eq!(sink, b"yz");
+        assert_eq!(inbox.invalidations, ["a", "b"]);
+
+        let response = receive_response(&mut stream, &mut inbox, 4, 1024).unwrap();
+        assert_eq!(response, Response::Pong);
+        assert_eq!(inbox.invalidations, ["a", "b", "c"]);
+        assert!(inbox.unread.is_empty());
+    }
+
+    #[test]
+    fn test_keepalive_pings_idle_connection() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut pings = 0;
+            let mut opcode = [0];
+            while stream.read_exact(&mut opcode).is_ok() {
+                assert_eq!(opcode, [21]);
+                pings += 1;
+                stream.write_all(&Response::Pong.serialize()).unwrap();
+            }
+            pings
+        });
+
+        let client = Client::builder()
+            .address(addr)
+            .keepalive_interval(Duration::from_millis(10))
+            .build()
+            .unwrap();
+        thread::sleep(Duration::from_millis(100));
+        drop(client);
+        assert!(server.join().unwrap() >= 2);
+    }
+
+    /// An in-memory stream replaying one canned response per read and recording the sent requests.
+    struct ScriptedStream {
+        responses: VecDeque<Vec<u8>>,
+        requests: Vec<u8>,
+    }
+
+    impl Read for ScriptedStream {
+        fn read(
+            &mut self,
+            buf: &mut [u8],
+        ) -> io::Result<usize> {
+            let Some(response) = self.responses.pop_front() else {
+                return Ok(0);
+            };
+            buf[..response.len()].copy_from_slice(&response);
+            Ok(response.len())
+        }
+    }
+
+    impl Write for ScriptedStream {
+        fn write(
+            &mut self,
+            buf: &[u8],
+        ) -> io::Result<usize> {
+            self.requests.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_client_works_over_any_stream() {
+        let responses = [
+            Response::Set.serialize(),
+            Response::Get(Some("1".to_string())).serialize(),
+        ];
+        let mut client = Client::from_stream(ScriptedStream {
+            responses: responses.into(),
+            requests: Vec::new(),
+        });
+        assert_eq!(client.set("abc", "1").unwrap(), Response::Set);
+        assert_eq!(
+            client.get("abc").unwrap(),
+            Response::Get(Some("1".to_string()))
+        );
+
+        let mut requests = Request::Set {
+            key: "abc",
+            value: "1",
+        }
+        .serialize();
+        requests.extend(Request::Get("abc").serialize());
+        assert_eq!(client.stream.requests, requests);
+    }
+
+    #[test]
+    fn test_batch_is_sent_at_the_byte_threshold() {
+        let set = Request::Set {
+            key: "abc",
+            value: "1",
+        }
+        .serialize();
+        // All responses to a batch arrive in a single read
+        let responses = [
+            Response::Set.serialize().repeat(3),
+            Response::Error {
+                code: ErrorCode::OutOfMemory,
+                message: None,
+            }
+            .serialize(),
+        ];
+        let mut client = BatchingClient::new(Client::from_stream(ScriptedStream {
+            responses: responses.into(),
+            requests: Vec::new(),
+        }));
+        client.max_bytes = Some(3 * set.len());
+
+        client.queue_set("abc", "1").unwrap();
+        client.queue_set("abc", "1").unwrap();
+        assert_eq!(client.queued(), 2);
+        assert!(client.client.stream.requests.is_empty());
+        client.queue_set("abc", "1").unwrap();
+        assert_eq!(client.queued(), 0);
+        assert_eq!(client.client.stream.requests, set.repeat(3));
+
+        client.queue_set("abc", "1").unwrap();
+        assert_eq!(client.queued(), 1);
+        assert_eq!(
+            client.flush().unwrap(),
+            [
+                Response::Set,
+                Response::Set,
+                Response::Set,
+                Response::Error {
+                    code: ErrorCode::OutOfMemory,
+                    message: None,
+                },
+            ]
+        );
+        assert_eq!(client.client.stream.requests, set.repeat(4));
+        assert_eq!(client.flush().unwrap(), []);
+    }
+
+    #[test]
+    fn test_batch_is_sent_after_the_delay() {
+        let mut client = BatchingClient::new(Client::from_stream(ScriptedStream {
+            responses: [Response::Set.serialize().repeat(2)].into(),
+            requests: Vec::new(),
+        }));
+        client.max_delay = Some(Duration::from_millis(10));
+        client.queue_set("abc", "1").unwrap();
+        thread::sleep(Duration::from_millis(10));
+        client.queue_set("def", "2").unwrap();
+        assert_eq!(client.queued(), 0);
+        assert_eq!(client.flush().unwrap(), [Response::Set, Response::Set]);
+    }
+
+    /// Binds a listener whose backlog is full, so that connecting to it hangs.
+    fn unreachable_listener() -> (Socket, SocketAddr) {
+        let listener = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+        listener
+            .bind(&SocketAddr::from(([127, 0, 0, 1], 0)).into())
+            .unwrap();
+        listener.listen(0).unwrap();
+        let addr = listener.local_addr().unwrap().as_socket().unwrap();
+        // Takes the only place in the backlog, later connection attempts are dropped
+        mem::forget(TcpStream::connect(addr).unwrap());
+        (listener, addr)
+    }
+
+    #[test]
+    fn test_connecting_races_the_resolved_addresses() {
+        let (_dead, dead_addr) = unreachable_listener();
+        let live = TcpListener::bind("127.0.0.1:0").unwrap();
+        let live_addr = live.local_addr().unwrap();
+        let start = Instant::now();
+        let client = Client::builder()
+            .address(&[dead_addr, live_addr][..])
+            .connect_stagger(Duration::from_millis(50))
+            .connect_timeout(Duration::from_secs(10))
+            .build()
+            .unwrap();
+        assert_eq!(client.peer, Some(live_addr));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_connecting_fails_after_the_connect_timeout() {
+        let (_dead, dead_addr) = unreachable_listener();
+        let start = Instant::now();
+        let result = Client::builder()
+            .address(dead_addr)
+            .connect_timeout(Duration::from_millis(100))
+            .build();
+        assert!(matches!(result, Err(Error::IO(e)) if e.kind() == ErrorKind::TimedOut));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_address_families_are_interleaved() {
+        let v4 = |port| SocketAddr::from(([127, 0, 0, 1], port));
+        let v6 = |port| SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 1], port));
+        let addrs = [v6(1), v6(2), v6(3), v4(4)];
+        assert_eq!(
+            interleave_families(addrs.into_iter()),
+            [v6(1), v4(4), v6(2), v6(3)]
+        );
+    }
+    #[test]
+    fn test_backoff_delays_grow_up_to_the_cap() {
+        let base = Duration::from_millis(100);
+        let cap = Duration::from_secs(1);
+        let longest: Vec<Duration> = (0..6)
+            .map(|attempt| backoff_delay(attempt, base, cap, &mut || u64::MAX))
+            .collect();
+        let expected = [100, 200, 400, 800, 1000, 1000].map(Duration::from_millis);
+        assert_eq!(longest, expected);
+        // The factor overflows long before
+        assert_eq!(backoff_delay(40, base, cap, &mut || u64::MAX), cap);
+        assert_eq!(backoff_delay(3, base, cap, &mut || 0), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_backoff_delays_stay_within_the_jitter_bounds() {
+        let base = Duration::from_millis(10);
+        let cap = Duration::from_secs(5);
+        // xorshift64, seeded arbitrarily
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let mut random = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        for attempt in 0..20 {
+            let bound = (base * 2u32.pow(attempt)).min(cap);
+            let delays: HashSet<Duration> = (0..100)
+                .map(|_| backoff_delay(attempt, base, cap, &mut random))
+                .collect();
+            assert!(delays.iter().all(|delay| *delay <= bound), "{attempt}");
+            assert!(delays.len() > 90, "delays are not spread out");
+        }
+    }
+
+    #[test]
+    fn test_reconnect_retries_until_the_server_is_back() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut client = Client::builder()
+            .address(addr)
+            .reconnect_attempts(100)
+            .reconnect_backoff_base(Duration::from_millis(20))
+            .reconnect_backoff_cap(Duration::from_millis(20))
+            .build()
+            .unwrap();
+        drop(listener);
+        let restart = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            TcpListener::bind(addr).unwrap()
+        });
+        client.reconnect().unwrap();
+        assert_eq!(client.peer, Some(addr));
+        restart.join().unwrap();
+    }
+
+    #[test]
+    fn test_reconnect_does_not_retry_by_default() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = Client::builder()
+            .address(listener.local_addr().unwrap())
+            .build()
+            .unwrap();
+        drop(listener);
+        assert!(client.reconnect().is_err());
     }
 }
@@ -1,52 +1,1184 @@
+use std::borrow::Cow;
+use std::collections::hash_map;
+use std::collections::hash_map::OccupiedEntry;
+use std::collections::hash_map::RandomState;
+use std::collections::hash_map::VacantEntry;
 use std::collections::HashMap;
+use std::fmt;
+use std::hash::BuildHasher;
+use std::mem;
 use std::ops::Deref;
+use std::str;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::OnceLock;
 use std::sync::RwLock;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
 
 use crate::error::DatabaseError;
 use crate::error::Result;
 use crate::error::ServerError;
+use crate::striped::StripedLock;
+use crate::GetModified;
+use crate::KeyMeta;
 
 /// The main trait to interact with the in-memory database.
+///
+/// Every operation is atomic with respect to the others. Concurrent writes to the same key,
+/// for example `set`s from different connections, have last-writer-wins semantics:
+/// the key ends up holding exactly one of the written values, never a mix of them.
+/// Which writer wins is not specified, as the order of operations across connections is not.
+///
+/// The provided methods are built on the required ones, so their default implementations
+/// are not atomic as a whole. [`DB`] overrides all of them, and so should other implementations
+/// that can do better.
 pub trait Database: Send + Sync {
     /// Gets the `key`'s value from the database.
     /// Returns `None` if the ket does not exist.
     fn get(
         &self,
         key: &str,
-    ) -> Result<Option<String>>;
+    ) -> Result<Option<Value>>;
+
+    /// Gets the textual representation of the `key`'s value like [`get`](Database::get),
+    /// but as a shared string that repeated reads of an unchanged value do not copy.
+    /// Returns `None` if the key does not exist.
+    ///
+    /// # Errors
+    /// Fails with [`DatabaseError::NotUtf8`] for [`Value::Bytes`] that are not valid Utf8.
+    fn get_arc(
+        &self,
+        key: &str,
+    ) -> Result<Option<Arc<str>>>;
+
+    /// Gets the `key`'s value and, if the key has an expiry, extends it to `ttl` from now.
+    /// Keys without an expiry are left as they are.
+    ///
+    /// Unlike [`get`](Database::get) this needs exclusive access to the database,
+    /// so concurrent reads are blocked while it runs.
+    fn get_and_touch(
+        &self,
+        key: &str,
+        ttl: Duration,
+    ) -> Result<Option<Value>>;
+
+    /// Gets the `key`'s value and, if the key exists, sets its expiry to `ttl` from now,
+    /// also if it had none before unlike [`get_and_touch`](Database::get_and_touch).
+    /// Missing keys are left as they are.
+    ///
+    /// [`DB`] gets the value and sets the expiry under the same exclusive lock like
+    /// [`get_and_touch`](Database::get_and_touch), whereas the default implementation
+    /// [`touch_many`](Database::touch_many)es the key after getting it.
+    fn get_touch(
+        &self,
+        key: &str,
+        ttl: Duration,
+    ) -> Result<Option<Value>> {
+        let value = self.get(key)?;
+        if value.is_some() {
+            self.touch_many(&[key], ttl)?;
+        }
+        Ok(value)
+    }
+
+    /// Gets the `key`'s value together with its metadata, both read under the same lock.
+    /// Returns `None` if the key does not exist.
+    fn get_with_meta(
+        &self,
+        key: &str,
+    ) -> Result<Option<(Value, KeyMeta)>>;
+
+    /// Gets the `key`'s value only if it was last written after `since`.
+    /// The value is not cloned if it was not modified.
+    fn get_if_modified_since(
+        &self,
+        key: &str,
+        since: SystemTime,
+    ) -> Result<GetModified<Value>>;
+
+    /// Gets the bytes of the `key`'s value from `start` up to but excluding `end`.
+    /// Returns `None` if the key does not exist.
+    ///
+    /// The offsets are byte offsets into the value's textual representation, not character offsets.
+    /// Negative offsets count from the end of the value and all offsets are clamped to the value,
+    /// so a range past the end or with `start >= end` is empty.
+    /// The range is returned as a [`Value::Str`] if it is valid Utf8, e.g. when it does not split
+    /// a multi-byte character, and as [`Value::Bytes`] otherwise.
+    fn get_range(
+        &self,
+        key: &str,
+        start: i64,
+        end: i64,
+    ) -> Result<Option<Value>>;
+
+    /// Gets the length in bytes of the `key`'s value without cloning it.
+    /// Returns `None` if the key does not exist.
+    /// Like [`get_range`](Database::get_range), the length is that of the value's textual representation.
+    fn value_len(
+        &self,
+        key: &str,
+    ) -> Result<Option<usize>>;
+
+    /// Returns how often the `key`'s value was read since the key was last set,
+    /// counting [`get`](Database::get) and the other `get_*` methods returning the value.
+    /// Updates of the stored value like [`incr`](Database::incr) keep the count.
+    /// Returns `None` if the key does not exist.
+    /// The count is kept in place, so reads do not need exclusive access to update it.
+    /// Implementations may count only if configured to, like [`DB::count_accesses`], and return `0` otherwise.
+    fn access_count(
+        &self,
+        key: &str,
+    ) -> Result<Option<u64>>;
+
+    /// Gets the values of all `keys` from the database in the order of `keys`.
+    /// A value is `None` if its key does not exist.
+    /// A key requested more than once has its value returned at every position.
+    fn get_many(
+        &self,
+        keys: &[&str],
+    ) -> Result<Vec<Option<Value>>>;
+
+    /// Calls `visit` with the value of each of the `keys` in the order of `keys`
+    /// while holding the lock only once and without cloning the values.
+    /// A value is `None` if its key does not exist.
+    fn get_many_ref(
+        &self,
+        keys: &[&str],
+        visit: &mut dyn FnMut(Option<&Value>),
+    ) -> Result<()>;
 
     /// Inserts the `value` for `key`.
     /// Overwrites the potentially existing value.
     fn insert(
         &self,
         key: String,
-        value: String,
+        value: Value,
+    ) -> Result<()>;
+
+    /// Inserts the string `value` for `key` like [`insert`](Database::insert).
+    /// If `key` already holds a string, its allocations are reused instead of allocating
+    /// a new key and value, as long as the existing string has enough capacity for `value`.
+    fn insert_str(
+        &self,
+        key: &str,
+        value: &str,
+    ) -> Result<()>;
+
+    /// Overwrites the bytes of the `key`'s value starting at byte `offset` with `value`
+    /// and returns the new length of the value in bytes.
+    /// The expiry is kept, unless the implementation is configured otherwise like [`DB::reset_ttl_on_update`].
+    /// A value shorter than `offset` is padded with zero bytes and a missing key is created.
+    ///
+    /// Fails with [`DatabaseError::NotUtf8`] without changing the value if it is a string
+    /// and the write would split one of its multi-byte characters.
+    fn set_range(
+        &self,
+        key: &str,
+        offset: usize,
+        value: &str,
+    ) -> Result<usize>;
+
+    /// Inserts the `value` for `key` which expires after `ttl`.
+    /// Overwrites the potentially existing value.
+    fn insert_with_ttl(
+        &self,
+        key: String,
+        value: Value,
+        ttl: Duration,
     ) -> Result<()>;
 
+    /// Inserts all key value `pairs`, overwriting the potentially existing values.
+    /// [`DB`] inserts them while holding the lock only once,
+    /// whereas the default implementation inserts them one by one.
+    fn extend(
+        &self,
+        pairs: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<()>
+    where
+        Self: Sized,
+    {
+        for (key, value) in pairs {
+            self.insert(key, value.into())?;
+        }
+        Ok(())
+    }
+
+    /// Replaces the entire contents of the database with the key value `pairs`.
+    /// Later pairs overwrite earlier ones with the same key.
+    ///
+    /// [`DB`] builds the new contents before taking the lock and swaps them in at once, so concurrent
+    /// readers see either all of the old or all of the new entries, never an empty or
+    /// partially rebuilt database like with the default implementation, which clears the database
+    /// and [`extend`](Database::extend)s it one after the other.
+    fn replace_all(
+        &self,
+        pairs: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<()>
+    where
+        Self: Sized,
+    {
+        self.clear()?;
+        self.extend(pairs)
+    }
+
+    /// Inserts the `value` for `key` only if `key` does not exist.
+    /// Returns whether the value was inserted.
+    fn insert_if_absent(
+        &self,
+        key: String,
+        value: Value,
+    ) -> Result<bool>;
+
+    /// Replaces the value of `key` with `new` only if its current value equals `expected`.
+    /// The values are compared by their textual representations, so that for example
+    /// an integer written by [`incr`](Database::incr) matches the string of its digits.
+    /// The expiry of the key is kept.
+    /// Returns whether the value was replaced.
+    fn compare_and_swap(
+        &self,
+        key: &str,
+        expected: &Value,
+        new: Value,
+    ) -> Result<bool>;
+
+    /// Increments the integer value of `key` by `delta` and returns the new value.
+    /// A missing key is treated as `0`, the expiry of an existing key is kept,
+    /// unless the implementation is configured otherwise like [`DB::reset_ttl_on_update`].
+    ///
+    /// # Errors
+    /// Returns [`DatabaseError::NotAnInteger`] if the value is not a [`Value::Int`]
+    /// and [`DatabaseError::IntegerOverflow`] if the new value does not fit into an `i64`.
+    fn incr(
+        &self,
+        key: &str,
+        delta: i64,
+    ) -> Result<i64>;
+
+    /// Returns for each of the `keys` whether it exists, in the order of `keys`.
+    fn contains_many(
+        &self,
+        keys: &[&str],
+    ) -> Result<Vec<bool>>;
+
+    /// Sets the expiry of all existing `keys` to `ttl` from now.
+    /// Returns the number of keys that were touched.
+    fn touch_many(
+        &self,
+        keys: &[&str],
+        ttl: Duration,
+    ) -> Result<usize>;
+
+    /// Returns the number of keys starting with `prefix`.
+    /// An empty `prefix` counts all keys.
+    ///
+    /// This iterates over all keys in the database while holding its lock,
+    /// so it takes O(n) time in the number of keys and blocks writers meanwhile.
+    fn count_prefix(
+        &self,
+        prefix: &str,
+    ) -> Result<usize>;
+
+    /// Returns the keys starting with `prefix` and their values, sorted by key.
+    /// An empty `prefix` returns all keys.
+    ///
+    /// Like [`count_prefix`](Database::count_prefix) this iterates over all keys, so it takes O(n) time
+    /// in the number of keys. [`DB`] does so while holding the lock, so that all pairs are from
+    /// the same point in time, whereas the default implementation [`scan`](Database::scan)s the keys
+    /// and gets their values afterwards.
+    fn get_prefix(
+        &self,
+        prefix: &str,
+    ) -> Result<Vec<(String, Value)>> {
+        let keys: Vec<_> = all_keys(self)?
+            .into_iter()
+            .filter(|key| key.starts_with(prefix))
+            .collect();
+        let key_refs: Vec<&str> = keys.iter().map(String::as_str).collect();
+        let values = self.get_many(&key_refs)?;
+        let mut pairs: Vec<_> = keys
+            .into_iter()
+            .zip(values)
+            .filter_map(|(key, value)| Some((key, value?)))
+            .collect();
+        pairs.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        Ok(pairs)
+    }
+
     /// Removes `key` from the database.
     fn remove(
         &self,
         key: &str,
     ) -> Result<()>;
 
+    /// Removes `key` only if it was last written before `cutoff`, so that a fresher write is kept.
+    /// Returns whether the key was removed.
+    fn delete_if_older_than(
+        &self,
+        key: &str,
+        cutoff: SystemTime,
+    ) -> Result<bool>;
+
+    /// Removes `key` only if its current value equals `expected`, so that a value another writer
+    /// just stored is kept. Like in [`compare_and_swap`](Database::compare_and_swap) the values
+    /// are compared by their textual representations. Returns whether the key was removed.
+    /// The default implementation gets the value and removes the key afterwards,
+    /// so a value written in between is removed as well.
+    fn remove_if(
+        &self,
+        key: &str,
+        expected: &Value,
+    ) -> Result<bool> {
+        if !self.get(key)?.is_some_and(|value| value.text_eq(expected)) {
+            return Ok(false);
+        }
+        self.remove(key)?;
+        Ok(true)
+    }
+
+    /// Keeps only the entries for which `keep` returns `true` and returns the number of removed keys.
+    /// [`DB`] visits all entries under a single lock and removes expired entries as well,
+    /// without passing them to `keep` or counting them. The default implementation
+    /// [`scan`](Database::scan)s the keys and removes them one by one.
+    fn retain(
+        &self,
+        keep: impl Fn(&str, &Value) -> bool,
+    ) -> Result<usize>
+    where
+        Self: Sized,
+    {
+        let mut removed = 0;
+        for key in all_keys(self)? {
+            if self.get(&key)?.is_some_and(|value| !keep(&key, &value)) {
+                self.remove(&key)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
     /// Clears the entire database.
     fn clear(&self) -> Result<()>;
+
+    /// Clears the entire database and returns the keys that were removed, expired keys excluded.
+    /// This allocates all keys, so prefer [`clear`](Database::clear) if they are not needed.
+    fn clear_returning(&self) -> Result<Vec<String>>;
+
+    /// Returns whether a panic while the database was locked left it unusable.
+    fn is_poisoned(&self) -> bool;
+
+    /// Returns an estimate of the memory used by all stored keys and values in bytes.
+    /// Expired entries are counted until they are freed.
+    fn memory_usage(&self) -> Result<usize>;
+
+    /// Returns the number of stored keys.
+    /// [`DB`] counts expired keys until they are freed and takes O(1) time, whereas the default
+    /// implementation [`scan`](Database::scan)s all keys, skipping expired ones.
+    fn len(&self) -> Result<usize> {
+        let mut len = 0;
+        let mut cursor = 0;
+        loop {
+            let (next, keys) = self.scan(cursor, SCAN_BATCH_SIZE)?;
+            len += keys.len();
+            if next == 0 {
+                return Ok(len);
+            }
+            cursor = next;
+        }
+    }
+
+    /// Returns `true` if no keys are stored, see [`len`](Database::len).
+    fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Returns up to `count` keys starting at position `cursor` and the cursor to continue with.
+    /// The returned cursor is `0` once all keys were returned.
+    /// Every key is returned exactly once as long as the database is not modified during a scan.
+    /// Expired keys are skipped.
+    fn scan(
+        &self,
+        cursor: usize,
+        count: usize,
+    ) -> Result<(usize, Vec<String>)>;
+}
+
+/// The number of keys the default implementations of [`Database`] [`scan`](Database::scan) at once.
+const SCAN_BATCH_SIZE: usize = 1024;
+
+/// Returns all keys of the `db` by [`scan`](Database::scan)ning it.
+/// All keys are collected before they are used, as modifying the database during a scan may skip keys.
+fn all_keys<D: Database + ?Sized>(db: &D) -> Result<Vec<String>> {
+    let mut keys = Vec::new();
+    let mut cursor = 0;
+    loop {
+        let (next, batch) = db.scan(cursor, SCAN_BATCH_SIZE)?;
+        keys.extend(batch);
+        if next == 0 {
+            return Ok(keys);
+        }
+        cursor = next;
+    }
+}
+
+/// A typed value stored in the database.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Str(String),
+    Int(i64),
+    Bytes(Vec<u8>),
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Value::Str(value)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::Str(value.to_string())
+    }
+}
+
+impl Value {
+    /// Returns the size of the value in bytes.
+    fn len(&self) -> usize {
+        match self {
+            Value::Str(s) => s.len(),
+            Value::Int(i) => mem::size_of_val(i),
+            Value::Bytes(bytes) => bytes.len(),
+        }
+    }
+
+    /// Returns whether the textual representations of both values are equal,
+    /// e.g. of `Value::Int(5)` and `Value::Str("5")`.
+    fn text_eq(
+        &self,
+        other: &Value,
+    ) -> bool {
+        match (self, other) {
+            (Value::Int(a), Value::Int(b)) => a == b,
+            _ => self.to_bytes() == other.to_bytes(),
+        }
+    }
+
+    /// Returns the bytes of the value's textual representation.
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        match self {
+            Value::Str(s) => Cow::Borrowed(s.as_bytes()),
+            Value::Int(i) => Cow::Owned(i.to_string().into_bytes()),
+            Value::Bytes(bytes) => Cow::Borrowed(bytes),
+        }
+    }
+
+    /// Returns the length of [`to_bytes`](Value::to_bytes) without allocating.
+    fn text_len(&self) -> usize {
+        match self {
+            Value::Str(s) => s.len(),
+            Value::Int(i) => {
+                let digits = i
+                    .unsigned_abs()
+                    .checked_ilog10()
+                    .map_or(1, |log| log as usize + 1);
+                digits + usize::from(*i < 0)
+            }
+            Value::Bytes(bytes) => bytes.len(),
+        }
+    }
+
+    /// Returns whether writing the bytes from `offset` to `end` would split a character of a string value.
+    fn splits_char(
+        &self,
+        offset: usize,
+        end: usize,
+    ) -> bool {
+        match self {
+            Value::Str(s) => {
+                !s.is_char_boundary(offset.min(s.len())) || !s.is_char_boundary(end.min(s.len()))
+            }
+            Value::Int(_) | Value::Bytes(_) => false,
+        }
+    }
+
+    /// Overwrites the bytes starting at `offset` with `bytes`, padding with zero bytes up to `offset`.
+    /// Strings and integers become a string if the result is valid Utf8.
+    fn overwrite_range(
+        self,
+        offset: usize,
+        bytes: &[u8],
+    ) -> Value {
+        let (mut data, is_bytes) = match self {
+            Value::Str(s) => (s.into_bytes(), false),
+            Value::Int(i) => (i.to_string().into_bytes(), false),
+            Value::Bytes(data) => (data, true),
+        };
+        let end = offset + bytes.len();
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+        data[offset..end].copy_from_slice(bytes);
+        if is_bytes {
+            return Value::Bytes(data);
+        }
+        String::from_utf8(data)
+            .map(Value::Str)
+            .unwrap_or_else(|e| Value::Bytes(e.into_bytes()))
+    }
+
+    /// Returns the number of bytes the value allocates on the heap.
+    fn heap_size(&self) -> usize {
+        match self {
+            Value::Str(s) => s.len(),
+            Value::Int(_) => 0,
+            Value::Bytes(bytes) => bytes.len(),
+        }
+    }
+}
+
+impl From<i64> for Value {
+    fn from(value: i64) -> Self {
+        Value::Int(value)
+    }
+}
+
+impl From<Vec<u8>> for Value {
+    fn from(value: Vec<u8>) -> Self {
+        Value::Bytes(value)
+    }
+}
+
+impl TryFrom<&Value> for String {
+    type Error = DatabaseError;
+
+    /// Converts the `value` into its textual representation.
+    /// Fails for [`Value::Bytes`] that are not valid Utf8.
+    fn try_from(value: &Value) -> std::result::Result<Self, Self::Error> {
+        match value {
+            Value::Str(s) => Ok(s.clone()),
+            Value::Int(i) => Ok(i.to_string()),
+            Value::Bytes(bytes) => std::str::from_utf8(bytes)
+                .map(str::to_string)
+                .map_err(|_| DatabaseError::NotUtf8),
+        }
+    }
+}
+
+impl TryFrom<Value> for String {
+    type Error = DatabaseError;
+
+    /// Converts the `value` into its textual representation.
+    /// Fails for [`Value::Bytes`] that are not valid Utf8.
+    fn try_from(value: Value) -> std::result::Result<Self, Self::Error> {
+        match value {
+            Value::Str(s) => Ok(s),
+            Value::Int(i) => Ok(i.to_string()),
+            Value::Bytes(bytes) => String::from_utf8(bytes).map_err(|_| DatabaseError::NotUtf8),
+        }
+    }
+}
+
+impl PartialEq<str> for Value {
+    fn eq(
+        &self,
+        other: &str,
+    ) -> bool {
+        matches!(self, Value::Str(s) if s == other)
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        match self {
+            Value::Str(s) => write!(f, "{s}"),
+            Value::Int(i) => write!(f, "{i}"),
+            Value::Bytes(bytes) => write!(f, "{}", String::from_utf8_lossy(bytes)),
+        }
+    }
+}
+
+/// A value stored in the database together with its expiry, the time it was last written
+/// and how often it was read.
+#[derive(Debug)]
+pub struct Entry {
+    value: Value,
+    expires_at: Option<Instant>,
+    written_at: SystemTime,
+    // Counted while holding just the shared lock, if the database counts accesses.
+    accesses: AtomicU64,
+    // The tick of the database's access clock of the last read or write,
+    // only kept for the eviction policies that need it.
+    last_used: AtomicU64,
+    // The textual representation of the value, created by the first `get_arc` after a write.
+    // Counted in the entry's size once created.
+    shared: OnceLock<Arc<str>>,
+}
+
+impl Clone for Entry {
+    fn clone(&self) -> Self {
+        Self {
+            value: self.value.clone(),
+            expires_at: self.expires_at,
+            written_at: self.written_at,
+            accesses: AtomicU64::new(self.accesses()),
+            last_used: AtomicU64::new(self.last_used()),
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl PartialEq for Entry {
+    fn eq(
+        &self,
+        other: &Self,
+    ) -> bool {
+        self.value == other.value
+            && self.expires_at == other.expires_at
+            && self.written_at == other.written_at
+            && self.accesses() == other.accesses()
+            && self.last_used() == other.last_used()
+    }
+}
+
+impl Eq for Entry {}
+
+impl Entry {
+    /// Creates a new `Entry` that never expires.
+    pub fn new(value: Value) -> Self {
+        Self {
+            value,
+            expires_at: None,
+            written_at: SystemTime::now(),
+            accesses: AtomicU64::new(0),
+            last_used: AtomicU64::new(0),
+            shared: OnceLock::new(),
+        }
+    }
+
+    /// Creates a new `Entry` that expires after `ttl`.
+    pub fn with_ttl(
+        value: Value,
+        ttl: Duration,
+    ) -> Self {
+        Self {
+            value,
+            // A deadline too far in the future to be represented never expires
+            expires_at: Instant::now().checked_add(ttl),
+            written_at: SystemTime::now(),
+            accesses: AtomicU64::new(0),
+            last_used: AtomicU64::new(0),
+            shared: OnceLock::new(),
+        }
+    }
+
+    /// Returns the stored value.
+    pub fn value(&self) -> &Value {
+        &self.value
+    }
+
+    /// Returns the point in time the entry expires at, `None` if it never expires.
+    pub fn expires_at(&self) -> Option<Instant> {
+        self.expires_at
+    }
+
+    /// Returns the point in time the value was last written.
+    pub fn written_at(&self) -> SystemTime {
+        self.written_at
+    }
+
+    /// Returns how often the value was read, see [`Database::access_count`].
+    pub fn accesses(&self) -> u64 {
+        self.accesses.load(Ordering::Relaxed)
+    }
+
+    /// Returns the textual representation of the value as a shared string and whether this call created it.
+    /// The string is created once per write and only cloned afterwards.
+    fn shared(&self) -> std::result::Result<(Arc<str>, bool), DatabaseError> {
+        if let Some(shared) = self.shared.get() {
+            return Ok((Arc::clone(shared), false));
+        }
+        let shared: Arc<str> = match &self.value {
+            Value::Str(s) => s.as_str().into(),
+            Value::Int(i) => i.to_string().into(),
+            Value::Bytes(bytes) => str::from_utf8(bytes)
+                .map_err(|_| DatabaseError::NotUtf8)?
+                .into(),
+        };
+        // Another reader may have been faster, then its string is used.
+        let stored = self.shared.get_or_init(|| Arc::clone(&shared));
+        let created = Arc::ptr_eq(stored, &shared);
+        Ok((Arc::clone(stored), created))
+    }
+
+    /// Returns the tick of the database's access clock of the last read or write.
+    fn last_used(&self) -> u64 {
+        self.last_used.load(Ordering::Relaxed)
+    }
+
+    /// Replaces the value and records the time of the write.
+    fn set_value(
+        &mut self,
+        value: Value,
+    ) {
+        self.value = value;
+        self.written_at = SystemTime::now();
+        self.shared = OnceLock::new();
+    }
+
+    /// Overwrites a string value in place, keeping its allocation, and removes the expiry.
+    /// Returns `false` without changing anything if the value is not a string.
+    fn overwrite_str(
+        &mut self,
+        value: &str,
+    ) -> bool {
+        let Value::Str(s) = &mut self.value else {
+            return false;
+        };
+        s.clear();
+        s.push_str(value);
+        self.expires_at = None;
+        self.written_at = SystemTime::now();
+        *self.accesses.get_mut() = 0;
+        self.shared = OnceLock::new();
+        true
+    }
+
+    /// Returns whether the entry is expired at `now`.
+    fn is_expired(
+        &self,
+        now: Instant,
+    ) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    /// Returns the estimated number of bytes the entry occupies.
+    fn size(&self) -> usize {
+        let shared = self.shared.get().map_or(0, |shared| shared.len());
+        mem::size_of::<Entry>() + self.value.heap_size() + shared
+    }
+}
+
+/// Returns the estimated number of bytes `key` occupies in the database.
+fn key_size(key: &str) -> usize {
+    mem::size_of::<String>() + key.len()
 }
 
-/// An w
+/// An in-memory database storing typed [`Value`]s.
+/// Expired entries are not returned anymore but only freed when they are overwritten or removed.
+///
+/// The memory usage and the number of entries are only tracked for changes made through [`Database`],
+/// not for changes made to the map directly.
+///
+/// Read-modify-write operations like [`incr`](Database::incr) read and compute the new value
+/// holding only the shared lock and a lock for their key, and take the exclusive lock just to store it.
+/// So they do not block each other on different keys while reading, and neither do they block readers.
+/// If a plain write or an eviction changed the value in the meantime, the new value is computed again.
 #[derive(Debug, Clone)]
-pub struct DB(Arc<RwLock<HashMap<String, String>>>);
+pub struct DB {
+    map: Arc<RwLock<HashMap<String, Entry>>>,
+    // The estimated memory usage in bytes, see `Database::memory_usage`.
+    memory_usage: Arc<AtomicUsize>,
+    // The number of entries, see `DB::len`.
+    entry_count: Arc<AtomicUsize>,
+    // Whether `clear` frees the old entries on a background thread.
+    background_clear: bool,
+    // When and how entries are evicted to make room for writes.
+    eviction: Eviction,
+    // The logical clock ordering the reads and writes of the entries, see `DB::record_use`.
+    clock: Arc<AtomicU64>,
+    // The keys the evictions sample their victims from, see `EvictionPolicy::victim`.
+    eviction_candidates: Arc<Mutex<Vec<String>>>,
+    // Whether read-modify-writes remove the expiry of the key they update.
+    reset_ttl_on_update: bool,
+    // Whether reads are counted, see `DB::count_accesses`.
+    count_accesses: bool,
+    // Serializes the read-modify-write operations per key.
+    key_locks: Arc<StripedLock>,
+}
+
+/// The number of key locks of a [`DB`].
+const KEY_LOCK_STRIPES: usize = 64;
+
+/// The number of entries an eviction picks its victim from, see [`EvictionPolicy::victim`].
+const EVICTION_SAMPLES: usize = 5;
+
+/// How a [`DB`] makes room for a write once it reached its [`Capacity`], see [`DB::eviction`].
+/// The victim is picked from a sample of the entries, of which expired ones are always evicted first,
+/// whatever the policy.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Evicts nothing, writes fail with [`DatabaseError::Full`] instead.
+    #[default]
+    NoEviction,
+    /// Evicts the entry that was read or written least recently.
+    Lru,
+    /// Evicts the entry that was read least often, see [`Database::access_count`].
+    /// Of equally often read entries the least recently used one is evicted.
+    Lfu,
+    /// Evicts a random entry.
+    Random,
+    /// Evicts the entry that expires next. Entries without an expiry are never evicted,
+    /// so writes fail like with [`NoEviction`](EvictionPolicy::NoEviction) once none is left.
+    VolatileTtl,
+}
+
+impl EvictionPolicy {
+    /// Returns the position in `candidates` of the key of the entry of `map` to evict for writing `key`,
+    /// which is never evicted itself.
+    ///
+    /// The victim is the best of up to [`EVICTION_SAMPLES`] random entries, so for larger maps
+    /// it is only approximately the one the policy describes. The entries are sampled from `candidates`,
+    /// a copy of the map's keys which is taken again once fewer than [`EVICTION_SAMPLES`] keys are left in it.
+    /// So evicting takes amortized O(1) time, but keys written after the copy was taken are not sampled until then.
+    fn victim(
+        self,
+        map: &HashMap<String, Entry>,
+        candidates: &mut Vec<String>,
+        key: &str,
+        now: Instant,
+    ) -> Option<usize> {
+        let random = RandomState::new();
+        let mut copied = false;
+        loop {
+            if candidates.len() < EVICTION_SAMPLES {
+                candidates.clear();
+                candidates.extend(map.keys().cloned());
+                copied = true;
+            }
+            let mut victim = None;
+            let mut sampled = 0;
+            let mut next = 0;
+            while sampled < EVICTION_SAMPLES && next < candidates.len() {
+                // Moves a random one of the candidates not sampled yet to `next`
+                let pick = next + random.hash_one(next) as usize % (candidates.len() - next);
+                candidates.swap(next, pick);
+                let Some(entry) = map.get(&candidates[next]) else {
+                    // Removed since the keys were copied
+                    candidates.swap_remove(next);
+                    continue;
+                };
+                if candidates[next] != key {
+                    if entry.is_expired(now) {
+                        return Some(next);
+                    }
+                    let better = victim.is_none_or(|victim: usize| {
+                        self.evicts_before(entry, &map[&candidates[victim]])
+                    });
+                    if self.evicts(entry) && better {
+                        victim = Some(next);
+                    }
+                    sampled += 1;
+                }
+                next += 1;
+            }
+            // Too few of the copied keys were left to find a victim, so they are copied again
+            if victim.is_some() || sampled == EVICTION_SAMPLES || copied {
+                return victim;
+            }
+            candidates.clear();
+        }
+    }
+
+    /// Returns whether the policy evicts `entry` at all, expired entries aside.
+    fn evicts(
+        self,
+        entry: &Entry,
+    ) -> bool {
+        match self {
+            EvictionPolicy::NoEviction => false,
+            EvictionPolicy::Lru | EvictionPolicy::Lfu | EvictionPolicy::Random => true,
+            EvictionPolicy::VolatileTtl => entry.expires_at.is_some(),
+        }
+    }
+
+    /// Returns whether the policy evicts `entry` before `other`.
+    /// The first sampled entry is kept for [`Random`](EvictionPolicy::Random), as the sample is random already.
+    fn evicts_before(
+        self,
+        entry: &Entry,
+        other: &Entry,
+    ) -> bool {
+        match self {
+            EvictionPolicy::NoEviction | EvictionPolicy::Random => false,
+            EvictionPolicy::Lru => entry.last_used() < other.last_used(),
+            EvictionPolicy::Lfu => {
+                (entry.accesses(), entry.last_used()) < (other.accesses(), other.last_used())
+            }
+            EvictionPolicy::VolatileTtl => entry.expires_at < other.expires_at,
+        }
+    }
+}
+
+/// The limits at which a [`DB`] evicts entries to make room for a write, see [`DB::eviction`].
+/// A limit of `None` means unlimited, which is the default for both limits.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Capacity {
+    /// The maximum number of entries. Only writes of new keys are affected by it.
+    pub max_entries: Option<usize>,
+    /// The estimated memory usage in bytes, see [`Database::memory_usage`], at which writes evict entries.
+    /// It is checked before a write, so a write can exceed it by the size of the written entry.
+    pub max_memory_bytes: Option<usize>,
+}
+
+/// When and how a [`DB`] evicts entries, see [`DB::eviction`].
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+struct Eviction {
+    policy: EvictionPolicy,
+    capacity: Capacity,
+}
 
 impl DB {
     /// Creates a new instance of `DB`.
     pub fn new() -> Self {
-        Self(Arc::new(RwLock::new(HashMap::new())))
+        Self::with_capacity(0)
     }
 
     /// Creates a new instance of `DB` with the specified capacity.
     pub fn with_capacity(capacity: usize) -> Self {
-        Self(Arc::new(RwLock::new(HashMap::with_capacity(capacity))))
+        Self {
+            map: Arc::new(RwLock::new(HashMap::with_capacity(capacity))),
+            memory_usage: Arc::new(AtomicUsize::new(0)),
+            entry_count: Arc::new(AtomicUsize::new(0)),
+            background_clear: false,
+            eviction: Eviction::default(),
+            clock: Arc::new(AtomicU64::new(0)),
+            eviction_candidates: Arc::new(Mutex::new(Vec::new())),
+            reset_ttl_on_update: false,
+            count_accesses: false,
+            key_locks: Arc::new(StripedLock::new(KEY_LOCK_STRIPES)),
+        }
+    }
+
+    /// Makes [`clear`](Database::clear) swap in an empty map while holding the lock
+    /// and free the old entries on a background thread afterwards,
+    /// so that other connections are not blocked while a large database is deallocated.
+    /// The capacity of the old map is not kept then.
+    /// Only applies to clones made after calling this.
+    pub fn background_clear(
+        mut self,
+        background_clear: bool,
+    ) -> Self {
+        self.background_clear = background_clear;
+        self
+    }
+
+    /// Makes writes evict entries according to `policy` once the database reached its `capacity`,
+    /// i.e. writing a new key when it holds `max_entries` entries or writing any key
+    /// when it uses `max_memory_bytes`.
+    /// The entry to evict is picked from a small random sample, see [`EvictionPolicy`], which keeps
+    /// a copy of the keys that is not counted in the [`memory_usage`](Database::memory_usage).
+    /// Only the writes of keys evict, i.e. not [`compare_and_swap`](Database::compare_and_swap),
+    /// and [`replace_all`](Database::replace_all) fails with [`DatabaseError::Full`] instead
+    /// if the new contents exceed the capacity.
+    /// Only applies to clones made after calling this.
+    pub fn eviction(
+        mut self,
+        policy: EvictionPolicy,
+        capacity: Capacity,
+    ) -> Self {
+        self.eviction = Eviction { policy, capacity };
+        self
+    }
+
+    /// Makes [`incr`](Database::incr) and [`set_range`](Database::set_range) remove the expiry
+    /// of the key they update, so that it is kept forever like after a plain write.
+    /// By default, they keep the expiry like Redis does.
+    /// Only applies to clones made after calling this.
+    pub fn reset_ttl_on_update(
+        mut self,
+        reset_ttl_on_update: bool,
+    ) -> Self {
+        self.reset_ttl_on_update = reset_ttl_on_update;
+        self
+    }
+
+    /// Makes reads count the accesses of the entry they read, see [`Database::access_count`].
+    /// Counting is off by default, as every read of a key then writes to the same shared counter,
+    /// and always on with [`EvictionPolicy::Lfu`], which evicts by it.
+    /// Only applies to clones made after calling this.
+    pub fn count_accesses(
+        mut self,
+        count_accesses: bool,
+    ) -> Self {
+        self.count_accesses = count_accesses;
+        self
+    }
+
+    /// Returns the number of entries without taking the lock.
+    /// Like the map's length it includes expired entries that were not freed yet.
+    pub fn len(&self) -> usize {
+        self.entry_count.load(Ordering::Relaxed)
+    }
+
+    /// Returns `true` if the database contains no entries, see [`DB::len`].
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Gets the `key`'s value and sets its expiry to `ttl` from now, for keys without an expiry
+    /// only if `add_expiry` is set, see [`get_and_touch`](Database::get_and_touch)
+    /// and [`get_touch`](Database::get_touch).
+    fn get_expiring(
+        &self,
+        key: &str,
+        ttl: Duration,
+        add_expiry: bool,
+    ) -> Result<Option<Value>> {
+        let mut lock = self
+            .map
+            .write()
+            .map_err(|_| ServerError::Database(DatabaseError::DbLock))?;
+        let now = Instant::now();
+        Ok(lock
+            .get_mut(key)
+            .filter(|entry| !entry.is_expired(now))
+            .map(|entry| {
+                if add_expiry || entry.expires_at.is_some() {
+                    entry.expires_at = now.checked_add(ttl);
+                }
+                self.read_entry(entry).clone()
+            }))
+    }
+
+    /// Records a read or write of `entry` on the database's access clock
+    /// if the [`EvictionPolicy`] needs to know the least recently used entry.
+    fn record_use(
+        &self,
+        entry: &Entry,
+    ) {
+        if matches!(
+            self.eviction.policy,
+            EvictionPolicy::Lru | EvictionPolicy::Lfu
+        ) {
+            let tick = self.clock.fetch_add(1, Ordering::Relaxed);
+            entry.last_used.store(tick, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the value of `entry` and records the read.
+    fn read_entry<'a>(
+        &self,
+        entry: &'a Entry,
+    ) -> &'a Value {
+        if self.count_accesses || self.eviction.policy == EvictionPolicy::Lfu {
+            entry.accesses.fetch_add(1, Ordering::Relaxed);
+        }
+        self.record_use(entry);
+        &entry.value
+    }
+
+    /// Inserts `entry` into the locked map and keeps track of the memory usage.
+    fn insert_entry(
+        &self,
+        map: &mut HashMap<String, Entry>,
+        key: String,
+        entry: Entry,
+    ) {
+        match map.entry(key) {
+            hash_map::Entry::Occupied(occupied) => self.replace_occupied(occupied, entry),
+            hash_map::Entry::Vacant(vacant) => self.insert_vacant(vacant, entry),
+        }
+    }
+
+    /// Inserts `entry` into the slot of a key missing from the locked map,
+    /// which was looked up already, and keeps track of the memory usage.
+    fn insert_vacant(
+        &self,
+        vacant: VacantEntry<'_, String, Entry>,
+        entry: Entry,
+    ) {
+        self.memory_usage
+            .fetch_add(key_size(vacant.key()) + entry.size(), Ordering::Relaxed);
+        self.entry_count.fetch_add(1, Ordering::Relaxed);
+        self.record_use(&entry);
+        vacant.insert(entry);
+    }
+
+    /// Replaces the entry of a key of the locked map, which was looked up already,
+    /// and keeps track of the memory usage.
+    fn replace_occupied(
+        &self,
+        mut occupied: OccupiedEntry<'_, String, Entry>,
+        entry: Entry,
+    ) {
+        self.memory_usage.fetch_add(entry.size(), Ordering::Relaxed);
+        self.record_use(&entry);
+        let replaced = occupied.insert(entry);
+        self.release(replaced.size());
+    }
+
+    /// Evicts entries other than `key` from the locked map until it is below its [`Capacity`],
+    /// so that `key` can be written.
+    ///
+    /// # Errors
+    /// If the [`EvictionPolicy`] finds nothing to evict then [`DatabaseError::Full`] is returned.
+    fn make_room(
+        &self,
+        map: &mut HashMap<String, Entry>,
+        key: &str,
+    ) -> Result<()> {
+        let Eviction { policy, capacity } = self.eviction;
+        let mut candidates = None;
+        loop {
+            let too_many = capacity
+                .max_entries
+                .is_some_and(|max| map.len() >= max && !map.contains_key(key));
+            let too_large = capacity
+                .max_memory_bytes
+                .is_some_and(|max| self.memory_usage.load(Ordering::Relaxed) >= max);
+            if !too_many && !too_large {
+                return Ok(());
+            }
+            // Only locked for evicting, which happens under the exclusive lock of the map anyway
+            let candidates = candidates.get_or_insert_with(|| {
+                self.eviction_candidates
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+            });
+            let Some(victim) = policy.victim(map, candidates, key, Instant::now()) else {
+                return Err(ServerError::Database(DatabaseError::Full).into());
+            };
+            let victim = candidates.swap_remove(victim);
+            self.remove_entry(map, &victim);
+        }
+    }
+
+    /// Removes `key` from the locked map and keeps track of the memory usage and the number of entries.
+    fn remove_entry(
+        &self,
+        map: &mut HashMap<String, Entry>,
+        key: &str,
+    ) {
+        if let Some(entry) = map.remove(key) {
+            self.release(key_size(key) + entry.size());
+            // The map can be modified directly, so the tracked length might already be zero.
+            let _ = self
+                .entry_count
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |len| {
+                    len.checked_sub(1)
+                });
+        }
+    }
+
+    /// Subtracts `size` bytes from the tracked memory usage.
+    fn release(
+        &self,
+        size: usize,
+    ) {
+        // The map can be modified directly, so the tracked usage might be lower than `size`.
+        let _ = self
+            .memory_usage
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |usage| {
+                Some(usage.saturating_sub(size))
+            });
     }
 }
 
@@ -57,10 +1189,10 @@ impl Default for DB {
 }
 
 impl Deref for DB {
-    type Target = Arc<RwLock<HashMap<String, String>>>;
+    type Target = Arc<RwLock<HashMap<String, Entry>>>;
 
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.map
     }
 }
 
@@ -68,45 +1200,1581 @@ impl Database for DB {
     fn get(
         &self,
         key: &str,
-    ) -> Result<Option<String>> {
+    ) -> Result<Option<Value>> {
         let lock = self
-            .0
+            .map
             .read()
             .map_err(|_| ServerError::Database(DatabaseError::DbLock))?;
-        Ok(lock.get(key).map(ToString::to_string))
+        let now = Instant::now();
+        Ok(lock
+            .get(key)
+            .filter(|entry| !entry.is_expired(now))
+            .map(|entry| self.read_entry(entry).clone()))
     }
 
-    fn insert(
+    fn get_arc(
         &self,
-        key: String,
-        value: String,
-    ) -> Result<()> {
-        let mut lock = self
-            .0
-            .write()
+        key: &str,
+    ) -> Result<Option<Arc<str>>> {
+        let lock = self
+            .map
+            .read()
             .map_err(|_| ServerError::Database(DatabaseError::DbLock))?;
-        lock.insert(key, value);
-        Ok(())
+        let now = Instant::now();
+        match lock.get(key).filter(|entry| !entry.is_expired(now)) {
+            Some(entry) => {
+                self.read_entry(entry);
+                let (shared, created) = entry.shared().map_err(ServerError::Database)?;
+                // The string is only stored under the shared lock, so its size is added separately
+                if created {
+                    self.memory_usage.fetch_add(shared.len(), Ordering::Relaxed);
+                }
+                Ok(Some(shared))
+            }
+            None => Ok(None),
+        }
     }
 
-    fn remove(
+    fn get_many(
         &self,
-        key: &str,
-    ) -> Result<()> {
-        let mut lock = self
-            .0
-            .write()
+        keys: &[&str],
+    ) -> Result<Vec<Option<Value>>> {
+        let lock = self
+            .map
+            .read()
             .map_err(|_| ServerError::Database(DatabaseError::DbLock))?;
-        lock.remove(key);
-        Ok(())
+        let now = Instant::now();
+        let mut values = Vec::with_capacity(keys.len());
+        values.extend(keys.iter().map(|key| {
+            lock.get(*key)
+                .filter(|entry| !entry.is_expired(now))
+                .map(|entry| self.read_entry(entry).clone())
+        }));
+        Ok(values)
+    }
+
+    fn get_and_touch(
+        &self,
+        key: &str,
+        ttl: Duration,
+    ) -> Result<Option<Value>> {
+        self.get_expiring(key, ttl, false)
+    }
+
+    fn get_touch(
+        &self,
+        key: &str,
+        ttl: Duration,
+    ) -> Result<Option<Value>> {
+        self.get_expiring(key, ttl, true)
+    }
+
+    fn get_with_meta(
+        &self,
+        key: &str,
+    ) -> Result<Option<(Value, KeyMeta)>> {
+        let lock = self
+            .map
+            .read()
+            .map_err(|_| ServerError::Database(DatabaseError::DbLock))?;
+        let now = Instant::now();
+        Ok(lock
+            .get(key)
+            .filter(|entry| !entry.is_expired(now))
+            .map(|entry| {
+                let meta = KeyMeta {
+                    ttl: entry
+                        .expires_at
+                        .map(|expires_at| expires_at.saturating_duration_since(now)),
+                    size: entry.value.text_len() as u64,
+                };
+                (self.read_entry(entry).clone(), meta)
+            }))
+    }
+
+    fn get_if_modified_since(
+        &self,
+        key: &str,
+        since: SystemTime,
+    ) -> Result<GetModified<Value>> {
+        let lock = self
+            .map
+            .read()
+            .map_err(|_| ServerError::Database(DatabaseError::DbLock))?;
+        let now = Instant::now();
+        Ok(match lock.get(key).filter(|entry| !entry.is_expired(now)) {
+            Some(entry) if entry.written_at > since => {
+                GetModified::Value(self.read_entry(entry).clone())
+            }
+            Some(_) => GetModified::NotModified,
+            None => GetModified::Missing,
+        })
+    }
+
+    fn get_range(
+        &self,
+        key: &str,
+        start: i64,
+        end: i64,
+    ) -> Result<Option<Value>> {
+        let lock = self
+            .map
+            .read()
+            .map_err(|_| ServerError::Database(DatabaseError::DbLock))?;
+        let now = Instant::now();
+        let Some(entry) = lock.get(key).filter(|entry| !entry.is_expired(now)) else {
+            return Ok(None);
+        };
+        let bytes = self.read_entry(entry).to_bytes();
+        let len = bytes.len() as i64;
+        let clamp = |offset: i64| {
+            let offset = if offset < 0 { offset + len } else { offset };
+            offset.clamp(0, len) as usize
+        };
+        let range = bytes.get(clamp(start)..clamp(end)).unwrap_or_default();
+        Ok(Some(match String::from_utf8(range.to_vec()) {
+            Ok(s) => Value::Str(s),
+            Err(e) => Value::Bytes(e.into_bytes()),
+        }))
+    }
+
+    fn value_len(
+        &self,
+        key: &str,
+    ) -> Result<Option<usize>> {
+        let lock = self
+            .map
+            .read()
+            .map_err(|_| ServerError::Database(DatabaseError::DbLock))?;
+        let now = Instant::now();
+        Ok(lock
+            .get(key)
+            .filter(|entry| !entry.is_expired(now))
+            .map(|entry| entry.value.text_len()))
+    }
+
+    fn access_count(
+        &self,
+        key: &str,
+    ) -> Result<Option<u64>> {
+        let lock = self
+            .map
+            .read()
+            .map_err(|_| ServerError::Database(DatabaseError::DbLock))?;
+        let now = Instant::now();
+        Ok(lock
+            .get(key)
+            .filter(|entry| !entry.is_expired(now))
+            .map(Entry::accesses))
+    }
+
+    fn get_many_ref(
+        &self,
+        keys: &[&str],
+        visit: &mut dyn FnMut(Option<&Value>),
+    ) -> Result<()> {
+        let lock = self
+            .map
+            .read()
+            .map_err(|_| ServerError::Database(DatabaseError::DbLock))?;
+        let now = Instant::now();
+        for key in keys {
+            visit(
+                lock.get(*key)
+                    .filter(|entry| !entry.is_expired(now))
+                    .map(|entry| self.read_entry(entry)),
+            );
+        }
+        Ok(())
+    }
+
+    fn insert(
+        &self,
+        key: String,
+        value: Value,
+    ) -> Result<()> {
+        let mut lock = self
+            .map
+            .write()
+            .map_err(|_| ServerError::Database(DatabaseError::DbLock))?;
+        self.make_room(&mut lock, &key)?;
+        self.insert_entry(&mut lock, key, Entry::new(value));
+        Ok(())
+    }
+
+    fn insert_str(
+        &self,
+        key: &str,
+        value: &str,
+    ) -> Result<()> {
+        let mut lock = self
+            .map
+            .write()
+            .map_err(|_| ServerError::Database(DatabaseError::DbLock))?;
+        self.make_room(&mut lock, key)?;
+        if let Some(entry) = lock.get_mut(key) {
+            let size = entry.size();
+            if entry.overwrite_str(value) {
+                self.record_use(entry);
+                self.release(size);
+                self.memory_usage.fetch_add(entry.size(), Ordering::Relaxed);
+                return Ok(());
+            }
+        }
+        self.insert_entry(&mut lock, key.to_string(), Entry::new(value.into()));
+        Ok(())
+    }
+
+    fn insert_with_ttl(
+        &self,
+        key: String,
+        value: Value,
+        ttl: Duration,
+    ) -> Result<()> {
+        let mut lock = self
+            .map
+            .write()
+            .map_err(|_| ServerError::Database(DatabaseError::DbLock))?;
+        self.make_room(&mut lock, &key)?;
+        self.insert_entry(&mut lock, key, Entry::with_ttl(value, ttl));
+        Ok(())
+    }
+
+    fn extend(
+        &self,
+        pairs: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<()> {
+        let pairs = pairs.into_iter();
+        let mut lock = self
+            .map
+            .write()
+            .map_err(|_| ServerError::Database(DatabaseError::DbLock))?;
+        lock.reserve(pairs.size_hint().0);
+        for (key, value) in pairs {
+            self.make_room(&mut lock, &key)?;
+            self.insert_entry(&mut lock, key, Entry::new(value.into()));
+        }
+        Ok(())
+    }
+
+    fn replace_all(
+        &self,
+        pairs: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<()> {
+        let pairs = pairs.into_iter();
+        let mut map = HashMap::with_capacity(pairs.size_hint().0);
+        let mut usage = 0;
+        for (key, value) in pairs {
+            let key_size = key_size(&key);
+            let entry = Entry::new(value.into());
+            self.record_use(&entry);
+            usage += key_size + entry.size();
+            if let Some(replaced) = map.insert(key, entry) {
+                usage -= key_size + replaced.size();
+            }
+        }
+        let len = map.len();
+        // Nothing of the new contents could be evicted without losing part of the request
+        let capacity = self.eviction.capacity;
+        if capacity.max_entries.is_some_and(|max| len > max)
+            || capacity.max_memory_bytes.is_some_and(|max| usage > max)
+        {
+            return Err(ServerError::Database(DatabaseError::Full).into());
+        }
+        let mut lock = self
+            .map
+            .write()
+            .map_err(|_| ServerError::Database(DatabaseError::DbLock))?;
+        let old = mem::replace(&mut *lock, map);
+        self.memory_usage.store(usage, Ordering::Relaxed);
+        self.entry_count.store(len, Ordering::Relaxed);
+        drop(lock);
+        if self.background_clear {
+            thread::spawn(move || drop(old));
+        }
+        Ok(())
+    }
+
+    fn set_range(
+        &self,
+        key: &str,
+        offset: usize,
+        value: &str,
+    ) -> Result<usize> {
+        let end = offset
+            .checked_add(value.len())
+            .ok_or(ServerError::Database(DatabaseError::IntegerOverflow))?;
+        let _key_lock = self.key_locks.lock(key);
+        loop {
+            let lock = self
+                .map
+                .read()
+                .map_err(|_| ServerError::Database(DatabaseError::DbLock))?;
+            let now = Instant::now();
+            let current = lock
+                .get(key)
+                .filter(|entry| !entry.is_expired(now))
+                .map(|entry| entry.value.clone());
+            drop(lock);
+            if current
+                .as_ref()
+                .is_some_and(|current| current.splits_char(offset, end))
+            {
+                return Err(ServerError::Database(DatabaseError::NotUtf8).into());
+            }
+            // The value is copied to be computed outside the exclusive lock, the current one is kept for comparing
+            let new = current
+                .clone()
+                .unwrap_or(Value::Str(String::new()))
+                .overwrite_range(offset, value.as_bytes());
+            let len = new.len();
+
+            let mut lock = self
+                .map
+                .write()
+                .map_err(|_| ServerError::Database(DatabaseError::DbLock))?;
+            self.make_room(&mut lock, key)?;
+            let now = Instant::now();
+            // Other read-modify-writes of the key wait for the key lock, so only a plain write or an eviction
+            // can have changed the value in the meantime. Then the new value is computed again.
+            match (
+                lock.get_mut(key).filter(|entry| !entry.is_expired(now)),
+                current,
+            ) {
+                (Some(entry), Some(current)) if entry.value == current => {
+                    self.release(entry.size());
+                    entry.set_value(new);
+                    self.record_use(entry);
+                    if self.reset_ttl_on_update {
+                        entry.expires_at = None;
+                    }
+                    self.memory_usage.fetch_add(entry.size(), Ordering::Relaxed);
+                    return Ok(len);
+                }
+                (None, None) => {
+                    self.insert_entry(&mut lock, key.to_string(), Entry::new(new));
+                    return Ok(len);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn insert_if_absent(
+        &self,
+        key: String,
+        value: Value,
+    ) -> Result<bool> {
+        let mut lock = self
+            .map
+            .write()
+            .map_err(|_| ServerError::Database(DatabaseError::DbLock))?;
+        let now = Instant::now();
+        // Nothing is written for an existing key, so nothing is evicted either.
+        let bounded = self.eviction.capacity != Capacity::default();
+        if bounded && lock.get(&key).is_none_or(|entry| entry.is_expired(now)) {
+            self.make_room(&mut lock, &key)?;
+        }
+        match lock.entry(key) {
+            hash_map::Entry::Occupied(occupied) if !occupied.get().is_expired(now) => Ok(false),
+            hash_map::Entry::Occupied(occupied) => {
+                self.replace_occupied(occupied, Entry::new(value));
+                Ok(true)
+            }
+            hash_map::Entry::Vacant(vacant) => {
+                self.insert_vacant(vacant, Entry::new(value));
+                Ok(true)
+            }
+        }
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: &str,
+        expected: &Value,
+        new: Value,
+    ) -> Result<bool> {
+        let _key_lock = self.key_locks.lock(key);
+        let lock = self
+            .map
+            .read()
+            .map_err(|_| ServerError::Database(DatabaseError::DbLock))?;
+        let now = Instant::now();
+        let matches = lock
+            .get(key)
+            .is_some_and(|entry| !entry.is_expired(now) && entry.value.text_eq(expected));
+        drop(lock);
+        // A mismatch never takes the exclusive lock
+        if !matches {
+            return Ok(false);
+        }
+        let mut lock = self
+            .map
+            .write()
+            .map_err(|_| ServerError::Database(DatabaseError::DbLock))?;
+        let now = Instant::now();
+        // Compared again, as a plain write may have changed the value in the meantime
+        match lock
+            .get_mut(key)
+            .filter(|entry| !entry.is_expired(now) && entry.value.text_eq(expected))
+        {
+            Some(entry) => {
+                self.release(entry.size());
+                entry.set_value(new);
+                self.record_use(entry);
+                self.memory_usage.fetch_add(entry.size(), Ordering::Relaxed);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn incr(
+        &self,
+        key: &str,
+        delta: i64,
+    ) -> Result<i64> {
+        let _key_lock = self.key_locks.lock(key);
+        loop {
+            let lock = self
+                .map
+                .read()
+                .map_err(|_| ServerError::Database(DatabaseError::DbLock))?;
+            let now = Instant::now();
+            let current = match lock.get(key).filter(|entry| !entry.is_expired(now)) {
+                Some(entry) => match entry.value {
+                    Value::Int(current) => Some(current),
+                    _ => return Err(ServerError::Database(DatabaseError::NotAnInteger).into()),
+                },
+                None => None,
+            };
+            drop(lock);
+            let new = current
+                .unwrap_or(0)
+                .checked_add(delta)
+                .ok_or(ServerError::Database(DatabaseError::IntegerOverflow))?;
+
+            let mut lock = self
+                .map
+                .write()
+                .map_err(|_| ServerError::Database(DatabaseError::DbLock))?;
+            let now = Instant::now();
+            // Other read-modify-writes of the key wait for the key lock, so only a plain write or an eviction
+            // can have changed the value in the meantime. Then the new value is computed again.
+            match current {
+                Some(current) => {
+                    if let Some(entry) = lock.get_mut(key).filter(|entry| {
+                        !entry.is_expired(now) && entry.value == Value::Int(current)
+                    }) {
+                        // The shared string of the old value is dropped
+                        self.release(entry.size());
+                        entry.set_value(Value::Int(new));
+                        self.memory_usage.fetch_add(entry.size(), Ordering::Relaxed);
+                        self.record_use(entry);
+                        if self.reset_ttl_on_update {
+                            entry.expires_at = None;
+                        }
+                        return Ok(new);
+                    }
+                }
+                None if lock.get(key).is_none_or(|entry| entry.is_expired(now)) => {
+                    self.make_room(&mut lock, key)?;
+                    self.insert_entry(&mut lock, key.to_string(), Entry::new(Value::Int(new)));
+                    return Ok(new);
+                }
+                None => {}
+            }
+        }
+    }
+
+    fn contains_many(
+        &self,
+        keys: &[&str],
+    ) -> Result<Vec<bool>> {
+        let lock = self
+            .map
+            .read()
+            .map_err(|_| ServerError::Database(DatabaseError::DbLock))?;
+        let now = Instant::now();
+        Ok(keys
+            .iter()
+            .map(|key| lock.get(*key).is_some_and(|entry| !entry.is_expired(now)))
+            .collect())
+    }
+
+    fn touch_many(
+        &self,
+        keys: &[&str],
+        ttl: Duration,
+    ) -> Result<usize> {
+        let mut lock = self
+            .map
+            .write()
+            .map_err(|_| ServerError::Database(DatabaseError::DbLock))?;
+        let now = Instant::now();
+        let expires_at = now.checked_add(ttl);
+        let mut touched = 0;
+        for key in keys {
+            if let Some(entry) = lock.get_mut(*key).filter(|entry| !entry.is_expired(now)) {
+                entry.expires_at = expires_at;
+                touched += 1;
+            }
+        }
+        Ok(touched)
+    }
+
+    fn count_prefix(
+        &self,
+        prefix: &str,
+    ) -> Result<usize> {
+        let lock = self
+            .map
+            .read()
+            .map_err(|_| ServerError::Database(DatabaseError::DbLock))?;
+        let now = Instant::now();
+        Ok(lock
+            .iter()
+            .filter(|(key, entry)| key.starts_with(prefix) && !entry.is_expired(now))
+            .count())
+    }
+
+    fn get_prefix(
+        &self,
+        prefix: &str,
+    ) -> Result<Vec<(String, Value)>> {
+        let lock = self
+            .map
+            .read()
+            .map_err(|_| ServerError::Database(DatabaseError::DbLock))?;
+        let now = Instant::now();
+        let mut pairs: Vec<_> = lock
+            .iter()
+            .filter(|(key, entry)| key.starts_with(prefix) && !entry.is_expired(now))
+            .map(|(key, entry)| (key.clone(), entry.value.clone()))
+            .collect();
+        drop(lock);
+        pairs.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        Ok(pairs)
+    }
+
+    fn remove(
+        &self,
+        key: &str,
+    ) -> Result<()> {
+        let mut lock = self
+            .map
+            .write()
+            .map_err(|_| ServerError::Database(DatabaseError::DbLock))?;
+        self.remove_entry(&mut lock, key);
+        Ok(())
+    }
+
+    fn delete_if_older_than(
+        &self,
+        key: &str,
+        cutoff: SystemTime,
+    ) -> Result<bool> {
+        let mut lock = self
+            .map
+            .write()
+            .map_err(|_| ServerError::Database(DatabaseError::DbLock))?;
+        let now = Instant::now();
+        if !lock
+            .get(key)
+            .is_some_and(|entry| !entry.is_expired(now) && entry.written_at < cutoff)
+        {
+            return Ok(false);
+        }
+        self.remove_entry(&mut lock, key);
+        Ok(true)
+    }
+
+    fn remove_if(
+        &self,
+        key: &str,
+        expected: &Value,
+    ) -> Result<bool> {
+        let mut lock = self
+            .map
+            .write()
+            .map_err(|_| ServerError::Database(DatabaseError::DbLock))?;
+        let now = Instant::now();
+        if !lock
+            .get(key)
+            .is_some_and(|entry| !entry.is_expired(now) && entry.value.text_eq(expected))
+        {
+            return Ok(false);
+        }
+        self.remove_entry(&mut lock, key);
+        Ok(true)
+    }
+
+    fn retain(
+        &self,
+        keep: impl Fn(&str, &Value) -> bool,
+    ) -> Result<usize> {
+        let mut lock = self
+            .map
+            .write()
+            .map_err(|_| ServerError::Database(DatabaseError::DbLock))?;
+        let now = Instant::now();
+        let mut removed = 0;
+        let mut removed_expired = 0;
+        let mut released = 0;
+        lock.retain(|key, entry| {
+            if entry.is_expired(now) {
+                removed_expired += 1;
+            } else if keep(key, &entry.value) {
+                return true;
+            } else {
+                removed += 1;
+            }
+            released += key_size(key) + entry.size();
+            false
+        });
+        drop(lock);
+        self.release(released);
+        // The map can be modified directly, so the tracked length might be lower.
+        let _ = self
+            .entry_count
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |len| {
+                Some(len.saturating_sub(removed + removed_expired))
+            });
+        Ok(removed)
     }
 
     fn clear(&self) -> Result<()> {
         let mut lock = self
-            .0
+            .map
             .write()
             .map_err(|_| ServerError::Database(DatabaseError::DbLock))?;
-        lock.clear();
+        self.memory_usage.store(0, Ordering::Relaxed);
+        self.entry_count.store(0, Ordering::Relaxed);
+        if !self.background_clear {
+            lock.clear();
+            return Ok(());
+        }
+        let old = mem::take(&mut *lock);
+        drop(lock);
+        thread::spawn(move || drop(old));
         Ok(())
     }
+
+    fn clear_returning(&self) -> Result<Vec<String>> {
+        let mut lock = self
+            .map
+            .write()
+            .map_err(|_| ServerError::Database(DatabaseError::DbLock))?;
+        let now = Instant::now();
+        self.memory_usage.store(0, Ordering::Relaxed);
+        self.entry_count.store(0, Ordering::Relaxed);
+        Ok(lock
+            .drain()
+            .filter(|(_, entry)| !entry.is_expired(now))
+            .map(|(key, _)| key)
+            .collect())
+    }
+
+    fn is_poisoned(&self) -> bool {
+        self.map.is_poisoned()
+    }
+
+    fn memory_usage(&self) -> Result<usize> {
+        Ok(self.memory_usage.load(Ordering::Relaxed))
+    }
+
+    fn len(&self) -> Result<usize> {
+        Ok(DB::len(self))
+    }
+
+    fn scan(
+        &self,
+        cursor: usize,
+        count: usize,
+    ) -> Result<(usize, Vec<String>)> {
+        let lock = self
+            .map
+            .read()
+            .map_err(|_| ServerError::Database(DatabaseError::DbLock))?;
+        let now = Instant::now();
+        let mut keys = Vec::with_capacity(count.min(lock.len()));
+        // The cursor is the position in the map so that expired entries don't shift it
+        let mut next_cursor = cursor;
+        for (key, entry) in lock.iter().skip(cursor) {
+            if keys.len() == count {
+                break;
+            }
+            next_cursor += 1;
+            if !entry.is_expired(now) {
+                keys.push(key.clone());
+            }
+        }
+        if next_cursor >= lock.len() {
+            Ok((0, keys))
+        } else {
+            Ok((next_cursor, keys))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_get_many_ref_preserves_order() {
+        let db = DB::new();
+        db.insert("a".to_string(), "1".into()).unwrap();
+        db.insert("b".to_string(), 2.into()).unwrap();
+        db.insert_with_ttl("expired".to_string(), "3".into(), Duration::ZERO)
+            .unwrap();
+
+        let keys = ["b", "missing", "a", "expired", "b"];
+        let mut values = Vec::new();
+        db.get_many_ref(&keys, &mut |value| values.push(value.cloned()))
+            .unwrap();
+        let expected = vec![
+            Some(Value::Int(2)),
+            None,
+            Some(Value::Str("1".to_string())),
+            None,
+            Some(Value::Int(2)),
+        ];
+        assert_eq!(values, expected);
+        assert_eq!(db.get_many(&keys).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_get_many_returns_duplicates_at_every_position() {
+        let db = DB::new();
+        db.insert("a".to_string(), "1".into()).unwrap();
+        db.insert("b".to_string(), "2".into()).unwrap();
+
+        let values = db.get_many(&["a", "a", "missing", "b", "a"]).unwrap();
+        assert_eq!(
+            values,
+            vec![
+                Some("1".into()),
+                Some("1".into()),
+                None,
+                Some("2".into()),
+                Some("1".into()),
+            ]
+        );
+        assert_eq!(
+            db.get_many(&["missing", "missing"]).unwrap(),
+            vec![None, None]
+        );
+    }
+
+    #[test]
+    fn test_get_many_without_keys_is_empty() {
+        let db = DB::new();
+        db.insert("a".to_string(), "1".into()).unwrap();
+        let values = db.get_many(&[]).unwrap();
+        assert!(values.is_empty());
+        assert_eq!(values.capacity(), 0);
+    }
+
+    #[test]
+    fn test_get_range_clamps_byte_offsets() {
+        let db = DB::new();
+        db.insert("a".to_string(), "abcdef".into()).unwrap();
+        db.insert("n".to_string(), 12345.into()).unwrap();
+        let range = |key, start, end| db.get_range(key, start, end).unwrap();
+
+        assert_eq!(range("a", 1, 3), Some("bc".into()));
+        assert_eq!(range("a", 4, 100), Some("ef".into()));
+        assert_eq!(range("a", 10, 20), Some("".into()));
+        assert_eq!(range("a", 3, 1), Some("".into()));
+        // Negative offsets count from the end and are clamped to the start
+        assert_eq!(range("a", -2, 6), Some("ef".into()));
+        assert_eq!(range("a", -100, -4), Some("ab".into()));
+        assert_eq!(range("a", i64::MIN, i64::MAX), Some("abcdef".into()));
+        // Integers are ranged over their digits
+        assert_eq!(range("n", 1, 3), Some("23".into()));
+        assert_eq!(range("missing", 0, 1), None);
+    }
+
+    #[test]
+    fn test_value_len_returns_byte_length() {
+        let db = DB::new();
+        db.insert("a".to_string(), "aäb".into()).unwrap();
+        db.insert("b".to_string(), "".into()).unwrap();
+        db.insert("c".to_string(), Value::Bytes(vec![0xff; 3]))
+            .unwrap();
+        db.insert_with_ttl("d".to_string(), "abc".into(), Duration::ZERO)
+            .unwrap();
+        assert_eq!(db.value_len("a").unwrap(), Some(4));
+        assert_eq!(db.value_len("b").unwrap(), Some(0));
+        assert_eq!(db.value_len("c").unwrap(), Some(3));
+        assert_eq!(db.value_len("d").unwrap(), None);
+        assert_eq!(db.value_len("missing").unwrap(), None);
+        for i in [0, 7, 10, -10, 12345, i64::MIN, i64::MAX] {
+            db.insert("n".to_string(), i.into()).unwrap();
+            assert_eq!(db.value_len("n").unwrap(), Some(i.to_string().len()));
+        }
+    }
+
+    #[test]
+    fn test_get_range_splitting_a_character_returns_bytes() {
+        let db = DB::new();
+        // `ä` takes two bytes
+        db.insert("a".to_string(), "aäb".into()).unwrap();
+        assert_eq!(db.get_range("a", 1, 3).unwrap(), Some("ä".into()));
+        assert_eq!(
+            db.get_range("a", 0, 2).unwrap(),
+            Some(Value::Bytes(vec![b'a', 0xc3]))
+        );
+    }
+
+    #[test]
+    fn test_set_range_overwrites_and_pads() {
+        let db = DB::new();
+        db.insert("a".to_string(), "abcdef".into()).unwrap();
+        assert_eq!(db.set_range("a", 2, "XY").unwrap(), 6);
+        assert_eq!(db.get("a").unwrap(), Some("abXYef".into()));
+        assert_eq!(db.set_range("a", 5, "123").unwrap(), 8);
+        assert_eq!(db.get("a").unwrap(), Some("abXYe123".into()));
+
+        // Missing keys are created and padded with zero bytes
+        assert_eq!(db.set_range("b", 3, "x").unwrap(), 4);
+        assert_eq!(db.get("b").unwrap(), Some("\0\0\0x".into()));
+        assert_eq!(db.len(), 2);
+
+        let expected = DB::new();
+        expected.insert("a".to_string(), "abXYe123".into()).unwrap();
+        expected.insert("b".to_string(), "\0\0\0x".into()).unwrap();
+        assert_eq!(db.memory_usage().unwrap(), expected.memory_usage().unwrap());
+    }
+
+    #[test]
+    fn test_set_range_keeps_the_expiry() {
+        let db = DB::new();
+        db.insert_with_ttl("a".to_string(), "abc".into(), Duration::from_secs(60))
+            .unwrap();
+        db.set_range("a", 1, "x").unwrap();
+        let (value, meta) = db.get_with_meta("a").unwrap().unwrap();
+        assert_eq!(value, Value::from("axc"));
+        assert!(meta.ttl.is_some());
+    }
+
+    #[test]
+    fn test_get_touch_sets_the_expiry_only_on_a_hit() {
+        let db = DB::new();
+        db.insert("a".to_string(), "1".into()).unwrap();
+        db.insert_with_ttl("b".to_string(), "2".into(), Duration::from_secs(1))
+            .unwrap();
+        let ttl = Duration::from_secs(60);
+        assert_eq!(db.get_touch("a", ttl).unwrap(), Some("1".into()));
+        assert_eq!(db.get_touch("b", ttl).unwrap(), Some("2".into()));
+        assert_eq!(db.get_touch("c", ttl).unwrap(), None);
+        for key in ["a", "b"] {
+            let (_, meta) = db.get_with_meta(key).unwrap().unwrap();
+            assert!(meta.ttl.is_some_and(|ttl| ttl > Duration::from_secs(50)));
+        }
+        assert_eq!(db.len(), 2);
+    }
+
+    #[test]
+    fn test_meta_size_is_the_length_of_the_text() {
+        let db = DB::new();
+        db.insert("a".to_string(), "abc".into()).unwrap();
+        db.insert("b".to_string(), Value::Int(-120)).unwrap();
+        assert_eq!(db.get_with_meta("a").unwrap().unwrap().1.size, 3);
+        assert_eq!(db.get_with_meta("b").unwrap().unwrap().1.size, 4);
+    }
+
+    #[test]
+    fn test_incr_keeps_the_expiry() {
+        let db = DB::new();
+        db.insert_with_ttl("a".to_string(), Value::Int(1), Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(db.incr("a", 2).unwrap(), 3);
+        let (value, meta) = db.get_with_meta("a").unwrap().unwrap();
+        assert_eq!(value, Value::Int(3));
+        assert!(meta.ttl.is_some_and(|ttl| ttl > Duration::from_secs(50)));
+    }
+
+    #[test]
+    fn test_reset_ttl_on_update_removes_the_expiry() {
+        let db = DB::new().reset_ttl_on_update(true);
+        db.insert_with_ttl("a".to_string(), Value::Int(1), Duration::from_secs(60))
+            .unwrap();
+        db.insert_with_ttl("b".to_string(), "abc".into(), Duration::from_secs(60))
+            .unwrap();
+        db.incr("a", 1).unwrap();
+        db.set_range("b", 1, "x").unwrap();
+        assert_eq!(db.get_with_meta("a").unwrap().unwrap().1.ttl, None);
+        assert_eq!(db.get_with_meta("b").unwrap().unwrap().1.ttl, None);
+    }
+
+    #[test]
+    fn test_set_range_does_not_split_characters() {
+        let db = DB::new();
+        db.insert("a".to_string(), "aäb".into()).unwrap();
+        assert!(matches!(
+            db.set_range("a", 2, "x"),
+            Err(crate::error::Error::Server(ServerError::Database(
+                DatabaseError::NotUtf8
+            )))
+        ));
+        assert_eq!(db.get("a").unwrap(), Some("aäb".into()));
+        // Replacing the whole character is fine
+        assert_eq!(db.set_range("a", 1, "xy").unwrap(), 4);
+        assert_eq!(db.get("a").unwrap(), Some("axyb".into()));
+    }
+
+    #[test]
+    fn test_clear_returning_returns_removed_keys() {
+        let db = DB::new();
+        for key in ["a", "b", "c"] {
+            db.insert(key.to_string(), "1".into()).unwrap();
+        }
+        db.insert_with_ttl("expired".to_string(), "1".into(), Duration::ZERO)
+            .unwrap();
+
+        let mut keys = db.clear_returning().unwrap();
+        keys.sort();
+        assert_eq!(keys, vec!["a", "b", "c"]);
+        assert!(db.read().unwrap().is_empty());
+        assert!(db.clear_returning().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_extend_inserts_all_pairs() {
+        let db = DB::new();
+        db.insert("a".to_string(), "old".into()).unwrap();
+        db.extend((0..1000).map(|i| (format!("key-{i}"), i.to_string())))
+            .unwrap();
+        db.extend([("a".to_string(), "new".to_string())]).unwrap();
+
+        assert_eq!(db.read().unwrap().len(), 1001);
+        assert_eq!(db.get("key-999").unwrap(), Some("999".into()));
+        assert_eq!(db.get("a").unwrap(), Some("new".into()));
+    }
+
+    #[test]
+    fn test_replace_all_swaps_the_contents() {
+        let db = DB::new();
+        db.insert_with_ttl("a".to_string(), "old".into(), Duration::from_secs(60))
+            .unwrap();
+        db.insert("b".to_string(), "old".into()).unwrap();
+        db.replace_all([
+            ("a".to_string(), "1".to_string()),
+            ("c".to_string(), "2".to_string()),
+            ("c".to_string(), "3".to_string()),
+        ])
+        .unwrap();
+
+        assert_eq!(db.get("a").unwrap(), Some("1".into()));
+        assert_eq!(db.get("b").unwrap(), None);
+        assert_eq!(db.get("c").unwrap(), Some("3".into()));
+        assert_eq!(db.get_with_meta("a").unwrap().unwrap().1.ttl, None);
+        assert_eq!(db.len(), 2);
+
+        let expected = DB::new();
+        expected.insert("a".to_string(), "1".into()).unwrap();
+        expected.insert("c".to_string(), "3".into()).unwrap();
+        assert_eq!(db.memory_usage().unwrap(), expected.memory_usage().unwrap());
+
+        db.replace_all([]).unwrap();
+        assert!(db.is_empty());
+        assert_eq!(db.memory_usage().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_replace_all_is_never_seen_partially() {
+        let generation = |name: &str| {
+            let mut pairs: Vec<_> = (0..1000)
+                .map(|i| (format!("key-{i}"), name.to_string()))
+                .collect();
+            pairs.push((format!("only-{name}"), name.to_string()));
+            pairs
+        };
+        let db = DB::new();
+        db.replace_all(generation("old")).unwrap();
+
+        let reader = {
+            let db = db.clone();
+            thread::spawn(move || {
+                let mut keys: Vec<String> = (0..1000).map(|i| format!("key-{i}")).collect();
+                keys.extend(["only-old".to_string(), "only-new".to_string()]);
+                let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+                for _ in 0..200 {
+                    let values = db.get_many(&keys).unwrap();
+                    let name = match &values[0] {
+                        Some(Value::Str(name)) => name.clone(),
+                        value => panic!("unexpected value {value:?}"),
+                    };
+                    let (only_old, only_new) = (&values[1000], &values[1001]);
+                    assert!(values[..1000]
+                        .iter()
+                        .all(|value| value.as_ref().is_some_and(|value| *value == *name)));
+                    assert_eq!(only_old.is_some(), name == "old");
+                    assert_eq!(only_new.is_some(), name == "new");
+                }
+            })
+        };
+        for i in 0..200 {
+            let name = if i % 2 == 0 { "new" } else { "old" };
+            db.replace_all(generation(name)).unwrap();
+        }
+        reader.join().unwrap();
+    }
+
+    #[test]
+    fn test_delete_if_older_than() {
+        let db = DB::new();
+        db.insert("a".to_string(), "1".into()).unwrap();
+        let hour = Duration::from_secs(3600);
+
+        // The key was written after the cutoff, so it is fresher and survives
+        assert!(!db
+            .delete_if_older_than("a", SystemTime::now() - hour)
+            .unwrap());
+        assert_eq!(db.get("a").unwrap(), Some("1".into()));
+
+        assert!(db
+            .delete_if_older_than("a", SystemTime::now() + hour)
+            .unwrap());
+        assert_eq!(db.get("a").unwrap(), None);
+        assert!(!db
+            .delete_if_older_than("a", SystemTime::now() + hour)
+            .unwrap());
+    }
+
+    #[test]
+    fn test_remove_if() {
+        let db = DB::new();
+        db.insert("a".to_string(), "1".into()).unwrap();
+
+        assert!(!db.remove_if("a", &"2".into()).unwrap());
+        assert_eq!(db.get("a").unwrap(), Some("1".into()));
+        // Values of different types are equal if their textual representations are
+        db.insert("b".to_string(), 1.into()).unwrap();
+        assert!(!db.remove_if("b", &"2".into()).unwrap());
+        assert!(db.remove_if("b", &"1".into()).unwrap());
+        db.insert("b".to_string(), Value::Bytes(b"1".to_vec()))
+            .unwrap();
+
+        assert!(db.remove_if("a", &"1".into()).unwrap());
+        assert_eq!(db.get("a").unwrap(), None);
+        assert!(!db.remove_if("a", &"1".into()).unwrap());
+        assert_eq!(db.len(), 1);
+    }
+
+    #[test]
+    fn test_database_len_tracks_inserts_and_removes() {
+        // Called through the trait, as the server and other implementors do
+        fn assert_len<D: Database>(
+            db: &D,
+            len: usize,
+        ) {
+            assert_eq!(db.len().unwrap(), len);
+            assert_eq!(db.is_empty().unwrap(), len == 0);
+        }
+
+        let db = DB::new();
+        assert_len(&db, 0);
+        db.insert("a".to_string(), "1".into()).unwrap();
+        db.insert_str("b", "2").unwrap();
+        assert_len(&db, 2);
+        db.insert("a".to_string(), "3".into()).unwrap();
+        assert_len(&db, 2);
+        db.remove("a").unwrap();
+        db.remove("missing").unwrap();
+        assert_len(&db, 1);
+        db.clear().unwrap();
+        assert_len(&db, 0);
+    }
+
+    #[test]
+    fn test_len_tracks_concurrent_inserts_and_removes() {
+        let db = DB::new();
+        let threads: Vec<_> = (0..8)
+            .map(|t| {
+                let db = db.clone();
+                thread::spawn(move || {
+                    for i in 0..1000 {
+                        let key = format!("{}", (t * 7 + i) % 300);
+                        match i % 3 {
+                            0 | 1 => db.insert(key, Value::Int(i)).unwrap(),
+                            _ => db.remove(&key).unwrap(),
+                        }
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(db.len(), db.read().unwrap().len());
+        assert!(!db.is_empty());
+
+        db.clear().unwrap();
+        assert!(db.is_empty());
+    }
+
+    #[test]
+    fn test_get_prefix_returns_matching_pairs_sorted_by_key() {
+        let db = DB::new();
+        db.insert_str("a:2", "x").unwrap();
+        db.insert("a:1".to_string(), Value::Int(1)).unwrap();
+        db.insert_str("b:1", "y").unwrap();
+        db.insert_with_ttl("a:3".to_string(), "z".into(), Duration::ZERO)
+            .unwrap();
+
+        assert_eq!(
+            db.get_prefix("a:").unwrap(),
+            vec![
+                ("a:1".to_string(), Value::Int(1)),
+                ("a:2".to_string(), "x".into()),
+            ]
+        );
+        // An empty prefix matches everything, expired keys excepted
+        let keys: Vec<_> = db
+            .get_prefix("")
+            .unwrap()
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(keys, ["a:1", "a:2", "b:1"]);
+        assert!(db.get_prefix("c:").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_concurrent_incrs_of_the_same_key_sum_up() {
+        let db = DB::new();
+        let threads: Vec<_> = (0..8)
+            .map(|t| {
+                let db = db.clone();
+                thread::spawn(move || {
+                    for i in 0..1000 {
+                        db.incr("counter", t + 1).unwrap();
+                        // Plain writes to other keys in between
+                        db.insert(format!("{t}"), Value::Int(i)).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(
+            db.get("counter").unwrap(),
+            Some(Value::Int((1..=8).sum::<i64>() * 1000))
+        );
+    }
+
+    #[test]
+    fn test_concurrent_set_ranges_of_the_same_key_are_all_applied() {
+        let db = DB::new();
+        let threads: Vec<_> = (0..8)
+            .map(|t| {
+                let db = db.clone();
+                thread::spawn(move || {
+                    for i in 0..100 {
+                        db.set_range("a", t, &t.to_string()).unwrap();
+                        db.insert(format!("{t}"), Value::Int(i)).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(db.get("a").unwrap(), Some(Value::from("01234567")));
+    }
+
+    #[test]
+    fn test_insert_str_overwrites_like_insert() {
+        let db = DB::new();
+        db.insert_with_ttl("a".to_string(), "1".into(), Duration::from_secs(60))
+            .unwrap();
+        db.insert("b".to_string(), 2.into()).unwrap();
+
+        db.insert_str("a", "3").unwrap();
+        db.insert_str("b", "4").unwrap();
+        db.insert_str("c", "5").unwrap();
+        let lock = db.read().unwrap();
+        assert_eq!(lock.get("a").unwrap().expires_at(), None);
+        assert_eq!(lock.get("a").unwrap().value(), &Value::from("3"));
+        assert_eq!(lock.get("b").unwrap().value(), &Value::from("4"));
+        assert_eq!(lock.get("c").unwrap().value(), &Value::from("5"));
+        drop(lock);
+        assert_eq!(db.len(), 3);
+    }
+
+    #[test]
+    fn test_retain_removes_long_values() {
+        let db = DB::new();
+        db.insert("short".to_string(), "abc".into()).unwrap();
+        db.insert("long".to_string(), "abcdefghij".into()).unwrap();
+        db.insert("longer".to_string(), "abcdefghijkl".into())
+            .unwrap();
+        db.insert("int".to_string(), 1.into()).unwrap();
+        db.insert_with_ttl("expired".to_string(), "abc".into(), Duration::ZERO)
+            .unwrap();
+
+        // Remove all values longer than 8 bytes
+        let removed = db.retain(|_, value| value.len() <= 8).unwrap();
+        assert_eq!(removed, 2);
+        let mut survivors = db.scan(0, 10).unwrap().1;
+        survivors.sort();
+        assert_eq!(survivors, ["int", "short"]);
+        assert_eq!(db.get("short").unwrap(), Some("abc".into()));
+        assert_eq!(db.len(), 2);
+
+        let expected = DB::new();
+        expected.insert("short".to_string(), "abc".into()).unwrap();
+        expected.insert("int".to_string(), 1.into()).unwrap();
+        assert_eq!(db.memory_usage().unwrap(), expected.memory_usage().unwrap());
+    }
+
+    #[test]
+    fn test_background_clear_empties_the_database() {
+        let db = DB::new().background_clear(true);
+        db.insert("a".to_string(), "1".into()).unwrap();
+        db.insert("b".to_string(), 2.into()).unwrap();
+        db.clear().unwrap();
+        assert!(db.read().unwrap().is_empty());
+        assert!(db.is_empty());
+        assert_eq!(db.memory_usage().unwrap(), 0);
+
+        db.insert("a".to_string(), "3".into()).unwrap();
+        assert_eq!(db.get("a").unwrap(), Some("3".into()));
+        assert_eq!(db.len(), 1);
+    }
+
+    #[test]
+    fn test_background_clear_returns_before_freeing() {
+        // Best-effort: freeing many entries takes far longer than swapping the map.
+        let fill = |db: &DB| {
+            db.extend((0..200_000).map(|i| (i.to_string(), i.to_string())))
+                .unwrap();
+        };
+        let db = DB::new();
+        fill(&db);
+        let started_at = Instant::now();
+        db.clear().unwrap();
+        let in_place = started_at.elapsed();
+
+        let db = DB::new().background_clear(true);
+        fill(&db);
+        let started_at = Instant::now();
+        db.clear().unwrap();
+        let in_background = started_at.elapsed();
+        assert!(
+            in_background < in_place,
+            "{in_background:?} >= {in_place:?}"
+        );
+    }
+
+    #[test]
+    fn test_memory_usage_tracks_changes() {
+        let db = DB::new();
+        assert_eq!(db.memory_usage().unwrap(), 0);
+
+        db.insert("a".to_string(), "12345".into()).unwrap();
+        let usage = db.memory_usage().unwrap();
+        assert!(usage > 5);
+
+        // Replacing a value only accounts for the size difference
+        db.insert("a".to_string(), "1234567890".into()).unwrap();
+        assert_eq!(db.memory_usage().unwrap(), usage + 5);
+        assert!(db
+            .compare_and_swap("a", &"1234567890".into(), "12345".into())
+            .unwrap());
+        assert_eq!(db.memory_usage().unwrap(), usage);
+
+        db.insert("b".to_string(), "1".into()).unwrap();
+        db.remove("a").unwrap();
+        db.remove("b").unwrap();
+        assert_eq!(db.memory_usage().unwrap(), 0);
+
+        db.insert("c".to_string(), "1".into()).unwrap();
+        db.clear().unwrap();
+        assert_eq!(db.memory_usage().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_access_count_counts_reads_since_the_key_was_set() {
+        let db = DB::new().count_accesses(true);
+        db.insert("a".to_string(), 1.into()).unwrap();
+        assert_eq!(db.access_count("a").unwrap(), Some(0));
+        for _ in 0..10 {
+            db.get("a").unwrap();
+        }
+        assert_eq!(db.access_count("a").unwrap(), Some(10));
+        db.get_many(&["a", "b", "a"]).unwrap();
+        db.value_len("a").unwrap();
+        assert_eq!(db.access_count("a").unwrap(), Some(12));
+
+        db.incr("a", 1).unwrap();
+        assert_eq!(db.access_count("a").unwrap(), Some(12));
+        db.insert_str("a", "new").unwrap();
+        assert_eq!(db.access_count("a").unwrap(), Some(0));
+        assert_eq!(db.access_count("b").unwrap(), None);
+    }
+
+    #[test]
+    fn test_access_count_is_off_by_default() {
+        let db = DB::new();
+        db.insert("a".to_string(), 1.into()).unwrap();
+        db.get("a").unwrap();
+        assert_eq!(db.access_count("a").unwrap(), Some(0));
+        let db = bounded(EvictionPolicy::Lfu, 10);
+        db.insert("a".to_string(), 1.into()).unwrap();
+        db.get("a").unwrap();
+        assert_eq!(db.access_count("a").unwrap(), Some(1));
+    }
+
+    #[test]
+    fn test_get_arc_shares_the_value_until_it_is_written() {
+        let db = DB::new().count_accesses(true);
+        assert_eq!(db.get_arc("abc").unwrap(), None);
+        db.insert("abc".to_string(), "value".into()).unwrap();
+        let first = db.get_arc("abc").unwrap().unwrap();
+        let second = db.get_arc("abc").unwrap().unwrap();
+        assert_eq!(&*first, "value");
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(db.access_count("abc").unwrap(), Some(2));
+
+        db.insert_str("abc", "new").unwrap();
+        assert_eq!(db.get_arc("abc").unwrap().as_deref(), Some("new"));
+        assert_eq!(&*first, "value");
+        db.incr("abc", 1).unwrap_err();
+        db.insert("abc".to_string(), 1.into()).unwrap();
+        db.incr("abc", 1).unwrap();
+        assert_eq!(db.get_arc("abc").unwrap().as_deref(), Some("2"));
+
+        db.insert("bytes".to_string(), Value::Bytes(vec![0xff]))
+            .unwrap();
+        assert!(matches!(
+            db.get_arc("bytes"),
+            Err(crate::error::Error::Server(ServerError::Database(
+                DatabaseError::NotUtf8
+            )))
+        ));
+        db.insert_with_ttl("expired".to_string(), "1".into(), Duration::ZERO)
+            .unwrap();
+        assert_eq!(db.get_arc("expired").unwrap(), None);
+    }
+
+    #[test]
+    fn test_memory_usage_counts_shared_strings() {
+        let db = DB::new();
+        db.insert("a".to_string(), "value".into()).unwrap();
+        db.insert("n".to_string(), 12345.into()).unwrap();
+        let usage = db.memory_usage().unwrap();
+        db.get_arc("a").unwrap();
+        db.get_arc("a").unwrap();
+        db.get_arc("n").unwrap();
+        assert_eq!(
+            db.memory_usage().unwrap(),
+            usage + "value".len() + "12345".len()
+        );
+
+        // Writes drop the shared strings
+        db.insert_str("a", "value").unwrap();
+        db.incr("n", 0).unwrap();
+        assert_eq!(db.memory_usage().unwrap(), usage);
+        db.get_arc("a").unwrap();
+        db.remove("a").unwrap();
+        db.remove("n").unwrap();
+        assert_eq!(db.memory_usage().unwrap(), 0);
+    }
+
+    fn bounded(
+        policy: EvictionPolicy,
+        max_entries: usize,
+    ) -> DB {
+        let capacity = Capacity {
+            max_entries: Some(max_entries),
+            ..Capacity::default()
+        };
+        DB::new().eviction(policy, capacity)
+    }
+
+    fn keys(db: &DB) -> Vec<String> {
+        let mut keys: Vec<_> = db.read().unwrap().keys().cloned().collect();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn test_no_eviction_rejects_new_keys_once_full() {
+        let db = bounded(EvictionPolicy::NoEviction, 2);
+        db.insert("a".to_string(), 1.into()).unwrap();
+        db.insert("b".to_string(), 2.into()).unwrap();
+        assert!(matches!(
+            db.insert("c".to_string(), 3.into()),
+            Err(crate::error::Error::Server(ServerError::Database(
+                DatabaseError::Full
+            )))
+        ));
+        assert!(db.incr("c", 1).is_err());
+        // Existing keys can still be written
+        db.insert("a".to_string(), 3.into()).unwrap();
+        assert_eq!(db.incr("b", 1).unwrap(), 3);
+        assert!(!db.insert_if_absent("a".to_string(), 4.into()).unwrap());
+        assert_eq!(keys(&db), ["a", "b"]);
+    }
+
+    #[test]
+    fn test_expired_entries_are_evicted_first() {
+        let db = bounded(EvictionPolicy::NoEviction, 2);
+        db.insert("a".to_string(), 1.into()).unwrap();
+        db.insert_with_ttl("b".to_string(), 2.into(), Duration::ZERO)
+            .unwrap();
+        db.insert("c".to_string(), 3.into()).unwrap();
+        assert_eq!(keys(&db), ["a", "c"]);
+    }
+
+    #[test]
+    fn test_lru_evicts_the_least_recently_used_entry() {
+        let db = bounded(EvictionPolicy::Lru, 3);
+        for key in ["a", "b", "c"] {
+            db.insert(key.to_string(), 1.into()).unwrap();
+        }
+        db.get("a").unwrap();
+        db.incr("b", 1).unwrap();
+        db.insert("d".to_string(), 1.into()).unwrap();
+        assert_eq!(keys(&db), ["a", "b", "d"]);
+        db.insert_str("e", "1").unwrap();
+        assert_eq!(keys(&db), ["b", "d", "e"]);
+    }
+
+    #[test]
+    fn test_lfu_evicts_the_least_often_read_entry() {
+        let db = bounded(EvictionPolicy::Lfu, 3);
+        for (key, reads) in [("a", 3), ("b", 1), ("c", 2)] {
+            db.insert(key.to_string(), 1.into()).unwrap();
+            for _ in 0..reads {
+                db.get(key).unwrap();
+            }
+        }
+        db.insert("d".to_string(), 1.into()).unwrap();
+        assert_eq!(keys(&db), ["a", "c", "d"]);
+        // The new key was not read yet
+        db.insert("e".to_string(), 1.into()).unwrap();
+        assert_eq!(keys(&db), ["a", "c", "e"]);
+    }
+
+    #[test]
+    fn test_random_eviction_keeps_the_written_key() {
+        let db = bounded(EvictionPolicy::Random, 3);
+        for i in 0..100 {
+            db.insert(i.to_string(), i.into()).unwrap();
+            assert_eq!(db.get(&i.to_string()).unwrap(), Some(i.into()));
+            assert!(db.len() <= 3);
+        }
+        assert_eq!(db.len(), 3);
+    }
+
+    #[test]
+    fn test_volatile_ttl_evicts_the_entry_expiring_next() {
+        let db = bounded(EvictionPolicy::VolatileTtl, 3);
+        db.insert("a".to_string(), 1.into()).unwrap();
+        db.insert_with_ttl("b".to_string(), 1.into(), Duration::from_secs(60))
+            .unwrap();
+        db.insert_with_ttl("c".to_string(), 1.into(), Duration::from_secs(30))
+            .unwrap();
+        db.insert("d".to_string(), 1.into()).unwrap();
+        assert_eq!(keys(&db), ["a", "b", "d"]);
+        db.insert("e".to_string(), 1.into()).unwrap();
+        assert_eq!(keys(&db), ["a", "d", "e"]);
+        // Only entries without an expiry are left
+        assert!(matches!(
+            db.insert("f".to_string(), 1.into()),
+            Err(crate::error::Error::Server(ServerError::Database(
+                DatabaseError::Full
+            )))
+        ));
+    }
+
+    #[test]
+    fn test_sampled_eviction_keeps_large_databases_bounded() {
+        let db = bounded(EvictionPolicy::Lru, 100);
+        for i in 0..1000 {
+            db.insert(i.to_string(), i.into()).unwrap();
+            assert_eq!(db.get(&i.to_string()).unwrap(), Some(i.into()));
+            db.get("0").unwrap();
+            assert!(db.len() <= 100);
+        }
+        assert_eq!(db.len(), 100);
+        // The most recently used key is never the best of a sample
+        assert_eq!(db.get("0").unwrap(), Some(0.into()));
+        // Removed keys left in the sampled copy are skipped
+        db.retain(|key, _| key.len() < 3).unwrap();
+        for i in 1000..1200 {
+            db.insert(i.to_string(), i.into()).unwrap();
+        }
+        assert_eq!(db.len(), 100);
+    }
+
+    #[test]
+    fn test_replace_all_rejects_contents_exceeding_the_capacity() {
+        let db = bounded(EvictionPolicy::Lru, 2);
+        db.insert("a".to_string(), 1.into()).unwrap();
+        let pairs = ["x", "y", "z"].map(|key| (key.to_string(), "1".to_string()));
+        assert!(matches!(
+            db.replace_all(pairs.clone()),
+            Err(crate::error::Error::Server(ServerError::Database(
+                DatabaseError::Full
+            )))
+        ));
+        assert_eq!(keys(&db), ["a"]);
+        db.replace_all(pairs.into_iter().skip(1)).unwrap();
+        assert_eq!(keys(&db), ["y", "z"]);
+    }
+
+    #[test]
+    fn test_exceeding_the_max_memory_evicts() {
+        let probe = DB::new();
+        probe.insert("a".to_string(), "1".into()).unwrap();
+        probe.insert("b".to_string(), "2".into()).unwrap();
+        let capacity = Capacity {
+            max_memory_bytes: Some(probe.memory_usage().unwrap()),
+            ..Capacity::default()
+        };
+        let db = DB::new().eviction(EvictionPolicy::Lru, capacity);
+        for key in ["a", "b", "c", "d"] {
+            db.insert(key.to_string(), "1".into()).unwrap();
+        }
+        assert_eq!(keys(&db), ["c", "d"]);
+        assert!(db.memory_usage().unwrap() <= probe.memory_usage().unwrap());
+    }
+
+    #[test]
+    fn test_read_modify_writes_treat_expired_keys_as_missing() {
+        let db = DB::new();
+        db.insert_with_ttl("counter".to_string(), 5.into(), Duration::ZERO)
+            .unwrap();
+        db.insert_with_ttl("absent".to_string(), "old".into(), Duration::ZERO)
+            .unwrap();
+        assert_eq!(db.incr("counter", 2).unwrap(), 2);
+        assert_eq!(db.incr("counter", 3).unwrap(), 5);
+        assert_eq!(db.incr("new", -1).unwrap(), -1);
+        assert!(db
+            .insert_if_absent("absent".to_string(), "new".into())
+            .unwrap());
+        assert!(!db
+            .insert_if_absent("absent".to_string(), "newer".into())
+            .unwrap());
+        assert!(db
+            .insert_if_absent("other".to_string(), "1".into())
+            .unwrap());
+        assert_eq!(db.get("counter").unwrap(), Some(5.into()));
+        assert_eq!(db.get("absent").unwrap(), Some("new".into()));
+        assert_eq!(db.len(), 4);
+
+        let expected = DB::new();
+        expected.insert("counter".to_string(), 5.into()).unwrap();
+        expected.insert("new".to_string(), (-1).into()).unwrap();
+        expected.insert("absent".to_string(), "new".into()).unwrap();
+        expected.insert("other".to_string(), "1".into()).unwrap();
+        assert_eq!(db.memory_usage().unwrap(), expected.memory_usage().unwrap());
+    }
 }
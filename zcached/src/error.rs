@@ -1,10 +1,18 @@
+use std::collections::TryReserveError;
+
 use thiserror::Error;
 
+use crate::ErrorCode;
+use crate::ParsingError;
+use crate::SerializationError;
+
 #[derive(Debug, Error)]
 pub enum Error {
     #[error(transparent)]
     Parsing(#[from] ParsingError),
     #[error(transparent)]
+    Serialization(#[from] SerializationError),
+    #[error(transparent)]
     Server(#[from] ServerError),
     #[error(transparent)]
     Client(#[from] ClientError),
@@ -12,40 +20,71 @@ pub enum Error {
     IO(#[from] std::io::Error),
 }
 
-#[derive(Debug, Error)]
-pub enum ParsingError {
-    #[error("cannot convert Utf8")]
-    Utf8Error(#[from] std::str::Utf8Error),
-    #[error("other parsing error")]
-    Other,
-}
-
 #[derive(Debug, Error)]
 pub enum ServerError {
     #[error("no address provided for starting server")]
     NoAddress,
+    /// The address is already in use or binding to it is not permitted.
+    #[error("could not bind to the address")]
+    Bind(#[source] std::io::Error),
     #[error("received too much data")]
     TooMuchData,
+    /// The buffer of a connection could not be grown, e.g. because the maximum buffer size
+    /// is larger than what can be allocated.
+    #[error("could not grow the connection buffer to {len} bytes")]
+    BufferAllocation {
+        len: usize,
+        #[source]
+        source: TryReserveError,
+    },
     #[error("connection reset by peer")]
     ConnectionResetByPeer,
+    #[error("timed out while receiving a request")]
+    Timeout,
     #[error("database error")]
     Database(#[from] DatabaseError),
     #[error("database IO issue")]
     IO(#[from] std::io::Error),
+    #[error("panicked while handling connection: {0}")]
+    Panic(String),
 }
 
 #[derive(Debug, Error)]
 pub enum DatabaseError {
     #[error("database locking issue")]
     DbLock,
+    #[error("value is not an integer")]
+    NotAnInteger,
+    #[error("integer overflow")]
+    IntegerOverflow,
+    #[error("value is not valid Utf8")]
+    NotUtf8,
+    /// The database reached its capacity and its eviction policy found no entry to evict.
+    #[error("database is full")]
+    Full,
 }
 
 #[derive(Debug, Error)]
 pub enum ClientError {
+    #[error("no address provided for connecting the client")]
+    NoAddress,
     #[error("connection reset by peer")]
     ConnectionResetByPeer,
+    /// A response exceeded the client's maximum buffer size,
+    /// or a request exceeded the server's, which closed the connection then.
     #[error("received too much data")]
     TooMuchData,
+    /// The server did not respond in time.
+    /// A response to the request may still arrive later, so the connection should not be reused.
+    #[error("timed out waiting for a response")]
+    Timeout,
+    #[error("received an unexpected response")]
+    UnexpectedResponse,
+    #[error("server error {code:?}: {}", message.as_deref().unwrap_or("no message"))]
+    Server {
+        code: ErrorCode,
+        message: Option<String>,
+    },
 }
 
 pub(crate) type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,379 @@
+use std::io;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Write;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::net::ToSocketAddrs;
+use std::sync::atomic::AtomicUsize;
+use std::thread;
+use std::time::Duration;
+
+use tracing::error;
+
+use crate::db::Database;
+use crate::db::Value;
+use crate::error::Error;
+use crate::error::Result;
+use crate::server::accept_loop;
+
+/// The maximum size of the request line and all headers in bytes.
+const MAX_HEADER_SIZE: usize = 8 * 1024;
+/// The maximum size of a request body in bytes.
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+/// How long a connection is waited for by default, see [`HttpGateway::read_timeout`].
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A minimal HTTP server exposing a [`Database`] as JSON.
+///
+/// - `GET /<key>` returns `200` with `{"key":"<key>","value":"<value>"}` or `404` if the key does not exist.
+/// - `PUT /<key>` stores the request body as the value and returns `200` with `{"key":"<key>"}`.
+/// - `DELETE /<key>` removes the key and returns `200` with `{"key":"<key>"}`.
+///
+/// Keys are taken verbatim from the path, they are not percent-decoded.
+/// Every connection handles a single request and is closed afterwards.
+pub struct HttpGateway<DB> {
+    db: DB,
+    read_timeout: Duration,
+}
+
+impl<DB> HttpGateway<DB>
+where
+    DB: Database + Clone + 'static,
+{
+    /// Creates a new `HttpGateway` serving `db`.
+    pub fn new(db: DB) -> Self {
+        Self {
+            db,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+        }
+    }
+
+    /// Sets how long the gateway waits for data from a client before answering with `408`
+    /// and closing the connection, so that clients that never finish their request do not keep
+    /// a thread busy forever. Defaults to 30 seconds.
+    pub fn read_timeout(
+        mut self,
+        read_timeout: Duration,
+    ) -> Self {
+        self.read_timeout = read_timeout;
+        self
+    }
+
+    /// Binds to `addr` and serves requests, see [`serve_listener`](HttpGateway::serve_listener).
+    pub fn serve<A: ToSocketAddrs>(
+        self,
+        addr: A,
+    ) -> Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        self.serve_listener(listener)
+    }
+
+    /// Serves requests from the already bound `listener`.
+    /// Failures to accept a connection are logged and retried like in [`Server::run`](crate::Server::run).
+    pub fn serve_listener(
+        self,
+        listener: TcpListener,
+    ) -> Result<()> {
+        let accept_failures = AtomicUsize::new(0);
+        accept_loop(listener.incoming(), &accept_failures, |stream| {
+            let db = self.db.clone();
+            let read_timeout = self.read_timeout;
+            thread::spawn(move || {
+                if let Err(e) = handle_http_connection(stream, &db, read_timeout) {
+                    error!("failed to handle http connection: {e}");
+                }
+            });
+        });
+        Ok(())
+    }
+}
+
+/// A parsed HTTP request.
+struct HttpRequest {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+}
+
+/// An HTTP response with a JSON body.
+struct HttpResponse {
+    status: u16,
+    body: String,
+}
+
+impl HttpResponse {
+    fn new(
+        status: u16,
+        body: String,
+    ) -> Self {
+        Self { status, body }
+    }
+
+    fn error(
+        status: u16,
+        message: &str,
+    ) -> Self {
+        Self::new(status, format!("{{\"error\":{}}}", json_string(message)))
+    }
+}
+
+fn handle_http_connection<DB: Database>(
+    mut stream: TcpStream,
+    db: &DB,
+    read_timeout: Duration,
+) -> Result<()> {
+    stream.set_read_timeout(Some(read_timeout))?;
+    let response = match read_http_request(&mut stream) {
+        Ok(Some(request)) => apply_http_request(request, db),
+        Ok(None) => HttpResponse::error(400, "bad request"),
+        Err(Error::IO(e)) if e.kind() == io::ErrorKind::InvalidData => {
+            HttpResponse::error(413, "request too large")
+        }
+        // A read timeout is reported as one of these depending on the platform
+        Err(Error::IO(e))
+            if matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ) =>
+        {
+            HttpResponse::error(408, "request timeout")
+        }
+        Err(e) => return Err(e),
+    };
+    write_http_response(&mut stream, &response)?;
+    Ok(())
+}
+
+/// Reads a single request from the `stream`.
+/// Returns `None` if the request is malformed, e.g. if its headers are not valid Utf8.
+/// Fails with [`io::ErrorKind::InvalidData`] if the headers or the body are too large.
+fn read_http_request<R: Read>(stream: R) -> Result<Option<HttpRequest>> {
+    let mut reader = BufReader::new(stream);
+    let mut header_size = 0;
+    let mut read_line = |reader: &mut BufReader<R>| -> Result<Option<String>> {
+        let mut line = Vec::new();
+        let n = reader
+            .by_ref()
+            .take((MAX_HEADER_SIZE - header_size) as u64)
+            .read_until(b'\n', &mut line)?;
+        header_size += n;
+        if header_size >= MAX_HEADER_SIZE {
+            return Err(io::Error::from(io::ErrorKind::InvalidData).into());
+        }
+        let Ok(line) = String::from_utf8(line) else {
+            return Ok(None);
+        };
+        Ok(Some(line.trim_end_matches(['\r', '\n']).to_string()))
+    };
+
+    let Some(request_line) = read_line(&mut reader)? else {
+        return Ok(None);
+    };
+    let mut parts = request_line.split(' ');
+    let (Some(method), Some(path), Some(_version)) = (parts.next(), parts.next(), parts.next())
+    else {
+        return Ok(None);
+    };
+    let (method, path) = (method.to_string(), path.to_string());
+
+    let mut content_length = 0;
+    loop {
+        let Some(line) = read_line(&mut reader)? else {
+            return Ok(None);
+        };
+        if line.is_empty() {
+            break;
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            return Ok(None);
+        };
+        if name.trim().eq_ignore_ascii_case("content-length") {
+            let Ok(length) = value.trim().parse::<usize>() else {
+                return Ok(None);
+            };
+            content_length = length;
+        }
+    }
+    if content_length > MAX_BODY_SIZE {
+        return Err(io::Error::from(io::ErrorKind::InvalidData).into());
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    Ok(Some(HttpRequest { method, path, body }))
+}
+
+/// Applies the `request` to the database and returns the response to be sent to the client.
+fn apply_http_request<DB: Database>(
+    request: HttpRequest,
+    db: &DB,
+) -> HttpResponse {
+    let Some(key) = request.path.strip_prefix('/').filter(|key| !key.is_empty()) else {
+        return HttpResponse::error(404, "not found");
+    };
+    let key_json = json_string(key);
+    let result = match request.method.as_str() {
+        "GET" => db.get(key).map(|value| match value.map(String::try_from) {
+            Some(Ok(value)) => HttpResponse::new(
+                200,
+                format!("{{\"key\":{key_json},\"value\":{}}}", json_string(&value)),
+            ),
+            Some(Err(_)) => HttpResponse::error(422, "value is not valid Utf8"),
+            None => HttpResponse::error(404, "not found"),
+        }),
+        "PUT" => match String::from_utf8(request.body) {
+            Ok(value) => db
+                .insert(key.to_string(), Value::Str(value))
+                .map(|_| HttpResponse::new(200, format!("{{\"key\":{key_json}}}"))),
+            Err(_) => Ok(HttpResponse::error(400, "body is not valid Utf8")),
+        },
+        "DELETE" => db
+            .remove(key)
+            .map(|_| HttpResponse::new(200, format!("{{\"key\":{key_json}}}"))),
+        _ => Ok(HttpResponse::error(405, "method not allowed")),
+    };
+    result.unwrap_or_else(|e| HttpResponse::error(500, &e.to_string()))
+}
+
+fn write_http_response<W: Write>(
+    stream: &mut W,
+    response: &HttpResponse,
+) -> io::Result<()> {
+    let reason = match response.status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        413 => "Payload Too Large",
+        422 => "Unprocessable Entity",
+        _ => "Internal Server Error",
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {} {reason}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        response.body.len(),
+        response.body
+    )?;
+    stream.flush()
+}
+
+/// Encodes `s` as a JSON string including the surrounding quotes.
+fn json_string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c.is_control() => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::DB;
+
+    /// Sends a single request to the gateway and returns the status code and body.
+    fn send(
+        addr: std::net::SocketAddr,
+        method: &str,
+        path: &str,
+        body: &str,
+    ) -> (u16, String) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "{method} {path} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+        (status, body.to_string())
+    }
+
+    fn start_gateway() -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || HttpGateway::new(DB::new()).serve_listener(listener));
+        addr
+    }
+
+    #[test]
+    fn test_get_missing_key_is_not_found() {
+        let addr = start_gateway();
+        let (status, body) = send(addr, "GET", "/abc", "");
+        assert_eq!(status, 404);
+        assert_eq!(body, r#"{"error":"not found"}"#);
+    }
+
+    #[test]
+    fn test_put_get_and_delete() {
+        let addr = start_gateway();
+        let (status, body) = send(addr, "PUT", "/abc", "some \"value\"");
+        assert_eq!(status, 200);
+        assert_eq!(body, r#"{"key":"abc"}"#);
+
+        let (status, body) = send(addr, "GET", "/abc", "");
+        assert_eq!(status, 200);
+        assert_eq!(body, r#"{"key":"abc","value":"some \"value\""}"#);
+
+        let (status, body) = send(addr, "DELETE", "/abc", "");
+        assert_eq!(status, 200);
+        assert_eq!(body, r#"{"key":"abc"}"#);
+
+        let (status, _) = send(addr, "GET", "/abc", "");
+        assert_eq!(status, 404);
+    }
+
+    #[test]
+    fn test_unsupported_method_is_rejected() {
+        let addr = start_gateway();
+        let (status, body) = send(addr, "POST", "/abc", "");
+        assert_eq!(status, 405);
+        assert_eq!(body, r#"{"error":"method not allowed"}"#);
+    }
+
+    #[test]
+    fn test_headers_that_are_not_utf8_are_a_bad_request() {
+        let request = b"GET /abc HTTP/1.1\r\nX-Name: \xff\r\n\r\n";
+        assert!(read_http_request(&request[..]).unwrap().is_none());
+        let request = b"GET /\xff HTTP/1.1\r\n\r\n";
+        assert!(read_http_request(&request[..]).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_stalling_client_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let gateway = HttpGateway::new(DB::new()).read_timeout(Duration::from_millis(50));
+        thread::spawn(move || gateway.serve_listener(listener));
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET /abc HTTP/1.1\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(
+            response.starts_with("HTTP/1.1 408 Request Timeout"),
+            "{response}"
+        );
+    }
+
+    #[test]
+    fn test_json_string_escapes() {
+        assert_eq!(json_string("a\"b\\c\nd\u{1}"), r#""a\"b\\c\nd\u0001""#);
+    }
+}
@@ -0,0 +1,392 @@
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+
+use crate::db::Database;
+use crate::db::Value;
+use crate::db::DB;
+use crate::error::Result;
+use crate::GetModified;
+use crate::KeyMeta;
+
+/// A [`Database`] decorator counting and timing every operation on the inner database `D`.
+///
+/// Clones share their metrics, so a clone can be kept to read the [`metrics`](MeteredDb::metrics)
+/// after handing the `MeteredDb` to a [`Server`](crate::Server).
+/// Querying the memory usage or whether the database is poisoned is not counted.
+#[derive(Debug, Clone)]
+pub struct MeteredDb<D> {
+    inner: D,
+    counters: Arc<Counters>,
+}
+
+/// A snapshot of the operations on a [`MeteredDb`].
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct DbMetrics {
+    /// The number of operations only reading from the database.
+    pub reads: u64,
+    /// The number of operations inserting or changing values or expiries.
+    pub writes: u64,
+    /// The number of operations removing keys, including flushes.
+    pub removals: u64,
+    /// The number of operations that returned an error.
+    pub errors: u64,
+    /// The total time spent in all operations, including waiting for the database's lock.
+    pub busy_time: Duration,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    reads: AtomicU64,
+    writes: AtomicU64,
+    removals: AtomicU64,
+    errors: AtomicU64,
+    busy_nanos: AtomicU64,
+}
+
+/// The kind of an operation, see [`DbMetrics`].
+#[derive(Debug, Copy, Clone)]
+enum Operation {
+    Read,
+    Write,
+    Removal,
+}
+
+impl<D: Database> MeteredDb<D> {
+    /// Wraps `inner` with metrics starting at zero.
+    pub fn new(inner: D) -> Self {
+        Self {
+            inner,
+            counters: Arc::default(),
+        }
+    }
+
+    /// Returns the wrapped database.
+    pub fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    /// Returns a snapshot of the metrics collected so far.
+    pub fn metrics(&self) -> DbMetrics {
+        let counters = &self.counters;
+        DbMetrics {
+            reads: counters.reads.load(Ordering::Relaxed),
+            writes: counters.writes.load(Ordering::Relaxed),
+            removals: counters.removals.load(Ordering::Relaxed),
+            errors: counters.errors.load(Ordering::Relaxed),
+            busy_time: Duration::from_nanos(counters.busy_nanos.load(Ordering::Relaxed)),
+        }
+    }
+
+    /// Runs `f` on the inner database and records it as `operation`.
+    fn measure<T>(
+        &self,
+        operation: Operation,
+        f: impl FnOnce(&D) -> Result<T>,
+    ) -> Result<T> {
+        let started_at = Instant::now();
+        let result = f(&self.inner);
+        let elapsed = u64::try_from(started_at.elapsed().as_nanos()).unwrap_or(u64::MAX);
+        let counters = &self.counters;
+        let counter = match operation {
+            Operation::Read => &counters.reads,
+            Operation::Write => &counters.writes,
+            Operation::Removal => &counters.removals,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        counters.busy_nanos.fetch_add(elapsed, Ordering::Relaxed);
+        if result.is_err() {
+            counters.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+}
+
+impl<D: Database> From<D> for MeteredDb<D> {
+    fn from(inner: D) -> Self {
+        Self::new(inner)
+    }
+}
+
+impl Default for MeteredDb<DB> {
+    fn default() -> Self {
+        Self::new(DB::new())
+    }
+}
+
+impl<D: Database> Database for MeteredDb<D> {
+    fn get(
+        &self,
+        key: &str,
+    ) -> Result<Option<Value>> {
+        self.measure(Operation::Read, |db| db.get(key))
+    }
+
+    fn get_arc(
+        &self,
+        key: &str,
+    ) -> Result<Option<Arc<str>>> {
+        self.measure(Operation::Read, |db| db.get_arc(key))
+    }
+
+    fn get_and_touch(
+        &self,
+        key: &str,
+        ttl: Duration,
+    ) -> Result<Option<Value>> {
+        self.measure(Operation::Read, |db| db.get_and_touch(key, ttl))
+    }
+
+    fn get_with_meta(
+        &self,
+        key: &str,
+    ) -> Result<Option<(Value, KeyMeta)>> {
+        self.measure(Operation::Read, |db| db.get_with_meta(key))
+    }
+
+    fn get_if_modified_since(
+        &self,
+        key: &str,
+        since: SystemTime,
+    ) -> Result<GetModified<Value>> {
+        self.measure(Operation::Read, |db| db.get_if_modified_since(key, since))
+    }
+
+    fn get_range(
+        &self,
+        key: &str,
+        start: i64,
+        end: i64,
+    ) -> Result<Option<Value>> {
+        self.measure(Operation::Read, |db| db.get_range(key, start, end))
+    }
+
+    fn value_len(
+        &self,
+        key: &str,
+    ) -> Result<Option<usize>> {
+        self.measure(Operation::Read, |db| db.value_len(key))
+    }
+
+    fn access_count(
+        &self,
+        key: &str,
+    ) -> Result<Option<u64>> {
+        self.measure(Operation::Read, |db| db.access_count(key))
+    }
+
+    fn get_many(
+        &self,
+        keys: &[&str],
+    ) -> Result<Vec<Option<Value>>> {
+        self.measure(Operation::Read, |db| db.get_many(keys))
+    }
+
+    fn get_many_ref(
+        &self,
+        keys: &[&str],
+        visit: &mut dyn FnMut(Option<&Value>),
+    ) -> Result<()> {
+        self.measure(Operation::Read, |db| db.get_many_ref(keys, visit))
+    }
+
+    fn insert(
+        &self,
+        key: String,
+        value: Value,
+    ) -> Result<()> {
+        self.measure(Operation::Write, |db| db.insert(key, value))
+    }
+
+    fn insert_str(
+        &self,
+        key: &str,
+        value: &str,
+    ) -> Result<()> {
+        self.measure(Operation::Write, |db| db.insert_str(key, value))
+    }
+
+    fn set_range(
+        &self,
+        key: &str,
+        offset: usize,
+        value: &str,
+    ) -> Result<usize> {
+        self.measure(Operation::Write, |db| db.set_range(key, offset, value))
+    }
+
+    fn insert_with_ttl(
+        &self,
+        key: String,
+        value: Value,
+        ttl: Duration,
+    ) -> Result<()> {
+        self.measure(Operation::Write, |db| db.insert_with_ttl(key, value, ttl))
+    }
+
+    // Forwarded instead of using the provided implementation, which would lose the inner database's atomicity.
+    fn extend(
+        &self,
+        pairs: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<()> {
+        self.measure(Operation::Write, |db| db.extend(pairs))
+    }
+
+    fn replace_all(
+        &self,
+        pairs: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<()> {
+        self.measure(Operation::Write, |db| db.replace_all(pairs))
+    }
+
+    fn insert_if_absent(
+        &self,
+        key: String,
+        value: Value,
+    ) -> Result<bool> {
+        self.measure(Operation::Write, |db| db.insert_if_absent(key, value))
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: &str,
+        expected: &Value,
+        new: Value,
+    ) -> Result<bool> {
+        self.measure(Operation::Write, |db| {
+            db.compare_and_swap(key, expected, new)
+        })
+    }
+
+    fn incr(
+        &self,
+        key: &str,
+        delta: i64,
+    ) -> Result<i64> {
+        self.measure(Operation::Write, |db| db.incr(key, delta))
+    }
+
+    fn contains_many(
+        &self,
+        keys: &[&str],
+    ) -> Result<Vec<bool>> {
+        self.measure(Operation::Read, |db| db.contains_many(keys))
+    }
+
+    fn touch_many(
+        &self,
+        keys: &[&str],
+        ttl: Duration,
+    ) -> Result<usize> {
+        self.measure(Operation::Write, |db| db.touch_many(keys, ttl))
+    }
+
+    fn count_prefix(
+        &self,
+        prefix: &str,
+    ) -> Result<usize> {
+        self.measure(Operation::Read, |db| db.count_prefix(prefix))
+    }
+
+    fn get_prefix(
+        &self,
+        prefix: &str,
+    ) -> Result<Vec<(String, Value)>> {
+        self.measure(Operation::Read, |db| db.get_prefix(prefix))
+    }
+
+    fn remove(
+        &self,
+        key: &str,
+    ) -> Result<()> {
+        self.measure(Operation::Removal, |db| db.remove(key))
+    }
+
+    fn delete_if_older_than(
+        &self,
+        key: &str,
+        cutoff: SystemTime,
+    ) -> Result<bool> {
+        self.measure(Operation::Removal, |db| {
+            db.delete_if_older_than(key, cutoff)
+        })
+    }
+
+    fn remove_if(
+        &self,
+        key: &str,
+        expected: &Value,
+    ) -> Result<bool> {
+        self.measure(Operation::Removal, |db| db.remove_if(key, expected))
+    }
+
+    fn retain(
+        &self,
+        keep: impl Fn(&str, &Value) -> bool,
+    ) -> Result<usize> {
+        self.measure(Operation::Removal, |db| db.retain(keep))
+    }
+
+    fn clear(&self) -> Result<()> {
+        self.measure(Operation::Removal, |db| db.clear())
+    }
+
+    fn clear_returning(&self) -> Result<Vec<String>> {
+        self.measure(Operation::Removal, |db| db.clear_returning())
+    }
+
+    fn is_poisoned(&self) -> bool {
+        self.inner.is_poisoned()
+    }
+
+    fn memory_usage(&self) -> Result<usize> {
+        self.inner.memory_usage()
+    }
+
+    fn len(&self) -> Result<usize> {
+        self.inner.len()
+    }
+
+    fn scan(
+        &self,
+        cursor: usize,
+        count: usize,
+    ) -> Result<(usize, Vec<String>)> {
+        self.measure(Operation::Read, |db| db.scan(cursor, count))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_operations_are_counted() {
+        let db = MeteredDb::new(DB::new());
+        let metrics = db.clone();
+        db.insert("a".to_string(), "1".into()).unwrap();
+        db.insert_str("b", "2").unwrap();
+        assert_eq!(db.get("a").unwrap(), Some("1".into()));
+        assert_eq!(db.get_many(&["a", "b"]).unwrap().len(), 2);
+        assert!(db.incr("a", 1).is_err());
+        db.remove("b").unwrap();
+        db.memory_usage().unwrap();
+
+        let metrics = metrics.metrics();
+        assert_eq!(
+            metrics,
+            DbMetrics {
+                reads: 2,
+                writes: 3,
+                removals: 1,
+                errors: 1,
+                busy_time: metrics.busy_time,
+            }
+        );
+        assert!(metrics.busy_time > Duration::ZERO);
+        assert_eq!(db.inner().len(), 1);
+    }
+}
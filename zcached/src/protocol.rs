@@ -0,0 +1,1794 @@
+//! The wire protocol spoken between the server and the client.
+//!
+//! Only `core` and `alloc` are needed here, so that the protocol can be used without `std`.
+
+use alloc::string::String;
+use alloc::string::ToString;
+use alloc::vec::Vec;
+use core::fmt;
+use core::str::Utf8Error;
+use core::time::Duration;
+
+use crate::serialization::read_count_prefixed;
+use crate::serialization::read_element;
+use crate::serialization::read_element_within;
+use crate::serialization::read_u32;
+use crate::serialization::read_u64;
+use crate::serialization::read_u8;
+#[cfg(feature = "std")]
+use crate::serialization::write_element;
+
+pub(crate) type Result<T> = core::result::Result<T, ParsingError>;
+
+/// The version of the wire protocol spoken by this crate.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Information about the server a client is connected to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerInfo {
+    /// The version of the wire protocol.
+    pub protocol_version: u32,
+    /// The version of the server crate.
+    pub version: String,
+    /// The time since the server was started, with millisecond precision.
+    pub uptime: Duration,
+    /// The optional features supported by the server, e.g. `ttl`, or `auth` if the server has an
+    /// admin password that [`Request::Auth`] must be sent before flushing.
+    pub features: Vec<String>,
+}
+
+/// The outcome of a conditional get, see [`Request::GetIfModifiedSince`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GetModified<T = String> {
+    /// The key exists but was not written since the given time, so its value is not sent.
+    NotModified,
+    /// The key was written since the given time.
+    Value(T),
+    /// The key does not exist.
+    Missing,
+}
+
+/// Metadata about a stored key.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct KeyMeta {
+    /// The remaining time until the key expires, with millisecond precision on the wire.
+    /// `None` if the key never expires.
+    pub ttl: Option<Duration>,
+    /// The size of the value in bytes, as it is returned by a GET, so counters count their digits.
+    pub size: u64,
+}
+
+/// A response sent by the server.
+#[derive(Debug, PartialEq)]
+pub enum Response {
+    /// The value of the requested key, `None` if the key does not exist.
+    /// Empty values are sent as `None`.
+    Get(Option<String>),
+    Set,
+    Delete,
+    Flush,
+    /// A page of keys and the cursor to continue the scan with.
+    /// A cursor of `0` means the scan is complete.
+    Scan {
+        cursor: u64,
+        keys: Vec<String>,
+    },
+    Select,
+    FlushAll,
+    /// The values of the requested keys in request order, `None` for keys that do not exist.
+    /// Empty values are sent as `None`.
+    MGet(Vec<Option<String>>),
+    MSet,
+    /// The value of the key after incrementing it.
+    Incr(i64),
+    /// Whether each of the requested keys exists, in the order of the request.
+    MExists(Vec<bool>),
+    /// The number of keys that were touched.
+    MTouch(u32),
+    /// The number of keys starting with the requested prefix.
+    CountPrefix(u64),
+    /// Whether the value was swapped.
+    Cas(bool),
+    /// Whether the value was set.
+    SetNx(bool),
+    /// Information about the server.
+    Info(ServerInfo),
+    /// Whether the key was deleted.
+    DeleteIfOlderThan(bool),
+    Auth,
+    /// The value of the requested key and its metadata, `None` if the key does not exist.
+    /// Empty values are sent as `None`.
+    GetMeta(Option<(String, KeyMeta)>),
+    /// The answer to a [`Request::Ping`].
+    Pong,
+    /// The value of the requested key if it was modified since the given time.
+    GetIfModifiedSince(GetModified),
+    /// The client id was accepted.
+    Hello,
+    /// The requested byte range of the value, `None` if the key does not exist.
+    GetRange(Option<String>),
+    /// The length of the value in bytes after the write.
+    SetRange(u64),
+    /// The length of the value in bytes, `None` if the key does not exist.
+    StrLen(Option<u64>),
+    ReplaceAll,
+    /// A transaction was started, see [`Request::Multi`].
+    Multi,
+    /// The request was queued in the transaction and is applied on [`Request::Exec`].
+    Queued,
+    /// The responses to the requests of the transaction in the order they were queued.
+    Exec(Vec<Response>),
+    /// The keys are watched, see [`Request::Watch`].
+    Watch,
+    /// Pushed without a request when another connection wrote a watched key,
+    /// see [`Request::Watch`].
+    Invalidate(String),
+    /// The keys starting with the requested prefix and their values, sorted by key.
+    GetPrefix(Vec<(String, String)>),
+    /// Whether the key was deleted.
+    DeleteIf(bool),
+    /// The server could not handle the request.
+    Error {
+        code: ErrorCode,
+        /// A short, human readable description of the error.
+        message: Option<String>,
+    },
+}
+
+impl Response {
+    /// Returns `true` if the response to a lookup of a single key found the key,
+    /// i.e. for [`Get`], [`GetRange`], [`GetMeta`] and [`StrLen`] with a value
+    /// and for [`GetIfModifiedSince`] unless the key is missing.
+    /// Since empty values are sent as `None`, an empty value is a miss.
+    ///
+    /// [`Get`]: Response::Get
+    /// [`GetRange`]: Response::GetRange
+    /// [`GetMeta`]: Response::GetMeta
+    /// [`StrLen`]: Response::StrLen
+    /// [`GetIfModifiedSince`]: Response::GetIfModifiedSince
+    pub fn is_hit(&self) -> bool {
+        match self {
+            Response::Get(value) | Response::GetRange(value) => value.is_some(),
+            Response::GetMeta(value) => value.is_some(),
+            Response::StrLen(len) => len.is_some(),
+            Response::GetIfModifiedSince(modified) => modified != &GetModified::Missing,
+            _ => false,
+        }
+    }
+
+    /// Returns `true` if the response to a lookup of a single key did not find the key,
+    /// see [`is_hit`](Response::is_hit). Responses to other requests are neither a hit nor a miss.
+    pub fn is_miss(&self) -> bool {
+        match self {
+            Response::Get(value) | Response::GetRange(value) => value.is_none(),
+            Response::GetMeta(value) => value.is_none(),
+            Response::StrLen(len) => len.is_none(),
+            Response::GetIfModifiedSince(modified) => modified == &GetModified::Missing,
+            _ => false,
+        }
+    }
+
+    /// Returns the value of a response to a lookup of a single key, `None` if the key
+    /// was not found, the value was not modified, or the response carries no single value.
+    pub fn value(&self) -> Option<&str> {
+        match self {
+            Response::Get(value) | Response::GetRange(value) => value.as_deref(),
+            Response::GetMeta(value) => value.as_ref().map(|(value, _)| value.as_str()),
+            Response::GetIfModifiedSince(GetModified::Value(value)) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+/// A machine readable code describing why the server could not handle a request.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The request could not be parsed.
+    InvalidRequest,
+    /// The server failed internally while handling the request.
+    Internal,
+    /// The selected database does not exist.
+    InvalidDatabase,
+    /// The value is not an integer.
+    NotAnInteger,
+    /// Incrementing the value overflowed.
+    IntegerOverflow,
+    /// The value cannot be sent as a string.
+    WrongType,
+    /// The request exceeds the maximum frame size.
+    FrameTooLarge,
+    /// A key exceeds the maximum key size.
+    KeyTooLarge,
+    /// A value exceeds the maximum value size.
+    ValueTooLarge,
+    /// A batch request contains more keys than allowed.
+    TooManyKeys,
+    /// The server's memory usage is above its high watermark, so writes are rejected,
+    /// or the database is full and evicts nothing.
+    OutOfMemory,
+    /// The connection lacks the privileges for the request, or the password was wrong.
+    Unauthorized,
+    /// Another connection already presented the same client id.
+    DuplicateClientId,
+    /// A request was rejected while being queued in a transaction, so the transaction was discarded.
+    TransactionAborted,
+    /// The request exceeds the server's maximum buffer size, so the connection is closed.
+    TooMuchData,
+}
+
+impl From<ErrorCode> for u8 {
+    fn from(code: ErrorCode) -> Self {
+        match code {
+            ErrorCode::InvalidRequest => 1,
+            ErrorCode::Internal => 2,
+            ErrorCode::InvalidDatabase => 3,
+            ErrorCode::NotAnInteger => 4,
+            ErrorCode::IntegerOverflow => 5,
+            ErrorCode::WrongType => 6,
+            ErrorCode::FrameTooLarge => 7,
+            ErrorCode::KeyTooLarge => 8,
+            ErrorCode::ValueTooLarge => 9,
+            ErrorCode::TooManyKeys => 10,
+            ErrorCode::OutOfMemory => 11,
+            ErrorCode::Unauthorized => 12,
+            ErrorCode::DuplicateClientId => 13,
+            ErrorCode::TransactionAborted => 14,
+            ErrorCode::TooMuchData => 15,
+        }
+    }
+}
+
+impl TryFrom<u8> for ErrorCode {
+    type Error = ParsingError;
+
+    fn try_from(value: u8) -> core::result::Result<Self, Self::Error> {
+        match value {
+            1 => Ok(ErrorCode::InvalidRequest),
+            2 => Ok(ErrorCode::Internal),
+            3 => Ok(ErrorCode::InvalidDatabase),
+            4 => Ok(ErrorCode::NotAnInteger),
+            5 => Ok(ErrorCode::IntegerOverflow),
+            6 => Ok(ErrorCode::WrongType),
+            7 => Ok(ErrorCode::FrameTooLarge),
+            8 => Ok(ErrorCode::KeyTooLarge),
+            9 => Ok(ErrorCode::ValueTooLarge),
+            10 => Ok(ErrorCode::TooManyKeys),
+            11 => Ok(ErrorCode::OutOfMemory),
+            12 => Ok(ErrorCode::Unauthorized),
+            13 => Ok(ErrorCode::DuplicateClientId),
+            14 => Ok(ErrorCode::TransactionAborted),
+            15 => Ok(ErrorCode::TooMuchData),
+            code => Err(ParsingError::UnknownErrorCode(code)),
+        }
+    }
+}
+
+pub enum Request<'a> {
+    Get(&'a str),
+    Set {
+        key: &'a str,
+        value: &'a str,
+    },
+    Delete(&'a str),
+    Flush,
+    /// Requests up to `count` keys starting at `cursor`.
+    /// A scan starts with a cursor of `0`.
+    Scan {
+        cursor: u64,
+        count: u32,
+    },
+    /// Sets the `value` for `key` which expires after `ttl_ms` milliseconds.
+    /// Answered with [`Response::Set`].
+    SetEx {
+        key: &'a str,
+        value: &'a str,
+        ttl_ms: u64,
+    },
+    /// Selects the database with the given index for all following requests on this connection.
+    /// Connections start out with database `0` selected.
+    Select(u32),
+    /// Clears all databases, whereas [`Request::Flush`] only clears the selected one.
+    FlushAll,
+    /// Gets the values of all keys.
+    MGet(Vec<&'a str>),
+    /// Sets all key value pairs.
+    MSet(Vec<(&'a str, &'a str)>),
+    /// Increments the integer value of `key` by `delta`.
+    Incr {
+        key: &'a str,
+        delta: i64,
+    },
+    /// Checks whether each of the keys exists.
+    MExists(Vec<&'a str>),
+    /// Sets the expiry of all existing keys to `ttl_ms` milliseconds from now.
+    MTouch {
+        keys: Vec<&'a str>,
+        ttl_ms: u64,
+    },
+    /// Counts the keys starting with the prefix.
+    CountPrefix(&'a str),
+    /// Sets the value of `key` to `new` only if its current value is `expected`.
+    Cas {
+        key: &'a str,
+        expected: &'a str,
+        new: &'a str,
+    },
+    /// Sets the value of `key` only if it does not exist.
+    SetNx {
+        key: &'a str,
+        value: &'a str,
+    },
+    /// Requests information about the server.
+    Info,
+    /// Deletes `key` only if it was last written before `cutoff_ms` milliseconds since the Unix epoch.
+    DeleteIfOlderThan {
+        key: &'a str,
+        cutoff_ms: u64,
+    },
+    /// Grants the connection admin privileges if the password matches the server's admin password.
+    Auth(&'a str),
+    /// Gets the value of the key together with its metadata.
+    GetMeta(&'a str),
+    /// Checks that the connection is alive without touching the database.
+    Ping,
+    /// Gets the value of `key` only if it was written after `since_ms` milliseconds since the Unix epoch.
+    GetIfModifiedSince {
+        key: &'a str,
+        since_ms: u64,
+    },
+    /// Identifies the client, see [`ServerBuilder::unique_client_ids`](crate::ServerBuilder::unique_client_ids).
+    Hello(&'a str),
+    /// Gets the bytes of the value of `key` from `start` up to but excluding `end`.
+    /// Negative offsets count from the end of the value, see [`Database::get_range`](crate::Database::get_range).
+    GetRange {
+        key: &'a str,
+        start: i64,
+        end: i64,
+    },
+    /// Overwrites the bytes of the value of `key` starting at byte `offset` with `value`.
+    SetRange {
+        key: &'a str,
+        offset: u64,
+        value: &'a str,
+    },
+    /// Gets the length of the value of the key in bytes without fetching the value.
+    StrLen(&'a str),
+    /// Atomically replaces the entire contents of the selected database with the key value pairs,
+    /// see [`Database::replace_all`](crate::Database::replace_all).
+    ReplaceAll(Vec<(&'a str, &'a str)>),
+    /// Starts a transaction: all following requests on this connection are queued
+    /// and answered with [`Response::Queued`] until [`Request::Exec`].
+    Multi,
+    /// Applies the queued requests of the transaction without requests of other connections
+    /// interleaving and answers with their responses in a [`Response::Exec`].
+    Exec,
+    /// Watches the keys in the selected database: whenever another connection writes one of them,
+    /// the server pushes a [`Response::Invalidate`] with the key to this connection.
+    /// Keys stay watched until the connection is closed.
+    /// A connection that falls too far behind with reading the invalidations is closed, as some were lost.
+    Watch(Vec<&'a str>),
+    /// Gets all keys starting with the prefix and their values.
+    GetPrefix(&'a str),
+    /// Deletes `key` only if its current value is `expected`.
+    DeleteIf {
+        key: &'a str,
+        expected: &'a str,
+    },
+    /// Gets the value of `key` and, if it exists, sets its expiry to `ttl_ms` milliseconds from now.
+    /// Answered with [`Response::Get`].
+    GetTouch {
+        key: &'a str,
+        ttl_ms: u64,
+    },
+}
+
+impl<'a> Request<'a> {
+    /// Returns the opcode identifying the request on the wire.
+    pub fn opcode(&self) -> u8 {
+        match self {
+            Request::Get(_) => 1,
+            Request::Set { .. } => 2,
+            Request::Delete(_) => 3,
+            Request::Flush => 4,
+            Request::Scan { .. } => 5,
+            Request::SetEx { .. } => 6,
+            Request::Select(_) => 7,
+            Request::FlushAll => 8,
+            Request::MGet(_) => 9,
+            Request::MSet(_) => 10,
+            Request::Incr { .. } => 11,
+            Request::MExists(_) => 12,
+            Request::MTouch { .. } => 13,
+            Request::CountPrefix(_) => 14,
+            Request::Cas { .. } => 15,
+            Request::SetNx { .. } => 16,
+            Request::Info => 17,
+            Request::DeleteIfOlderThan { .. } => 18,
+            Request::Auth(_) => 19,
+            Request::GetMeta(_) => 20,
+            Request::Ping => 21,
+            Request::GetIfModifiedSince { .. } => 22,
+            Request::Hello(_) => 23,
+            Request::GetRange { .. } => 24,
+            Request::SetRange { .. } => 25,
+            Request::StrLen(_) => 26,
+            Request::ReplaceAll(_) => 27,
+            Request::Multi => 28,
+            Request::Exec => 29,
+            Request::Watch(_) => 30,
+            Request::GetPrefix(_) => 31,
+            Request::DeleteIf { .. } => 32,
+            Request::GetTouch { .. } => 33,
+        }
+    }
+
+    /// Returns the key of requests operating on a single key.
+    pub fn key(&self) -> Option<&'a str> {
+        match self {
+            Request::Get(key)
+            | Request::Set { key, .. }
+            | Request::Delete(key)
+            | Request::SetEx { key, .. }
+            | Request::Incr { key, .. }
+            | Request::CountPrefix(key)
+            | Request::GetPrefix(key)
+            | Request::Cas { key, .. }
+            | Request::DeleteIf { key, .. }
+            | Request::GetTouch { key, .. }
+            | Request::SetNx { key, .. }
+            | Request::DeleteIfOlderThan { key, .. }
+            | Request::GetMeta(key)
+            | Request::GetIfModifiedSince { key, .. }
+            | Request::GetRange { key, .. }
+            | Request::SetRange { key, .. }
+            | Request::StrLen(key) => Some(key),
+            _ => None,
+        }
+    }
+}
+
+/// Parses a single request from `input`.
+/// Returns the request and the number of bytes it occupied,
+/// or `None` if `input` does not contain a complete request yet.
+pub fn parse_request(input: &[u8]) -> Result<Option<(Request<'_>, usize)>> {
+    parse_request_within(input, usize::MAX)
+}
+
+/// Parses a single request from `input` like [`parse_request`].
+/// Fails with [`ParsingError::FrameTooLarge`] as soon as a length prefix declares
+/// an element ending beyond `max_len` bytes, instead of waiting for more input that can never fit.
+pub(crate) fn parse_request_within(
+    input: &[u8],
+    max_len: usize,
+) -> Result<Option<(Request<'_>, usize)>> {
+    parse_request_with(input, |input, cursor| {
+        read_element_within(input, cursor, max_len)
+    })
+}
+
+/// Parses a request like [`parse_request_within`] but replaces invalid Utf8 in its elements
+/// with the replacement character `U+FFFD` instead of failing.
+/// Returns the request re-serialized with the replaced elements, which can then be parsed
+/// with [`parse_request`], and the number of bytes the original request occupied.
+#[cfg(feature = "std")]
+pub(crate) fn sanitize_request_within(
+    input: &[u8],
+    max_len: usize,
+) -> Result<Option<(Vec<u8>, usize)>> {
+    // The ranges of the invalid elements including their length prefix
+    let mut invalid = Vec::new();
+    let parsed = parse_request_with(input, |input, cursor| {
+        let start = *cursor;
+        match read_element_within(input, cursor, max_len) {
+            Err(ParsingError::Utf8Error(_)) => {
+                invalid.push((start, *cursor));
+                Ok(Some(""))
+            }
+            result => result,
+        }
+    })?;
+    let Some((_, n_parsed_bytes)) = parsed else {
+        return Ok(None);
+    };
+    let mut request = Vec::with_capacity(n_parsed_bytes);
+    let mut copied = 0;
+    for (start, end) in invalid {
+        request.extend_from_slice(&input[copied..start]);
+        // Skip the length prefix, the element is written with its new length
+        // The replacement characters can make the element too long for its length prefix
+        write_element(
+            &mut request,
+            &String::from_utf8_lossy(&input[start + 4..end]),
+        )
+        .map_err(|_| ParsingError::FrameTooLarge)?;
+        copied = end;
+    }
+    request.extend_from_slice(&input[copied..n_parsed_bytes]);
+    Ok(Some((request, n_parsed_bytes)))
+}
+
+/// Parses a single request from `input`, reading its keys and values with `read`.
+fn parse_request_with<'a>(
+    input: &'a [u8],
+    mut read: impl FnMut(&'a [u8], &mut usize) -> Result<Option<&'a str>>,
+) -> Result<Option<(Request<'a>, usize)>> {
+    let mut cursor = 0;
+    let Some(op_code) = input.get(cursor) else {
+        return Ok(None);
+    };
+    cursor += 1;
+
+    // We don't use 0 as opcode as we're using 0-initialised buffers in the server which would
+    // lead to wrong parsing.
+    let request = match &op_code {
+        1 => read(input, &mut cursor)?.map(Request::Get),
+        2 => match (read(input, &mut cursor), read(input, &mut cursor)) {
+            (Ok(Some(key)), Ok(Some(value))) => Some(Request::Set { key, value }),
+            (Ok(_), Ok(_)) => None,
+            (Err(e), _) | (_, Err(e)) => return Err(e),
+        },
+        3 => read(input, &mut cursor)?.map(Request::Delete),
+        4 => Some(Request::Flush),
+        5 => match (read_u64(input, &mut cursor), read_u32(input, &mut cursor)) {
+            (Some(scan_cursor), Some(count)) => Some(Request::Scan {
+                cursor: scan_cursor,
+                count,
+            }),
+            _ => None,
+        },
+        6 => {
+            let Some(key) = read(input, &mut cursor)? else {
+                return Ok(None);
+            };
+            let Some(value) = read(input, &mut cursor)? else {
+                return Ok(None);
+            };
+            read_u64(input, &mut cursor).map(|ttl_ms| Request::SetEx { key, value, ttl_ms })
+        }
+        7 => read_u32(input, &mut cursor).map(Request::Select),
+        8 => Some(Request::FlushAll),
+        9 => read_count_prefixed(input, &mut cursor, |input, cursor| read(input, cursor))?
+            .map(Request::MGet),
+        10 => read_count_prefixed(input, &mut cursor, |input, cursor| {
+            let Some(key) = read(input, cursor)? else {
+                return Ok(None);
+            };
+            Ok(read(input, cursor)?.map(|value| (key, value)))
+        })?
+        .map(Request::MSet),
+        11 => {
+            let Some(key) = read(input, &mut cursor)? else {
+                return Ok(None);
+            };
+            read_u64(input, &mut cursor).map(|delta| Request::Incr {
+                key,
+                delta: delta as i64,
+            })
+        }
+        12 => read_count_prefixed(input, &mut cursor, |input, cursor| read(input, cursor))?
+            .map(Request::MExists),
+        13 => {
+            let Some(ttl_ms) = read_u64(input, &mut cursor) else {
+                return Ok(None);
+            };
+            read_count_prefixed(input, &mut cursor, |input, cursor| read(input, cursor))?
+                .map(|keys| Request::MTouch { keys, ttl_ms })
+        }
+        14 => read(input, &mut cursor)?.map(Request::CountPrefix),
+        15 => {
+            let Some(key) = read(input, &mut cursor)? else {
+                return Ok(None);
+            };
+            let Some(expected) = read(input, &mut cursor)? else {
+                return Ok(None);
+            };
+            read(input, &mut cursor)?.map(|new| Request::Cas { key, expected, new })
+        }
+        16 => {
+            let Some(key) = read(input, &mut cursor)? else {
+                return Ok(None);
+            };
+            read(input, &mut cursor)?.map(|value| Request::SetNx { key, value })
+        }
+        17 => Some(Request::Info),
+        18 => {
+            let Some(key) = read(input, &mut cursor)? else {
+                return Ok(None);
+            };
+            read_u64(input, &mut cursor)
+                .map(|cutoff_ms| Request::DeleteIfOlderThan { key, cutoff_ms })
+        }
+        19 => read(input, &mut cursor)?.map(Request::Auth),
+        20 => read(input, &mut cursor)?.map(Request::GetMeta),
+        21 => Some(Request::Ping),
+        22 => {
+            let Some(key) = read(input, &mut cursor)? else {
+                return Ok(None);
+            };
+            read_u64(input, &mut cursor)
+                .map(|since_ms| Request::GetIfModifiedSince { key, since_ms })
+        }
+        23 => read(input, &mut cursor)?.map(Request::Hello),
+        24 => {
+            let Some(key) = read(input, &mut cursor)? else {
+                return Ok(None);
+            };
+            let Some(start) = read_u64(input, &mut cursor) else {
+                return Ok(None);
+            };
+            read_u64(input, &mut cursor).map(|end| Request::GetRange {
+                key,
+                start: start as i64,
+                end: end as i64,
+            })
+        }
+        25 => {
+            let Some(key) = read(input, &mut cursor)? else {
+                return Ok(None);
+            };
+            let Some(offset) = read_u64(input, &mut cursor) else {
+                return Ok(None);
+            };
+            read(input, &mut cursor)?.map(|value| Request::SetRange { key, offset, value })
+        }
+        26 => read(input, &mut cursor)?.map(Request::StrLen),
+        27 => read_count_prefixed(input, &mut cursor, |input, cursor| {
+            let Some(key) = read(input, cursor)? else {
+                return Ok(None);
+            };
+            Ok(read(input, cursor)?.map(|value| (key, value)))
+        })?
+        .map(Request::ReplaceAll),
+        28 => Some(Request::Multi),
+        29 => Some(Request::Exec),
+        30 => read_count_prefixed(input, &mut cursor, |input, cursor| read(input, cursor))?
+            .map(Request::Watch),
+        31 => read(input, &mut cursor)?.map(Request::GetPrefix),
+        32 => {
+            let Some(key) = read(input, &mut cursor)? else {
+                return Ok(None);
+            };
+            read(input, &mut cursor)?.map(|expected| Request::DeleteIf { key, expected })
+        }
+        33 => {
+            let Some(key) = read(input, &mut cursor)? else {
+                return Ok(None);
+            };
+            read_u64(input, &mut cursor).map(|ttl_ms| Request::GetTouch { key, ttl_ms })
+        }
+        _ => return Ok(None),
+    };
+    Ok(request.map(|req| (req, cursor)))
+}
+
+/// Parses a single response from `input`.
+/// Returns the response and the number of bytes it occupied,
+/// or `None` if `input` does not contain a complete response yet.
+pub fn parse_response(input: &[u8]) -> Result<Option<(Response, usize)>> {
+    let mut cursor = 0;
+    let Some(op_code) = input.get(cursor) else {
+        return Ok(None);
+    };
+    cursor += 1;
+
+    // We don't use 0 as opcode as we're using 0-initialised buffers in the server which would
+    // lead to wrong parsing.
+    let response = match &op_code {
+        1 => {
+            let Some(value) = read_element(input, &mut cursor)? else {
+                return Ok(None);
+            };
+            Response::Get(non_empty(value))
+        }
+        2 => Response::Set,
+        3 => Response::Delete,
+        4 => Response::Flush,
+        5 => {
+            let Some(code) = input.get(cursor) else {
+                return Ok(None);
+            };
+            cursor += 1;
+            let code = ErrorCode::try_from(*code)?;
+            let Some(message) = read_element(input, &mut cursor)? else {
+                return Ok(None);
+            };
+            Response::Error {
+                code,
+                message: non_empty(message),
+            }
+        }
+        6 => {
+            let Some(scan_cursor) = read_u64(input, &mut cursor) else {
+                return Ok(None);
+            };
+            let Some(keys) = read_count_prefixed(input, &mut cursor, |input, cursor| {
+                Ok(read_element(input, cursor)?.map(ToString::to_string))
+            })?
+            else {
+                return Ok(None);
+            };
+            Response::Scan {
+                cursor: scan_cursor,
+                keys,
+            }
+        }
+        7 => Response::Select,
+        8 => Response::FlushAll,
+        9 => {
+            let Some(values) = read_count_prefixed(input, &mut cursor, |input, cursor| {
+                Ok(read_element(input, cursor)?.map(non_empty))
+            })?
+            else {
+                return Ok(None);
+            };
+            Response::MGet(values)
+        }
+        10 => Response::MSet,
+        11 => {
+            let Some(value) = read_u64(input, &mut cursor) else {
+                return Ok(None);
+            };
+            Response::Incr(value as i64)
+        }
+        12 => {
+            let Some(exists) = read_count_prefixed(input, &mut cursor, |input, cursor| {
+                Ok(read_u8(input, cursor).map(|exists| exists != 0))
+            })?
+            else {
+                return Ok(None);
+            };
+            Response::MExists(exists)
+        }
+        13 => {
+            let Some(touched) = read_u32(input, &mut cursor) else {
+                return Ok(None);
+            };
+            Response::MTouch(touched)
+        }
+        14 => {
+            let Some(count) = read_u64(input, &mut cursor) else {
+                return Ok(None);
+            };
+            Response::CountPrefix(count)
+        }
+        15 => {
+            let Some(swapped) = read_u8(input, &mut cursor) else {
+                return Ok(None);
+            };
+            Response::Cas(swapped != 0)
+        }
+        16 => {
+            let Some(set) = read_u8(input, &mut cursor) else {
+                return Ok(None);
+            };
+            Response::SetNx(set != 0)
+        }
+        17 => {
+            let Some(protocol_version) = read_u32(input, &mut cursor) else {
+                return Ok(None);
+            };
+            let Some(version) = read_element(input, &mut cursor)? else {
+                return Ok(None);
+            };
+            let Some(uptime_ms) = read_u64(input, &mut cursor) else {
+                return Ok(None);
+            };
+            let Some(features) = read_count_prefixed(input, &mut cursor, |input, cursor| {
+                Ok(read_element(input, cursor)?.map(ToString::to_string))
+            })?
+            else {
+                return Ok(None);
+            };
+            Response::Info(ServerInfo {
+                protocol_version,
+                version: version.to_string(),
+                uptime: Duration::from_millis(uptime_ms),
+                features,
+            })
+        }
+        18 => {
+            let Some(deleted) = read_u8(input, &mut cursor) else {
+                return Ok(None);
+            };
+            Response::DeleteIfOlderThan(deleted != 0)
+        }
+        19 => Response::Auth,
+        20 => {
+            let Some(value) = read_element(input, &mut cursor)? else {
+                return Ok(None);
+            };
+            let Some(has_ttl) = read_u8(input, &mut cursor) else {
+                return Ok(None);
+            };
+            let Some(ttl_ms) = read_u64(input, &mut cursor) else {
+                return Ok(None);
+            };
+            let Some(size) = read_u64(input, &mut cursor) else {
+                return Ok(None);
+            };
+            let meta = KeyMeta {
+                ttl: (has_ttl != 0).then(|| Duration::from_millis(ttl_ms)),
+                size,
+            };
+            Response::GetMeta(non_empty(value).map(|value| (value, meta)))
+        }
+        21 => Response::Pong,
+        22 => {
+            let Some(tag) = read_u8(input, &mut cursor) else {
+                return Ok(None);
+            };
+            let modified = match tag {
+                0 => GetModified::Missing,
+                1 => GetModified::NotModified,
+                2 => {
+                    let Some(value) = read_element(input, &mut cursor)? else {
+                        return Ok(None);
+                    };
+                    GetModified::Value(value.to_string())
+                }
+                _ => return Err(ParsingError::Other),
+            };
+            Response::GetIfModifiedSince(modified)
+        }
+        23 => Response::Hello,
+        24 => {
+            let Some(exists) = read_u8(input, &mut cursor) else {
+                return Ok(None);
+            };
+            if exists == 0 {
+                Response::GetRange(None)
+            } else {
+                let Some(range) = read_element(input, &mut cursor)? else {
+                    return Ok(None);
+                };
+                Response::GetRange(Some(range.to_string()))
+            }
+        }
+        25 => {
+            let Some(len) = read_u64(input, &mut cursor) else {
+                return Ok(None);
+            };
+            Response::SetRange(len)
+        }
+        26 => {
+            let Some(exists) = read_u8(input, &mut cursor) else {
+                return Ok(None);
+            };
+            if exists == 0 {
+                Response::StrLen(None)
+            } else {
+                let Some(len) = read_u64(input, &mut cursor) else {
+                    return Ok(None);
+                };
+                Response::StrLen(Some(len))
+            }
+        }
+        27 => Response::ReplaceAll,
+        28 => Response::Multi,
+        29 => {
+            let Some(responses) = read_count_prefixed(input, &mut cursor, |input, cursor| {
+                let Some((response, len)) = parse_response(&input[*cursor..])? else {
+                    return Ok(None);
+                };
+                *cursor += len;
+                Ok(Some(response))
+            })?
+            else {
+                return Ok(None);
+            };
+            Response::Exec(responses)
+        }
+        30 => Response::Queued,
+        31 => Response::Watch,
+        32 => {
+            let Some(key) = read_element(input, &mut cursor)? else {
+                return Ok(None);
+            };
+            Response::Invalidate(key.to_string())
+        }
+        33 => {
+            let Some(pairs) = read_count_prefixed(input, &mut cursor, |input, cursor| {
+                let Some(key) = read_element(input, cursor)? else {
+                    return Ok(None);
+                };
+                let Some(value) = read_element(input, cursor)? else {
+                    return Ok(None);
+                };
+                Ok(Some((key.to_string(), value.to_string())))
+            })?
+            else {
+                return Ok(None);
+            };
+            Response::GetPrefix(pairs)
+        }
+        34 => {
+            let Some(deleted) = read_u8(input, &mut cursor) else {
+                return Ok(None);
+            };
+            Response::DeleteIf(deleted != 0)
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some((response, cursor)))
+}
+
+/// An error while parsing a request or a response.
+#[derive(Debug)]
+pub enum ParsingError {
+    Utf8Error(Utf8Error),
+    UnknownErrorCode(u8),
+    /// A length prefix declares a frame larger than the maximum size.
+    FrameTooLarge,
+    Other,
+}
+
+impl fmt::Display for ParsingError {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        match self {
+            ParsingError::Utf8Error(_) => write!(f, "cannot convert Utf8"),
+            ParsingError::UnknownErrorCode(code) => write!(f, "unknown error code {code}"),
+            ParsingError::FrameTooLarge => write!(f, "frame exceeds the maximum size"),
+            ParsingError::Other => write!(f, "other parsing error"),
+        }
+    }
+}
+
+impl core::error::Error for ParsingError {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        match self {
+            ParsingError::Utf8Error(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<Utf8Error> for ParsingError {
+    fn from(e: Utf8Error) -> Self {
+        ParsingError::Utf8Error(e)
+    }
+}
+
+/// Maps an empty element to `None`.
+fn non_empty(element: &str) -> Option<String> {
+    (!element.is_empty()).then(|| element.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::serialization::Serialize;
+
+    #[test]
+    fn test_response_hit_miss_and_value() {
+        let meta = KeyMeta { ttl: None, size: 5 };
+        let info = ServerInfo {
+            protocol_version: PROTOCOL_VERSION,
+            version: "1.2.3".to_string(),
+            uptime: Duration::ZERO,
+            features: Vec::new(),
+        };
+        let value = || "value".to_string();
+        // The response, whether it is a hit, whether it is a miss and its value
+        let cases = [
+            (Response::Get(Some(value())), true, false, Some("value")),
+            (Response::Get(None), false, true, None),
+            (
+                Response::GetRange(Some(value())),
+                true,
+                false,
+                Some("value"),
+            ),
+            (Response::GetRange(None), false, true, None),
+            (
+                Response::GetMeta(Some((value(), meta))),
+                true,
+                false,
+                Some("value"),
+            ),
+            (Response::GetMeta(None), false, true, None),
+            (Response::StrLen(Some(5)), true, false, None),
+            (Response::StrLen(None), false, true, None),
+            (
+                Response::GetIfModifiedSince(GetModified::Value(value())),
+                true,
+                false,
+                Some("value"),
+            ),
+            (
+                Response::GetIfModifiedSince(GetModified::NotModified),
+                true,
+                false,
+                None,
+            ),
+            (
+                Response::GetIfModifiedSince(GetModified::Missing),
+                false,
+                true,
+                None,
+            ),
+            (Response::Set, false, false, None),
+            (Response::Delete, false, false, None),
+            (Response::Flush, false, false, None),
+            (
+                Response::Scan {
+                    cursor: 0,
+                    keys: vec![value()],
+                },
+                false,
+                false,
+                None,
+            ),
+            (Response::Select, false, false, None),
+            (Response::FlushAll, false, false, None),
+            (Response::MGet(vec![Some(value())]), false, false, None),
+            (Response::MGet(vec![None]), false, false, None),
+            (Response::MSet, false, false, None),
+            (Response::Incr(1), false, false, None),
+            (Response::MExists(vec![true]), false, false, None),
+            (Response::MTouch(1), false, false, None),
+            (Response::CountPrefix(1), false, false, None),
+            (Response::Cas(true), false, false, None),
+            (Response::SetNx(true), false, false, None),
+            (Response::Info(info), false, false, None),
+            (Response::DeleteIfOlderThan(true), false, false, None),
+            (Response::DeleteIf(true), false, false, None),
+            (Response::Auth, false, false, None),
+            (Response::Pong, false, false, None),
+            (Response::Hello, false, false, None),
+            (Response::SetRange(5), false, false, None),
+            (Response::ReplaceAll, false, false, None),
+            (Response::Multi, false, false, None),
+            (Response::Queued, false, false, None),
+            (
+                Response::Exec(vec![Response::Get(Some(value()))]),
+                false,
+                false,
+                None,
+            ),
+            (Response::Watch, false, false, None),
+            (Response::Invalidate(value()), false, false, None),
+            (
+                Response::GetPrefix(vec![(value(), value())]),
+                false,
+                false,
+                None,
+            ),
+            (
+                Response::Error {
+                    code: ErrorCode::InvalidRequest,
+                    message: None,
+                },
+                false,
+                false,
+                None,
+            ),
+        ];
+        for (response, is_hit, is_miss, value) in cases {
+            assert_eq!(response.is_hit(), is_hit, "{response:?}");
+            assert_eq!(response.is_miss(), is_miss, "{response:?}");
+            assert_eq!(response.value(), value, "{response:?}");
+        }
+    }
+
+    #[test]
+    fn test_get_response_round_trip() {
+        for response in [
+            Response::Get(Some("value".to_string())),
+            Response::Get(None),
+        ] {
+            let bytes = response.serialize();
+            let (parsed, n_parsed_bytes) = parse_response(&bytes).unwrap().unwrap();
+            assert_eq!(n_parsed_bytes, bytes.len());
+            assert_eq!(bytes, parsed.serialize(), "response changed in round trip");
+        }
+    }
+
+    #[test]
+    fn test_error_response_with_message_round_trip() {
+        let response = Response::Error {
+            code: ErrorCode::InvalidRequest,
+            message: Some("cannot convert Utf8".to_string()),
+        };
+        let bytes = response.serialize();
+        let (parsed, n_parsed_bytes) = parse_response(&bytes).unwrap().unwrap();
+        assert_eq!(n_parsed_bytes, bytes.len());
+        assert_eq!(
+            parsed,
+            Response::Error {
+                code: ErrorCode::InvalidRequest,
+                message: Some("cannot convert Utf8".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn test_error_response_without_message_round_trip() {
+        let response = Response::Error {
+            code: ErrorCode::Internal,
+            message: None,
+        };
+        let bytes = response.serialize();
+        assert_eq!(bytes, vec![5, 2, 0, 0, 0, 0]);
+        let (parsed, n_parsed_bytes) = parse_response(&bytes).unwrap().unwrap();
+        assert_eq!(n_parsed_bytes, bytes.len());
+        assert_eq!(
+            parsed,
+            Response::Error {
+                code: ErrorCode::Internal,
+                message: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_incomplete_responses_are_not_parsed() {
+        let bytes = Response::Get(Some("value".to_string())).serialize();
+        for end in 0..bytes.len() {
+            assert!(parse_response(&bytes[..end]).unwrap().is_none());
+        }
+        let bytes = Response::Error {
+            code: ErrorCode::InvalidRequest,
+            message: Some("message".to_string()),
+        }
+        .serialize();
+        for end in 0..bytes.len() {
+            assert!(parse_response(&bytes[..end]).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn test_scan_request_round_trip() {
+        let bytes = Request::Scan {
+            cursor: 42,
+            count: 10,
+        }
+        .serialize();
+        let (request, n_parsed_bytes) = parse_request(&bytes).unwrap().unwrap();
+        assert_eq!(n_parsed_bytes, bytes.len());
+        assert!(matches!(
+            request,
+            Request::Scan {
+                cursor: 42,
+                count: 10
+            }
+        ));
+        for end in 0..bytes.len() {
+            assert!(parse_request(&bytes[..end]).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn test_scan_response_round_trip() {
+        for keys in [vec![], vec!["abc".to_string(), "defg".to_string()]] {
+            let response = Response::Scan {
+                cursor: 7,
+                keys: keys.clone(),
+            };
+            let bytes = response.serialize();
+            let (parsed, n_parsed_bytes) = parse_response(&bytes).unwrap().unwrap();
+            assert_eq!(n_parsed_bytes, bytes.len());
+            assert_eq!(parsed, Response::Scan { cursor: 7, keys });
+            for end in 0..bytes.len() {
+                assert!(parse_response(&bytes[..end]).unwrap().is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn test_multi_key_requests_round_trip() {
+        for keys in [vec![], vec!["abc"], vec!["abc", "defg", "abc"]] {
+            let bytes = Request::MGet(keys.clone()).serialize();
+            let (request, n_parsed_bytes) = parse_request(&bytes).unwrap().unwrap();
+            assert_eq!(n_parsed_bytes, bytes.len());
+            assert!(matches!(request, Request::MGet(parsed) if parsed == keys));
+            for end in 0..bytes.len() {
+                assert!(parse_request(&bytes[..end]).unwrap().is_none());
+            }
+        }
+        for pairs in [
+            vec![],
+            vec![("abc", "123")],
+            vec![("abc", "123"), ("d", "")],
+        ] {
+            let bytes = Request::MSet(pairs.clone()).serialize();
+            let (request, n_parsed_bytes) = parse_request(&bytes).unwrap().unwrap();
+            assert_eq!(n_parsed_bytes, bytes.len());
+            assert!(matches!(request, Request::MSet(parsed) if parsed == pairs));
+            for end in 0..bytes.len() {
+                assert!(parse_request(&bytes[..end]).unwrap().is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn test_empty_multi_get_does_not_read_past_count() {
+        assert_eq!(Request::MGet(vec![]).serialize(), vec![9, 0, 0, 0, 0]);
+        // A Flush request following the empty MGet
+        let bytes = [9, 0, 0, 0, 0, 4];
+        let (request, n_parsed_bytes) = parse_request(&bytes).unwrap().unwrap();
+        assert_eq!(n_parsed_bytes, 5);
+        assert!(matches!(request, Request::MGet(keys) if keys.is_empty()));
+    }
+
+    #[test]
+    fn test_multi_get_response_round_trip() {
+        for values in [
+            vec![],
+            vec![Some("123".to_string())],
+            vec![Some("123".to_string()), None, Some("456".to_string())],
+        ] {
+            let bytes = Response::MGet(values.clone()).serialize();
+            let (parsed, n_parsed_bytes) = parse_response(&bytes).unwrap().unwrap();
+            assert_eq!(n_parsed_bytes, bytes.len());
+            assert_eq!(parsed, Response::MGet(values));
+            for end in 0..bytes.len() {
+                assert!(parse_response(&bytes[..end]).unwrap().is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn test_exists_and_touch_round_trip() {
+        for keys in [vec![], vec!["abc"], vec!["abc", "defg", "abc"]] {
+            let bytes = Request::MExists(keys.clone()).serialize();
+            let (request, n_parsed_bytes) = parse_request(&bytes).unwrap().unwrap();
+            assert_eq!(n_parsed_bytes, bytes.len());
+            assert!(matches!(request, Request::MExists(parsed) if parsed == keys));
+
+            let bytes = Request::MTouch {
+                keys: keys.clone(),
+                ttl_ms: 1000,
+            }
+            .serialize();
+            let (request, n_parsed_bytes) = parse_request(&bytes).unwrap().unwrap();
+            assert_eq!(n_parsed_bytes, bytes.len());
+            assert!(
+                matches!(request, Request::MTouch { keys: parsed, ttl_ms: 1000 } if parsed == keys)
+            );
+            for end in 0..bytes.len() {
+                assert!(parse_request(&bytes[..end]).unwrap().is_none());
+            }
+        }
+        for exists in [vec![], vec![true], vec![true, false, true]] {
+            let bytes = Response::MExists(exists.clone()).serialize();
+            let (parsed, n_parsed_bytes) = parse_response(&bytes).unwrap().unwrap();
+            assert_eq!(n_parsed_bytes, bytes.len());
+            assert_eq!(parsed, Response::MExists(exists));
+            for end in 0..bytes.len() {
+                assert!(parse_response(&bytes[..end]).unwrap().is_none());
+            }
+        }
+        let bytes = Response::MTouch(3).serialize();
+        assert_eq!(
+            parse_response(&bytes).unwrap().unwrap(),
+            (Response::MTouch(3), bytes.len())
+        );
+    }
+
+    #[test]
+    fn test_count_prefix_round_trip() {
+        for prefix in ["", "tenant:1:"] {
+            let bytes = Request::CountPrefix(prefix).serialize();
+            let (request, n_parsed_bytes) = parse_request(&bytes).unwrap().unwrap();
+            assert_eq!(n_parsed_bytes, bytes.len());
+            assert!(matches!(request, Request::CountPrefix(parsed) if parsed == prefix));
+        }
+        let bytes = Response::CountPrefix(42).serialize();
+        assert_eq!(
+            parse_response(&bytes).unwrap().unwrap(),
+            (Response::CountPrefix(42), bytes.len())
+        );
+    }
+
+    #[test]
+    fn test_get_prefix_round_trip() {
+        for prefix in ["", "tenant:1:"] {
+            let bytes = Request::GetPrefix(prefix).serialize();
+            let (request, n_parsed_bytes) = parse_request(&bytes).unwrap().unwrap();
+            assert_eq!(n_parsed_bytes, bytes.len());
+            assert!(matches!(request, Request::GetPrefix(parsed) if parsed == prefix));
+        }
+        for pairs in [
+            vec![],
+            vec![
+                ("a:1".to_string(), "x".to_string()),
+                ("a:2".to_string(), String::new()),
+            ],
+        ] {
+            let bytes = Response::GetPrefix(pairs.clone()).serialize();
+            assert_eq!(
+                parse_response(&bytes).unwrap().unwrap(),
+                (Response::GetPrefix(pairs), bytes.len())
+            );
+        }
+    }
+
+    #[test]
+    fn test_conditional_writes_round_trip() {
+        let bytes = Request::Cas {
+            key: "abc",
+            expected: "1",
+            new: "",
+        }
+        .serialize();
+        let (request, n_parsed_bytes) = parse_request(&bytes).unwrap().unwrap();
+        assert_eq!(n_parsed_bytes, bytes.len());
+        assert!(matches!(
+            request,
+            Request::Cas {
+                key: "abc",
+                expected: "1",
+                new: "",
+            }
+        ));
+        let bytes = Request::SetNx {
+            key: "abc",
+            value: "1",
+        }
+        .serialize();
+        let (request, n_parsed_bytes) = parse_request(&bytes).unwrap().unwrap();
+        assert_eq!(n_parsed_bytes, bytes.len());
+        assert!(matches!(
+            request,
+            Request::SetNx {
+                key: "abc",
+                value: "1",
+            }
+        ));
+        for response in [Response::Cas(true), Response::SetNx(false)] {
+            let bytes = response.serialize();
+            assert_eq!(
+                parse_response(&bytes).unwrap().unwrap(),
+                (response, bytes.len())
+            );
+        }
+    }
+
+    #[test]
+    fn test_delete_if_older_than_round_trip() {
+        let bytes = Request::DeleteIfOlderThan {
+            key: "abc",
+            cutoff_ms: 1_700_000_000_000,
+        }
+        .serialize();
+        let (request, n_parsed_bytes) = parse_request(&bytes).unwrap().unwrap();
+        assert_eq!(n_parsed_bytes, bytes.len());
+        assert!(matches!(
+            request,
+            Request::DeleteIfOlderThan {
+                key: "abc",
+                cutoff_ms: 1_700_000_000_000,
+            }
+        ));
+        for end in 0..bytes.len() {
+            assert!(parse_request(&bytes[..end]).unwrap().is_none());
+        }
+
+        let response = Response::DeleteIfOlderThan(true);
+        let bytes = response.serialize();
+        assert_eq!(
+            parse_response(&bytes).unwrap().unwrap(),
+            (response, bytes.len())
+        );
+    }
+
+    #[test]
+    fn test_delete_if_round_trip() {
+        let bytes = Request::DeleteIf {
+            key: "abc",
+            expected: "123",
+        }
+        .serialize();
+        let (request, n_parsed_bytes) = parse_request(&bytes).unwrap().unwrap();
+        assert_eq!(n_parsed_bytes, bytes.len());
+        assert!(matches!(
+            request,
+            Request::DeleteIf {
+                key: "abc",
+                expected: "123",
+            }
+        ));
+        for end in 0..bytes.len() {
+            assert!(parse_request(&bytes[..end]).unwrap().is_none());
+        }
+
+        for response in [Response::DeleteIf(true), Response::DeleteIf(false)] {
+            let bytes = response.serialize();
+            assert_eq!(
+                parse_response(&bytes).unwrap().unwrap(),
+                (response, bytes.len())
+            );
+        }
+    }
+
+    #[test]
+    fn test_get_touch_round_trip() {
+        let bytes = Request::GetTouch {
+            key: "abc",
+            ttl_ms: 1000,
+        }
+        .serialize();
+        let (request, n_parsed_bytes) = parse_request(&bytes).unwrap().unwrap();
+        assert_eq!(n_parsed_bytes, bytes.len());
+        assert!(matches!(
+            request,
+            Request::GetTouch {
+                key: "abc",
+                ttl_ms: 1000,
+            }
+        ));
+        for end in 0..bytes.len() {
+            assert!(parse_request(&bytes[..end]).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn test_auth_round_trip() {
+        let bytes = Request::Auth("secret").serialize();
+        let (request, n_parsed_bytes) = parse_request(&bytes).unwrap().unwrap();
+        assert_eq!(n_parsed_bytes, bytes.len());
+        assert!(matches!(request, Request::Auth("secret")));
+
+        let bytes = Response::Auth.serialize();
+        assert_eq!(
+            parse_response(&bytes).unwrap().unwrap(),
+            (Response::Auth, bytes.len())
+        );
+    }
+
+    #[test]
+    fn test_hello_round_trip() {
+        let bytes = Request::Hello("client-1").serialize();
+        let (request, n_parsed_bytes) = parse_request(&bytes).unwrap().unwrap();
+        assert_eq!(n_parsed_bytes, bytes.len());
+        assert!(matches!(request, Request::Hello("client-1")));
+
+        let bytes = Response::Hello.serialize();
+        assert_eq!(
+            parse_response(&bytes).unwrap().unwrap(),
+            (Response::Hello, bytes.len())
+        );
+    }
+
+    #[test]
+    fn test_range_round_trip() {
+        let bytes = Request::GetRange {
+            key: "abc",
+            start: -3,
+            end: 10,
+        }
+        .serialize();
+        let (request, n_parsed_bytes) = parse_request(&bytes).unwrap().unwrap();
+        assert_eq!(n_parsed_bytes, bytes.len());
+        assert!(matches!(
+            request,
+            Request::GetRange {
+                key: "abc",
+                start: -3,
+                end: 10
+            }
+        ));
+
+        let bytes = Request::SetRange {
+            key: "abc",
+            offset: 2,
+            value: "def",
+        }
+        .serialize();
+        let (request, n_parsed_bytes) = parse_request(&bytes).unwrap().unwrap();
+        assert_eq!(n_parsed_bytes, bytes.len());
+        assert!(matches!(
+            request,
+            Request::SetRange {
+                key: "abc",
+                offset: 2,
+                value: "def"
+            }
+        ));
+
+        // An empty range is told apart from a missing key
+        for response in [
+            Response::GetRange(None),
+            Response::GetRange(Some(String::new())),
+            Response::GetRange(Some("abc".to_string())),
+            Response::SetRange(5),
+        ] {
+            let bytes = response.serialize();
+            assert_eq!(
+                parse_response(&bytes).unwrap().unwrap(),
+                (response, bytes.len())
+            );
+        }
+    }
+
+    #[test]
+    fn test_strlen_round_trip() {
+        let bytes = Request::StrLen("abc").serialize();
+        let (request, n_parsed_bytes) = parse_request(&bytes).unwrap().unwrap();
+        assert_eq!(n_parsed_bytes, bytes.len());
+        assert!(matches!(request, Request::StrLen("abc")));
+
+        // An empty value is told apart from a missing key
+        for response in [
+            Response::StrLen(None),
+            Response::StrLen(Some(0)),
+            Response::StrLen(Some(5)),
+        ] {
+            let bytes = response.serialize();
+            assert_eq!(
+                parse_response(&bytes).unwrap().unwrap(),
+                (response, bytes.len())
+            );
+        }
+    }
+
+    #[test]
+    fn test_replace_all_round_trip() {
+        for pairs in [vec![], vec![("abc", "123"), ("d", "")]] {
+            let bytes = Request::ReplaceAll(pairs.clone()).serialize();
+            let (request, n_parsed_bytes) = parse_request(&bytes).unwrap().unwrap();
+            assert_eq!(n_parsed_bytes, bytes.len());
+            assert!(matches!(request, Request::ReplaceAll(parsed) if parsed == pairs));
+            for end in 0..bytes.len() {
+                assert!(parse_request(&bytes[..end]).unwrap().is_none());
+            }
+        }
+
+        let bytes = Response::ReplaceAll.serialize();
+        assert_eq!(
+            parse_response(&bytes).unwrap().unwrap(),
+            (Response::ReplaceAll, bytes.len())
+        );
+    }
+
+    #[test]
+    fn test_transaction_round_trip() {
+        for (request, opcode) in [(Request::Multi, 28), (Request::Exec, 29)] {
+            let bytes = request.serialize();
+            assert_eq!(bytes, [opcode]);
+            let (parsed, n_parsed_bytes) = parse_request(&bytes).unwrap().unwrap();
+            assert_eq!(n_parsed_bytes, 1);
+            assert_eq!(parsed.opcode(), opcode);
+        }
+
+        let exec = Response::Exec(vec![
+            Response::Set,
+            Response::Get(Some("abc".to_string())),
+            Response::Exec(vec![]),
+            Response::Error {
+                code: ErrorCode::NotAnInteger,
+                message: None,
+            },
+        ]);
+        for response in [
+            Response::Multi,
+            Response::Queued,
+            Response::Exec(vec![]),
+            exec,
+        ] {
+            let bytes = response.serialize();
+            assert_eq!(
+                parse_response(&bytes).unwrap().unwrap(),
+                (response, bytes.len())
+            );
+            for end in 0..bytes.len() {
+                assert!(parse_response(&bytes[..end]).unwrap().is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn test_watch_round_trip() {
+        let keys = vec!["abc", "d"];
+        let bytes = Request::Watch(keys.clone()).serialize();
+        let (request, n_parsed_bytes) = parse_request(&bytes).unwrap().unwrap();
+        assert_eq!(n_parsed_bytes, bytes.len());
+        assert!(matches!(request, Request::Watch(parsed) if parsed == keys));
+
+        for response in [Response::Watch, Response::Invalidate("abc".to_string())] {
+            let bytes = response.serialize();
+            assert_eq!(
+                parse_response(&bytes).unwrap().unwrap(),
+                (response, bytes.len())
+            );
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_sanitize_request_replaces_invalid_utf8() {
+        let request = [
+            6, 0, 0, 0, 2, 0xC3, 0x28, 0, 0, 0, 1, 97, 0, 0, 0, 0, 0, 0, 0, 7,
+        ];
+        assert!(matches!(
+            parse_request(&request),
+            Err(ParsingError::Utf8Error(_))
+        ));
+        // Trailing bytes belong to the next request
+        let mut input = request.to_vec();
+        input.push(4);
+        for end in 0..request.len() {
+            assert!(sanitize_request_within(&input[..end], usize::MAX)
+                .unwrap()
+                .is_none());
+        }
+
+        let (sanitized, n_parsed_bytes) = sanitize_request_within(&input, usize::MAX)
+            .unwrap()
+            .unwrap();
+        assert_eq!(n_parsed_bytes, request.len());
+        let (request, _) = parse_request(&sanitized).unwrap().unwrap();
+        assert!(matches!(
+            request,
+            Request::SetEx {
+                key: "\u{FFFD}(",
+                value: "a",
+                ttl_ms: 7
+            }
+        ));
+    }
+
+    #[test]
+    fn test_ping_round_trip() {
+        let bytes = Request::Ping.serialize();
+        let (request, n_parsed_bytes) = parse_request(&bytes).unwrap().unwrap();
+        assert_eq!(n_parsed_bytes, bytes.len());
+        assert!(matches!(request, Request::Ping));
+
+        let bytes = Response::Pong.serialize();
+        assert_eq!(
+            parse_response(&bytes).unwrap().unwrap(),
+            (Response::Pong, bytes.len())
+        );
+    }
+
+    #[test]
+    fn test_get_if_modified_since_round_trip() {
+        let bytes = Request::GetIfModifiedSince {
+            key: "abc",
+            since_ms: 42,
+        }
+        .serialize();
+        let (request, n_parsed_bytes) = parse_request(&bytes).unwrap().unwrap();
+        assert_eq!(n_parsed_bytes, bytes.len());
+        assert!(matches!(
+            request,
+            Request::GetIfModifiedSince {
+                key: "abc",
+                since_ms: 42
+            }
+        ));
+
+        for modified in [
+            GetModified::NotModified,
+            GetModified::Value(String::new()),
+            GetModified::Value("value".to_string()),
+            GetModified::Missing,
+        ] {
+            let response = Response::GetIfModifiedSince(modified);
+            let bytes = response.serialize();
+            assert_eq!(
+                parse_response(&bytes).unwrap().unwrap(),
+                (response, bytes.len())
+            );
+        }
+        assert!(matches!(parse_response(&[22, 3]), Err(ParsingError::Other)));
+    }
+
+    #[test]
+    fn test_get_meta_round_trip() {
+        let bytes = Request::GetMeta("abc").serialize();
+        let (request, n_parsed_bytes) = parse_request(&bytes).unwrap().unwrap();
+        assert_eq!(n_parsed_bytes, bytes.len());
+        assert!(matches!(request, Request::GetMeta("abc")));
+
+        for response in [
+            Response::GetMeta(Some((
+                "value".to_string(),
+                KeyMeta {
+                    ttl: Some(Duration::from_millis(1500)),
+                    size: 5,
+                },
+            ))),
+            Response::GetMeta(Some(("value".to_string(), KeyMeta { ttl: None, size: 5 }))),
+            Response::GetMeta(None),
+        ] {
+            let bytes = response.serialize();
+            assert_eq!(
+                parse_response(&bytes).unwrap().unwrap(),
+                (response, bytes.len())
+            );
+            for end in 0..bytes.len() {
+                assert!(parse_response(&bytes[..end]).unwrap().is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn test_info_round_trip() {
+        let bytes = Request::Info.serialize();
+        let (request, n_parsed_bytes) = parse_request(&bytes).unwrap().unwrap();
+        assert_eq!(n_parsed_bytes, bytes.len());
+        assert!(matches!(request, Request::Info));
+
+        let response = Response::Info(ServerInfo {
+            protocol_version: PROTOCOL_VERSION,
+            version: "1.2.3".to_string(),
+            uptime: Duration::from_millis(1234),
+            features: vec!["ttl".to_string(), "http".to_string()],
+        });
+        let bytes = response.serialize();
+        assert_eq!(
+            parse_response(&bytes).unwrap().unwrap(),
+            (response, bytes.len())
+        );
+        for end in 0..bytes.len() {
+            assert!(parse_response(&bytes[..end]).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn test_incr_round_trip() {
+        for delta in [i64::MIN, -1, 0, 1, i64::MAX] {
+            let bytes = Request::Incr { key: "abc", delta }.serialize();
+            let (request, n_parsed_bytes) = parse_request(&bytes).unwrap().unwrap();
+            assert_eq!(n_parsed_bytes, bytes.len());
+            assert!(matches!(request, Request::Incr { key: "abc", delta: d } if d == delta));
+
+            let bytes = Response::Incr(delta).serialize();
+            let (response, n_parsed_bytes) = parse_response(&bytes).unwrap().unwrap();
+            assert_eq!(n_parsed_bytes, bytes.len());
+            assert_eq!(response, Response::Incr(delta));
+        }
+    }
+
+    #[test]
+    fn test_set_ex_and_set_are_not_confused() {
+        let set_ex_bytes = Request::SetEx {
+            key: "abc",
+            value: "ghi",
+            ttl_ms: 1000,
+        }
+        .serialize();
+        let (request, n_parsed_bytes) = parse_request(&set_ex_bytes).unwrap().unwrap();
+        assert_eq!(n_parsed_bytes, set_ex_bytes.len());
+        assert!(matches!(
+            request,
+            Request::SetEx {
+                key: "abc",
+                value: "ghi",
+                ttl_ms: 1000
+            }
+        ));
+        // A truncated SetEx must never be parsed, in particular not as a Set
+        for end in 0..set_ex_bytes.len() {
+            assert!(parse_request(&set_ex_bytes[..end]).unwrap().is_none());
+        }
+
+        let set_bytes = Request::Set {
+            key: "abc",
+            value: "ghi",
+        }
+        .serialize();
+        let (request, n_parsed_bytes) = parse_request(&set_bytes).unwrap().unwrap();
+        assert_eq!(n_parsed_bytes, set_bytes.len());
+        assert!(matches!(
+            request,
+            Request::Set {
+                key: "abc",
+                value: "ghi"
+            }
+        ));
+    }
+
+    #[test]
+    fn test_unknown_error_code_is_rejected() {
+        assert!(matches!(
+            parse_response(&[5, 255, 0, 0, 0, 0]),
+            Err(ParsingError::UnknownErrorCode(255))
+        ));
+    }
+}
@@ -0,0 +1,365 @@
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use crate::db::Database;
+use crate::db::Value;
+use crate::error::Result;
+use crate::error::ServerError;
+use crate::GetModified;
+use crate::KeyMeta;
+
+/// A [`Database`] decorator loading missing keys from a backing store.
+///
+/// When [`get`](Database::get) misses the inner database `D`, the `loader` is called with the key.
+/// A loaded value is stored in the inner database and returned, so later reads are served from
+/// the inner database. If the `loader` returns `None` as well, the read stays a miss.
+/// All other operations, including other reads, only see what is stored in the inner database.
+pub struct ReadThroughDb<D, F> {
+    inner: D,
+    loader: Arc<F>,
+}
+
+impl<D, F> ReadThroughDb<D, F>
+where
+    D: Database,
+    F: Fn(&str) -> Option<Value>,
+{
+    /// Wraps `inner`, loading keys missing from it with `loader`.
+    pub fn new(
+        inner: D,
+        loader: F,
+    ) -> Self {
+        Self {
+            inner,
+            loader: Arc::new(loader),
+        }
+    }
+
+    /// Returns the wrapped database.
+    pub fn inner(&self) -> &D {
+        &self.inner
+    }
+}
+
+impl<D: Clone, F> Clone for ReadThroughDb<D, F> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            loader: Arc::clone(&self.loader),
+        }
+    }
+}
+
+impl<D: fmt::Debug, F> fmt::Debug for ReadThroughDb<D, F> {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        f.debug_struct("ReadThroughDb")
+            .field("inner", &self.inner)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<D, F> Database for ReadThroughDb<D, F>
+where
+    D: Database,
+    F: Fn(&str) -> Option<Value>,
+    F: Send + Sync,
+{
+    fn get(
+        &self,
+        key: &str,
+    ) -> Result<Option<Value>> {
+        if let Some(value) = self.inner.get(key)? {
+            return Ok(Some(value));
+        }
+        let Some(value) = (self.loader)(key) else {
+            return Ok(None);
+        };
+        self.inner.insert(key.to_string(), value.clone())?;
+        Ok(Some(value))
+    }
+
+    fn get_arc(
+        &self,
+        key: &str,
+    ) -> Result<Option<Arc<str>>> {
+        if let Some(value) = self.inner.get_arc(key)? {
+            return Ok(Some(value));
+        }
+        let Some(value) = (self.loader)(key) else {
+            return Ok(None);
+        };
+        self.inner.insert(key.to_string(), value.clone())?;
+        let value = String::try_from(value).map_err(ServerError::Database)?;
+        Ok(Some(value.into()))
+    }
+
+    fn get_and_touch(
+        &self,
+        key: &str,
+        ttl: Duration,
+    ) -> Result<Option<Value>> {
+        self.inner.get_and_touch(key, ttl)
+    }
+
+    fn get_with_meta(
+        &self,
+        key: &str,
+    ) -> Result<Option<(Value, KeyMeta)>> {
+        self.inner.get_with_meta(key)
+    }
+
+    fn get_if_modified_since(
+        &self,
+        key: &str,
+        since: SystemTime,
+    ) -> Result<GetModified<Value>> {
+        self.inner.get_if_modified_since(key, since)
+    }
+
+    fn get_range(
+        &self,
+        key: &str,
+        start: i64,
+        end: i64,
+    ) -> Result<Option<Value>> {
+        self.inner.get_range(key, start, end)
+    }
+
+    fn value_len(
+        &self,
+        key: &str,
+    ) -> Result<Option<usize>> {
+        self.inner.value_len(key)
+    }
+
+    fn access_count(
+        &self,
+        key: &str,
+    ) -> Result<Option<u64>> {
+        self.inner.access_count(key)
+    }
+
+    fn get_many(
+        &self,
+        keys: &[&str],
+    ) -> Result<Vec<Option<Value>>> {
+        self.inner.get_many(keys)
+    }
+
+    fn get_many_ref(
+        &self,
+        keys: &[&str],
+        visit: &mut dyn FnMut(Option<&Value>),
+    ) -> Result<()> {
+        self.inner.get_many_ref(keys, visit)
+    }
+
+    fn insert(
+        &self,
+        key: String,
+        value: Value,
+    ) -> Result<()> {
+        self.inner.insert(key, value)
+    }
+
+    fn insert_str(
+        &self,
+        key: &str,
+        value: &str,
+    ) -> Result<()> {
+        self.inner.insert_str(key, value)
+    }
+
+    fn set_range(
+        &self,
+        key: &str,
+        offset: usize,
+        value: &str,
+    ) -> Result<usize> {
+        self.inner.set_range(key, offset, value)
+    }
+
+    fn insert_with_ttl(
+        &self,
+        key: String,
+        value: Value,
+        ttl: Duration,
+    ) -> Result<()> {
+        self.inner.insert_with_ttl(key, value, ttl)
+    }
+
+    // Forwarded instead of using the provided implementation, which would lose the inner database's atomicity.
+    fn extend(
+        &self,
+        pairs: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<()> {
+        self.inner.extend(pairs)
+    }
+
+    fn replace_all(
+        &self,
+        pairs: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<()> {
+        self.inner.replace_all(pairs)
+    }
+
+    fn insert_if_absent(
+        &self,
+        key: String,
+        value: Value,
+    ) -> Result<bool> {
+        self.inner.insert_if_absent(key, value)
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: &str,
+        expected: &Value,
+        new: Value,
+    ) -> Result<bool> {
+        self.inner.compare_and_swap(key, expected, new)
+    }
+
+    fn incr(
+        &self,
+        key: &str,
+        delta: i64,
+    ) -> Result<i64> {
+        self.inner.incr(key, delta)
+    }
+
+    fn contains_many(
+        &self,
+        keys: &[&str],
+    ) -> Result<Vec<bool>> {
+        self.inner.contains_many(keys)
+    }
+
+    fn touch_many(
+        &self,
+        keys: &[&str],
+        ttl: Duration,
+    ) -> Result<usize> {
+        self.inner.touch_many(keys, ttl)
+    }
+
+    fn count_prefix(
+        &self,
+        prefix: &str,
+    ) -> Result<usize> {
+        self.inner.count_prefix(prefix)
+    }
+
+    fn get_prefix(
+        &self,
+        prefix: &str,
+    ) -> Result<Vec<(String, Value)>> {
+        self.inner.get_prefix(prefix)
+    }
+
+    fn remove(
+        &self,
+        key: &str,
+    ) -> Result<()> {
+        self.inner.remove(key)
+    }
+
+    fn delete_if_older_than(
+        &self,
+        key: &str,
+        cutoff: SystemTime,
+    ) -> Result<bool> {
+        self.inner.delete_if_older_than(key, cutoff)
+    }
+
+    fn remove_if(
+        &self,
+        key: &str,
+        expected: &Value,
+    ) -> Result<bool> {
+        self.inner.remove_if(key, expected)
+    }
+
+    fn clear(&self) -> Result<()> {
+        self.inner.clear()
+    }
+
+    fn retain(
+        &self,
+        keep: impl Fn(&str, &Value) -> bool,
+    ) -> Result<usize> {
+        self.inner.retain(keep)
+    }
+
+    fn clear_returning(&self) -> Result<Vec<String>> {
+        self.inner.clear_returning()
+    }
+
+    fn is_poisoned(&self) -> bool {
+        self.inner.is_poisoned()
+    }
+
+    fn memory_usage(&self) -> Result<usize> {
+        self.inner.memory_usage()
+    }
+
+    fn len(&self) -> Result<usize> {
+        self.inner.len()
+    }
+
+    fn scan(
+        &self,
+        cursor: usize,
+        count: usize,
+    ) -> Result<(usize, Vec<String>)> {
+        self.inner.scan(cursor, count)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+
+    use super::*;
+    use crate::db::DB;
+
+    #[test]
+    fn test_loader_is_only_called_on_misses() {
+        let loads = AtomicUsize::new(0);
+        let db = ReadThroughDb::new(DB::new(), |key: &str| {
+            loads.fetch_add(1, Ordering::Relaxed);
+            key.strip_prefix("backed-").map(Value::from)
+        });
+        db.insert("stored".to_string(), "1".into()).unwrap();
+
+        assert_eq!(db.get("stored").unwrap(), Some("1".into()));
+        assert_eq!(loads.load(Ordering::Relaxed), 0);
+
+        assert_eq!(db.get("backed-abc").unwrap(), Some("abc".into()));
+        assert_eq!(loads.load(Ordering::Relaxed), 1);
+        // The loaded value is cached in the inner database.
+        assert_eq!(db.get("backed-abc").unwrap(), Some("abc".into()));
+        assert_eq!(loads.load(Ordering::Relaxed), 1);
+        assert_eq!(db.inner().get("backed-abc").unwrap(), Some("abc".into()));
+    }
+
+    #[test]
+    fn test_loader_returning_none_stays_a_miss() {
+        let loads = AtomicUsize::new(0);
+        let db = ReadThroughDb::new(DB::new(), |_: &str| {
+            loads.fetch_add(1, Ordering::Relaxed);
+            None
+        });
+        assert_eq!(db.get("missing").unwrap(), None);
+        assert_eq!(db.get("missing").unwrap(), None);
+        // Misses are not cached, so the loader is asked again.
+        assert_eq!(loads.load(Ordering::Relaxed), 2);
+        assert!(db.inner().is_empty());
+    }
+}
@@ -0,0 +1,1153 @@
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::result;
+use core::str::from_utf8;
+
+use tracing::debug;
+
+use crate::protocol::GetModified;
+use crate::protocol::ParsingError;
+use crate::protocol::Result;
+use crate::Request;
+use crate::Response;
+
+/// Serializes a type into its wire format.
+///
+/// Every frame starts with a one byte opcode. All integers, including the `u32` length
+/// prefix of every key and value, are encoded big-endian independent of the platform.
+pub trait Serialize {
+    /// Returns the serialized frame.
+    ///
+    /// # Panics
+    /// Panics if a key, value or list is too long for its `u32` length prefix,
+    /// see [`try_serialize`](Serialize::try_serialize) for a non-panicking version.
+    fn serialize(&self) -> Vec<u8> {
+        match self.try_serialize() {
+            Ok(data) => data,
+            Err(e) => panic!("cannot serialize frame: {e}"),
+        }
+    }
+
+    /// Returns the serialized frame.
+    ///
+    /// # Errors
+    /// If a key, value or list is longer than `u32::MAX`, a [`SerializationError`] is returned
+    /// instead of truncating its length prefix and producing a corrupt frame.
+    fn try_serialize(&self) -> result::Result<Vec<u8>, SerializationError>;
+}
+
+/// An error while serializing a request or a response, see [`Serialize::try_serialize`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SerializationError {
+    /// A key, value or list of the given length does not fit its `u32` length prefix.
+    TooLong(usize),
+}
+
+impl fmt::Display for SerializationError {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        match self {
+            SerializationError::TooLong(len) => {
+                write!(f, "length of {len} exceeds the maximum of {}", u32::MAX)
+            }
+        }
+    }
+}
+
+impl core::error::Error for SerializationError {}
+
+impl Serialize for Request<'_> {
+    fn try_serialize(&self) -> result::Result<Vec<u8>, SerializationError> {
+        Ok(match self {
+            Request::Get(key) => {
+                let mut data = Vec::with_capacity(key.len() + 5);
+                data.push(1);
+                write_element(&mut data, key)?;
+                data
+            }
+            Request::Set { key, value } => {
+                let mut data = Vec::with_capacity(key.len() + value.len() + 9);
+                data.push(2);
+                write_element(&mut data, key)?;
+                write_element(&mut data, value)?;
+                data
+            }
+            Request::Delete(key) => {
+                let mut data = Vec::with_capacity(key.len() + 5);
+                data.push(3);
+                write_element(&mut data, key)?;
+                data
+            }
+            Request::Flush => {
+                vec![4]
+            }
+            Request::Scan { cursor, count } => {
+                let mut data = Vec::with_capacity(13);
+                data.push(5);
+                data.extend(cursor.to_be_bytes());
+                data.extend(count.to_be_bytes());
+                data
+            }
+            Request::SetEx { key, value, ttl_ms } => {
+                let mut data = Vec::with_capacity(key.len() + value.len() + 17);
+                data.push(6);
+                write_element(&mut data, key)?;
+                write_element(&mut data, value)?;
+                data.extend(ttl_ms.to_be_bytes());
+                data
+            }
+            Request::Select(index) => {
+                let mut data = Vec::with_capacity(5);
+                data.push(7);
+                data.extend(index.to_be_bytes());
+                data
+            }
+            Request::FlushAll => {
+                vec![8]
+            }
+            Request::MGet(keys) => {
+                let keys_len: usize = keys.iter().map(|key| key.len() + 4).sum();
+                let mut data = Vec::with_capacity(keys_len + 5);
+                data.push(9);
+                write_count_prefixed(&mut data, keys, |data, key| write_element(data, key))?;
+                data
+            }
+            Request::MSet(pairs) => {
+                let pairs_len: usize = pairs
+                    .iter()
+                    .map(|(key, value)| key.len() + value.len() + 8)
+                    .sum();
+                let mut data = Vec::with_capacity(pairs_len + 5);
+                data.push(10);
+                write_count_prefixed(&mut data, pairs, |data, (key, value)| {
+                    write_element(data, key)?;
+                    write_element(data, value)
+                })?;
+                data
+            }
+            Request::Incr { key, delta } => {
+                let mut data = Vec::with_capacity(key.len() + 13);
+                data.push(11);
+                write_element(&mut data, key)?;
+                data.extend(delta.to_be_bytes());
+                data
+            }
+            Request::MExists(keys) => {
+                let keys_len: usize = keys.iter().map(|key| key.len() + 4).sum();
+                let mut data = Vec::with_capacity(keys_len + 5);
+                data.push(12);
+                write_count_prefixed(&mut data, keys, |data, key| write_element(data, key))?;
+                data
+            }
+            Request::MTouch { keys, ttl_ms } => {
+                let keys_len: usize = keys.iter().map(|key| key.len() + 4).sum();
+                let mut data = Vec::with_capacity(keys_len + 13);
+                data.push(13);
+                data.extend(ttl_ms.to_be_bytes());
+                write_count_prefixed(&mut data, keys, |data, key| write_element(data, key))?;
+                data
+            }
+            Request::CountPrefix(prefix) => {
+                let mut data = Vec::with_capacity(prefix.len() + 5);
+                data.push(14);
+                write_element(&mut data, prefix)?;
+                data
+            }
+            Request::Cas { key, expected, new } => {
+                let mut data = Vec::with_capacity(key.len() + expected.len() + new.len() + 13);
+                data.push(15);
+                write_element(&mut data, key)?;
+                write_element(&mut data, expected)?;
+                write_element(&mut data, new)?;
+                data
+            }
+            Request::SetNx { key, value } => {
+                let mut data = Vec::with_capacity(key.len() + value.len() + 9);
+                data.push(16);
+                write_element(&mut data, key)?;
+                write_element(&mut data, value)?;
+                data
+            }
+            Request::Info => {
+                vec![17]
+            }
+            Request::Ping => {
+                vec![21]
+            }
+            Request::GetIfModifiedSince { key, since_ms } => {
+                let mut data = Vec::with_capacity(key.len() + 13);
+                data.push(22);
+                write_element(&mut data, key)?;
+                data.extend(since_ms.to_be_bytes());
+                data
+            }
+            Request::DeleteIfOlderThan { key, cutoff_ms } => {
+                let mut data = Vec::with_capacity(key.len() + 13);
+                data.push(18);
+                write_element(&mut data, key)?;
+                data.extend(cutoff_ms.to_be_bytes());
+                data
+            }
+            Request::Auth(password) => {
+                let mut data = Vec::with_capacity(password.len() + 5);
+                data.push(19);
+                write_element(&mut data, password)?;
+                data
+            }
+            Request::GetMeta(key) => {
+                let mut data = Vec::with_capacity(key.len() + 5);
+                data.push(20);
+                write_element(&mut data, key)?;
+                data
+            }
+            Request::Hello(client_id) => {
+                let mut data = Vec::with_capacity(client_id.len() + 5);
+                data.push(23);
+                write_element(&mut data, client_id)?;
+                data
+            }
+            Request::GetRange { key, start, end } => {
+                let mut data = Vec::with_capacity(key.len() + 21);
+                data.push(24);
+                write_element(&mut data, key)?;
+                data.extend(start.to_be_bytes());
+                data.extend(end.to_be_bytes());
+                data
+            }
+            Request::SetRange { key, offset, value } => {
+                let mut data = Vec::with_capacity(key.len() + value.len() + 17);
+                data.push(25);
+                write_element(&mut data, key)?;
+                data.extend(offset.to_be_bytes());
+                write_element(&mut data, value)?;
+                data
+            }
+            Request::StrLen(key) => {
+                let mut data = Vec::with_capacity(key.len() + 5);
+                data.push(26);
+                write_element(&mut data, key)?;
+                data
+            }
+            Request::ReplaceAll(pairs) => {
+                let pairs_len: usize = pairs
+                    .iter()
+                    .map(|(key, value)| key.len() + value.len() + 8)
+                    .sum();
+                let mut data = Vec::with_capacity(pairs_len + 5);
+                data.push(27);
+                write_count_prefixed(&mut data, pairs, |data, (key, value)| {
+                    write_element(data, key)?;
+                    write_element(data, value)
+                })?;
+                data
+            }
+            Request::Multi => {
+                vec![28]
+            }
+            Request::Exec => {
+                vec![29]
+            }
+            Request::Watch(keys) => {
+                let keys_len: usize = keys.iter().map(|key| key.len() + 4).sum();
+                let mut data = Vec::with_capacity(keys_len + 5);
+                data.push(30);
+                write_count_prefixed(&mut data, keys, |data, key| write_element(data, key))?;
+                data
+            }
+            Request::GetPrefix(prefix) => {
+                let mut data = Vec::with_capacity(prefix.len() + 5);
+                data.push(31);
+                write_element(&mut data, prefix)?;
+                data
+            }
+            Request::DeleteIf { key, expected } => {
+                let mut data = Vec::with_capacity(key.len() + expected.len() + 9);
+                data.push(32);
+                write_element(&mut data, key)?;
+                write_element(&mut data, expected)?;
+                data
+            }
+            Request::GetTouch { key, ttl_ms } => {
+                let mut data = Vec::with_capacity(key.len() + 13);
+                data.push(33);
+                write_element(&mut data, key)?;
+                data.extend(ttl_ms.to_be_bytes());
+                data
+            }
+        })
+    }
+}
+
+impl Serialize for Response {
+    fn try_serialize(&self) -> result::Result<Vec<u8>, SerializationError> {
+        Ok(match self {
+            Response::Get(maybe_value) => serialize_get_response(maybe_value.as_deref())?,
+            Response::Set => {
+                vec![2]
+            }
+            Response::Delete => {
+                vec![3]
+            }
+            Response::Flush => {
+                vec![4]
+            }
+            Response::Error { code, message } => {
+                let message = message.as_deref().unwrap_or_default();
+                let mut data = Vec::with_capacity(message.len() + 6);
+                data.push(5);
+                data.push((*code).into());
+                // A missing message is sent with a length of 0
+                write_element(&mut data, message)?;
+                data
+            }
+            Response::Scan { cursor, keys } => {
+                let keys_len: usize = keys.iter().map(|key| key.len() + 4).sum();
+                let mut data = Vec::with_capacity(keys_len + 13);
+                data.push(6);
+                data.extend(cursor.to_be_bytes());
+                write_count_prefixed(&mut data, keys, |data, key| write_element(data, key))?;
+                data
+            }
+            Response::Select => {
+                vec![7]
+            }
+            Response::FlushAll => {
+                vec![8]
+            }
+            Response::MGet(values) => {
+                let values_len: usize = values
+                    .iter()
+                    .map(|value| value.as_ref().map_or(0, String::len) + 4)
+                    .sum();
+                let mut data = Vec::with_capacity(values_len + 5);
+                data.push(9);
+                // Missing values are sent with a length of 0
+                write_count_prefixed(&mut data, values, |data, value| {
+                    write_element(data, value.as_deref().unwrap_or_default())
+                })?;
+                data
+            }
+            Response::MSet => {
+                vec![10]
+            }
+            Response::Incr(value) => {
+                let mut data = Vec::with_capacity(9);
+                data.push(11);
+                data.extend(value.to_be_bytes());
+                data
+            }
+            Response::MExists(exists) => {
+                let mut data = Vec::with_capacity(exists.len() + 5);
+                data.push(12);
+                write_count_prefixed(&mut data, exists, |data, exists| {
+                    data.push(u8::from(*exists));
+                    Ok(())
+                })?;
+                data
+            }
+            Response::MTouch(touched) => {
+                let mut data = Vec::with_capacity(5);
+                data.push(13);
+                data.extend(touched.to_be_bytes());
+                data
+            }
+            Response::CountPrefix(count) => {
+                let mut data = Vec::with_capacity(9);
+                data.push(14);
+                data.extend(count.to_be_bytes());
+                data
+            }
+            Response::Cas(swapped) => {
+                vec![15, u8::from(*swapped)]
+            }
+            Response::SetNx(set) => {
+                vec![16, u8::from(*set)]
+            }
+            Response::Info(info) => {
+                let features_len: usize = info.features.iter().map(|f| f.len() + 4).sum();
+                let mut data = Vec::with_capacity(info.version.len() + features_len + 21);
+                data.push(17);
+                data.extend(info.protocol_version.to_be_bytes());
+                write_element(&mut data, &info.version)?;
+                let uptime_ms = u64::try_from(info.uptime.as_millis()).unwrap_or(u64::MAX);
+                data.extend(uptime_ms.to_be_bytes());
+                write_count_prefixed(&mut data, &info.features, |data, feature| {
+                    write_element(data, feature)
+                })?;
+                data
+            }
+            Response::DeleteIfOlderThan(deleted) => {
+                vec![18, u8::from(*deleted)]
+            }
+            Response::Auth => {
+                vec![19]
+            }
+            Response::Pong => {
+                vec![21]
+            }
+            Response::Hello => {
+                vec![23]
+            }
+            Response::GetRange(None) => {
+                vec![24, 0]
+            }
+            Response::GetRange(Some(range)) => {
+                let mut data = Vec::with_capacity(range.len() + 6);
+                data.extend([24, 1]);
+                write_element(&mut data, range)?;
+                data
+            }
+            Response::SetRange(len) => {
+                let mut data = Vec::with_capacity(9);
+                data.push(25);
+                data.extend(len.to_be_bytes());
+                data
+            }
+            Response::StrLen(None) => {
+                vec![26, 0]
+            }
+            Response::StrLen(Some(len)) => {
+                let mut data = Vec::with_capacity(10);
+                data.extend([26, 1]);
+                data.extend(len.to_be_bytes());
+                data
+            }
+            Response::ReplaceAll => {
+                vec![27]
+            }
+            Response::Multi => {
+                vec![28]
+            }
+            Response::Exec(responses) => {
+                let mut data = vec![29];
+                write_count_prefixed(&mut data, responses, |data, response| {
+                    data.extend(response.try_serialize()?);
+                    Ok(())
+                })?;
+                data
+            }
+            Response::Queued => {
+                vec![30]
+            }
+            Response::Watch => {
+                vec![31]
+            }
+            Response::Invalidate(key) => {
+                let mut data = Vec::with_capacity(key.len() + 5);
+                data.push(32);
+                write_element(&mut data, key)?;
+                data
+            }
+            Response::GetPrefix(pairs) => {
+                let pairs_len: usize = pairs
+                    .iter()
+                    .map(|(key, value)| key.len() + value.len() + 8)
+                    .sum();
+                let mut data = Vec::with_capacity(pairs_len + 5);
+                data.push(33);
+                write_count_prefixed(&mut data, pairs, |data, (key, value)| {
+                    write_element(data, key)?;
+                    write_element(data, value)
+                })?;
+                data
+            }
+            Response::DeleteIf(deleted) => {
+                vec![34, u8::from(*deleted)]
+            }
+            Response::GetIfModifiedSince(modified) => match modified {
+                GetModified::Missing => vec![22, 0],
+                GetModified::NotModified => vec![22, 1],
+                GetModified::Value(value) => {
+                    let mut data = Vec::with_capacity(value.len() + 6);
+                    data.extend([22, 2]);
+                    write_element(&mut data, value)?;
+                    data
+                }
+            },
+            Response::GetMeta(value) => {
+                let (value, meta) = match value {
+                    Some((value, meta)) => (value.as_str(), Some(meta)),
+                    None => ("", None),
+                };
+                let mut data = Vec::with_capacity(value.len() + 22);
+                data.push(20);
+                write_element(&mut data, value)?;
+                let ttl = meta.and_then(|meta| meta.ttl);
+                data.push(u8::from(ttl.is_some()));
+                let ttl_ms =
+                    ttl.map_or(0, |ttl| u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX));
+                data.extend(ttl_ms.to_be_bytes());
+                data.extend(meta.map_or(0, |meta| meta.size).to_be_bytes());
+                data
+            }
+        })
+    }
+}
+
+/// Encodes requests straight into a buffer in their wire format, without building a [`Request`].
+///
+/// Useful to build a pipelined batch from owned strings that would otherwise have to be kept
+/// alive for the borrowed [`Request`] variants. The output of every method is the same
+/// as [`Serialize::serialize`] of the corresponding request.
+///
+/// # Errors
+/// Like [`Serialize::try_serialize`], appending a key or value longer than `u32::MAX` fails
+/// with a [`SerializationError`]. The failed request is not appended, the ones before it are kept.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RequestEncoder {
+    data: Vec<u8>,
+}
+
+impl RequestEncoder {
+    /// Creates an empty encoder.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends a `Get` request.
+    pub fn get(
+        &mut self,
+        key: &str,
+    ) -> result::Result<&mut Self, SerializationError> {
+        self.push_request(1, &[key])
+    }
+
+    /// Appends a `Set` request.
+    pub fn set(
+        &mut self,
+        key: &str,
+        value: &str,
+    ) -> result::Result<&mut Self, SerializationError> {
+        self.push_request(2, &[key, value])
+    }
+
+    /// Appends a `Delete` request.
+    pub fn delete(
+        &mut self,
+        key: &str,
+    ) -> result::Result<&mut Self, SerializationError> {
+        self.push_request(3, &[key])
+    }
+
+    /// Appends a `Flush` request.
+    pub fn flush(&mut self) -> &mut Self {
+        self.data.push(4);
+        self
+    }
+
+    /// Returns the requests encoded so far.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Returns the encoded requests, consuming the encoder.
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+
+    /// Removes all encoded requests, keeping the allocated buffer.
+    pub fn clear(&mut self) {
+        self.data.clear();
+    }
+
+    /// Appends a request with the `opcode` and its `elements` each prefixed with its length.
+    fn push_request(
+        &mut self,
+        opcode: u8,
+        elements: &[&str],
+    ) -> result::Result<&mut Self, SerializationError> {
+        let len = self.data.len();
+        self.data.push(opcode);
+        for element in elements {
+            if let Err(e) = write_element(&mut self.data, element) {
+                // Leave no partial request behind to corrupt the batch
+                self.data.truncate(len);
+                return Err(e);
+            }
+        }
+        Ok(self)
+    }
+}
+
+/// Serializes a [`Response::Get`] with a borrowed value,
+/// so that the server does not have to copy a stored value into a `Response` first.
+pub(crate) fn serialize_get_response(
+    value: Option<&str>
+) -> result::Result<Vec<u8>, SerializationError> {
+    let value = value.unwrap_or_default();
+    // Reserve enough space so we don't have to reallocate
+    let mut data = Vec::with_capacity(value.len() + 5);
+    data.push(1);
+    // A missing value is sent with a length of 0
+    write_element(&mut data, value)?;
+    Ok(data)
+}
+
+/// Writes `element` prefixed with its length.
+pub(crate) fn write_element(
+    data: &mut Vec<u8>,
+    element: &str,
+) -> result::Result<(), SerializationError> {
+    data.extend(length_prefix(element.len())?.to_be_bytes());
+    data.extend(element.as_bytes());
+    Ok(())
+}
+
+/// Writes the number of `items` followed by every item written with `write_item`.
+pub(crate) fn write_count_prefixed<T>(
+    data: &mut Vec<u8>,
+    items: &[T],
+    mut write_item: impl FnMut(&mut Vec<u8>, &T) -> result::Result<(), SerializationError>,
+) -> result::Result<(), SerializationError> {
+    data.extend(length_prefix(items.len())?.to_be_bytes());
+    for item in items {
+        write_item(data, item)?;
+    }
+    Ok(())
+}
+
+/// Returns `len` as a `u32` length prefix, or an error if it would be truncated.
+fn length_prefix(len: usize) -> result::Result<u32, SerializationError> {
+    u32::try_from(len).map_err(|_| SerializationError::TooLong(len))
+}
+
+/// Reads a count followed by that many items read with `read_item` and advances the cursor.
+/// Returns `None` if the buffer does not contain all items yet.
+pub(crate) fn read_count_prefixed<'a, T>(
+    input: &'a [u8],
+    cursor: &mut usize,
+    mut read_item: impl FnMut(&'a [u8], &mut usize) -> Result<Option<T>>,
+) -> Result<Option<Vec<T>>> {
+    let Some(count) = read_u32(input, cursor) else {
+        return Ok(None);
+    };
+    // Don't trust the count for allocating as every item takes at least 1 byte
+    let remaining = input.len().saturating_sub(*cursor);
+    let mut items = Vec::with_capacity((count as usize).min(remaining));
+    for _ in 0..count {
+        let Some(item) = read_item(input, cursor)? else {
+            return Ok(None);
+        };
+        items.push(item);
+    }
+    Ok(Some(items))
+}
+
+/// Reads a single byte from the buffer and advances the cursor.
+/// Returns `None` if the buffer does not contain enough bytes yet.
+pub(crate) fn read_u8(
+    input: &[u8],
+    cursor: &mut usize,
+) -> Option<u8> {
+    let byte = *input.get(*cursor)?;
+    *cursor += 1;
+    Some(byte)
+}
+
+/// Reads a big-endian `u32` from the buffer and advances the cursor.
+/// Returns `None` if the buffer does not contain enough bytes yet.
+pub(crate) fn read_u32(
+    input: &[u8],
+    cursor: &mut usize,
+) -> Option<u32> {
+    let end = *cursor + 4;
+    let bytes = input.get(*cursor..end)?.try_into().ok()?;
+    *cursor = end;
+    Some(u32::from_be_bytes(bytes))
+}
+
+/// Reads a big-endian `u64` from the buffer and advances the cursor.
+/// Returns `None` if the buffer does not contain enough bytes yet.
+pub(crate) fn read_u64(
+    input: &[u8],
+    cursor: &mut usize,
+) -> Option<u64> {
+    let end = *cursor + 8;
+    let bytes = input.get(*cursor..end)?.try_into().ok()?;
+    *cursor = end;
+    Some(u64::from_be_bytes(bytes))
+}
+
+/// Reads an element (key or value) from the buffer and advances the cursor.
+/// Returns `None` if the buffer does not contain the full element yet.
+pub(crate) fn read_element<'a>(
+    input: &'a [u8],
+    cursor: &mut usize,
+) -> Result<Option<&'a str>> {
+    read_element_within(input, cursor, usize::MAX)
+}
+
+/// Reads an element like [`read_element`] but fails with [`ParsingError::FrameTooLarge`]
+/// if the element would end beyond `max_len` bytes of input.
+pub(crate) fn read_element_within<'a>(
+    input: &'a [u8],
+    cursor: &mut usize,
+    max_len: usize,
+) -> Result<Option<&'a str>> {
+    // The element's length is serialized with 4 bytes
+    let element_size_len = 4;
+    // Check that enough bytes are in input
+    let element_size_end = *cursor + element_size_len;
+    if input.len() < element_size_end {
+        debug!("not enough data for reading element size");
+        return Ok(None);
+    }
+    let bytes = input[*cursor..element_size_end]
+        .try_into()
+        .map_err(|_| ParsingError::Other)?;
+    let element_size = u32::from_be_bytes(bytes) as usize;
+    *cursor = element_size_end;
+    // Check that enough bytes are in input
+    let element_end = *cursor + element_size;
+    if element_end > max_len {
+        debug!("element of {element_size} bytes can never fit");
+        return Err(ParsingError::FrameTooLarge);
+    }
+    if input.len() < element_end {
+        debug!("not enough data for reading full element");
+        return Ok(None);
+    }
+    let element_bytes = &input[*cursor..element_end];
+    *cursor += element_size;
+    let element = from_utf8(element_bytes).map_err(ParsingError::from)?;
+    Ok(Some(element))
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::parse_request;
+    use crate::parse_response;
+    use crate::ErrorCode;
+    use crate::KeyMeta;
+    use crate::ServerInfo;
+
+    fn write_keys(keys: &[&str]) -> Vec<u8> {
+        let mut data = Vec::new();
+        write_count_prefixed(&mut data, keys, |data, key| write_element(data, key)).unwrap();
+        data
+    }
+
+    fn read_keys(data: &[u8]) -> Result<Option<(Vec<&str>, usize)>> {
+        let mut cursor = 0;
+        let keys = read_count_prefixed(data, &mut cursor, read_element)?;
+        Ok(keys.map(|keys| (keys, cursor)))
+    }
+
+    #[test]
+    fn test_empty_count_prefixed_round_trip() {
+        let data = write_keys(&[]);
+        assert_eq!(data, vec![0, 0, 0, 0]);
+        let (keys, cursor) = read_keys(&data).unwrap().unwrap();
+        assert!(keys.is_empty());
+        assert_eq!(cursor, 4);
+    }
+
+    #[test]
+    fn test_empty_count_prefixed_does_not_read_past_count() {
+        // Trailing bytes belong to the next frame
+        let mut data = write_keys(&[]);
+        data.extend([1, 0, 0, 0, 3]);
+        let (keys, cursor) = read_keys(&data).unwrap().unwrap();
+        assert!(keys.is_empty());
+        assert_eq!(cursor, 4);
+    }
+
+    #[test]
+    fn test_single_count_prefixed_round_trip() {
+        let data = write_keys(&["abc"]);
+        assert_eq!(data, vec![0, 0, 0, 1, 0, 0, 0, 3, 97, 98, 99]);
+        let (keys, cursor) = read_keys(&data).unwrap().unwrap();
+        assert_eq!(keys, vec!["abc"]);
+        assert_eq!(cursor, data.len());
+    }
+
+    #[test]
+    fn test_many_count_prefixed_round_trip() {
+        let expected: Vec<String> = (0..100).map(|i| format!("key-{i}")).collect();
+        let expected: Vec<&str> = expected.iter().map(String::as_str).collect();
+        let data = write_keys(&expected);
+        let (keys, cursor) = read_keys(&data).unwrap().unwrap();
+        assert_eq!(keys, expected);
+        assert_eq!(cursor, data.len());
+    }
+
+    #[test]
+    fn test_truncated_count_prefixed_is_incomplete() {
+        for data in [write_keys(&[]), write_keys(&["abc", "defg"])] {
+            for end in 0..data.len() {
+                assert!(read_keys(&data[..end]).unwrap().is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn test_count_prefixed_with_huge_count_does_not_allocate_upfront() {
+        let data = [255, 255, 255, 255, 0, 0, 0, 3, 97, 98, 99];
+        assert!(read_keys(&data).unwrap().is_none());
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    fn test_lengths_beyond_u32_are_not_truncated() {
+        assert_eq!(length_prefix(u32::MAX as usize), Ok(u32::MAX));
+        let len = u32::MAX as usize + 1;
+        assert_eq!(length_prefix(len), Err(SerializationError::TooLong(len)));
+
+        // A list of zero-sized items does not allocate
+        let items = vec![(); len];
+        let mut data = Vec::new();
+        assert_eq!(
+            write_count_prefixed(&mut data, &items, |_, _| Ok(())),
+            Err(SerializationError::TooLong(len))
+        );
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    #[ignore = "allocates more than 4 GiB"]
+    fn test_value_beyond_u32_fails_to_serialize() {
+        let value = "a".repeat(u32::MAX as usize + 1);
+        let request = Request::Set {
+            key: "abc",
+            value: &value,
+        };
+        assert_eq!(
+            request.try_serialize(),
+            Err(SerializationError::TooLong(value.len()))
+        );
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    #[ignore = "allocates more than 4 GiB"]
+    fn test_request_encoder_keeps_the_requests_before_a_value_beyond_u32() {
+        let value = "a".repeat(u32::MAX as usize + 1);
+        let mut encoder = RequestEncoder::new();
+        encoder.get("abc").unwrap();
+        assert_eq!(
+            encoder.set("abc", &value).err(),
+            Some(SerializationError::TooLong(value.len()))
+        );
+        assert_eq!(encoder.as_bytes(), Request::Get("abc").serialize());
+    }
+
+    #[test]
+    fn test_request_golden_bytes() {
+        // Part of the protocol contract, changing these bytes breaks other implementations
+        let cases: Vec<(Request, Vec<u8>)> = vec![
+            (Request::Get("abc"), vec![1, 0, 0, 0, 3, 97, 98, 99]),
+            (
+                Request::Set {
+                    key: "abc",
+                    value: "ghi",
+                },
+                vec![2, 0, 0, 0, 3, 97, 98, 99, 0, 0, 0, 3, 103, 104, 105],
+            ),
+            (Request::Delete("abc"), vec![3, 0, 0, 0, 3, 97, 98, 99]),
+            (Request::Flush, vec![4]),
+            (
+                Request::Scan {
+                    cursor: 258,
+                    count: 16,
+                },
+                vec![5, 0, 0, 0, 0, 0, 0, 1, 2, 0, 0, 0, 16],
+            ),
+            (
+                Request::SetEx {
+                    key: "abc",
+                    value: "ghi",
+                    ttl_ms: 1000,
+                },
+                vec![
+                    6, 0, 0, 0, 3, 97, 98, 99, 0, 0, 0, 3, 103, 104, 105, 0, 0, 0, 0, 0, 0, 3, 232,
+                ],
+            ),
+            (Request::Select(2), vec![7, 0, 0, 0, 2]),
+            (Request::FlushAll, vec![8]),
+            (
+                Request::MGet(vec!["a", "bc"]),
+                vec![9, 0, 0, 0, 2, 0, 0, 0, 1, 97, 0, 0, 0, 2, 98, 99],
+            ),
+            (
+                Request::MSet(vec![("a", "1")]),
+                vec![10, 0, 0, 0, 1, 0, 0, 0, 1, 97, 0, 0, 0, 1, 49],
+            ),
+            (
+                Request::Incr {
+                    key: "a",
+                    delta: -2,
+                },
+                vec![11, 0, 0, 0, 1, 97, 255, 255, 255, 255, 255, 255, 255, 254],
+            ),
+            (
+                Request::MExists(vec!["a"]),
+                vec![12, 0, 0, 0, 1, 0, 0, 0, 1, 97],
+            ),
+            (
+                Request::MTouch {
+                    keys: vec!["a"],
+                    ttl_ms: 1000,
+                },
+                vec![13, 0, 0, 0, 0, 0, 0, 3, 232, 0, 0, 0, 1, 0, 0, 0, 1, 97],
+            ),
+            (Request::CountPrefix("t:"), vec![14, 0, 0, 0, 2, 116, 58]),
+            (
+                Request::Cas {
+                    key: "a",
+                    expected: "1",
+                    new: "2",
+                },
+                vec![15, 0, 0, 0, 1, 97, 0, 0, 0, 1, 49, 0, 0, 0, 1, 50],
+            ),
+            (
+                Request::SetNx {
+                    key: "a",
+                    value: "1",
+                },
+                vec![16, 0, 0, 0, 1, 97, 0, 0, 0, 1, 49],
+            ),
+            (Request::Info, vec![17]),
+            (
+                Request::DeleteIfOlderThan {
+                    key: "a",
+                    cutoff_ms: 1000,
+                },
+                vec![18, 0, 0, 0, 1, 97, 0, 0, 0, 0, 0, 0, 3, 232],
+            ),
+            (Request::Auth("pw"), vec![19, 0, 0, 0, 2, 112, 119]),
+            (Request::GetMeta("a"), vec![20, 0, 0, 0, 1, 97]),
+            (Request::Ping, vec![21]),
+            (Request::Hello("id"), vec![23, 0, 0, 0, 2, 105, 100]),
+            (
+                Request::GetRange {
+                    key: "a",
+                    start: -1,
+                    end: 2,
+                },
+                vec![
+                    24, 0, 0, 0, 1, 97, 255, 255, 255, 255, 255, 255, 255, 255, 0, 0, 0, 0, 0, 0,
+                    0, 2,
+                ],
+            ),
+            (
+                Request::SetRange {
+                    key: "a",
+                    offset: 2,
+                    value: "b",
+                },
+                vec![25, 0, 0, 0, 1, 97, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 1, 98],
+            ),
+            (Request::StrLen("a"), vec![26, 0, 0, 0, 1, 97]),
+            (
+                Request::ReplaceAll(vec![("a", "1")]),
+                vec![27, 0, 0, 0, 1, 0, 0, 0, 1, 97, 0, 0, 0, 1, 49],
+            ),
+            (Request::Multi, vec![28]),
+            (Request::Exec, vec![29]),
+            (
+                Request::Watch(vec!["a"]),
+                vec![30, 0, 0, 0, 1, 0, 0, 0, 1, 97],
+            ),
+            (Request::GetPrefix("t:"), vec![31, 0, 0, 0, 2, 116, 58]),
+            (
+                Request::DeleteIf {
+                    key: "a",
+                    expected: "1",
+                },
+                vec![32, 0, 0, 0, 1, 97, 0, 0, 0, 1, 49],
+            ),
+            (
+                Request::GetTouch {
+                    key: "a",
+                    ttl_ms: 1000,
+                },
+                vec![33, 0, 0, 0, 1, 97, 0, 0, 0, 0, 0, 0, 3, 232],
+            ),
+            (
+                Request::GetIfModifiedSince {
+                    key: "a",
+                    since_ms: 1000,
+                },
+                vec![22, 0, 0, 0, 1, 97, 0, 0, 0, 0, 0, 0, 3, 232],
+            ),
+        ];
+        for (request, expected) in cases {
+            assert_eq!(request.serialize(), expected, "{expected:?}");
+            let (_, n_parsed_bytes) = parse_request(&expected).unwrap().unwrap();
+            assert_eq!(n_parsed_bytes, expected.len());
+        }
+    }
+
+    #[test]
+    fn test_request_encoder_matches_serialize() {
+        let mut encoder = RequestEncoder::new();
+        let mut expected = Vec::new();
+        for (key, value) in [("abc", "def"), ("", ""), ("ключ", "значение")] {
+            let key = key.to_string();
+            let value = value.to_string();
+            encoder.get(&key).unwrap();
+            expected.extend(Request::Get(&key).serialize());
+            encoder.set(&key, &value).unwrap();
+            expected.extend(
+                Request::Set {
+                    key: &key,
+                    value: &value,
+                }
+                .serialize(),
+            );
+            encoder.delete(&key).unwrap();
+            expected.extend(Request::Delete(&key).serialize());
+            encoder.flush();
+            expected.extend(Request::Flush.serialize());
+        }
+        assert_eq!(encoder.as_bytes(), expected.as_slice());
+        assert_eq!(encoder.clone().into_bytes(), expected);
+
+        encoder.clear();
+        assert!(encoder.as_bytes().is_empty());
+        encoder.set("abc", "def").unwrap().get("abc").unwrap();
+        let mut expected = Request::Set {
+            key: "abc",
+            value: "def",
+        }
+        .serialize();
+        expected.extend(Request::Get("abc").serialize());
+        assert_eq!(encoder.into_bytes(), expected);
+    }
+
+    #[test]
+    fn test_response_golden_bytes() {
+        // Part of the protocol contract, changing these bytes breaks other implementations
+        let cases: Vec<(Response, Vec<u8>)> = vec![
+            (
+                Response::Get(Some("ghi".to_string())),
+                vec![1, 0, 0, 0, 3, 103, 104, 105],
+            ),
+            (Response::Get(None), vec![1, 0, 0, 0, 0]),
+            (Response::Set, vec![2]),
+            (Response::Delete, vec![3]),
+            (Response::Flush, vec![4]),
+            (
+                Response::Error {
+                    code: ErrorCode::InvalidDatabase,
+                    message: Some("no".to_string()),
+                },
+                vec![5, 3, 0, 0, 0, 2, 110, 111],
+            ),
+            (
+                Response::Scan {
+                    cursor: 1,
+                    keys: vec!["a".to_string()],
+                },
+                vec![6, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 1, 97],
+            ),
+            (Response::Select, vec![7]),
+            (Response::FlushAll, vec![8]),
+            (
+                Response::MGet(vec![Some("1".to_string()), None]),
+                vec![9, 0, 0, 0, 2, 0, 0, 0, 1, 49, 0, 0, 0, 0],
+            ),
+            (Response::MSet, vec![10]),
+            (
+                Response::Incr(-2),
+                vec![11, 255, 255, 255, 255, 255, 255, 255, 254],
+            ),
+            (
+                Response::MExists(vec![true, false]),
+                vec![12, 0, 0, 0, 2, 1, 0],
+            ),
+            (Response::MTouch(3), vec![13, 0, 0, 0, 3]),
+            (Response::CountPrefix(3), vec![14, 0, 0, 0, 0, 0, 0, 0, 3]),
+            (Response::Cas(true), vec![15, 1]),
+            (Response::SetNx(false), vec![16, 0]),
+            (
+                Response::Info(ServerInfo {
+                    protocol_version: 1,
+                    version: "0.1.0".to_string(),
+                    uptime: Duration::from_millis(1000),
+                    features: vec!["ttl".to_string()],
+                }),
+                vec![
+                    17, 0, 0, 0, 1, 0, 0, 0, 5, 48, 46, 49, 46, 48, 0, 0, 0, 0, 0, 0, 3, 232, 0, 0,
+                    0, 1, 0, 0, 0, 3, 116, 116, 108,
+                ],
+            ),
+            (Response::DeleteIfOlderThan(true), vec![18, 1]),
+            (Response::Auth, vec![19]),
+            (Response::Pong, vec![21]),
+            (Response::Hello, vec![23]),
+            (Response::GetRange(None), vec![24, 0]),
+            (
+                Response::GetRange(Some("b".to_string())),
+                vec![24, 1, 0, 0, 0, 1, 98],
+            ),
+            (Response::SetRange(3), vec![25, 0, 0, 0, 0, 0, 0, 0, 3]),
+            (Response::StrLen(None), vec![26, 0]),
+            (Response::ReplaceAll, vec![27]),
+            (Response::Multi, vec![28]),
+            (Response::Queued, vec![30]),
+            (Response::Watch, vec![31]),
+            (
+                Response::Invalidate("a".to_string()),
+                vec![32, 0, 0, 0, 1, 97],
+            ),
+            (
+                Response::GetPrefix(vec![("a".to_string(), "1".to_string())]),
+                vec![33, 0, 0, 0, 1, 0, 0, 0, 1, 97, 0, 0, 0, 1, 49],
+            ),
+            (Response::DeleteIf(false), vec![34, 0]),
+            (
+                Response::Exec(vec![Response::Set, Response::Incr(2)]),
+                vec![29, 0, 0, 0, 2, 2, 11, 0, 0, 0, 0, 0, 0, 0, 2],
+            ),
+            (
+                Response::StrLen(Some(3)),
+                vec![26, 1, 0, 0, 0, 0, 0, 0, 0, 3],
+            ),
+            (
+                Response::GetIfModifiedSince(GetModified::Missing),
+                vec![22, 0],
+            ),
+            (
+                Response::GetIfModifiedSince(GetModified::NotModified),
+                vec![22, 1],
+            ),
+            (
+                Response::GetIfModifiedSince(GetModified::Value("v".to_string())),
+                vec![22, 2, 0, 0, 0, 1, 118],
+            ),
+            (
+                Response::GetMeta(Some((
+                    "v".to_string(),
+                    KeyMeta {
+                        ttl: Some(Duration::from_millis(1000)),
+                        size: 1,
+                    },
+                ))),
+                vec![
+                    20, 0, 0, 0, 1, 118, 1, 0, 0, 0, 0, 0, 0, 3, 232, 0, 0, 0, 0, 0, 0, 0, 1,
+                ],
+            ),
+            (
+                Response::GetMeta(None),
+                vec![
+                    20, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+                ],
+            ),
+        ];
+        for (response, expected) in cases {
+            assert_eq!(response.serialize(), expected);
+            assert_eq!(
+                parse_response(&expected).unwrap().unwrap(),
+                (response, expected.len())
+            );
+        }
+    }
+}
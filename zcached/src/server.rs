@@ -1,68 +1,336 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fmt;
 use std::io;
 use std::io::Read;
 use std::io::Write;
+use std::mem;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::net::Shutdown;
+use std::net::SocketAddr;
 use std::net::TcpListener;
+use std::net::TcpStream;
 use std::net::ToSocketAddrs;
+use std::ops::RangeInclusive;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::result;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::sync::RwLock;
+use std::sync::Weak;
 use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
 
+use socket2::Domain;
+use socket2::Socket;
+use socket2::Type;
+use tracing::debug;
 use tracing::error;
+use tracing::warn;
 
+use crate::db::Capacity;
 use crate::db::Database;
+use crate::db::EvictionPolicy;
+use crate::db::Value;
 use crate::db::DB;
+use crate::error::DatabaseError;
+use crate::error::Error;
 use crate::error::Result;
 use crate::error::ServerError;
-use crate::parse_request;
-use crate::serialize_response;
+use crate::protocol::parse_request;
+use crate::protocol::parse_request_within;
+use crate::protocol::sanitize_request_within;
+use crate::serialization::serialize_get_response;
+use crate::serialization::Serialize;
+use crate::ErrorCode;
+use crate::GetModified;
+use crate::ParsingError;
 use crate::Request;
 use crate::Response;
+use crate::ServerInfo;
+use crate::PROTOCOL_VERSION;
 
-/// A basic in-memory database server.
-pub struct Server {
+/// A basic in-memory database server serving databases of type `D`, by default [`DB`].
+pub struct Server<D = DB> {
     listener: TcpListener,
-    db: DB,
-    initial_buffer_size: InitialBufferSize,
-    // If the client requests too much data, we reject the request.
-    max_buffer_size: MaxBufferSize,
+    dbs: Vec<D>,
+    connection_config: ConnectionConfig,
+    active_connections: Arc<AtomicUsize>,
+    connections: Arc<ConnectionRegistry>,
+    accept_failures: AtomicUsize,
+    thread_stack_size: Option<usize>,
+    accept_filter: Option<AcceptFilter>,
+    stopped: AtomicBool,
+}
+
+/// Makes the databases of a [`ServerBuilder`] of new [`DB`]s configured by the builder,
+/// see [`ServerBuilder::database`].
+struct DatabaseFactory<D>(Box<dyn Fn(DB) -> D + Send + Sync>);
+
+impl<D> fmt::Debug for DatabaseFactory<D> {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        f.debug_struct("DatabaseFactory").finish_non_exhaustive()
+    }
 }
+
+/// A snapshot of an open connection, see [`Server::connections`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    /// The id of the connection, unique for the lifetime of the server.
+    pub id: u64,
+    /// The address of the client.
+    pub peer: SocketAddr,
+    /// The number of bytes read from the client so far.
+    pub bytes_read: u64,
+    /// The number of bytes written to the client so far.
+    pub bytes_written: u64,
+    /// The number of requests handled so far.
+    pub requests: u64,
+    /// When the connection was accepted.
+    pub connected_at: SystemTime,
+}
+
+/// A snapshot of the `Server`'s internal state, see [`Server::health`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Health {
+    /// Whether the listener is able to accept new connections, i.e. it is bound
+    /// and the server was not [stopped](Server::stop).
+    pub accepting: bool,
+    /// The number of connections currently being handled.
+    pub active_connections: usize,
+    /// Whether the database is usable, i.e. its lock is not poisoned.
+    pub db_healthy: bool,
+}
+
 /// A `ServerBuilder` can be used to create a `Server` with custom configuration.
+/// The address type `A` is set by [`address`](ServerBuilder::address) and the database type `D`
+/// by [`database`](ServerBuilder::database), so they never need to be named.
 #[derive(Debug)]
-pub struct ServerBuilder<A> {
+pub struct ServerBuilder<A = SocketAddr, D = DB> {
     addr: Option<A>,
+    databases: DatabaseFactory<D>,
+    listener: Option<TcpListener>,
     initial_db_size: Option<usize>,
+    num_databases: Option<usize>,
     initial_buffer_size: Option<InitialBufferSize>,
     max_buffer_size: Option<MaxBufferSize>,
+    buffer_growth: Option<Growth>,
+    shrink_buffer: bool,
+    read_batching: bool,
+    coalesce_responses: bool,
+    max_requests_per_cycle: Option<usize>,
+    dual_stack: bool,
+    slow_log_threshold: Option<Duration>,
+    limits: Limits,
+    read_timeout: Option<Duration>,
+    seed: Vec<(String, String)>,
+    sliding_ttl: Option<Duration>,
+    memory_watermarks: Option<MemoryWatermarks>,
+    admin_password: Option<String>,
+    client_ids: Option<ClientIds>,
+    background_clear: bool,
+    eviction_policy: EvictionPolicy,
+    capacity: Capacity,
+    reset_ttl_on_update: bool,
+    thread_stack_size: Option<usize>,
+    accept_filter: Option<AcceptFilter>,
+    invalid_utf8: InvalidUtf8Policy,
+    custom_handlers: CustomHandlers,
+}
+
+/// The opcodes that can be given a custom handler, see [`ServerBuilder::register_handler`].
+/// No built-in request will ever use them.
+pub const CUSTOM_OPCODES: RangeInclusive<u8> = 200..=255;
+
+/// Handles the requests with a custom opcode, see [`ServerBuilder::register_handler`].
+type CustomHandler = Box<dyn Fn(&[u8], &dyn Database) -> Vec<u8> + Send + Sync>;
+
+/// Decides by the peer address whether an accepted connection is served,
+/// see [`ServerBuilder::accept_filter`].
+struct AcceptFilter(Box<dyn Fn(&SocketAddr) -> bool + Send + Sync>);
+
+impl fmt::Debug for AcceptFilter {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        f.debug_struct("AcceptFilter").finish_non_exhaustive()
+    }
+}
+
+/// The handlers by custom opcode.
+#[derive(Default)]
+struct CustomHandlers(HashMap<u8, CustomHandler>);
+
+impl fmt::Debug for CustomHandlers {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        f.debug_set().entries(self.0.keys()).finish()
+    }
 }
 
 impl<A> Default for ServerBuilder<A> {
     fn default() -> Self {
         Self {
             addr: None,
+            databases: DatabaseFactory(Box::new(|db| db)),
+            listener: None,
             initial_db_size: None,
+            num_databases: None,
             initial_buffer_size: None,
             max_buffer_size: None,
+            buffer_growth: None,
+            shrink_buffer: false,
+            read_batching: false,
+            coalesce_responses: true,
+            max_requests_per_cycle: None,
+            dual_stack: false,
+            slow_log_threshold: None,
+            limits: Limits::default(),
+            read_timeout: None,
+            seed: Vec::new(),
+            sliding_ttl: None,
+            memory_watermarks: None,
+            admin_password: None,
+            client_ids: None,
+            background_clear: false,
+            eviction_policy: EvictionPolicy::default(),
+            capacity: Capacity::default(),
+            reset_ttl_on_update: false,
+            thread_stack_size: None,
+            accept_filter: None,
+            invalid_utf8: InvalidUtf8Policy::Reject,
+            custom_handlers: CustomHandlers::default(),
         }
     }
 }
 
-impl<A: ToSocketAddrs> ServerBuilder<A> {
+impl ServerBuilder {
     /// Creates a new `ServerBuilder`
     pub fn new() -> Self {
         Self::default()
     }
+}
 
-    /// Sets the address the `Server` listens at.
+impl<A: ToSocketAddrs, D> ServerBuilder<A, D> {
+    /// Sets the address the `Server` listens at, replacing a previously set address.
     /// The validity of `addr` is not verified here, but only when [`build`]ing the server.
     ///
     /// [`build`]: ServerBuilder::build
-    pub fn address(
+    pub fn address<B: ToSocketAddrs>(
+        self,
+        addr: B,
+    ) -> ServerBuilder<B, D> {
+        ServerBuilder {
+            addr: Some(addr),
+            databases: self.databases,
+            listener: self.listener,
+            initial_db_size: self.initial_db_size,
+            num_databases: self.num_databases,
+            initial_buffer_size: self.initial_buffer_size,
+            max_buffer_size: self.max_buffer_size,
+            buffer_growth: self.buffer_growth,
+            shrink_buffer: self.shrink_buffer,
+            read_batching: self.read_batching,
+            coalesce_responses: self.coalesce_responses,
+            max_requests_per_cycle: self.max_requests_per_cycle,
+            dual_stack: self.dual_stack,
+            slow_log_threshold: self.slow_log_threshold,
+            limits: self.limits,
+            read_timeout: self.read_timeout,
+            seed: self.seed,
+            sliding_ttl: self.sliding_ttl,
+            memory_watermarks: self.memory_watermarks,
+            admin_password: self.admin_password,
+            client_ids: self.client_ids,
+            background_clear: self.background_clear,
+            eviction_policy: self.eviction_policy,
+            capacity: self.capacity,
+            reset_ttl_on_update: self.reset_ttl_on_update,
+            thread_stack_size: self.thread_stack_size,
+            accept_filter: self.accept_filter,
+            invalid_utf8: self.invalid_utf8,
+            custom_handlers: self.custom_handlers,
+        }
+    }
+
+    /// Serves the databases `new_db` makes of new [`DB`]s instead of the [`DB`]s themselves,
+    /// e.g. a [`DB`] wrapped in decorators like [`MeteredDb`](crate::MeteredDb).
+    /// `new_db` is called once for each of the [`num_databases`] with a [`DB`] configured
+    /// by this builder, e.g. with its [`initial_db_size`] and [`capacity`].
+    ///
+    /// [`num_databases`]: ServerBuilder::num_databases
+    /// [`initial_db_size`]: ServerBuilder::initial_db_size
+    /// [`capacity`]: ServerBuilder::capacity
+    pub fn database<E>(
+        self,
+        new_db: impl Fn(DB) -> E + Send + Sync + 'static,
+    ) -> ServerBuilder<A, E> {
+        ServerBuilder {
+            addr: self.addr,
+            databases: DatabaseFactory(Box::new(new_db)),
+            listener: self.listener,
+            initial_db_size: self.initial_db_size,
+            num_databases: self.num_databases,
+            initial_buffer_size: self.initial_buffer_size,
+            max_buffer_size: self.max_buffer_size,
+            buffer_growth: self.buffer_growth,
+            shrink_buffer: self.shrink_buffer,
+            read_batching: self.read_batching,
+            coalesce_responses: self.coalesce_responses,
+            max_requests_per_cycle: self.max_requests_per_cycle,
+            dual_stack: self.dual_stack,
+            slow_log_threshold: self.slow_log_threshold,
+            limits: self.limits,
+            read_timeout: self.read_timeout,
+            seed: self.seed,
+            sliding_ttl: self.sliding_ttl,
+            memory_watermarks: self.memory_watermarks,
+            admin_password: self.admin_password,
+            client_ids: self.client_ids,
+            background_clear: self.background_clear,
+            eviction_policy: self.eviction_policy,
+            capacity: self.capacity,
+            reset_ttl_on_update: self.reset_ttl_on_update,
+            thread_stack_size: self.thread_stack_size,
+            accept_filter: self.accept_filter,
+            invalid_utf8: self.invalid_utf8,
+            custom_handlers: self.custom_handlers,
+        }
+    }
+
+    /// Sets an already bound `listener` the `Server` accepts connections from.
+    /// The `Server` does not bind to an [`address`] then but uses the `listener` directly.
+    ///
+    /// [`address`]: ServerBuilder::address
+    pub fn from_listener(
         mut self,
-        addr: A,
+        listener: TcpListener,
     ) -> Self {
-        self.addr = Some(addr);
+        self.listener = Some(listener);
         self
     }
 
-    /// Sets the initial memory allocation of the database in bytes.
+    /// Sets the number of entries every database has room for before it has to grow.
+    /// Each of them takes memory for a key and an entry upfront, so by default the databases start
+    /// without any and grow as keys are written.
     pub fn initial_db_size(
         mut self,
         initial_db_size: usize,
@@ -71,6 +339,18 @@ impl<A: ToSocketAddrs> ServerBuilder<A> {
         self
     }
 
+    /// Sets the number of logical databases a client can [`Select`] from.
+    /// There is always at least one database which is the one selected by default.
+    ///
+    /// [`Select`]: crate::Request::Select
+    pub fn num_databases(
+        mut self,
+        num_databases: usize,
+    ) -> Self {
+        self.num_databases = Some(num_databases);
+        self
+    }
+
     /// Sets the initial buffer size in bytes for every new incoming connection to the server.
     pub fn initial_buffer_size(
         mut self,
@@ -82,33 +362,380 @@ impl<A: ToSocketAddrs> ServerBuilder<A> {
 
     /// Sets the maximum buffer size in bytes for every new incoming connection to the server.
     /// If the client sends more than this number of byes, the request will be rejected.
+    /// It can be changed while the server runs with [`Server::set_max_buffer_size`].
     pub fn max_buffer_size(
         mut self,
         max_buffer_size: usize,
     ) -> Self {
-        self.max_buffer_size = Some(MaxBufferSize(max_buffer_size));
+        self.max_buffer_size = Some(MaxBufferSize::new(max_buffer_size));
+        self
+    }
+
+    /// Sets the strategy used to grow a connection's buffer when a request does not fit into it.
+    /// Defaults to [`Growth::Double`].
+    pub fn buffer_growth(
+        mut self,
+        growth: Growth,
+    ) -> Self {
+        self.buffer_growth = Some(growth);
+        self
+    }
+
+    /// Enables shrinking a connection's buffer back toward the initial buffer size
+    /// after a request that required the buffer to grow has been handled.
+    pub fn shrink_buffer(
+        mut self,
+        shrink_buffer: bool,
+    ) -> Self {
+        self.shrink_buffer = shrink_buffer;
+        self
+    }
+
+    /// Enables growing a connection's buffer whenever a read fills it completely,
+    /// so that a large pipelined batch is read in fewer, larger reads instead of
+    /// `initial_buffer_size` bytes at a time. The buffer never grows to the maximum buffer size
+    /// this way and is shrunk again after every request if [`shrink_buffer`] is enabled.
+    /// Disabled by default.
+    ///
+    /// [`shrink_buffer`]: ServerBuilder::shrink_buffer
+    pub fn read_batching(
+        mut self,
+        read_batching: bool,
+    ) -> Self {
+        self.read_batching = read_batching;
+        self
+    }
+
+    /// Enables writing the responses to all requests a client pipelined at once
+    /// instead of writing each response on its own, which saves a syscall per response.
+    /// Enabled by default.
+    pub fn coalesce_responses(
+        mut self,
+        coalesce_responses: bool,
+    ) -> Self {
+        self.coalesce_responses = coalesce_responses;
+        self
+    }
+
+    /// Sets the maximum number of buffered requests a connection handles at once.
+    /// Once a client pipelined more, the responses so far are written and the connection's thread
+    /// yields to other threads before it handles the next requests, so that a single large batch
+    /// delays neither its own responses nor other connections for long.
+    /// A maximum of `0` is treated as `1`. By default, all buffered requests are handled at once.
+    pub fn max_requests_per_cycle(
+        mut self,
+        max_requests_per_cycle: usize,
+    ) -> Self {
+        self.max_requests_per_cycle = Some(max_requests_per_cycle.max(1));
+        self
+    }
+
+    /// Sets how long the server waits for data from a client before closing the connection.
+    /// By default, the server waits indefinitely.
+    pub fn read_timeout(
+        mut self,
+        read_timeout: Duration,
+    ) -> Self {
+        self.read_timeout = Some(read_timeout);
+        self
+    }
+
+    /// Sets the maximum size in bytes of a single request.
+    /// Larger requests are answered with [`ErrorCode::FrameTooLarge`] without being applied.
+    /// Requests are only checked once fully received, so they are still bounded by [`max_buffer_size`].
+    /// This is a shorthand for setting [`Limits::max_frame_size`].
+    ///
+    /// [`max_buffer_size`]: ServerBuilder::max_buffer_size
+    pub fn max_frame_size(
+        mut self,
+        max_frame_size: usize,
+    ) -> Self {
+        self.limits.max_frame_size = Some(max_frame_size);
+        self
+    }
+
+    /// Sets the `limits` every request is validated against before being applied.
+    /// Replaces any previously set [`max_frame_size`].
+    ///
+    /// [`max_frame_size`]: ServerBuilder::max_frame_size
+    pub fn limits(
+        mut self,
+        limits: Limits,
+    ) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Logs a warning for every request whose handling, from parsing it until its response
+    /// was sent, takes longer than `threshold`.
+    /// Slow requests are not logged by default.
+    pub fn slow_log_threshold(
+        mut self,
+        threshold: Duration,
+    ) -> Self {
+        self.slow_log_threshold = Some(threshold);
+        self
+    }
+
+    /// Extends the expiry of a key to `sliding_ttl` from now every time it is read with `Get`.
+    /// Only keys that were set with an expiry are extended.
+    ///
+    /// Reading a key then requires exclusive access to the database,
+    /// so concurrent reads block each other.
+    pub fn sliding_ttl(
+        mut self,
+        sliding_ttl: Duration,
+    ) -> Self {
+        self.sliding_ttl = Some(sliding_ttl);
+        self
+    }
+
+    /// Restricts [`Flush`], [`FlushAll`] and [`ReplaceAll`] to connections that sent the `password` with [`Auth`].
+    /// Other connections are answered with [`ErrorCode::Unauthorized`].
+    /// By default, every connection can flush and replace all keys.
+    ///
+    /// [`Flush`]: Request::Flush
+    /// [`FlushAll`]: Request::FlushAll
+    /// [`ReplaceAll`]: Request::ReplaceAll
+    /// [`Auth`]: Request::Auth
+    pub fn admin_password(
+        mut self,
+        password: impl Into<String>,
+    ) -> Self {
+        self.admin_password = Some(password.into());
+        self
+    }
+
+    /// Allows only one open connection per client id presented with [`Hello`].
+    /// The `policy` decides whether a second connection presenting the same id is rejected
+    /// or takes the id over from the first one. Connections that never send a `Hello` are not affected.
+    /// By default, client ids are accepted without being tracked.
+    ///
+    /// [`Hello`]: Request::Hello
+    pub fn unique_client_ids(
+        mut self,
+        policy: ClientIdPolicy,
+    ) -> Self {
+        self.client_ids = Some(ClientIds {
+            policy,
+            active: Arc::default(),
+        });
+        self
+    }
+
+    /// Sets how keys and values that are not valid Utf8 are handled.
+    /// Defaults to [`InvalidUtf8Policy::Reject`].
+    pub fn on_invalid_utf8(
+        mut self,
+        policy: InvalidUtf8Policy,
+    ) -> Self {
+        self.invalid_utf8 = policy;
+        self
+    }
+
+    /// Rejects writes with [`ErrorCode::OutOfMemory`] once the estimated memory usage of all
+    /// databases exceeds `high` bytes, until it drops below `low` bytes again.
+    /// Reads, deletes and flushes are still served while writes are rejected.
+    /// A `low` watermark above `high` is lowered to `high`.
+    pub fn memory_watermarks(
+        mut self,
+        high: usize,
+        low: usize,
+    ) -> Self {
+        self.memory_watermarks = Some(MemoryWatermarks {
+            high,
+            low: low.min(high),
+            rejecting_writes: Arc::new(AtomicBool::new(false)),
+        });
+        self
+    }
+
+    /// Inserts all key value `pairs` into the first database when [`build`]ing the server,
+    /// so that they can be read as soon as the server runs.
+    /// Can be called multiple times, later pairs overwrite earlier ones with the same key.
+    ///
+    /// [`build`]: ServerBuilder::build
+    pub fn seed(
+        mut self,
+        pairs: impl IntoIterator<Item = (String, String)>,
+    ) -> Self {
+        self.seed.extend(pairs);
+        self
+    }
+
+    /// Makes flushing a database swap in an empty one and free the old entries on a background
+    /// thread, so that other connections are not blocked while a large database is deallocated.
+    /// See [`DB::background_clear`].
+    pub fn background_clear(
+        mut self,
+        background_clear: bool,
+    ) -> Self {
+        self.background_clear = background_clear;
+        self
+    }
+
+    /// Sets how the databases make room for writes once they reached their [`capacity`],
+    /// see [`DB::eviction`].
+    ///
+    /// [`capacity`]: ServerBuilder::capacity
+    pub fn eviction_policy(
+        mut self,
+        policy: EvictionPolicy,
+    ) -> Self {
+        self.eviction_policy = policy;
+        self
+    }
+
+    /// Sets the number of entries and the memory usage at which each database evicts entries
+    /// according to the [`eviction_policy`] for a write.
+    /// By default, the databases are unbounded.
+    ///
+    /// [`eviction_policy`]: ServerBuilder::eviction_policy
+    pub fn capacity(
+        mut self,
+        capacity: Capacity,
+    ) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Makes incrementing a key or overwriting a range of its value remove its expiry.
+    /// By default, the expiry is kept like in Redis, see [`DB::reset_ttl_on_update`].
+    pub fn reset_ttl_on_update(
+        mut self,
+        reset_ttl_on_update: bool,
+    ) -> Self {
+        self.reset_ttl_on_update = reset_ttl_on_update;
+        self
+    }
+
+    /// Sets the stack size in bytes of the threads handling connections.
+    /// Defaults to the standard library's default for spawned threads.
+    pub fn thread_stack_size(
+        mut self,
+        thread_stack_size: usize,
+    ) -> Self {
+        self.thread_stack_size = Some(thread_stack_size);
+        self
+    }
+
+    /// Sets a `filter` called with the address of every accepted client, e.g. to allow only certain IPs
+    /// or to reject clients under load. If it returns `false`, the connection is closed right away
+    /// without being handled. Connections whose peer address cannot be determined are closed as well.
+    pub fn accept_filter(
+        mut self,
+        filter: impl Fn(&SocketAddr) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.accept_filter = Some(AcceptFilter(Box::new(filter)));
+        self
+    }
+
+    /// Registers a `handler` for requests with the custom `opcode`, so that commands the built-in
+    /// requests do not cover can be added without changing the parser.
+    /// A custom request is the `opcode` followed by the length of its body as big-endian `u32`
+    /// and the body. The `handler` is called with the body and the selected database, and the bytes
+    /// it returns are sent as they are, so they should be a frame clients can parse,
+    /// e.g. a serialized [`Response`]. Registering an `opcode` again replaces its handler.
+    ///
+    /// Custom requests are only limited by the maximum buffer size, cannot be queued in transactions
+    /// and their writes are not pushed to connections watching the keys.
+    ///
+    /// # Panics
+    /// Panics if `opcode` is not one of the [`CUSTOM_OPCODES`].
+    pub fn register_handler(
+        mut self,
+        opcode: u8,
+        handler: impl Fn(&[u8], &dyn Database) -> Vec<u8> + Send + Sync + 'static,
+    ) -> Self {
+        assert!(
+            CUSTOM_OPCODES.contains(&opcode),
+            "opcode {opcode} is not in the custom range {CUSTOM_OPCODES:?}"
+        );
+        self.custom_handlers.0.insert(opcode, Box::new(handler));
+        self
+    }
+
+    /// Binds a dual-stack IPv6 socket that accepts both IPv4 and IPv6 clients.
+    /// An IPv4 `address` is bound as its IPv4-mapped IPv6 address, `0.0.0.0` becomes `[::]`.
+    /// Has no effect if a [`listener`] is used.
+    ///
+    /// [`listener`]: ServerBuilder::from_listener
+    pub fn dual_stack(
+        mut self,
+        dual_stack: bool,
+    ) -> Self {
+        self.dual_stack = dual_stack;
         self
     }
+}
 
+impl<A, D> ServerBuilder<A, D>
+where
+    A: ToSocketAddrs,
+    D: Database,
+{
     /// Starts a server from this `ServerBuilder`.
     ///
     /// # Errors
-    /// If no [`address`] was set then an error is returned.
+    /// If neither an [`address`] nor a [`listener`] was set then an error is returned.
+    /// If the server cannot bind to the specified `address` then a [`ServerError::Bind`] is returned.
     ///
     /// [`address`]: ServerBuilder::address
-    ///
-    /// # Panics
-    /// Panics if the server cannot bind to the specified `address`.
-    pub fn build(self) -> Result<Server> {
-        let Some(addr) = self.addr else {
-            return Err(ServerError::NoAddress.into());
+    /// [`listener`]: ServerBuilder::from_listener
+    pub fn build(self) -> Result<Server<D>> {
+        let listener = match (self.listener, self.addr) {
+            (Some(listener), _) => listener,
+            (None, Some(addr)) if self.dual_stack => {
+                bind_dual_stack(addr).map_err(ServerError::Bind)?
+            }
+            (None, Some(addr)) => TcpListener::bind(addr).map_err(ServerError::Bind)?,
+            (None, None) => return Err(ServerError::NoAddress.into()),
         };
-        let listener = TcpListener::bind(addr).expect("to be able to bind to address");
+        let dbs: Vec<D> = (0..self.num_databases.unwrap_or(1).max(1))
+            .map(|_| {
+                let db = DB::with_capacity(self.initial_db_size.unwrap_or(0))
+                    .background_clear(self.background_clear)
+                    .eviction(self.eviction_policy, self.capacity)
+                    .reset_ttl_on_update(self.reset_ttl_on_update);
+                (self.databases.0)(db)
+            })
+            .collect();
+        if !self.seed.is_empty() {
+            dbs[0].extend(self.seed)?;
+        }
         Ok(Server {
             listener,
-            initial_buffer_size: self.initial_buffer_size.unwrap_or_default(),
-            max_buffer_size: self.max_buffer_size.unwrap_or_default(),
-            db: DB::with_capacity(self.initial_db_size.unwrap_or(1024 * 1024)),
+            connection_config: ConnectionConfig {
+                initial_buffer_size: self.initial_buffer_size.unwrap_or_default(),
+                max_buffer_size: self.max_buffer_size.unwrap_or_default(),
+                buffer_growth: self.buffer_growth.unwrap_or_default(),
+                shrink_buffer: self.shrink_buffer,
+                read_batching: self.read_batching,
+                coalesce_responses: self.coalesce_responses,
+                max_requests_per_cycle: self.max_requests_per_cycle,
+                slow_log: self.slow_log_threshold.map(|threshold| SlowLog {
+                    threshold,
+                    hook: log_slow_request,
+                }),
+                limits: self.limits,
+                read_timeout: self.read_timeout,
+                sliding_ttl: self.sliding_ttl,
+                memory_watermarks: self.memory_watermarks,
+                admin_password: self.admin_password.map(Arc::from),
+                client_ids: self.client_ids,
+                invalid_utf8: self.invalid_utf8,
+                watches: Arc::default(),
+                transaction_locks: Arc::default(),
+                custom_handlers: Arc::new(self.custom_handlers),
+                server_started_at: Instant::now(),
+            },
+            dbs,
+            active_connections: Arc::new(AtomicUsize::new(0)),
+            connections: Arc::default(),
+            accept_failures: AtomicUsize::new(0),
+            stopped: AtomicBool::new(false),
+            thread_stack_size: self.thread_stack_size,
+            accept_filter: self.accept_filter,
         })
     }
 }
@@ -117,39 +744,175 @@ impl Server {
     /// Creates a new `Server` listening on `addr`.
     ///
     /// # Panics
-    /// Panics if it cannot bind to `addr`.
+    /// Panics if it cannot bind to `addr`, see [`try_bind`](Server::try_bind) for a non-panicking version.
     pub fn bind<A: ToSocketAddrs>(addr: A) -> Self {
-        let listener = TcpListener::bind(addr).expect("to be able to bind to address");
+        Self::try_bind(addr).expect("to be able to bind to address")
+    }
+
+    /// Creates a new `Server` listening on `addr`.
+    ///
+    /// # Errors
+    /// If it cannot bind to `addr` then a [`ServerError::Bind`] is returned.
+    pub fn try_bind<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        let listener = TcpListener::bind(addr).map_err(ServerError::Bind)?;
+        Ok(Self::from_listener(listener))
+    }
+
+    /// Creates a new `Server` accepting connections from the already bound `listener`.
+    pub fn from_listener(listener: TcpListener) -> Self {
         Self {
             listener,
-            db: DB::with_capacity(1024),
-            initial_buffer_size: InitialBufferSize::default(),
-            max_buffer_size: MaxBufferSize::default(),
+            dbs: vec![DB::with_capacity(1024)],
+            connection_config: ConnectionConfig::default(),
+            active_connections: Arc::new(AtomicUsize::new(0)),
+            connections: Arc::default(),
+            accept_failures: AtomicUsize::new(0),
+            stopped: AtomicBool::new(false),
+            thread_stack_size: None,
+            accept_filter: None,
         }
     }
 
     /// Returns a `ServerBuilder` that can be used to build a `Server`.
-    pub fn builder<A: ToSocketAddrs>() -> ServerBuilder<A> {
+    ///
+    /// ```
+    /// use std::net::TcpListener;
+    ///
+    /// use zcached::Server;
+    ///
+    /// let server = Server::builder().address("127.0.0.1:0").build().unwrap();
+    ///
+    /// // The address type does not need to be named when no address is set either
+    /// let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    /// let server = Server::builder().from_listener(listener).build().unwrap();
+    /// ```
+    pub fn builder() -> ServerBuilder {
         ServerBuilder::new()
     }
+}
+
+impl<D> Server<D> {
+    /// Stops the server: [`run`](Self::run) returns without accepting another connection
+    /// and all open connections are closed like with [`kill_connection`](Self::kill_connection).
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Release);
+        // `run` is blocked accepting, so wake it up with a connection of our own.
+        if let Ok(mut addr) = self.listener.local_addr() {
+            if addr.ip().is_unspecified() {
+                addr.set_ip(match addr {
+                    SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                    SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+                });
+            }
+            let _ = TcpStream::connect(addr);
+        }
+        for connection in self.connections.snapshot() {
+            self.connections.kill(connection.id);
+        }
+    }
+}
 
+impl<D> Server<D>
+where
+    D: Database,
+    D: Clone,
+    D: 'static,
+{
     /// Runs the server.
+    ///
+    /// If accepting a connection fails because the process or system ran out of resources
+    /// (e.g. too many open files), the server backs off before accepting again.
+    ///
+    /// Requests sent over the same connection are applied and answered strictly in the order
+    /// they were sent, also when they are pipelined without waiting for responses.
+    /// There is no ordering guarantee between requests of different connections,
+    /// except that no request of another connection interleaves with a transaction,
+    /// see [`Request::Multi`].
+    ///
+    /// Every connection is handled on its own thread named `zcached-conn-<n>`,
+    /// where `n` counts the accepted connections starting at 0.
+    ///
+    /// Returns once the server was [stopped](Self::stop).
     pub fn run(&self) {
-        for stream in self.listener.incoming() {
-            let db_clone = self.db.clone();
-            let init_buffer_size = self.initial_buffer_size;
-            let max_buffer_size = self.max_buffer_size;
-            thread::spawn(move || match stream {
-                Ok(mut stream) => {
-                    // TODO handle err
-                    let _ =
-                        handle_connection(&mut stream, db_clone, init_buffer_size, max_buffer_size);
+        let mut connection_number = 0u64;
+        accept_loop(
+            self.listener
+                .incoming()
+                .take_while(|_| !self.stopped.load(Ordering::Acquire)),
+            &self.accept_failures,
+            |mut stream| {
+                if let Some(filter) = &self.accept_filter {
+                    let peer = stream.peer_addr();
+                    if !peer.as_ref().is_ok_and(|peer| (filter.0)(peer)) {
+                        debug!("Rejected connection from {peer:?}");
+                        return;
+                    }
                 }
-                Err(e) => {
-                    error!("Could not read incoming stream: {:?}", e);
+                let dbs = self.dbs.clone();
+                let connection_config = self.connection_config.clone();
+                let socket = stream.try_clone().ok().map(Arc::new);
+                let killed = Arc::new(AtomicBool::new(false));
+                let stats = Arc::new(ConnectionStats::default());
+                let handles = ConnectionHandles {
+                    watcher: socket
+                        .as_ref()
+                        .map(|_| Arc::new(Watcher::new(Arc::clone(&stats)))),
+                    socket,
+                    killed: Some(Arc::clone(&killed)),
+                };
+                let active_connections = Arc::clone(&self.active_connections);
+                let connections = Arc::clone(&self.connections);
+                let id = connection_number;
+                let mut thread_builder =
+                    thread::Builder::new().name(format!("zcached-conn-{connection_number}"));
+                connection_number += 1;
+                if let Some(stack_size) = self.thread_stack_size {
+                    thread_builder = thread_builder.stack_size(stack_size);
                 }
-            });
-        }
+                let spawned = thread_builder.spawn(move || {
+                    let _active_connection = ActiveConnection::new(active_connections);
+                    let peer = stream.peer_addr();
+                    if let Err(e) = stream.set_read_timeout(connection_config.read_timeout) {
+                        error!("Could not set read timeout for {peer:?}: {e:?}");
+                        return;
+                    }
+                    let _registered = peer.as_ref().ok().map(|peer| {
+                        let connection = OpenConnection {
+                            peer: *peer,
+                            connected_at: SystemTime::now(),
+                            stats: Arc::clone(&stats),
+                            socket: handles.socket.clone(),
+                            killed,
+                        };
+                        RegisteredConnection::new(&connections, id, connection)
+                    });
+                    let result = handle_connection_catching_panic(
+                        &mut stream,
+                        &dbs,
+                        connection_config,
+                        handles,
+                        &stats,
+                    );
+                    debug!(
+                        "Closed connection from {peer:?} after reading {} and writing {} bytes",
+                        stats.bytes_read.load(Ordering::Relaxed),
+                        stats.bytes_written.load(Ordering::Relaxed)
+                    );
+                    // TODO handle other errors
+                    if let Err(Error::Server(ServerError::Panic(message))) = result {
+                        error!("Dropped connection from {peer:?} after panicking: {message}");
+                    }
+                });
+                if let Err(e) = spawned {
+                    error!("Could not spawn a thread to handle a connection: {e:?}");
+                }
+            },
+        );
+    }
+
+    /// Returns the number of times accepting a connection failed.
+    pub fn accept_failures(&self) -> usize {
+        self.accept_failures.load(Ordering::Relaxed)
     }
 
     /// Returns the port the server is listening on.
@@ -157,216 +920,3398 @@ impl Server {
         let addr = self.listener.local_addr().map_err(ServerError::IO)?;
         Ok(addr.port())
     }
-}
 
-#[derive(Debug, Copy, Clone)]
-struct InitialBufferSize(usize);
+    /// Returns a cheap snapshot of the server's health.
+    pub fn health(&self) -> Health {
+        // Reading the listener's pending error would clear it, so only its address is checked
+        let accepting = self.listener.local_addr().is_ok() && !self.stopped.load(Ordering::Acquire);
+        Health {
+            accepting,
+            active_connections: self.active_connections.load(Ordering::Relaxed),
+            db_healthy: self.dbs.iter().all(|db| !db.is_poisoned()),
+        }
+    }
 
-impl Default for InitialBufferSize {
-    fn default() -> Self {
-        // 4kB
-        Self(4096)
+    /// Returns a snapshot of the currently open connections, ordered by their id.
+    /// Connections are listed once their handler started and until it finished.
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        self.connections.snapshot()
     }
-}
 
-#[derive(Debug, Copy, Clone)]
-struct MaxBufferSize(usize);
+    /// Closes the open connection with the given [`id`](ConnectionInfo::id), e.g. one that misbehaves.
+    /// Its socket is shut down, so a request it is handling right now fails to send its response,
+    /// and requests it already buffered are not handled anymore.
+    /// Returns whether a connection with the id was open.
+    pub fn kill_connection(
+        &self,
+        id: u64,
+    ) -> bool {
+        self.connections.kill(id)
+    }
 
-impl Default for MaxBufferSize {
-    fn default() -> Self {
-        // 1MB
-        Self(1024 * 1024)
+    /// Returns the maximum buffer size in bytes of the server's connections.
+    pub fn max_buffer_size(&self) -> usize {
+        self.connection_config.max_buffer_size.get()
+    }
+
+    /// Changes the maximum buffer size in bytes of all connections, including the open ones,
+    /// without restarting the server, see [`ServerBuilder::max_buffer_size`].
+    /// Open connections apply it from the next request they parse on, so a lower maximum can
+    /// reject a request they are receiving right now.
+    pub fn set_max_buffer_size(
+        &self,
+        max_buffer_size: usize,
+    ) {
+        self.connection_config
+            .max_buffer_size
+            .0
+            .store(max_buffer_size, Ordering::Relaxed);
     }
 }
 
-fn handle_connection<RW, DB>(
-    stream: &mut RW,
-    db: DB,
-    initial_buffer_size: InitialBufferSize,
-    max_buffer_size: MaxBufferSize,
-) -> Result<()>
-where
-    RW: Read,
-    RW: Write,
-    RW: ?Sized,
-    DB: Database,
-{
-    let mut buffer = vec![0; initial_buffer_size.0];
-    let mut cursor = 0;
+/// Binds a listener on the IPv6 equivalent of `addr` with `IPV6_V6ONLY` disabled.
+fn bind_dual_stack<A: ToSocketAddrs>(addr: A) -> io::Result<TcpListener> {
+    let mut last_err = None;
+    for addr in addr.to_socket_addrs()? {
+        let ip = match addr.ip() {
+            IpAddr::V4(ip) if ip.is_unspecified() => Ipv6Addr::UNSPECIFIED,
+            IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+            IpAddr::V6(ip) => ip,
+        };
+        let addr = SocketAddr::new(IpAddr::V6(ip), addr.port());
+        let bind = || -> io::Result<TcpListener> {
+            let socket = Socket::new(Domain::IPV6, Type::STREAM, None)?;
+            socket.set_only_v6(false)?;
+            socket.bind(&addr.into())?;
+            socket.listen(1024)?;
+            Ok(socket.into())
+        };
+        match bind() {
+            Ok(listener) => return Ok(listener),
+            Err(e) => last_err = Some(e),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "could not resolve to any addresses",
+        )
+    }))
+}
 
-    loop {
-        if let Some((request, n_parsed_bytes)) = parse_request(&buffer[0..cursor]).unwrap() {
-            let response = match request {
-                Request::Get(key) => {
-                    let v = db.get(key)?;
-                    Response::Get(v)
-                }
-                Request::Set { key, value } => {
-                    db.insert(key.to_string(), value.to_string())?;
-                    Response::Set
-                }
-                Request::Delete(key) => {
-                    db.remove(key)?;
-                    Response::Delete
-                }
-                Request::Flush => {
-                    db.clear()?;
-                    Response::Flush
-                }
-            };
-            send_response(stream, response).map_err(ServerError::IO)?;
+/// The initial delay before accepting again after running out of resources.
+const ACCEPT_BACKOFF_INITIAL: Duration = Duration::from_millis(10);
+/// The maximum delay before accepting again after running out of resources.
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
 
-            if n_parsed_bytes <= cursor {
-                // We parsed less data than there is in the buffer.
-                // Move the remaining bytes in the buffer that were not parsed yet to the front.
-                // This way we don't have to resize the buffer more than necessary when more data is sent.
-                // Since we have a maximum buffer size, this prevents running into it for repeated sends.
-                buffer.copy_within(n_parsed_bytes..cursor, 0);
-                cursor -= n_parsed_bytes;
+/// Hands every successfully accepted stream from `incoming` to `handle`.
+/// Repeated failures due to resource exhaustion are retried with an exponential backoff
+/// instead of spinning the loop.
+pub(crate) fn accept_loop<S>(
+    incoming: impl Iterator<Item = io::Result<S>>,
+    accept_failures: &AtomicUsize,
+    mut handle: impl FnMut(S),
+) {
+    let mut backoff = ACCEPT_BACKOFF_INITIAL;
+    for stream in incoming {
+        match stream {
+            Ok(stream) => {
+                backoff = ACCEPT_BACKOFF_INITIAL;
+                handle(stream);
+            }
+            Err(e) => {
+                accept_failures.fetch_add(1, Ordering::Relaxed);
+                if is_resource_exhaustion(&e) {
+                    error!("Could not accept connection, retrying in {backoff:?}: {e:?}");
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(ACCEPT_BACKOFF_MAX);
+                } else {
+                    error!("Could not accept connection: {:?}", e);
+                }
             }
-            continue;
         }
+    }
+}
 
-        if buffer.len() >= max_buffer_size.0 {
+/// Returns whether `e` was caused by the process or system running out of resources,
+/// in which case retrying immediately would most likely fail again.
+fn is_resource_exhaustion(e: &io::Error) -> bool {
+    // ENOMEM, ENFILE and EMFILE have the same numbers on all common Unix platforms
+    const RESOURCE_EXHAUSTION_CODES: [i32; 3] = [12, 23, 24];
+    e.kind() == io::ErrorKind::OutOfMemory
+        || (cfg!(unix)
+            && e.raw_os_error()
+                .is_some_and(|code| RESOURCE_EXHAUSTION_CODES.contains(&code)))
+}
+
+/// Tracks a connection as active for as long as it is alive.
+struct ActiveConnection(Arc<AtomicUsize>);
+
+impl ActiveConnection {
+    fn new(active_connections: Arc<AtomicUsize>) -> Self {
+        active_connections.fetch_add(1, Ordering::Relaxed);
+        Self(active_connections)
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// The open connections of a server, see [`Server::connections`].
+/// The lock is only taken when a connection is opened or closed and when taking a snapshot,
+/// the traffic is counted in each connection's [`ConnectionStats`] without it.
+#[derive(Debug, Default)]
+struct ConnectionRegistry(Mutex<HashMap<u64, OpenConnection>>);
+
+#[derive(Debug)]
+struct OpenConnection {
+    peer: SocketAddr,
+    connected_at: SystemTime,
+    stats: Arc<ConnectionStats>,
+    socket: Option<Arc<TcpStream>>,
+    // Checked by the connection's handler between requests.
+    killed: Arc<AtomicBool>,
+}
+
+impl ConnectionRegistry {
+    fn snapshot(&self) -> Vec<ConnectionInfo> {
+        // The registry only holds plain data, so a poisoned lock is still consistent.
+        let connections = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        let mut snapshot: Vec<_> = connections
+            .iter()
+            .map(|(id, connection)| ConnectionInfo {
+                id: *id,
+                peer: connection.peer,
+                bytes_read: connection.stats.bytes_read.load(Ordering::Relaxed),
+                bytes_written: connection.stats.bytes_written.load(Ordering::Relaxed),
+                requests: connection.stats.requests.load(Ordering::Relaxed),
+                connected_at: connection.connected_at,
+            })
+            .collect();
+        drop(connections);
+        snapshot.sort_unstable_by_key(|connection| connection.id);
+        snapshot
+    }
+
+    /// Makes the connection with `id` stop and shuts down its socket.
+    fn kill(
+        &self,
+        id: u64,
+    ) -> bool {
+        let connections = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(connection) = connections.get(&id) else {
+            return false;
+        };
+        connection.killed.store(true, Ordering::Relaxed);
+        if let Some(socket) = &connection.socket {
+            let _ = socket.shutdown(Shutdown::Both);
+        }
+        true
+    }
+}
+
+/// Keeps a connection listed in the [`ConnectionRegistry`] until it is dropped.
+struct RegisteredConnection<'a> {
+    registry: &'a ConnectionRegistry,
+    id: u64,
+}
+
+impl<'a> RegisteredConnection<'a> {
+    fn new(
+        registry: &'a ConnectionRegistry,
+        id: u64,
+        connection: OpenConnection,
+    ) -> Self {
+        registry
+            .0
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(id, connection);
+        Self { registry, id }
+    }
+}
+
+impl Drop for RegisteredConnection<'_> {
+    fn drop(&mut self) {
+        self.registry
+            .0
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&self.id);
+    }
+}
+
+/// The strategy used to grow a connection's buffer when a request does not fit into it.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum Growth {
+    /// Doubles the buffer size.
+    #[default]
+    Double,
+    /// Grows the buffer by a fixed number of bytes.
+    Linear(usize),
+}
+
+impl Growth {
+    /// Returns the new buffer length for a buffer of length `len` that needs to grow.
+    fn next_len(
+        self,
+        len: usize,
+    ) -> usize {
+        match self {
+            // Make sure an empty buffer grows as well
+            Growth::Double => len.saturating_mul(2).max(1),
+            Growth::Linear(step) => len.saturating_add(step.max(1)),
+        }
+    }
+}
+
+/// Limits every request is validated against before being applied.
+/// A limit of `None` means unlimited, which is the default for all limits.
+/// Requests are only validated once fully received, so they are still bounded by the maximum buffer size.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// The maximum size of a key in bytes, violations are answered with [`ErrorCode::KeyTooLarge`].
+    pub max_key_size: Option<usize>,
+    /// The maximum size of a value in bytes, violations are answered with [`ErrorCode::ValueTooLarge`].
+    pub max_value_size: Option<usize>,
+    /// The maximum number of keys in a batch request, violations are answered with [`ErrorCode::TooManyKeys`].
+    pub max_batch_keys: Option<usize>,
+    /// The maximum size of a request in bytes, violations are answered with [`ErrorCode::FrameTooLarge`].
+    pub max_frame_size: Option<usize>,
+}
+
+/// The number of bytes of queued responses after which they are written even if more
+/// pipelined requests are buffered, so that many large responses are not held in memory.
+const MAX_PENDING_RESPONSE_BYTES: usize = 64 * 1024;
+
+/// The largest value a `SetRange` can write up to if no maximum value size is configured.
+const MAX_SET_RANGE_END: u64 = 512 * 1024 * 1024;
+
+/// Returns the error response for the first limit the request of `n_bytes` violates, if any.
+fn check_limits(
+    request: &Request,
+    n_bytes: usize,
+    limits: &Limits,
+) -> Option<Response> {
+    let exceeds = |limit: Option<usize>, len: usize| limit.is_some_and(|limit| len > limit);
+    if let Some(max_frame_size) = limits.max_frame_size.filter(|max| n_bytes > *max) {
+        return Some(Response::Error {
+            code: ErrorCode::FrameTooLarge,
+            message: Some(format!(
+                "request of {n_bytes} bytes exceeds the maximum of {max_frame_size} bytes"
+            )),
+        });
+    }
+    // Writing far past the end of a value would allocate all the bytes up to the write
+    if let Request::SetRange { offset, value, .. } = request {
+        let end = offset.saturating_add(value.len() as u64);
+        let max_end = limits
+            .max_value_size
+            .map_or(MAX_SET_RANGE_END, |max| max as u64);
+        if end > max_end {
+            return Some(Response::Error {
+                code: ErrorCode::ValueTooLarge,
+                message: Some(format!(
+                    "value of {end} bytes exceeds the maximum of {max_end} bytes"
+                )),
+            });
+        }
+    }
+    // Avoid collecting keys and values on the hot path if there is nothing to check
+    if limits.max_key_size.is_none()
+        && limits.max_value_size.is_none()
+        && limits.max_batch_keys.is_none()
+    {
+        return None;
+    }
+
+    let (keys, values): (Vec<&str>, Vec<&str>) = match request {
+        Request::Get(key)
+        | Request::Delete(key)
+        | Request::CountPrefix(key)
+        | Request::GetPrefix(key)
+        | Request::GetMeta(key) => (vec![key], vec![]),
+        Request::Set { key, value }
+        | Request::SetEx { key, value, .. }
+        | Request::SetNx { key, value }
+        | Request::SetRange { key, value, .. } => (vec![key], vec![value]),
+        Request::Incr { key, .. }
+        | Request::DeleteIfOlderThan { key, .. }
+        | Request::GetIfModifiedSince { key, .. }
+        | Request::GetRange { key, .. }
+        | Request::StrLen(key) => (vec![key], vec![]),
+        Request::GetTouch { key, .. } => (vec![key], vec![]),
+        Request::Cas { key, expected, new } => (vec![key], vec![expected, new]),
+        Request::DeleteIf { key, expected } => (vec![key], vec![expected]),
+        Request::MGet(keys)
+        | Request::MExists(keys)
+        | Request::MTouch { keys, .. }
+        | Request::Watch(keys) => (keys.clone(), vec![]),
+        Request::MSet(pairs) | Request::ReplaceAll(pairs) => pairs.iter().copied().unzip(),
+        Request::Flush
+        | Request::FlushAll
+        | Request::Scan { .. }
+        | Request::Select(_)
+        | Request::Info
+        | Request::Auth(_)
+        | Request::Hello(_)
+        | Request::Ping
+        | Request::Multi
+        | Request::Exec => return None,
+    };
+    let is_batch = matches!(
+        request,
+        Request::MGet(_)
+            | Request::MSet(_)
+            | Request::MExists(_)
+            | Request::MTouch { .. }
+            | Request::ReplaceAll(_)
+            | Request::Watch(_)
+    );
+    let (code, message) = if is_batch && exceeds(limits.max_batch_keys, keys.len()) {
+        (
+            ErrorCode::TooManyKeys,
+            format!("batch of {} keys exceeds the maximum", keys.len()),
+        )
+    } else if let Some(key) = keys
+        .iter()
+        .find(|key| exceeds(limits.max_key_size, key.len()))
+    {
+        (
+            ErrorCode::KeyTooLarge,
+            format!("key of {} bytes exceeds the maximum", key.len()),
+        )
+    } else if let Some(value) = values
+        .iter()
+        .find(|value| exceeds(limits.max_value_size, value.len()))
+    {
+        (
+            ErrorCode::ValueTooLarge,
+            format!("value of {} bytes exceeds the maximum", value.len()),
+        )
+    } else {
+        return None;
+    };
+    Some(Response::Error {
+        code,
+        message: Some(message),
+    })
+}
+
+/// The configuration applied to every incoming connection.
+#[derive(Debug, Clone)]
+struct ConnectionConfig {
+    initial_buffer_size: InitialBufferSize,
+    // If the client requests too much data, we reject the request.
+    max_buffer_size: MaxBufferSize,
+    buffer_growth: Growth,
+    shrink_buffer: bool,
+    read_batching: bool,
+    coalesce_responses: bool,
+    max_requests_per_cycle: Option<usize>,
+    slow_log: Option<SlowLog>,
+    limits: Limits,
+    read_timeout: Option<Duration>,
+    sliding_ttl: Option<Duration>,
+    memory_watermarks: Option<MemoryWatermarks>,
+    admin_password: Option<Arc<str>>,
+    client_ids: Option<ClientIds>,
+    invalid_utf8: InvalidUtf8Policy,
+    // Shared by all connections of a server.
+    watches: Arc<Watches>,
+    // Shared by all connections of a server, so that transactions are never interleaved.
+    transaction_locks: Arc<TransactionLocks>,
+    custom_handlers: Arc<CustomHandlers>,
+    // Used for reporting the uptime in `Request::Info`.
+    server_started_at: Instant,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            initial_buffer_size: InitialBufferSize::default(),
+            max_buffer_size: MaxBufferSize::default(),
+            buffer_growth: Growth::default(),
+            shrink_buffer: false,
+            read_batching: false,
+            coalesce_responses: true,
+            max_requests_per_cycle: None,
+            slow_log: None,
+            limits: Limits::default(),
+            read_timeout: None,
+            sliding_ttl: None,
+            memory_watermarks: None,
+            admin_password: None,
+            client_ids: None,
+            invalid_utf8: InvalidUtf8Policy::Reject,
+            watches: Arc::default(),
+            transaction_locks: Arc::default(),
+            custom_handlers: Arc::default(),
+            server_started_at: Instant::now(),
+        }
+    }
+}
+
+/// The state of a single connection that requests can change.
+#[derive(Debug, Default)]
+struct Session {
+    selected_db: usize,
+    // Whether the connection authenticated with the admin password.
+    is_admin: bool,
+    client_id: Option<ClaimedClientId>,
+    // The transaction started with `Request::Multi`, if any.
+    transaction: Option<Transaction>,
+    watched: Option<WatchedKeys>,
+    handles: ConnectionHandles,
+    // Held while a request is applied, see `TransactionLocks`.
+    transaction_lock: Arc<Mutex<()>>,
+}
+
+/// The handles through which the `Server` and other connections reach a single connection,
+/// set by `Server::run`. They are unset for a connection that is not a `TcpStream`.
+#[derive(Debug, Default)]
+struct ConnectionHandles {
+    // The connection's own socket, so that it can be evicted or killed.
+    socket: Option<Arc<TcpStream>>,
+    // See `Server::kill_connection`.
+    killed: Option<Arc<AtomicBool>>,
+    // Other connections queue their invalidations for the keys this connection watches here.
+    watcher: Option<Arc<Watcher>>,
+}
+
+/// The requests queued in a transaction, see [`Request::Multi`].
+#[derive(Debug, Default)]
+struct Transaction {
+    // The serialized requests in the order they were queued.
+    requests: Vec<u8>,
+    // Whether a request was rejected while being queued, which discards the transaction.
+    aborted: bool,
+}
+
+/// Excludes the requests of all connections while a transaction is executed, see [`Request::Exec`].
+///
+/// Every connection holds a lock of its own while applying a request, which a transaction takes
+/// all of. So requests only contend with transactions, not with the requests of other connections.
+#[derive(Debug, Default)]
+struct TransactionLocks {
+    // The locks of the connections, of which the dropped ones are removed lazily.
+    locks: Mutex<Vec<Weak<Mutex<()>>>>,
+}
+
+impl TransactionLocks {
+    /// Returns a new lock for a connection, which is taken by every transaction started after this.
+    fn register(&self) -> Arc<Mutex<()>> {
+        let lock = Arc::default();
+        let mut locks = self.locks.lock().unwrap_or_else(|e| e.into_inner());
+        // Pruned before growing only, so that registering takes amortized constant time
+        if locks.len() == locks.capacity() {
+            locks.retain(|lock| lock.strong_count() > 0);
+        }
+        locks.push(Arc::downgrade(&lock));
+        lock
+    }
+
+    /// Runs `f` while no other connection applies a request or executes a transaction.
+    /// The connection running `f` must not hold its own lock.
+    fn exclusively<T>(
+        &self,
+        f: impl FnOnce() -> T,
+    ) -> T {
+        // Held throughout, so that transactions are executed one by one
+        // and connections registering meanwhile wait for the transaction to finish.
+        let mut locks = self.locks.lock().unwrap_or_else(|e| e.into_inner());
+        locks.retain(|lock| lock.strong_count() > 0);
+        let live: Vec<Arc<Mutex<()>>> = locks.iter().filter_map(Weak::upgrade).collect();
+        let _guards: Vec<MutexGuard<'_, ()>> = live
+            .iter()
+            .map(|lock| lock.lock().unwrap_or_else(|e| e.into_inner()))
+            .collect();
+        f()
+    }
+}
+
+impl Session {
+    /// Returns whether another connection took over this connection's client id.
+    fn is_evicted(&self) -> bool {
+        self.client_id
+            .as_ref()
+            .is_some_and(|client_id| client_id.evicted.load(Ordering::Relaxed))
+    }
+}
+
+/// What happens when a connection presents a client id that another open connection already
+/// presented, see [`ServerBuilder::unique_client_ids`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ClientIdPolicy {
+    /// The new connection's `Hello` is answered with [`ErrorCode::DuplicateClientId`].
+    RejectNew,
+    /// The old connection is closed and the new connection takes over the id.
+    EvictOld,
+}
+
+/// The keys watched by the connections of a server, see [`Request::Watch`].
+#[derive(Debug, Default)]
+struct Watches {
+    // The watchers of every key by database index and key.
+    keys: RwLock<HashMap<usize, WatchersByKey>>,
+    // The number of keys watched by all connections together,
+    // so that writes do not take the lock while nothing is watched.
+    len: AtomicUsize,
+}
+
+type WatchersByKey = HashMap<String, Vec<Arc<Watcher>>>;
+
+/// The number of invalidations queued for a [`Watcher`], beyond which its connection is closed.
+const MAX_QUEUED_INVALIDATIONS: usize = 1024;
+
+/// A connection that can watch keys, see [`Request::Watch`].
+/// Other connections only queue invalidations for it, so that a slow watcher never blocks the writers.
+/// The connection writes them itself before its responses. While it waits for the next request,
+/// a flusher thread writes them instead, which sleeps until an invalidation is queued.
+#[derive(Debug)]
+struct Watcher {
+    state: Mutex<WatcherState>,
+    // Notified whenever the state changes.
+    changed: Condvar,
+    // The stats of the connection, which also count the invalidations written by the flusher.
+    stats: Arc<ConnectionStats>,
+}
+
+#[derive(Debug, Default)]
+struct WatcherState {
+    invalidations: Vec<String>,
+    // Set once an invalidation did not fit into the queue, which closes the connection.
+    overflowed: bool,
+    // Whether the connection waits for the next request, so that the flusher may write.
+    waiting: bool,
+    // Whether the flusher is writing, so that the connection must not write before it is done.
+    flushing: bool,
+    // Set once the connection stopped watching, which stops the flusher.
+    closed: bool,
+}
+
+/// The keys watched by the connection of a [`Session`], unwatched once the session ends.
+#[derive(Debug)]
+struct WatchedKeys {
+    watcher: Arc<Watcher>,
+    keys: HashSet<(usize, String)>,
+    watches: Arc<Watches>,
+}
+
+/// The keys written by a request, see [`written_keys`].
+enum Written<'a> {
+    Keys(Vec<&'a str>),
+    /// All keys of the selected database.
+    Database,
+    /// All keys of all databases.
+    All,
+}
+
+impl Watches {
+    fn is_empty(&self) -> bool {
+        self.len.load(Ordering::Relaxed) == 0
+    }
+
+    /// Queues an invalidation for every connection but the `writer` watching a key that was written
+    /// in the database with index `db`.
+    fn invalidate(
+        &self,
+        writer: Option<&Arc<Watcher>>,
+        db: usize,
+        written: Written,
+    ) {
+        let push = |key: &str, watchers: &[Arc<Watcher>]| {
+            for watcher in watchers {
+                if !writer.is_some_and(|writer| Arc::ptr_eq(writer, watcher)) {
+                    watcher.push(key.to_string());
+                }
+            }
+        };
+        let keys = self.keys.read().unwrap_or_else(|e| e.into_inner());
+        match written {
+            Written::Keys(written) => {
+                let Some(keys) = keys.get(&db) else {
+                    return;
+                };
+                for key in written {
+                    if let Some(watchers) = keys.get(key) {
+                        push(key, watchers);
+                    }
+                }
+            }
+            Written::Database => {
+                for (key, watchers) in keys.get(&db).into_iter().flatten() {
+                    push(key, watchers);
+                }
+            }
+            Written::All => {
+                for (key, watchers) in keys.values().flatten() {
+                    push(key, watchers);
+                }
+            }
+        }
+    }
+}
+
+impl Watcher {
+    fn new(stats: Arc<ConnectionStats>) -> Self {
+        Self {
+            state: Mutex::default(),
+            changed: Condvar::new(),
+            stats,
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, WatcherState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Queues an invalidation of `key`, only waiting for the queue's lock but never for the connection.
+    fn push(
+        &self,
+        key: String,
+    ) {
+        let mut state = self.state();
+        if state.invalidations.len() >= MAX_QUEUED_INVALIDATIONS {
+            state.overflowed = true;
+        } else {
+            state.invalidations.push(key);
+        }
+        self.changed.notify_all();
+    }
+
+    /// Appends the queued invalidations to the `pending` responses.
+    /// Returns `false` if invalidations were lost because the queue overflowed.
+    fn drain(
+        &self,
+        pending: &mut Vec<u8>,
+    ) -> bool {
+        Self::drain_state(&mut self.state(), pending)
+    }
+
+    fn drain_state(
+        state: &mut WatcherState,
+        pending: &mut Vec<u8>,
+    ) -> bool {
+        for key in state.invalidations.drain(..) {
+            pending.extend(Response::Invalidate(key).serialize());
+        }
+        !state.overflowed
+    }
+
+    /// Lets the flusher write the queued invalidations while the connection waits for the next request.
+    fn start_waiting(&self) {
+        self.state().waiting = true;
+        self.changed.notify_all();
+    }
+
+    /// Stops the flusher from writing once the connection received a request,
+    /// waiting for the invalidations it is writing.
+    fn stop_waiting(&self) {
+        let mut state = self.state();
+        state.waiting = false;
+        while state.flushing {
+            state = self.changed.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    /// Stops the flusher, once the connection stopped watching.
+    fn close(&self) {
+        self.state().closed = true;
+        self.changed.notify_all();
+    }
+
+    /// Spawns the flusher, which writes the queued invalidations to the `socket`
+    /// while the connection waits for the next request.
+    /// If invalidations were lost, the socket is shut down, which closes the connection.
+    fn spawn_flusher(
+        self: &Arc<Self>,
+        socket: Arc<TcpStream>,
+    ) -> io::Result<()> {
+        let watcher = Arc::clone(self);
+        thread::Builder::new()
+            .name("zcached-watcher".to_string())
+            .spawn(move || watcher.flush(&socket))?;
+        Ok(())
+    }
+
+    fn flush(
+        &self,
+        socket: &TcpStream,
+    ) {
+        let mut pending = Vec::new();
+        let mut state = self.state();
+        loop {
+            let has_work = state.overflowed || !state.invalidations.is_empty();
+            if state.closed {
+                return;
+            }
+            if !(state.waiting && has_work) {
+                state = self.changed.wait(state).unwrap_or_else(|e| e.into_inner());
+                continue;
+            }
+            if !Self::drain_state(&mut state, &mut pending) {
+                debug!("Closing a connection that did not keep up with the invalidations of its watched keys");
+                let _ = socket.shutdown(Shutdown::Both);
+                return;
+            }
+            // Written without holding the lock, so that writers can queue more meanwhile
+            state.flushing = true;
+            drop(state);
+            let written = write_pending(&mut &*socket, &mut pending, &self.stats);
+            state = self.state();
+            state.flushing = false;
+            self.changed.notify_all();
+            if written.is_err() {
+                // The connection notices the broken socket itself
+                return;
+            }
+        }
+    }
+}
+
+impl WatchedKeys {
+    fn new(
+        watcher: Arc<Watcher>,
+        watches: Arc<Watches>,
+    ) -> Self {
+        Self {
+            watcher,
+            keys: HashSet::new(),
+            watches,
+        }
+    }
+
+    /// Watches the `keys` in the database with index `db`.
+    fn watch(
+        &mut self,
+        db: usize,
+        keys: &[&str],
+    ) {
+        let mut watched = self.watches.keys.write().unwrap_or_else(|e| e.into_inner());
+        for key in keys {
+            if self.keys.insert((db, key.to_string())) {
+                let watchers = watched.entry(db).or_default().entry(key.to_string());
+                watchers.or_default().push(Arc::clone(&self.watcher));
+                self.watches.len.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+impl Drop for WatchedKeys {
+    fn drop(&mut self) {
+        self.watcher.close();
+        let mut watched = self.watches.keys.write().unwrap_or_else(|e| e.into_inner());
+        for (db, key) in &self.keys {
+            let Some(keys) = watched.get_mut(db) else {
+                continue;
+            };
+            if let Some(watchers) = keys.get_mut(key) {
+                watchers.retain(|watcher| !Arc::ptr_eq(watcher, &self.watcher));
+                if watchers.is_empty() {
+                    keys.remove(key);
+                }
+            }
+            self.watches.len.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// How the server handles keys and values that are not valid Utf8,
+/// see [`ServerBuilder::on_invalid_utf8`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum InvalidUtf8Policy {
+    /// The request is answered with [`ErrorCode::InvalidRequest`] and the connection is closed.
+    Reject,
+    /// Invalid sequences are replaced with the replacement character `U+FFFD`
+    /// and the request is handled like any other.
+    Lossy,
+}
+
+/// The client ids of the open connections, see [`ServerBuilder::unique_client_ids`].
+#[derive(Debug, Clone)]
+struct ClientIds {
+    policy: ClientIdPolicy,
+    // Shared by all connections.
+    active: Arc<Mutex<HashMap<String, ClientIdHolder>>>,
+}
+
+/// The connection currently holding a client id.
+#[derive(Debug)]
+struct ClientIdHolder {
+    evicted: Arc<AtomicBool>,
+    socket: Option<Arc<TcpStream>>,
+}
+
+/// A client id held by the connection of a [`Session`], released once the session ends.
+#[derive(Debug)]
+struct ClaimedClientId {
+    id: String,
+    evicted: Arc<AtomicBool>,
+    active: Arc<Mutex<HashMap<String, ClientIdHolder>>>,
+}
+
+impl ClientIds {
+    /// Claims `id` for the connection with `socket` according to the policy.
+    /// Returns `None` if the id is held by another connection and new connections are rejected.
+    fn claim(
+        &self,
+        id: &str,
+        socket: Option<Arc<TcpStream>>,
+    ) -> Option<ClaimedClientId> {
+        let mut active = self.active.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(holder) = active.get(id) {
+            match self.policy {
+                ClientIdPolicy::RejectNew => return None,
+                ClientIdPolicy::EvictOld => {
+                    holder.evicted.store(true, Ordering::Relaxed);
+                    // Wakes the old connection up if it is waiting for a request.
+                    if let Some(socket) = &holder.socket {
+                        let _ = socket.shutdown(Shutdown::Both);
+                    }
+                }
+            }
+        }
+        let evicted = Arc::new(AtomicBool::new(false));
+        active.insert(
+            id.to_string(),
+            ClientIdHolder {
+                evicted: Arc::clone(&evicted),
+                socket,
+            },
+        );
+        Some(ClaimedClientId {
+            id: id.to_string(),
+            evicted,
+            active: Arc::clone(&self.active),
+        })
+    }
+}
+
+impl Drop for ClaimedClientId {
+    fn drop(&mut self) {
+        let mut active = self.active.lock().unwrap_or_else(|e| e.into_inner());
+        // An evicted connection must not release the id of the connection that took it over.
+        if active
+            .get(&self.id)
+            .is_some_and(|holder| Arc::ptr_eq(&holder.evicted, &self.evicted))
+        {
+            active.remove(&self.id);
+        }
+    }
+}
+
+/// The memory usage thresholds between which writes are rejected, see [`ServerBuilder::memory_watermarks`].
+#[derive(Debug, Clone)]
+struct MemoryWatermarks {
+    high: usize,
+    low: usize,
+    // Shared by all connections so that they agree on whether writes are rejected.
+    rejecting_writes: Arc<AtomicBool>,
+}
+
+impl MemoryWatermarks {
+    /// Returns whether writes are rejected given the current memory usage of the `dbs`.
+    fn rejects_writes<DB: Database>(
+        &self,
+        dbs: &[DB],
+    ) -> Result<bool> {
+        let mut usage = 0;
+        for db in dbs {
+            usage += db.memory_usage()?;
+        }
+        let rejecting = if self.rejecting_writes.load(Ordering::Relaxed) {
+            usage >= self.low
+        } else {
+            usage > self.high
+        };
+        self.rejecting_writes.store(rejecting, Ordering::Relaxed);
+        Ok(rejecting)
+    }
+}
+
+/// The traffic of a single connection.
+/// The counters are atomic so that they can be read while the connection is handled.
+#[derive(Debug, Default)]
+struct ConnectionStats {
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    requests: AtomicU64,
+}
+
+/// Reports requests whose handling took longer than `threshold`.
+#[derive(Debug, Copy, Clone)]
+struct SlowLog {
+    threshold: Duration,
+    /// Called with the request's opcode, its key if it has a single one, and the elapsed time.
+    hook: fn(u8, Option<&str>, Duration),
+}
+
+fn log_slow_request(
+    opcode: u8,
+    key: Option<&str>,
+    elapsed: Duration,
+) {
+    warn!("Slow request with opcode {opcode} for key {key:?} took {elapsed:?}");
+}
+
+#[derive(Debug, Copy, Clone)]
+struct InitialBufferSize(usize);
+
+impl Default for InitialBufferSize {
+    fn default() -> Self {
+        // 4kB
+        Self(4096)
+    }
+}
+
+/// The maximum buffer size shared by all connections of a server, so that it can be changed
+/// while they are open.
+#[derive(Debug, Clone)]
+struct MaxBufferSize(Arc<AtomicUsize>);
+
+impl MaxBufferSize {
+    fn new(max_buffer_size: usize) -> Self {
+        Self(Arc::new(AtomicUsize::new(max_buffer_size)))
+    }
+
+    fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for MaxBufferSize {
+    fn default() -> Self {
+        // 1MB
+        Self::new(1024 * 1024)
+    }
+}
+
+/// Serves the requests of a single connection over `stream` with the default configuration
+/// until the client closes the stream.
+/// Combined with [`duplex`](crate::duplex) and [`Client::from_stream`](crate::Client::from_stream)
+/// this allows testing against a server without opening a port.
+#[cfg(feature = "test-util")]
+pub fn serve_on<RW, DB>(
+    mut stream: RW,
+    db: DB,
+) -> Result<()>
+where
+    RW: Read,
+    RW: Write,
+    DB: Database,
+{
+    handle_connection(
+        &mut stream,
+        std::slice::from_ref(&db),
+        ConnectionConfig::default(),
+        ConnectionHandles::default(),
+        &ConnectionStats::default(),
+    )
+}
+
+/// Handles the connection like [`handle_connection`] but catches a panic while doing so.
+/// A panic is returned as [`ServerError::Panic`] so that it only affects this connection.
+fn handle_connection_catching_panic<RW, DB>(
+    stream: &mut RW,
+    dbs: &[DB],
+    config: ConnectionConfig,
+    handles: ConnectionHandles,
+    stats: &ConnectionStats,
+) -> Result<()>
+where
+    RW: Read,
+    RW: Write,
+    RW: ?Sized,
+    DB: Database,
+{
+    panic::catch_unwind(AssertUnwindSafe(|| {
+        handle_connection(stream, dbs, config, handles, stats)
+    }))
+    .unwrap_or_else(|payload| {
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        Err(ServerError::Panic(message).into())
+    })
+}
+
+/// Grows the `buffer` to `new_len` zeroed bytes, failing instead of aborting if the memory
+/// cannot be allocated, e.g. for a length beyond `isize::MAX` bytes.
+fn grow_buffer(
+    buffer: &mut Vec<u8>,
+    new_len: usize,
+) -> result::Result<(), ServerError> {
+    buffer
+        .try_reserve_exact(new_len.saturating_sub(buffer.len()))
+        .map_err(|source| ServerError::BufferAllocation {
+            len: new_len,
+            source,
+        })?;
+    buffer.resize(new_len, 0);
+    Ok(())
+}
+
+/// The error sent before closing a connection whose request exceeds the `max_buffer_size`,
+/// so that the client can tell it apart from a connection lost for other reasons.
+fn too_much_data(max_buffer_size: usize) -> Response {
+    Response::Error {
+        code: ErrorCode::TooMuchData,
+        message: Some(format!(
+            "request exceeds the maximum buffer size of {max_buffer_size} bytes"
+        )),
+    }
+}
+
+/// A request with a custom opcode, see [`ServerBuilder::register_handler`].
+struct CustomFrame<'a> {
+    handler: &'a CustomHandler,
+    body: &'a [u8],
+    // The length of the whole frame.
+    len: usize,
+}
+
+/// Parses a request with a custom opcode that has a handler.
+/// Returns `None` for other opcodes and if the frame is not complete yet.
+fn parse_custom_frame<'a>(
+    input: &'a [u8],
+    handlers: &'a CustomHandlers,
+    max_buffer_size: usize,
+) -> result::Result<Option<CustomFrame<'a>>, ParsingError> {
+    let Some(handler) = input.first().and_then(|opcode| handlers.0.get(opcode)) else {
+        return Ok(None);
+    };
+    let Some(len) = input.get(1..5) else {
+        return Ok(None);
+    };
+    let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize;
+    let frame_len = len.saturating_add(5);
+    if frame_len > max_buffer_size {
+        return Err(ParsingError::FrameTooLarge);
+    }
+    Ok(input.get(5..frame_len).map(|body| CustomFrame {
+        handler,
+        body,
+        len: frame_len,
+    }))
+}
+
+/// Calls the handler of a custom request with its body and the selected database.
+fn handle_custom_request<DB: Database>(
+    frame: CustomFrame,
+    dbs: &[DB],
+    session: &Session,
+) -> Vec<u8> {
+    if session.transaction.is_some() {
+        let response = Response::Error {
+            code: ErrorCode::InvalidRequest,
+            message: Some("custom requests cannot be queued in a transaction".to_string()),
+        };
+        return response.serialize();
+    }
+    let _applying = session
+        .transaction_lock
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+    (frame.handler)(frame.body, &dbs[session.selected_db])
+}
+
+/// Handles all requests of a single connection.
+/// Every request is applied before the next request is parsed and the responses are queued
+/// in request order, which guarantees the per-connection ordering documented on [`Server::run`].
+/// The queued responses are written once no complete request is left in the buffer,
+/// or after every request if responses are not coalesced.
+/// The bytes of handled requests are only removed from the buffer before the next read,
+/// so that a batch of pipelined requests is not moved within the buffer once per request.
+/// The bytes read and written are counted in `stats`, also if handling the connection fails.
+fn handle_connection<RW, DB>(
+    stream: &mut RW,
+    dbs: &[DB],
+    config: ConnectionConfig,
+    handles: ConnectionHandles,
+    stats: &ConnectionStats,
+) -> Result<()>
+where
+    RW: Read,
+    RW: Write,
+    RW: ?Sized,
+    DB: Database,
+{
+    let mut buffer = vec![0; config.initial_buffer_size.0];
+    // The requests before `start` were handled, the bytes up to `cursor` were read.
+    let mut start = 0;
+    let mut cursor = 0;
+    let mut session = Session {
+        handles,
+        transaction_lock: config.transaction_locks.register(),
+        ..Default::default()
+    };
+    let mut pending = Vec::new();
+    // The number of requests handled since the last read or yield.
+    let mut handled = 0;
+
+    loop {
+        // The maximum can be changed while the connection is open, see `Server::set_max_buffer_size`.
+        let max_buffer_size = config.max_buffer_size.get();
+        // Another connection took over the client id or the connection was killed,
+        // so this one is closed.
+        let is_killed = session
+            .handles
+            .killed
+            .as_ref()
+            .is_some_and(|killed| killed.load(Ordering::Relaxed));
+        if session.is_evicted() || is_killed {
+            return Ok(());
+        }
+        if config
+            .max_requests_per_cycle
+            .is_some_and(|max_requests| handled >= max_requests)
+        {
+            write_pending(stream, &mut pending, stats).map_err(ServerError::IO)?;
+            thread::yield_now();
+            handled = 0;
+        }
+        // The parser does not know custom opcodes, so their frames are handled first.
+        match parse_custom_frame(
+            &buffer[start..cursor],
+            &config.custom_handlers,
+            max_buffer_size,
+        ) {
+            Ok(Some(frame)) => {
+                let n_parsed_bytes = frame.len;
+                pending.extend(handle_custom_request(frame, dbs, &session));
+                stats.requests.fetch_add(1, Ordering::Relaxed);
+                handled += 1;
+                if !config.coalesce_responses || pending.len() >= MAX_PENDING_RESPONSE_BYTES {
+                    write_pending(stream, &mut pending, stats).map_err(ServerError::IO)?;
+                }
+                start += n_parsed_bytes;
+                continue;
+            }
+            Ok(None) => {}
+            Err(_) => {
+                pending.extend(too_much_data(max_buffer_size).serialize());
+                write_pending(stream, &mut pending, stats).map_err(ServerError::IO)?;
+                return Err(ServerError::TooMuchData.into());
+            }
+        }
+        let mut sanitized = Vec::new();
+        let parsed = match parse_request_within(&buffer[start..cursor], max_buffer_size) {
+            Err(ParsingError::Utf8Error(_)) if config.invalid_utf8 == InvalidUtf8Policy::Lossy => {
+                parse_request_lossy(&buffer[start..cursor], max_buffer_size, &mut sanitized)
+            }
+            parsed => parsed,
+        };
+        let parsed = match parsed {
+            Ok(parsed) => parsed,
+            // The request can never fit into the buffer, so we don't read any more of it.
+            Err(ParsingError::FrameTooLarge) => {
+                pending.extend(too_much_data(max_buffer_size).serialize());
+                write_pending(stream, &mut pending, stats).map_err(ServerError::IO)?;
+                return Err(ServerError::TooMuchData.into());
+            }
+            Err(e) => {
+                // We cannot tell where the invalid request ends, so we close the connection.
+                let response = Response::Error {
+                    code: ErrorCode::InvalidRequest,
+                    message: Some(e.to_string()),
+                };
+                pending.extend(response.serialize());
+                write_pending(stream, &mut pending, stats).map_err(ServerError::IO)?;
+                return Err(e.into());
+            }
+        };
+        if let Some((request, n_parsed_bytes)) = parsed {
+            let started_at = config.slow_log.map(|_| Instant::now());
+            let (opcode, key) = (request.opcode(), request.key());
+            let response = match check_limits(&request, n_parsed_bytes, &config.limits) {
+                // The frame was fully consumed, so the connection stays usable.
+                Some(violation) => {
+                    if let Some(transaction) = &mut session.transaction {
+                        transaction.aborted = true;
+                    }
+                    violation.serialize()
+                }
+                None => match handle_request(request, dbs, &mut session, &config) {
+                    Ok(response) => response,
+                    Err(e) => match recoverable_error_code(&e) {
+                        // The request failed because of the stored value, the connection stays usable.
+                        Some(code) => Response::Error {
+                            code,
+                            message: Some(e.to_string()),
+                        }
+                        .serialize(),
+                        None => {
+                            let response = Response::Error {
+                                code: ErrorCode::Internal,
+                                message: None,
+                            };
+                            pending.extend(response.serialize());
+                            write_pending(stream, &mut pending, stats).map_err(ServerError::IO)?;
+                            return Err(e);
+                        }
+                    },
+                },
+            };
+            pending.extend(response);
+            stats.requests.fetch_add(1, Ordering::Relaxed);
+            handled += 1;
+            if !config.coalesce_responses || pending.len() >= MAX_PENDING_RESPONSE_BYTES {
+                write_pending(stream, &mut pending, stats).map_err(ServerError::IO)?;
+            }
+            if let (Some(slow_log), Some(started_at)) = (config.slow_log, started_at) {
+                let elapsed = started_at.elapsed();
+                if elapsed > slow_log.threshold {
+                    (slow_log.hook)(opcode, key, elapsed);
+                }
+            }
+
+            start += n_parsed_bytes;
+            continue;
+        }
+        // Invalidations queued by other connections are written before waiting for the next request.
+        if !drain_invalidations(&session, &mut pending) {
+            return Ok(());
+        }
+        // All complete requests were handled, so the client waits for their responses.
+        write_pending(stream, &mut pending, stats).map_err(ServerError::IO)?;
+
+        cursor = consume_parsed_bytes(&mut buffer, cursor, start)?;
+        start = 0;
+        handled = 0;
+        if config.shrink_buffer {
+            shrink_buffer(&mut buffer, cursor, config.initial_buffer_size);
+        }
+
+        if buffer.len() >= max_buffer_size {
+            // Nothing is pending anymore, so the error is the only response left to write.
+            pending.extend(too_much_data(max_buffer_size).serialize());
+            write_pending(stream, &mut pending, stats).map_err(ServerError::IO)?;
             return Err(ServerError::TooMuchData.into());
         }
 
-        if buffer.len() == cursor {
-            buffer.resize(buffer.capacity() * 2, 0);
-        }
+        if buffer.len() == cursor {
+            // The last growth lands exactly at the maximum instead of overshooting it
+            let new_len = config
+                .buffer_growth
+                .next_len(buffer.len())
+                .min(max_buffer_size);
+            if let Err(e) = grow_buffer(&mut buffer, new_len) {
+                pending.extend(
+                    Response::Error {
+                        code: ErrorCode::OutOfMemory,
+                        message: Some(e.to_string()),
+                    }
+                    .serialize(),
+                );
+                write_pending(stream, &mut pending, stats).map_err(ServerError::IO)?;
+                return Err(e.into());
+            }
+        }
+
+        // Handle the case where there is still a frame in the buffer
+        let read_end = buffer.len();
+        let watcher = session.watched.as_ref().map(|watched| &watched.watcher);
+        if let Some(watcher) = watcher {
+            watcher.start_waiting();
+        }
+        let read = stream.read(&mut buffer[cursor..read_end]);
+        if let Some(watcher) = watcher {
+            watcher.stop_waiting();
+        }
+        let n_bytes_read = match read {
+            Ok(n_bytes_read) => n_bytes_read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            // A read timeout is reported as one of these depending on the platform
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                // An idle connection without a pending request is closed cleanly.
+                if cursor == 0 {
+                    return Ok(());
+                }
+                // A client stalling in the middle of a request is not waited for any longer.
+                return Err(ServerError::Timeout.into());
+            }
+            Err(e) => return Err(ServerError::IO(e).into()),
+        };
+        if n_bytes_read == 0 {
+            // The client closed the connection.
+            // All requests were consumed if the buffer is empty, so this is a clean close.
+            if cursor == 0 {
+                return Ok(());
+            }
+            // Otherwise the connection was closed in the middle of a frame.
+            return Err(ServerError::ConnectionResetByPeer.into());
+        }
+        cursor += n_bytes_read;
+        stats
+            .bytes_read
+            .fetch_add(n_bytes_read as u64, Ordering::Relaxed);
+        // Invalidations queued while waiting are answered before the requests that were just read.
+        if !drain_invalidations(&session, &mut pending) {
+            return Ok(());
+        }
+        // The read filled the buffer, so the client likely sent more that is waiting to be read.
+        // The buffer stays below the maximum, which is reserved for rejecting too large requests.
+        if config.read_batching && cursor == read_end {
+            let new_len = config
+                .buffer_growth
+                .next_len(read_end)
+                .min(max_buffer_size.saturating_sub(1));
+            // Growing is only an optimization here, so a failed allocation keeps the buffer as it is
+            if new_len > read_end {
+                let _ = grow_buffer(&mut buffer, new_len);
+            }
+        }
+    }
+}
+
+/// Queues the `request` if a transaction was started and applies it otherwise.
+/// The queued requests are limited to the maximum buffer size together, beyond which
+/// the transaction is aborted.
+/// Returns the serialized response to be sent to the client.
+fn handle_request<DB: Database>(
+    request: Request,
+    dbs: &[DB],
+    session: &mut Session,
+    config: &ConnectionConfig,
+) -> Result<Vec<u8>> {
+    let Some(transaction) = &mut session.transaction else {
+        // Cloned, as the session is borrowed mutably while the lock is held
+        let transaction_lock = Arc::clone(&session.transaction_lock);
+        let _applying = transaction_lock.lock().unwrap_or_else(|e| e.into_inner());
+        return match request {
+            // The value is serialized from the shared string, so a hot key's value is not copied per get.
+            Request::Get(key) if config.sliding_ttl.is_none() => {
+                let value = dbs[session.selected_db].get_arc(key)?;
+                Ok(serialize_get_response(value.as_deref())?)
+            }
+            request => Ok(apply_request(request, dbs, session, config)?.serialize()),
+        };
+    };
+    let response = match request {
+        Request::Multi => Ok(Response::Error {
+            code: ErrorCode::InvalidRequest,
+            message: Some("transactions cannot be nested".to_string()),
+        }),
+        Request::Exec => {
+            let transaction = mem::take(transaction);
+            session.transaction = None;
+            exec_transaction(transaction, dbs, session, config)
+        }
+        // Nothing of an aborted transaction is applied, so its requests are not kept.
+        _ if transaction.aborted => Ok(Response::Queued),
+        request => {
+            let bytes = request.serialize();
+            let max_buffer_size = config.max_buffer_size.get();
+            if transaction.requests.len() + bytes.len() > max_buffer_size {
+                transaction.aborted = true;
+                transaction.requests = Vec::new();
+                Ok(Response::Error {
+                    code: ErrorCode::TransactionAborted,
+                    message: Some(format!(
+                        "the queued requests exceed the maximum buffer size of {max_buffer_size} bytes"
+                    )),
+                })
+            } else {
+                transaction.requests.extend(bytes);
+                Ok(Response::Queued)
+            }
+        }
+    };
+    Ok(response?.serialize())
+}
+
+/// Applies the requests queued in the `transaction` in order while holding the transaction locks
+/// of all connections, so that no request of another connection interleaves with them.
+fn exec_transaction<DB: Database>(
+    transaction: Transaction,
+    dbs: &[DB],
+    session: &mut Session,
+    config: &ConnectionConfig,
+) -> Result<Response> {
+    if transaction.aborted {
+        return Ok(Response::Error {
+            code: ErrorCode::TransactionAborted,
+            message: Some("a queued request was rejected".to_string()),
+        });
+    }
+    config.transaction_locks.exclusively(|| {
+        let mut responses = Vec::new();
+        let mut requests = transaction.requests.as_slice();
+        while let Some((request, n_parsed_bytes)) = parse_request(requests)? {
+            let response = match apply_request(request, dbs, session, config) {
+                Ok(response) => response,
+                Err(e) => match recoverable_error_code(&e) {
+                    // A failed request does not roll back the requests applied before it.
+                    Some(code) => Response::Error {
+                        code,
+                        message: Some(e.to_string()),
+                    },
+                    None => return Err(e),
+                },
+            };
+            responses.push(response);
+            requests = &requests[n_parsed_bytes..];
+        }
+        Ok(Response::Exec(responses))
+    })
+}
+
+/// Applies the `request` to the selected database and returns the response to be sent to the client.
+fn apply_request<DB: Database>(
+    request: Request,
+    dbs: &[DB],
+    session: &mut Session,
+    config: &ConnectionConfig,
+) -> Result<Response> {
+    // Replacing all keys discards the database's contents just like flushing it
+    let is_flush = matches!(
+        request,
+        Request::Flush | Request::FlushAll | Request::ReplaceAll(_)
+    );
+    if is_flush && config.admin_password.is_some() && !session.is_admin {
+        return Ok(Response::Error {
+            code: ErrorCode::Unauthorized,
+            message: Some("flushing requires admin privileges".to_string()),
+        });
+    }
+    let is_write = matches!(
+        request,
+        Request::Set { .. }
+            | Request::SetEx { .. }
+            | Request::MSet(_)
+            | Request::ReplaceAll(_)
+            | Request::Incr { .. }
+            | Request::Cas { .. }
+            | Request::SetNx { .. }
+            | Request::SetRange { .. }
+    );
+    if let Some(watermarks) = config.memory_watermarks.as_ref().filter(|_| is_write) {
+        if watermarks.rejects_writes(dbs)? {
+            return Ok(Response::Error {
+                code: ErrorCode::OutOfMemory,
+                message: Some("memory usage is above the high watermark".to_string()),
+            });
+        }
+    }
+    // Collecting the written keys is skipped on the hot path if nothing is watched
+    let written = if config.watches.is_empty() {
+        None
+    } else {
+        written_keys(&request)
+    };
+    let db = &dbs[session.selected_db];
+    let response = match request {
+        Request::Get(key) => {
+            let v = match config.sliding_ttl {
+                Some(ttl) => db.get_and_touch(key, ttl)?,
+                None => db.get(key)?,
+            };
+            let v = v.map(value_to_string).transpose()?;
+            Response::Get(v)
+        }
+        Request::Set { key, value } => {
+            db.insert_str(key, value)?;
+            Response::Set
+        }
+        Request::Delete(key) => {
+            db.remove(key)?;
+            Response::Delete
+        }
+        Request::Flush => {
+            db.clear()?;
+            Response::Flush
+        }
+        Request::SetEx { key, value, ttl_ms } => {
+            db.insert_with_ttl(key.to_string(), value.into(), Duration::from_millis(ttl_ms))?;
+            Response::Set
+        }
+        Request::GetTouch { key, ttl_ms } => {
+            let v = db.get_touch(key, Duration::from_millis(ttl_ms))?;
+            Response::Get(v.map(value_to_string).transpose()?)
+        }
+        Request::Scan { cursor, count } => {
+            let (cursor, keys) = db.scan(cursor as usize, count as usize)?;
+            Response::Scan {
+                cursor: cursor as u64,
+                keys,
+            }
+        }
+        Request::Select(index) => match usize::try_from(index) {
+            Ok(index) if index < dbs.len() => {
+                session.selected_db = index;
+                Response::Select
+            }
+            _ => Response::Error {
+                code: ErrorCode::InvalidDatabase,
+                message: Some(format!("database index must be lower than {}", dbs.len())),
+            },
+        },
+        Request::FlushAll => {
+            for db in dbs {
+                db.clear()?;
+            }
+            Response::FlushAll
+        }
+        Request::MGet(keys) => {
+            let mut values = Vec::with_capacity(keys.len());
+            let mut conversion_error = None;
+            db.get_many_ref(
+                &keys,
+                &mut |value| match value.map(String::try_from).transpose() {
+                    Ok(value) => values.push(value),
+                    Err(e) => {
+                        conversion_error.get_or_insert(e);
+                    }
+                },
+            )?;
+            if let Some(e) = conversion_error {
+                return Err(ServerError::Database(e).into());
+            }
+            Response::MGet(values)
+        }
+        Request::MSet(pairs) => {
+            db.extend(
+                pairs
+                    .into_iter()
+                    .map(|(key, value)| (key.to_string(), value.to_string())),
+            )?;
+            Response::MSet
+        }
+        Request::ReplaceAll(pairs) => {
+            db.replace_all(
+                pairs
+                    .into_iter()
+                    .map(|(key, value)| (key.to_string(), value.to_string())),
+            )?;
+            Response::ReplaceAll
+        }
+        Request::Incr { key, delta } => {
+            let value = db.incr(key, delta)?;
+            Response::Incr(value)
+        }
+        Request::MExists(keys) => {
+            let exists = db.contains_many(&keys)?;
+            Response::MExists(exists)
+        }
+        Request::MTouch { keys, ttl_ms } => {
+            let touched = db.touch_many(&keys, Duration::from_millis(ttl_ms))?;
+            // At most `u32::MAX` keys can be sent in a single request
+            Response::MTouch(touched as u32)
+        }
+        Request::CountPrefix(prefix) => {
+            let count = db.count_prefix(prefix)?;
+            Response::CountPrefix(count as u64)
+        }
+        Request::GetPrefix(prefix) => {
+            let pairs = db
+                .get_prefix(prefix)?
+                .into_iter()
+                .map(|(key, value)| Ok((key, String::try_from(value)?)))
+                .collect::<std::result::Result<_, DatabaseError>>()
+                .map_err(ServerError::Database)?;
+            Response::GetPrefix(pairs)
+        }
+        Request::Cas { key, expected, new } => {
+            let swapped = db.compare_and_swap(key, &expected.into(), new.into())?;
+            Response::Cas(swapped)
+        }
+        Request::SetNx { key, value } => {
+            let set = db.insert_if_absent(key.to_string(), value.into())?;
+            Response::SetNx(set)
+        }
+        Request::Info => Response::Info(ServerInfo {
+            protocol_version: PROTOCOL_VERSION,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            uptime: config.server_started_at.elapsed(),
+            features: server_features(config.admin_password.is_some()),
+        }),
+        Request::DeleteIfOlderThan { key, cutoff_ms } => {
+            let cutoff = UNIX_EPOCH + Duration::from_millis(cutoff_ms);
+            let deleted = db.delete_if_older_than(key, cutoff)?;
+            Response::DeleteIfOlderThan(deleted)
+        }
+        Request::DeleteIf { key, expected } => {
+            let deleted = db.remove_if(key, &expected.into())?;
+            Response::DeleteIf(deleted)
+        }
+        Request::GetMeta(key) => {
+            let value = db
+                .get_with_meta(key)?
+                .map(|(value, meta)| value_to_string(value).map(|value| (value, meta)))
+                .transpose()?;
+            Response::GetMeta(value)
+        }
+        Request::Ping => Response::Pong,
+        Request::GetIfModifiedSince { key, since_ms } => {
+            let since = UNIX_EPOCH + Duration::from_millis(since_ms);
+            let modified = match db.get_if_modified_since(key, since)? {
+                GetModified::Value(value) => GetModified::Value(value_to_string(value)?),
+                GetModified::NotModified => GetModified::NotModified,
+                GetModified::Missing => GetModified::Missing,
+            };
+            Response::GetIfModifiedSince(modified)
+        }
+        Request::GetRange { key, start, end } => {
+            let range = db.get_range(key, start, end)?;
+            Response::GetRange(range.map(value_to_string).transpose()?)
+        }
+        Request::StrLen(key) => Response::StrLen(db.value_len(key)?.map(|len| len as u64)),
+        Request::SetRange { key, offset, value } => {
+            // `check_limits` keeps the offset far below `usize::MAX`
+            let offset = usize::try_from(offset)
+                .map_err(|_| ServerError::Database(DatabaseError::IntegerOverflow))?;
+            let len = db.set_range(key, offset, value)?;
+            Response::SetRange(len as u64)
+        }
+        Request::Hello(client_id) => match &config.client_ids {
+            None => Response::Hello,
+            Some(_)
+                if session
+                    .client_id
+                    .as_ref()
+                    .is_some_and(|claimed| claimed.id == client_id) =>
+            {
+                Response::Hello
+            }
+            Some(client_ids) => {
+                // A connection holds at most one id, so the previous one is released first.
+                session.client_id = None;
+                match client_ids.claim(client_id, session.handles.socket.clone()) {
+                    Some(claimed) => {
+                        session.client_id = Some(claimed);
+                        Response::Hello
+                    }
+                    None => Response::Error {
+                        code: ErrorCode::DuplicateClientId,
+                        message: Some(format!("client id {client_id} is already connected")),
+                    },
+                }
+            }
+        },
+        Request::Multi => {
+            session.transaction = Some(Transaction::default());
+            Response::Multi
+        }
+        Request::Exec => Response::Error {
+            code: ErrorCode::InvalidRequest,
+            message: Some("no transaction was started".to_string()),
+        },
+        Request::Watch(keys) => match &session.handles.watcher {
+            Some(watcher) => {
+                if let (None, Some(socket)) = (&session.watched, &session.handles.socket) {
+                    watcher
+                        .spawn_flusher(Arc::clone(socket))
+                        .map_err(ServerError::IO)?;
+                }
+                let selected_db = session.selected_db;
+                session
+                    .watched
+                    .get_or_insert_with(|| {
+                        WatchedKeys::new(Arc::clone(watcher), Arc::clone(&config.watches))
+                    })
+                    .watch(selected_db, &keys);
+                Response::Watch
+            }
+            None => Response::Error {
+                code: ErrorCode::InvalidRequest,
+                message: Some("watching keys requires a TCP connection".to_string()),
+            },
+        },
+        Request::Auth(password) => match &config.admin_password {
+            Some(admin_password) if is_admin_password(admin_password, password) => {
+                session.is_admin = true;
+                Response::Auth
+            }
+            _ => Response::Error {
+                code: ErrorCode::Unauthorized,
+                message: Some("invalid password".to_string()),
+            },
+        },
+    };
+    if let Some(written) = written.filter(|_| !matches!(response, Response::Error { .. })) {
+        config.watches.invalidate(
+            session.handles.watcher.as_ref(),
+            session.selected_db,
+            written,
+        );
+    }
+    Ok(response)
+}
+
+/// Returns the keys written by the `request`, whose watchers are sent an invalidation.
+fn written_keys<'a>(request: &Request<'a>) -> Option<Written<'a>> {
+    match request {
+        Request::Set { key, .. }
+        | Request::SetEx { key, .. }
+        | Request::Delete(key)
+        | Request::Incr { key, .. }
+        | Request::Cas { key, .. }
+        | Request::SetNx { key, .. }
+        | Request::DeleteIfOlderThan { key, .. }
+        | Request::DeleteIf { key, .. }
+        | Request::SetRange { key, .. } => Some(Written::Keys(vec![key])),
+        Request::MSet(pairs) => Some(Written::Keys(pairs.iter().map(|(key, _)| *key).collect())),
+        Request::Flush | Request::ReplaceAll(_) => Some(Written::Database),
+        Request::FlushAll => Some(Written::All),
+        _ => None,
+    }
+}
+
+/// Returns the optional features supported by this server.
+fn server_features(requires_auth: bool) -> Vec<String> {
+    let mut features = vec!["ttl".to_string()];
+    if cfg!(feature = "http") {
+        features.push("http".to_string());
+    }
+    if requires_auth {
+        features.push("auth".to_string());
+    }
+    features
+}
+
+/// Compares `password` with the `admin_password` in time independent of where they differ,
+/// so that the admin password cannot be guessed byte by byte from the response time.
+fn is_admin_password(
+    admin_password: &str,
+    password: &str,
+) -> bool {
+    let differences = admin_password
+        .bytes()
+        .zip(password.bytes())
+        .fold(0, |differences, (a, b)| differences | (a ^ b));
+    admin_password.len() == password.len() && differences == 0
+}
+
+/// Converts a stored `value` into a string that can be sent to the client.
+fn value_to_string(value: Value) -> Result<String> {
+    String::try_from(value).map_err(|e| ServerError::Database(e).into())
+}
+
+/// Parses a request whose keys or values are not valid Utf8 with the invalid sequences replaced.
+/// The request is borrowed from `sanitized`, but the returned number of bytes refers to `input`.
+fn parse_request_lossy<'a>(
+    input: &[u8],
+    max_len: usize,
+    sanitized: &'a mut Vec<u8>,
+) -> result::Result<Option<(Request<'a>, usize)>, ParsingError> {
+    let Some((request, n_parsed_bytes)) = sanitize_request_within(input, max_len)? else {
+        return Ok(None);
+    };
+    *sanitized = request;
+    Ok(parse_request(sanitized)?.map(|(request, _)| (request, n_parsed_bytes)))
+}
+
+/// Returns the error code to respond with if the error was caused by the stored value
+/// rather than by the server itself.
+fn recoverable_error_code(e: &Error) -> Option<ErrorCode> {
+    match e {
+        Error::Server(ServerError::Database(DatabaseError::NotAnInteger)) => {
+            Some(ErrorCode::NotAnInteger)
+        }
+        Error::Server(ServerError::Database(DatabaseError::IntegerOverflow)) => {
+            Some(ErrorCode::IntegerOverflow)
+        }
+        Error::Server(ServerError::Database(DatabaseError::NotUtf8)) => Some(ErrorCode::WrongType),
+        Error::Server(ServerError::Database(DatabaseError::Full)) => Some(ErrorCode::OutOfMemory),
+        _ => None,
+    }
+}
+
+/// Removes the `n_parsed_bytes` of a handled request from the front of the buffer
+/// and returns the new cursor.
+///
+/// The parser can never consume more bytes than were read, so `n_parsed_bytes > cursor`
+/// is a parser bug. It is reported as an error rather than continuing with stale bytes.
+fn consume_parsed_bytes(
+    buffer: &mut [u8],
+    cursor: usize,
+    n_parsed_bytes: usize,
+) -> Result<usize> {
+    if n_parsed_bytes > cursor {
+        return Err(ParsingError::Other.into());
+    }
+    // Move the remaining bytes in the buffer that were not parsed yet to the front.
+    // This way we don't have to resize the buffer more than necessary when more data is sent.
+    // Since we have a maximum buffer size, this prevents running into it for repeated sends.
+    buffer.copy_within(n_parsed_bytes..cursor, 0);
+    Ok(cursor - n_parsed_bytes)
+}
+
+/// Shrinks the buffer back toward the initial buffer size if it grew beyond it.
+/// The buffer never gets shrunk below `cursor` so that bytes which were not parsed yet are kept.
+fn shrink_buffer(
+    buffer: &mut Vec<u8>,
+    cursor: usize,
+    initial_buffer_size: InitialBufferSize,
+) {
+    let target_len = initial_buffer_size.0.max(cursor);
+    if buffer.len() > target_len {
+        buffer.truncate(target_len);
+        buffer.shrink_to_fit();
+    }
+}
+
+/// Appends the invalidations queued for the keys the `session` watches to the `pending` responses.
+/// Returns `false` if invalidations were lost, then the connection is closed so that the client
+/// does not keep values it was not told about.
+fn drain_invalidations(
+    session: &Session,
+    pending: &mut Vec<u8>,
+) -> bool {
+    let Some(watched) = &session.watched else {
+        return true;
+    };
+    let drained = watched.watcher.drain(pending);
+    if !drained {
+        debug!(
+            "Closing a connection that did not keep up with the invalidations of its watched keys"
+        );
+    }
+    drained
+}
+
+/// Writes the queued responses in `pending` at once and clears it.
+fn write_pending<W: Write + ?Sized>(
+    stream: &mut W,
+    pending: &mut Vec<u8>,
+    stats: &ConnectionStats,
+) -> io::Result<()> {
+    if pending.is_empty() {
+        return Ok(());
+    }
+    stream.write_all(pending)?;
+    stream.flush()?;
+    stats
+        .bytes_written
+        .fetch_add(pending.len() as u64, Ordering::Relaxed);
+    pending.clear();
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::VecDeque;
+    use std::io::Cursor;
+    use std::mem;
+    use std::slice;
+    use std::time::SystemTime;
+
+    use super::*;
+    use crate::error::Error;
+    use crate::parse_response;
+    use crate::server::InitialBufferSize;
+    use crate::server::MaxBufferSize;
+    use crate::Client;
+    use crate::KeyMeta;
+
+    const INITIAL_BUFFER_SIZE: usize = 32;
+    const MAX_BUFFER_SIZE: usize = 93;
+
+    fn test_config() -> ConnectionConfig {
+        ConnectionConfig {
+            initial_buffer_size: InitialBufferSize(INITIAL_BUFFER_SIZE),
+            max_buffer_size: MaxBufferSize::new(MAX_BUFFER_SIZE),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_read_request_single_request_in_stream() {
+        let db = DB::new();
+        let raw_data = vec![2, 0, 0, 0, 3, 97, 98, 99, 0, 0, 0, 3, 103, 104, 105];
+        assert!(raw_data.len() < INITIAL_BUFFER_SIZE);
+        assert!(raw_data.len() < 2 * MAX_BUFFER_SIZE);
+        let mut stream = Cursor::new(raw_data);
+        let _ = handle_connection(
+            &mut stream,
+            slice::from_ref(&db),
+            test_config(),
+            ConnectionHandles::default(),
+            &ConnectionStats::default(),
+        );
+        assert_eq!(db.read().unwrap().get("abc").unwrap().value(), "ghi");
+    }
+
+    #[test]
+    fn test_read_request_multiple_requests_in_stream() {
+        let db = DB::new();
+        // Two concatenated requests
+        let raw_data = vec![
+            2, 0, 0, 0, 3, 97, 98, 99, 0, 0, 0, 3, 103, 104, 105, 2, 0, 0, 0, 3, 49, 50, 51, 0, 0,
+            0, 3, 52, 53, 54,
+        ];
+        assert!(raw_data.len() < INITIAL_BUFFER_SIZE);
+        assert!(raw_data.len() < 2 * MAX_BUFFER_SIZE);
+        let mut stream = Cursor::new(raw_data);
+        let _ = handle_connection(
+            &mut stream,
+            slice::from_ref(&db),
+            test_config(),
+            ConnectionHandles::default(),
+            &ConnectionStats::default(),
+        );
+        assert_eq!(db.read().unwrap().get("abc").unwrap().value(), "ghi");
+        assert_eq!(db.read().unwrap().get("123").unwrap().value(), "456");
+    }
+
+    #[test]
+    fn test_close_on_frame_boundary_is_clean() {
+        let db = DB::new();
+        let raw_data = vec![2, 0, 0, 0, 3, 97, 98, 99, 0, 0, 0, 3, 103, 104, 105, 4];
+        let mut stream = Cursor::new(raw_data);
+        assert!(handle_connection(
+            &mut stream,
+            slice::from_ref(&db),
+            test_config(),
+            ConnectionHandles::default(),
+            &ConnectionStats::default()
+        )
+        .is_ok());
+        assert!(db.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_close_in_length_prefix_is_reset_by_peer() {
+        let db = DB::new();
+        // A complete request followed by a truncated length prefix
+        let raw_data = vec![
+            2, 0, 0, 0, 3, 97, 98, 99, 0, 0, 0, 3, 103, 104, 105, 2, 0, 0,
+        ];
+        let mut stream = Cursor::new(raw_data);
+        let result = handle_connection(
+            &mut stream,
+            slice::from_ref(&db),
+            test_config(),
+            ConnectionHandles::default(),
+            &ConnectionStats::default(),
+        );
+        assert!(matches!(
+            result,
+            Err(Error::Server(ServerError::ConnectionResetByPeer))
+        ));
+        assert_eq!(db.read().unwrap().get("abc").unwrap().value(), "ghi");
+    }
+
+    #[test]
+    fn test_invalid_utf8_is_replaced_when_lossy() {
+        let db = DB::new();
+        // A Set request with an invalid Utf8 key and value, followed by a valid Get request
+        let mut raw_data = vec![2, 0, 0, 0, 3, 97, 0xFF, 99, 0, 0, 0, 2, 0xC3, 0x28];
+        raw_data.extend(Request::Get("a\u{FFFD}c").serialize());
+        let n_request_bytes = raw_data.len();
+        let mut stream = Cursor::new(raw_data);
+        let config = ConnectionConfig {
+            invalid_utf8: InvalidUtf8Policy::Lossy,
+            ..test_config()
+        };
+        handle_connection(
+            &mut stream,
+            slice::from_ref(&db),
+            config,
+            ConnectionHandles::default(),
+            &ConnectionStats::default(),
+        )
+        .unwrap();
+
+        let written = &stream.get_ref()[n_request_bytes..];
+        let (response, n_parsed_bytes) = parse_response(written).unwrap().unwrap();
+        assert_eq!(response, Response::Set);
+        let (response, _) = parse_response(&written[n_parsed_bytes..]).unwrap().unwrap();
+        assert_eq!(response, Response::Get(Some("\u{FFFD}(".to_string())));
+    }
+
+    /// A stream returning one of `chunks` per read and recording every flushed write.
+    #[derive(Default)]
+    struct ChunkedStream {
+        chunks: VecDeque<Vec<u8>>,
+        unflushed: Vec<u8>,
+        writes: Vec<Vec<u8>>,
+        reads: usize,
+    }
+
+    impl Read for ChunkedStream {
+        fn read(
+            &mut self,
+            buf: &mut [u8],
+        ) -> io::Result<usize> {
+            self.reads += 1;
+            let Some(chunk) = self.chunks.front_mut() else {
+                return Ok(0);
+            };
+            let n = chunk.len().min(buf.len());
+            buf[..n].copy_from_slice(&chunk[..n]);
+            chunk.drain(..n);
+            if chunk.is_empty() {
+                self.chunks.pop_front();
+            }
+            Ok(n)
+        }
+    }
+
+    impl Write for ChunkedStream {
+        fn write(
+            &mut self,
+            buf: &[u8],
+        ) -> io::Result<usize> {
+            self.unflushed.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            if !self.unflushed.is_empty() {
+                self.writes.push(mem::take(&mut self.unflushed));
+            }
+            Ok(())
+        }
+    }
+
+    /// Parses all responses in `bytes`.
+    fn parse_responses(mut bytes: &[u8]) -> Vec<Response> {
+        let mut responses = Vec::new();
+        while let Some((response, n_parsed_bytes)) = parse_response(bytes).unwrap() {
+            responses.push(response);
+            bytes = &bytes[n_parsed_bytes..];
+        }
+        assert!(bytes.is_empty());
+        responses
+    }
+
+    /// The number of GETs [`pipelined_gets`] sends.
+    const PIPELINED_GETS: usize = 1000;
+
+    /// Returns a database holding only `key-7` and [`PIPELINED_GETS`] serialized GETs of
+    /// `key-0`, `key-1` and so on, checked with [`assert_pipelined_get_responses`].
+    fn pipelined_gets() -> (DB, Vec<u8>) {
+        let db = DB::new();
+        db.insert("key-7".to_string(), "7".into()).unwrap();
+        let requests = (0..PIPELINED_GETS)
+            .flat_map(|i| Request::Get(&format!("key-{i}")).serialize())
+            .collect();
+        (db, requests)
+    }
+
+    /// Asserts that `writes` answer the requests of [`pipelined_gets`] in order.
+    fn assert_pipelined_get_responses(writes: &[Vec<u8>]) {
+        let responses = parse_responses(&writes.concat());
+        assert_eq!(responses.len(), PIPELINED_GETS);
+        for (i, response) in responses.into_iter().enumerate() {
+            let expected = (i == 7).then(|| "7".to_string());
+            assert_eq!(response, Response::Get(expected));
+        }
+    }
+
+    #[test]
+    fn test_read_batching_reads_a_pipelined_batch_in_fewer_reads() {
+        let (db, requests) = pipelined_gets();
+
+        let mut reads = Vec::new();
+        for read_batching in [false, true] {
+            let mut stream = ChunkedStream {
+                chunks: VecDeque::from([requests.clone()]),
+                ..Default::default()
+            };
+            let config = ConnectionConfig {
+                initial_buffer_size: InitialBufferSize(64),
+                max_buffer_size: MaxBufferSize::new(4096),
+                read_batching,
+                ..Default::default()
+            };
+            handle_connection(
+                &mut stream,
+                slice::from_ref(&db),
+                config,
+                ConnectionHandles::default(),
+                &ConnectionStats::default(),
+            )
+            .unwrap();
+
+            assert_pipelined_get_responses(&stream.writes);
+            reads.push(stream.reads);
+        }
+        assert!(reads[1] * 10 < reads[0], "{reads:?}");
+    }
+
+    #[test]
+    fn test_max_requests_per_cycle_writes_responses_in_between() {
+        let (db, requests) = pipelined_gets();
+        let mut stream = ChunkedStream {
+            chunks: VecDeque::from([requests.clone()]),
+            ..Default::default()
+        };
+        // Read all requests at once
+        let config = ConnectionConfig {
+            initial_buffer_size: InitialBufferSize(requests.len()),
+            max_requests_per_cycle: Some(64),
+            ..Default::default()
+        };
+        handle_connection(
+            &mut stream,
+            slice::from_ref(&db),
+            config,
+            ConnectionHandles::default(),
+            &ConnectionStats::default(),
+        )
+        .unwrap();
+
+        assert_eq!(stream.writes.len(), PIPELINED_GETS.div_ceil(64));
+        assert_pipelined_get_responses(&stream.writes);
+    }
+
+    #[test]
+    fn test_pipelined_responses_are_coalesced() {
+        let (db, requests) = pipelined_gets();
+        // Read all requests at once
+        let config = ConnectionConfig {
+            initial_buffer_size: InitialBufferSize(requests.len()),
+            ..Default::default()
+        };
+
+        for coalesce_responses in [true, false] {
+            let mut stream = ChunkedStream {
+                chunks: VecDeque::from([requests.clone()]),
+                ..Default::default()
+            };
+            let config = ConnectionConfig {
+                coalesce_responses,
+                ..config.clone()
+            };
+            handle_connection(
+                &mut stream,
+                slice::from_ref(&db),
+                config,
+                ConnectionHandles::default(),
+                &ConnectionStats::default(),
+            )
+            .unwrap();
+
+            let expected_writes = if coalesce_responses {
+                1
+            } else {
+                PIPELINED_GETS
+            };
+            assert_eq!(stream.writes.len(), expected_writes);
+            assert_pipelined_get_responses(&stream.writes);
+        }
+    }
+
+    #[test]
+    fn test_partial_trailing_request_is_answered_after_the_next_read() {
+        let db = DB::new();
+        let mut requests = Request::Set {
+            key: "abc",
+            value: "ghi",
+        }
+        .serialize();
+        requests.extend(Request::Get("abc").serialize());
+        let trailing = Request::Get("abc").serialize();
+        requests.extend(&trailing[..3]);
+        let mut stream = ChunkedStream {
+            chunks: VecDeque::from([requests, trailing[3..].to_vec()]),
+            ..Default::default()
+        };
+        handle_connection(
+            &mut stream,
+            slice::from_ref(&db),
+            ConnectionConfig::default(),
+            ConnectionHandles::default(),
+            &ConnectionStats::default(),
+        )
+        .unwrap();
+
+        // The complete requests are answered before the rest of the trailing one is read
+        assert_eq!(stream.writes.len(), 2);
+        assert_eq!(
+            parse_responses(&stream.writes[0]),
+            vec![Response::Set, Response::Get(Some("ghi".to_string()))]
+        );
+        assert_eq!(
+            parse_responses(&stream.writes[1]),
+            vec![Response::Get(Some("ghi".to_string()))]
+        );
+    }
+
+    /// A stream that returns `WouldBlock` once all of its data was read, like a timed out socket.
+    struct StallingStream {
+        data: Cursor<Vec<u8>>,
+        written: Vec<u8>,
+    }
+
+    impl Read for StallingStream {
+        fn read(
+            &mut self,
+            buf: &mut [u8],
+        ) -> io::Result<usize> {
+            match self.data.read(buf)? {
+                0 => Err(io::ErrorKind::WouldBlock.into()),
+                n => Ok(n),
+            }
+        }
+    }
+
+    impl Write for StallingStream {
+        fn write(
+            &mut self,
+            buf: &[u8],
+        ) -> io::Result<usize> {
+            self.written.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_idle_timeout_closes_cleanly() {
+        let db = DB::new();
+        let mut stream = StallingStream {
+            data: Cursor::new(vec![2, 0, 0, 0, 3, 97, 98, 99, 0, 0, 0, 3, 103, 104, 105]),
+            written: Vec::new(),
+        };
+        assert!(handle_connection(
+            &mut stream,
+            slice::from_ref(&db),
+            test_config(),
+            ConnectionHandles::default(),
+            &ConnectionStats::default()
+        )
+        .is_ok());
+        assert_eq!(stream.written, [2]);
+        assert_eq!(db.read().unwrap().get("abc").unwrap().value(), "ghi");
+    }
+
+    #[test]
+    fn test_stats_count_bytes_read_and_written() {
+        let db = DB::new();
+        let requests = [
+            Request::Set {
+                key: "abc",
+                value: "ghi",
+            },
+            Request::Get("abc"),
+            Request::Get("missing"),
+        ];
+        let responses = [
+            Response::Set,
+            Response::Get(Some("ghi".to_string())),
+            Response::Get(None),
+        ];
+        let mut stream = StallingStream {
+            data: Cursor::new(requests.iter().flat_map(Request::serialize).collect()),
+            written: Vec::new(),
+        };
+        let stats = ConnectionStats::default();
+        handle_connection(
+            &mut stream,
+            slice::from_ref(&db),
+            test_config(),
+            ConnectionHandles::default(),
+            &stats,
+        )
+        .unwrap();
+
+        let request_bytes: usize = requests.iter().map(|r| r.serialize().len()).sum();
+        let response_bytes: usize = responses.iter().map(|r| r.serialize().len()).sum();
+        assert_eq!(
+            stats.bytes_read.load(Ordering::Relaxed),
+            request_bytes as u64
+        );
+        assert_eq!(
+            stats.bytes_written.load(Ordering::Relaxed),
+            response_bytes as u64
+        );
+        assert_eq!(stream.written.len(), response_bytes);
+    }
+
+    #[test]
+    fn test_timeout_mid_frame_is_an_error() {
+        let db = DB::new();
+        let mut stream = StallingStream {
+            data: Cursor::new(vec![2, 0, 0, 0, 3, 97, 98]),
+            written: Vec::new(),
+        };
+        let result = handle_connection(
+            &mut stream,
+            slice::from_ref(&db),
+            test_config(),
+            ConnectionHandles::default(),
+            &ConnectionStats::default(),
+        );
+        assert!(matches!(result, Err(Error::Server(ServerError::Timeout))));
+        assert!(stream.written.is_empty());
+        assert!(db.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_read_single_request_larger_than_initial_buffer() {
+        let db = DB::new();
+        // Two concatenated requests
+        let raw_data = vec![
+            2, 0, 0, 0, 3, 49, 50, 51, 0, 0, 0, 67, 84, 104, 105, 115, 32, 105, 115, 32, 115, 111,
+            109, 101, 32, 108, 111, 110, 103, 101, 114, 32, 116, 101, 120, 116, 32, 116, 104, 97,
+            116, 32, 100, 105, 100, 32, 110, 111, 116, 32, 102, 105, 116, 32, 105, 110, 116, 111,
+            32, 97, 32, 115, 105, 110, 103, 108, 101, 32, 84, 67, 80, 32, 114, 101, 113, 117, 101,
+            115, 116,
+        ];
+        assert!(raw_data.len() > INITIAL_BUFFER_SIZE);
+        assert!(raw_data.len() < 2 * MAX_BUFFER_SIZE);
+        let mut stream = Cursor::new(raw_data);
+        let _ = handle_connection(
+            &mut stream,
+            slice::from_ref(&db),
+            test_config(),
+            ConnectionHandles::default(),
+            &ConnectionStats::default(),
+        );
+        assert_eq!(
+            db.read().unwrap().get("123").unwrap().value(),
+            "This is some longer text that did not fit into a single TCP request"
+        );
+    }
+
+    #[test]
+    fn test_max_buffer_resize_is_respected() {
+        let db = DB::new();
+        // Two concatenated requests
+        let raw_data = vec![
+            2, 0, 0, 0, 3, 49, 50, 51, 0, 0, 0, 186, 84, 104, 105, 115, 32, 105, 115, 32, 115, 111,
+            109, 101, 32, 108, 111, 110, 103, 101, 114, 32, 116, 101, 120, 116, 32, 116, 104, 97,
+            116, 32, 100, 105, 100, 32, 110, 111, 116, 32, 102, 105, 116, 32, 105, 110, 116, 111,
+            32, 97, 32, 115, 105, 110, 103, 108, 101, 32, 84, 67, 80, 32, 114, 101, 113, 117, 101,
+            115, 116, 46, 32, 84, 104, 105, 115, 32, 105, 115, 32, 97, 110, 32, 101, 118, 101, 110,
+            32, 108, 111, 110, 103, 101, 114, 32, 116, 101, 120, 116, 32, 102, 111, 114, 32, 116,
+            101, 115, 116, 105, 110, 103, 32, 116, 104, 101, 32, 114, 101, 115, 105, 122, 105, 110,
+            103, 32, 111, 102, 32, 116, 104, 101, 32, 98, 117, 102, 102, 101, 114, 46, 32, 84, 104,
+            101, 114, 101, 32, 105, 115, 32, 101, 118, 101, 110, 32, 109, 111, 114, 101, 32, 100,
+            97, 116, 97, 32, 105, 110, 32, 104, 101, 114, 101, 32, 110, 111, 119, 46, 32, 76, 111,
+            111, 107, 32, 97, 116, 32, 116, 104, 97, 116,
+        ];
+        assert!(raw_data.len() > INITIAL_BUFFER_SIZE);
+        assert!(
+            raw_data.len() > 2 * MAX_BUFFER_SIZE,
+            "data len is {}",
+            raw_data.len()
+        );
+        let mut stream = Cursor::new(raw_data);
+        assert!(matches!(
+            handle_connection(
+                &mut stream,
+                &[db],
+                test_config(),
+                ConnectionHandles::default(),
+                &ConnectionStats::default()
+            )
+            .err(),
+            Some(Error::Server(ServerError::TooMuchData))
+        ));
+    }
+
+    #[test]
+    fn test_element_that_can_never_fit_is_rejected_immediately() {
+        let db = DB::new();
+        // A `Get` whose key claims to be larger than the maximum buffer size
+        let mut raw_data = vec![1];
+        raw_data.extend((MAX_BUFFER_SIZE as u32).to_be_bytes());
+        raw_data.extend(b"abc");
+        let mut stream = Cursor::new(raw_data);
+        let stats = ConnectionStats::default();
+        assert!(matches!(
+            handle_connection(
+                &mut stream,
+                &[db],
+                test_config(),
+                ConnectionHandles::default(),
+                &stats
+            )
+            .err(),
+            Some(Error::Server(ServerError::TooMuchData))
+        ));
+        // The length prefix was rejected right after the first read without growing the buffer.
+        assert_eq!(stats.bytes_read.load(Ordering::Relaxed), 8);
+        // The client is told why the connection is closed.
+        let response = too_much_data(MAX_BUFFER_SIZE).serialize();
+        assert_eq!(&stream.get_ref()[8..], response);
+        assert_eq!(
+            stats.bytes_written.load(Ordering::Relaxed),
+            response.len() as u64
+        );
+    }
+
+    #[test]
+    fn test_buffer_grows_exactly_to_the_max_buffer_size() {
+        let db = DB::new();
+        // A `MGet` of many short keys, which only exceeds the maximum buffer size as a whole
+        let keys = vec!["a"; MAX_BUFFER_SIZE];
+        let raw_data = Request::MGet(keys).serialize();
+        let mut stream = Cursor::new(raw_data);
+        let stats = ConnectionStats::default();
+        assert!(matches!(
+            handle_connection(
+                &mut stream,
+                &[db],
+                test_config(),
+                ConnectionHandles::default(),
+                &stats
+            )
+            .err(),
+            Some(Error::Server(ServerError::TooMuchData))
+        ));
+        // The maximum is not a power of two, so doubling from the initial size would have grown
+        // the buffer past it, and more bytes would have been read before rejecting the request.
+        assert_eq!(
+            stats.bytes_read.load(Ordering::Relaxed),
+            MAX_BUFFER_SIZE as u64
+        );
+    }
+
+    #[test]
+    fn test_growing_the_buffer_beyond_what_can_be_allocated_fails() {
+        let mut buffer = vec![0; 4];
+        assert!(matches!(
+            grow_buffer(&mut buffer, usize::MAX),
+            Err(ServerError::BufferAllocation {
+                len: usize::MAX,
+                ..
+            })
+        ));
+        assert_eq!(buffer.len(), 4);
+        grow_buffer(&mut buffer, 8).unwrap();
+        assert_eq!(buffer, [0; 8]);
+    }
+
+    #[test]
+    fn test_custom_frames_are_only_parsed_for_registered_opcodes() {
+        let mut handlers = CustomHandlers::default();
+        handlers.0.insert(
+            200,
+            Box::new(|body: &[u8], _: &dyn Database| body.to_vec()) as CustomHandler,
+        );
+        let frame = [200, 0, 0, 0, 2, 1, 2, 3];
+        let Ok(Some(parsed)) = parse_custom_frame(&frame, &handlers, 64) else {
+            panic!("expected a complete custom frame");
+        };
+        assert_eq!((parsed.body, parsed.len), (&[1, 2][..], 7));
+        assert_eq!((parsed.handler)(parsed.body, &DB::new()), [1, 2]);
+
+        assert!(matches!(
+            parse_custom_frame(&frame[..6], &handlers, 64),
+            Ok(None)
+        ));
+        assert!(matches!(
+            parse_custom_frame(&[201, 0, 0, 0, 0], &handlers, 64),
+            Ok(None)
+        ));
+        assert!(matches!(
+            parse_custom_frame(&frame, &handlers, 6),
+            Err(ParsingError::FrameTooLarge)
+        ));
+    }
+
+    #[test]
+    fn test_double_growth() {
+        assert_eq!(Growth::Double.next_len(0), 1);
+        assert_eq!(Growth::Double.next_len(32), 64);
+        assert_eq!(Growth::Double.next_len(usize::MAX), usize::MAX);
+    }
+
+    #[test]
+    fn test_linear_growth() {
+        assert_eq!(Growth::Linear(10).next_len(32), 42);
+        assert_eq!(Growth::Linear(0).next_len(32), 33);
+        assert_eq!(Growth::Linear(10).next_len(usize::MAX), usize::MAX);
+    }
+
+    #[test]
+    fn test_read_single_request_larger_than_initial_buffer_with_linear_growth() {
+        let db = DB::new();
+        let value = "This is some longer text that did not fit into a single TCP request";
+        let mut raw_data = vec![2, 0, 0, 0, 3, 49, 50, 51];
+        raw_data.extend((value.len() as u32).to_be_bytes());
+        raw_data.extend(value.as_bytes());
+        assert!(raw_data.len() > INITIAL_BUFFER_SIZE);
+        assert!(raw_data.len() < MAX_BUFFER_SIZE);
+        let mut stream = Cursor::new(raw_data);
+        let config = ConnectionConfig {
+            buffer_growth: Growth::Linear(7),
+            ..test_config()
+        };
+        handle_connection(
+            &mut stream,
+            slice::from_ref(&db),
+            config,
+            ConnectionHandles::default(),
+            &ConnectionStats::default(),
+        )
+        .unwrap();
+        assert_eq!(db.read().unwrap().get("123").unwrap().value(), value);
+    }
+
+    #[test]
+    fn test_max_buffer_resize_is_respected_with_linear_growth() {
+        let db = DB::new();
+        let value = "a".repeat(MAX_BUFFER_SIZE);
+        let mut raw_data = vec![2, 0, 0, 0, 3, 49, 50, 51];
+        raw_data.extend((value.len() as u32).to_be_bytes());
+        raw_data.extend(value.as_bytes());
+        let mut stream = Cursor::new(raw_data);
+        let config = ConnectionConfig {
+            buffer_growth: Growth::Linear(7),
+            ..test_config()
+        };
+        assert!(matches!(
+            handle_connection(
+                &mut stream,
+                &[db],
+                config,
+                ConnectionHandles::default(),
+                &ConnectionStats::default()
+            )
+            .err(),
+            Some(Error::Server(ServerError::TooMuchData))
+        ));
+    }
+
+    #[test]
+    fn test_consume_parsed_bytes_moves_unparsed_bytes_to_front() {
+        let mut buffer = vec![1, 2, 3, 4, 5, 0, 0];
+        assert_eq!(consume_parsed_bytes(&mut buffer, 5, 2).unwrap(), 3);
+        assert_eq!(buffer[..3], [3, 4, 5]);
+        assert_eq!(consume_parsed_bytes(&mut buffer, 3, 3).unwrap(), 0);
+    }
 
-        // Handle the case where there is still a frame in the buffer
-        let read_end = buffer.capacity();
-        let n_bytes_read = stream.read(&mut buffer[cursor..read_end]).unwrap();
-        if n_bytes_read == 0 {
-            return if cursor == 0 {
-                Ok(())
-            } else {
-                Err(ServerError::ConnectionResetByPeer.into())
-            };
-        } else {
-            cursor += n_bytes_read;
-        }
+    #[test]
+    fn test_consume_more_than_read_is_an_error() {
+        // A parser claiming to have consumed more bytes than were read
+        let mut buffer = vec![1, 2, 3, 0];
+        assert!(matches!(
+            consume_parsed_bytes(&mut buffer, 3, 4),
+            Err(Error::Parsing(ParsingError::Other))
+        ));
+        assert_eq!(buffer, [1, 2, 3, 0]);
     }
-}
 
-fn send_response<W: Write + ?Sized>(
-    stream: &mut W,
-    response: Response,
-) -> io::Result<()> {
-    let bytes = serialize_response(response);
-    stream.write_all(&bytes)?;
-    stream.flush()
-}
+    #[test]
+    fn test_shrink_buffer_after_large_request() {
+        let mut buffer = vec![0; 4 * INITIAL_BUFFER_SIZE];
+        shrink_buffer(&mut buffer, 0, InitialBufferSize(INITIAL_BUFFER_SIZE));
+        assert_eq!(buffer.len(), INITIAL_BUFFER_SIZE);
+    }
 
-#[cfg(test)]
-mod test {
-    use std::io::Cursor;
+    #[test]
+    fn test_shrink_buffer_keeps_unparsed_bytes() {
+        let mut buffer: Vec<u8> = (0..4 * INITIAL_BUFFER_SIZE as u8).collect();
+        let cursor = 2 * INITIAL_BUFFER_SIZE;
+        shrink_buffer(&mut buffer, cursor, InitialBufferSize(INITIAL_BUFFER_SIZE));
+        assert_eq!(buffer.len(), cursor);
+        assert_eq!(
+            buffer,
+            (0..cursor as u8).collect::<Vec<_>>(),
+            "unparsed bytes must be kept"
+        );
+    }
 
-    use super::*;
-    use crate::error::Error;
-    use crate::server::InitialBufferSize;
-    use crate::server::MaxBufferSize;
+    #[test]
+    fn test_shrink_buffer_does_not_grow_small_buffer() {
+        let mut buffer = vec![0; INITIAL_BUFFER_SIZE / 2];
+        shrink_buffer(&mut buffer, 0, InitialBufferSize(INITIAL_BUFFER_SIZE));
+        assert_eq!(buffer.len(), INITIAL_BUFFER_SIZE / 2);
+    }
 
-    const INITIAL_BUFFER_SIZE: usize = 32;
-    const MAX_BUFFER_SIZE: usize = 93;
+    #[test]
+    fn test_requests_are_handled_with_buffer_shrinking() {
+        let db = DB::new();
+        let value = "This is some longer text that did not fit into a single TCP request";
+        let mut raw_data = vec![2, 0, 0, 0, 3, 49, 50, 51];
+        raw_data.extend((value.len() as u32).to_be_bytes());
+        raw_data.extend(value.as_bytes());
+        // A small request following the large one
+        raw_data.extend([2, 0, 0, 0, 3, 97, 98, 99, 0, 0, 0, 3, 103, 104, 105]);
+        // Unlike a `Cursor`, the responses do not overwrite the bytes not read yet
+        let mut stream = ChunkedStream {
+            chunks: VecDeque::from([raw_data]),
+            ..ChunkedStream::default()
+        };
+        let config = ConnectionConfig {
+            shrink_buffer: true,
+            ..test_config()
+        };
+        handle_connection(
+            &mut stream,
+            slice::from_ref(&db),
+            config,
+            ConnectionHandles::default(),
+            &ConnectionStats::default(),
+        )
+        .unwrap();
+        assert_eq!(db.read().unwrap().get("123").unwrap().value(), value);
+        assert_eq!(db.read().unwrap().get("abc").unwrap().value(), "ghi");
+    }
 
     #[test]
-    fn test_read_request_single_request_in_stream() {
+    fn test_frame_at_max_frame_size_is_applied() {
         let db = DB::new();
+        // A Set request of 15 bytes
         let raw_data = vec![2, 0, 0, 0, 3, 97, 98, 99, 0, 0, 0, 3, 103, 104, 105];
-        assert!(raw_data.len() < INITIAL_BUFFER_SIZE);
-        assert!(raw_data.len() < 2 * MAX_BUFFER_SIZE);
+        let n_request_bytes = raw_data.len();
         let mut stream = Cursor::new(raw_data);
-        let _ = handle_connection(
+        let config = ConnectionConfig {
+            limits: Limits {
+                max_frame_size: Some(n_request_bytes),
+                ..Default::default()
+            },
+            ..test_config()
+        };
+        handle_connection(
             &mut stream,
-            db.clone(),
-            InitialBufferSize(INITIAL_BUFFER_SIZE),
-            MaxBufferSize(MAX_BUFFER_SIZE),
-        );
-        assert_eq!(db.read().unwrap().get("abc").unwrap(), "ghi");
+            slice::from_ref(&db),
+            config,
+            ConnectionHandles::default(),
+            &ConnectionStats::default(),
+        )
+        .unwrap();
+        assert_eq!(&stream.get_ref()[n_request_bytes..], [2]);
+        assert_eq!(db.read().unwrap().get("abc").unwrap().value(), "ghi");
     }
 
     #[test]
-    fn test_read_request_multiple_requests_in_stream() {
+    fn test_frame_over_max_frame_size_is_rejected() {
         let db = DB::new();
-        // Two concatenated requests
+        // A Set request of 15 bytes followed by a Get request
         let raw_data = vec![
-            2, 0, 0, 0, 3, 97, 98, 99, 0, 0, 0, 3, 103, 104, 105, 2, 0, 0, 0, 3, 49, 50, 51, 0, 0,
-            0, 3, 52, 53, 54,
+            2, 0, 0, 0, 3, 97, 98, 99, 0, 0, 0, 3, 103, 104, 105, 1, 0, 0, 0, 3, 97, 98, 99,
         ];
-        assert!(raw_data.len() < INITIAL_BUFFER_SIZE);
-        assert!(raw_data.len() < 2 * MAX_BUFFER_SIZE);
+        let n_request_bytes = raw_data.len();
         let mut stream = Cursor::new(raw_data);
-        let _ = handle_connection(
+        let config = ConnectionConfig {
+            limits: Limits {
+                max_frame_size: Some(14),
+                ..Default::default()
+            },
+            ..test_config()
+        };
+        handle_connection(
             &mut stream,
-            db.clone(),
-            InitialBufferSize(INITIAL_BUFFER_SIZE),
-            MaxBufferSize(MAX_BUFFER_SIZE),
-        );
-        assert_eq!(db.read().unwrap().get("abc").unwrap(), "ghi");
-        assert_eq!(db.read().unwrap().get("123").unwrap(), "456");
+            slice::from_ref(&db),
+            config,
+            ConnectionHandles::default(),
+            &ConnectionStats::default(),
+        )
+        .unwrap();
+        assert!(db.read().unwrap().is_empty());
+        let written = &stream.get_ref()[n_request_bytes..];
+        let (response, n_parsed_bytes) = parse_response(written).unwrap().unwrap();
+        assert!(matches!(
+            response,
+            Response::Error {
+                code: ErrorCode::FrameTooLarge,
+                message: Some(_),
+            }
+        ));
+        // The connection is still usable
+        let (response, _) = parse_response(&written[n_parsed_bytes..]).unwrap().unwrap();
+        assert_eq!(response, Response::Get(None));
     }
 
     #[test]
-    fn test_read_single_request_larger_than_initial_buffer() {
+    fn test_each_limit_is_enforced_independently() {
+        let limits = Limits {
+            max_key_size: Some(3),
+            max_value_size: Some(4),
+            max_batch_keys: Some(2),
+            max_frame_size: Some(30),
+        };
+        let cases = [
+            (Request::Get("abc"), None),
+            (Request::Get("abcd"), Some(ErrorCode::KeyTooLarge)),
+            (Request::Delete("abcd"), Some(ErrorCode::KeyTooLarge)),
+            (
+                Request::Set {
+                    key: "abc",
+                    value: "1234",
+                },
+                None,
+            ),
+            (
+                Request::Set {
+                    key: "abc",
+                    value: "12345",
+                },
+                Some(ErrorCode::ValueTooLarge),
+            ),
+            (
+                Request::SetNx {
+                    key: "abcd",
+                    value: "1",
+                },
+                Some(ErrorCode::KeyTooLarge),
+            ),
+            (
+                Request::Cas {
+                    key: "a",
+                    expected: "1",
+                    new: "12345",
+                },
+                Some(ErrorCode::ValueTooLarge),
+            ),
+            (Request::MGet(vec!["a", "b"]), None),
+            (
+                Request::MGet(vec!["a", "b", "c"]),
+                Some(ErrorCode::TooManyKeys),
+            ),
+            (
+                Request::MExists(vec!["a", "bcde"]),
+                Some(ErrorCode::KeyTooLarge),
+            ),
+            (
+                Request::MSet(vec![("a", "1"), ("b", "12345")]),
+                Some(ErrorCode::ValueTooLarge),
+            ),
+            // 31 bytes without violating any other limit
+            (
+                Request::MSet(vec![("a", "1234"), ("b", "1234")]),
+                Some(ErrorCode::FrameTooLarge),
+            ),
+            (Request::Flush, None),
+        ];
+        for (request, expected) in cases {
+            let bytes = request.serialize();
+            let response = check_limits(&request, bytes.len(), &limits);
+            match expected {
+                None => assert!(response.is_none(), "{response:?}"),
+                Some(expected) => assert!(
+                    matches!(response, Some(Response::Error { code, message: Some(_) }) if code == expected),
+                    "{response:?}"
+                ),
+            }
+        }
+        // Every limit is disabled by default
+        let request = Request::MSet(vec![("abcdefg", "123456789"); 10]);
+        assert!(check_limits(&request, usize::MAX, &Limits::default()).is_none());
+    }
+
+    #[test]
+    fn test_invalid_request_is_answered_with_error() {
         let db = DB::new();
-        // Two concatenated requests
-        let raw_data = vec![
-            2, 0, 0, 0, 3, 49, 50, 51, 0, 0, 0, 67, 84, 104, 105, 115, 32, 105, 115, 32, 115, 111,
-            109, 101, 32, 108, 111, 110, 103, 101, 114, 32, 116, 101, 120, 116, 32, 116, 104, 97,
-            116, 32, 100, 105, 100, 32, 110, 111, 116, 32, 102, 105, 116, 32, 105, 110, 116, 111,
-            32, 97, 32, 115, 105, 110, 103, 108, 101, 32, 84, 67, 80, 32, 114, 101, 113, 117, 101,
-            115, 116,
+        // A Get request with an invalid Utf8 key
+        let raw_data = vec![1, 0, 0, 0, 2, 0xC3, 0x28];
+        let n_request_bytes = raw_data.len();
+        let mut stream = Cursor::new(raw_data);
+        assert!(matches!(
+            handle_connection(
+                &mut stream,
+                &[db],
+                test_config(),
+                ConnectionHandles::default(),
+                &ConnectionStats::default()
+            )
+            .err(),
+            Some(Error::Parsing(ParsingError::Utf8Error(_)))
+        ));
+        let written = &stream.get_ref()[n_request_bytes..];
+        let (response, _) = parse_response(written).unwrap().unwrap();
+        assert!(matches!(
+            response,
+            Response::Error {
+                code: ErrorCode::InvalidRequest,
+                message: Some(_),
+            }
+        ));
+    }
+
+    #[test]
+    fn test_transaction_is_discarded_if_a_queued_request_is_rejected() {
+        let db = DB::new();
+        let requests = [
+            Request::Multi,
+            Request::Set {
+                key: "abc",
+                value: "1",
+            },
+            Request::Set {
+                key: "abcd",
+                value: "2",
+            },
+            Request::Exec,
+            Request::Exec,
         ];
-        assert!(raw_data.len() > INITIAL_BUFFER_SIZE);
-        assert!(raw_data.len() < 2 * MAX_BUFFER_SIZE);
+        let raw_data: Vec<u8> = requests.iter().flat_map(Serialize::serialize).collect();
+        let n_request_bytes = raw_data.len();
         let mut stream = Cursor::new(raw_data);
-        let _ = handle_connection(
+        let config = ConnectionConfig {
+            limits: Limits {
+                max_key_size: Some(3),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        handle_connection(
             &mut stream,
-            db.clone(),
-            InitialBufferSize(INITIAL_BUFFER_SIZE),
-            MaxBufferSize(MAX_BUFFER_SIZE),
-        );
+            slice::from_ref(&db),
+            config,
+            ConnectionHandles::default(),
+            &ConnectionStats::default(),
+        )
+        .unwrap();
+
+        let responses = parse_responses(&stream.get_ref()[n_request_bytes..]);
+        let codes: Vec<_> = responses
+            .iter()
+            .map(|response| match response {
+                Response::Error { code, .. } => Some(*code),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(responses[..2], [Response::Multi, Response::Queued]);
         assert_eq!(
-            db.read().unwrap().get("123").unwrap(),
-            "This is some longer text that did not fit into a single TCP request"
+            codes[2..],
+            [
+                Some(ErrorCode::KeyTooLarge),
+                Some(ErrorCode::TransactionAborted),
+                // The transaction ended with the first `Exec`
+                Some(ErrorCode::InvalidRequest),
+            ]
         );
+        assert_eq!(db.get("abc").unwrap(), None);
     }
 
     #[test]
-    fn test_max_buffer_resize_is_respected() {
+    fn test_transaction_is_aborted_once_the_queued_requests_exceed_the_max_buffer_size() {
         let db = DB::new();
-        // Two concatenated requests
+        let value = "x".repeat(MAX_BUFFER_SIZE / 3);
+        let mut requests = vec![Request::Multi];
+        requests.extend((0..4).map(|_| Request::Set {
+            key: "abc",
+            value: &value,
+        }));
+        requests.push(Request::Exec);
+        let mut stream = ChunkedStream {
+            chunks: requests.iter().map(Serialize::serialize).collect(),
+            ..Default::default()
+        };
+        handle_connection(
+            &mut stream,
+            slice::from_ref(&db),
+            test_config(),
+            ConnectionHandles::default(),
+            &ConnectionStats::default(),
+        )
+        .unwrap();
+
+        let responses = parse_responses(&stream.writes.concat());
+        assert_eq!(
+            responses[..3],
+            [Response::Multi, Response::Queued, Response::Queued]
+        );
+        // The third request exceeds the maximum, the fourth is not kept anymore
+        let codes: Vec<_> = responses[3..]
+            .iter()
+            .map(|response| match response {
+                Response::Error { code, .. } => Some(*code),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            codes,
+            [
+                Some(ErrorCode::TransactionAborted),
+                None,
+                Some(ErrorCode::TransactionAborted),
+            ]
+        );
+        assert_eq!(db.get("abc").unwrap(), None);
+    }
+
+    /// A database that calls `before_get` with the key before every `get`.
+    #[derive(Clone)]
+    struct HookedDb {
+        db: DB,
+        before_get: fn(&str),
+    }
+
+    impl Database for HookedDb {
+        fn get(
+            &self,
+            key: &str,
+        ) -> Result<Option<Value>> {
+            (self.before_get)(key);
+            self.db.get(key)
+        }
+
+        fn get_arc(
+            &self,
+            key: &str,
+        ) -> Result<Option<Arc<str>>> {
+            (self.before_get)(key);
+            self.db.get_arc(key)
+        }
+
+        fn get_many(
+            &self,
+            keys: &[&str],
+        ) -> Result<Vec<Option<Value>>> {
+            self.db.get_many(keys)
+        }
+
+        fn get_and_touch(
+            &self,
+            key: &str,
+            ttl: Duration,
+        ) -> Result<Option<Value>> {
+            (self.before_get)(key);
+            self.db.get_and_touch(key, ttl)
+        }
+
+        fn get_with_meta(
+            &self,
+            key: &str,
+        ) -> Result<Option<(Value, KeyMeta)>> {
+            (self.before_get)(key);
+            self.db.get_with_meta(key)
+        }
+
+        fn get_many_ref(
+            &self,
+            keys: &[&str],
+            visit: &mut dyn FnMut(Option<&Value>),
+        ) -> Result<()> {
+            self.db.get_many_ref(keys, visit)
+        }
+
+        fn insert(
+            &self,
+            key: String,
+            value: Value,
+        ) -> Result<()> {
+            self.db.insert(key, value)
+        }
+
+        fn insert_str(
+            &self,
+            key: &str,
+            value: &str,
+        ) -> Result<()> {
+            self.db.insert_str(key, value)
+        }
+
+        fn is_poisoned(&self) -> bool {
+            self.db.is_poisoned()
+        }
+
+        fn get_if_modified_since(
+            &self,
+            key: &str,
+            since: SystemTime,
+        ) -> Result<GetModified<Value>> {
+            (self.before_get)(key);
+            self.db.get_if_modified_since(key, since)
+        }
+
+        fn get_range(
+            &self,
+            key: &str,
+            start: i64,
+            end: i64,
+        ) -> Result<Option<Value>> {
+            (self.before_get)(key);
+            self.db.get_range(key, start, end)
+        }
+
+        fn value_len(
+            &self,
+            key: &str,
+        ) -> Result<Option<usize>> {
+            (self.before_get)(key);
+            self.db.value_len(key)
+        }
+
+        fn access_count(
+            &self,
+            key: &str,
+        ) -> Result<Option<u64>> {
+            self.db.access_count(key)
+        }
+
+        fn set_range(
+            &self,
+            key: &str,
+            offset: usize,
+            value: &str,
+        ) -> Result<usize> {
+            self.db.set_range(key, offset, value)
+        }
+
+        fn insert_with_ttl(
+            &self,
+            key: String,
+            value: Value,
+            ttl: Duration,
+        ) -> Result<()> {
+            self.db.insert_with_ttl(key, value, ttl)
+        }
+
+        fn insert_if_absent(
+            &self,
+            key: String,
+            value: Value,
+        ) -> Result<bool> {
+            self.db.insert_if_absent(key, value)
+        }
+
+        fn compare_and_swap(
+            &self,
+            key: &str,
+            expected: &Value,
+            new: Value,
+        ) -> Result<bool> {
+            self.db.compare_and_swap(key, expected, new)
+        }
+
+        fn incr(
+            &self,
+            key: &str,
+            delta: i64,
+        ) -> Result<i64> {
+            self.db.incr(key, delta)
+        }
+
+        fn contains_many(
+            &self,
+            keys: &[&str],
+        ) -> Result<Vec<bool>> {
+            self.db.contains_many(keys)
+        }
+
+        fn touch_many(
+            &self,
+            keys: &[&str],
+            ttl: Duration,
+        ) -> Result<usize> {
+            self.db.touch_many(keys, ttl)
+        }
+
+        fn count_prefix(
+            &self,
+            prefix: &str,
+        ) -> Result<usize> {
+            self.db.count_prefix(prefix)
+        }
+
+        fn remove(
+            &self,
+            key: &str,
+        ) -> Result<()> {
+            self.db.remove(key)
+        }
+
+        fn delete_if_older_than(
+            &self,
+            key: &str,
+            cutoff: SystemTime,
+        ) -> Result<bool> {
+            self.db.delete_if_older_than(key, cutoff)
+        }
+
+        fn clear(&self) -> Result<()> {
+            self.db.clear()
+        }
+
+        fn clear_returning(&self) -> Result<Vec<String>> {
+            self.db.clear_returning()
+        }
+
+        fn memory_usage(&self) -> Result<usize> {
+            self.db.memory_usage()
+        }
+
+        fn scan(
+            &self,
+            cursor: usize,
+            count: usize,
+        ) -> Result<(usize, Vec<String>)> {
+            self.db.scan(cursor, count)
+        }
+    }
+
+    static SLOW_REQUESTS: AtomicUsize = AtomicUsize::new(0);
+
+    fn count_slow_request(
+        opcode: u8,
+        key: Option<&str>,
+        elapsed: Duration,
+    ) {
+        assert_eq!(opcode, 1);
+        assert_eq!(key, Some("abc"));
+        assert!(elapsed >= Duration::from_millis(10));
+        SLOW_REQUESTS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn test_slow_requests_are_reported() {
+        let db = HookedDb {
+            db: DB::new(),
+            before_get: |_| thread::sleep(Duration::from_millis(10)),
+        };
+        // A Set request that is fast followed by a Get request that is slow
         let raw_data = vec![
-            2, 0, 0, 0, 3, 49, 50, 51, 0, 0, 0, 186, 84, 104, 105, 115, 32, 105, 115, 32, 115, 111,
-            109, 101, 32, 108, 111, 110, 103, 101, 114, 32, 116, 101, 120, 116, 32, 116, 104, 97,
-            116, 32, 100, 105, 100, 32, 110, 111, 116, 32, 102, 105, 116, 32, 105, 110, 116, 111,
-            32, 97, 32, 115, 105, 110, 103, 108, 101, 32, 84, 67, 80, 32, 114, 101, 113, 117, 101,
-            115, 116, 46, 32, 84, 104, 105, 115, 32, 105, 115, 32, 97, 110, 32, 101, 118, 101, 110,
-            32, 108, 111, 110, 103, 101, 114, 32, 116, 101, 120, 116, 32, 102, 111, 114, 32, 116,
-            101, 115, 116, 105, 110, 103, 32, 116, 104, 101, 32, 114, 101, 115, 105, 122, 105, 110,
-            103, 32, 111, 102, 32, 116, 104, 101, 32, 98, 117, 102, 102, 101, 114, 46, 32, 84, 104,
-            101, 114, 101, 32, 105, 115, 32, 101, 118, 101, 110, 32, 109, 111, 114, 101, 32, 100,
-            97, 116, 97, 32, 105, 110, 32, 104, 101, 114, 101, 32, 110, 111, 119, 46, 32, 76, 111,
-            111, 107, 32, 97, 116, 32, 116, 104, 97, 116,
+            2, 0, 0, 0, 3, 97, 98, 99, 0, 0, 0, 3, 103, 104, 105, 1, 0, 0, 0, 3, 97, 98, 99,
         ];
-        assert!(raw_data.len() > INITIAL_BUFFER_SIZE);
-        assert!(
-            raw_data.len() > 2 * MAX_BUFFER_SIZE,
-            "data len is {}",
-            raw_data.len()
+        let mut stream = Cursor::new(raw_data);
+        let config = ConnectionConfig {
+            slow_log: Some(SlowLog {
+                threshold: Duration::from_millis(5),
+                hook: count_slow_request,
+            }),
+            ..test_config()
+        };
+        handle_connection(
+            &mut stream,
+            &[db],
+            config,
+            ConnectionHandles::default(),
+            &ConnectionStats::default(),
+        )
+        .unwrap();
+        assert_eq!(SLOW_REQUESTS.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_provided_database_methods_are_built_on_the_required_ones() {
+        // Only overrides the required methods
+        let db = HookedDb {
+            db: DB::new(),
+            before_get: |_| {},
+        };
+        let pairs = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect::<Vec<_>>()
+        };
+        db.extend(pairs(&[("a1", "1"), ("a2", "22"), ("b", "3")]))
+            .unwrap();
+        assert_eq!(Database::len(&db).unwrap(), 3);
+        assert_eq!(
+            db.get_prefix("a").unwrap(),
+            [
+                ("a1".to_string(), Value::from("1")),
+                ("a2".to_string(), Value::from("22")),
+            ]
+        );
+        assert_eq!(
+            db.retain(|_, value| value.to_string().len() < 2).unwrap(),
+            1
         );
+        assert!(!db.remove_if("a1", &"2".into()).unwrap());
+        assert!(db.remove_if("a1", &1.into()).unwrap());
+        assert_eq!(db.get_prefix("").unwrap(), [("b".to_string(), "3".into())]);
+
+        db.replace_all(pairs(&[("c", "4")])).unwrap();
+        assert_eq!(db.get_prefix("").unwrap(), [("c".to_string(), "4".into())]);
+        assert!(!Database::is_empty(&db).unwrap());
+    }
+
+    #[test]
+    fn test_connections_are_handled_on_named_threads() {
+        static THREAD_NAME: Mutex<Option<String>> = Mutex::new(None);
+        let server = Server::builder()
+            .address("127.0.0.1:0")
+            .database(|db| HookedDb {
+                db,
+                before_get: |_| {
+                    *THREAD_NAME.lock().unwrap() = thread::current().name().map(String::from);
+                },
+            })
+            .thread_stack_size(256 * 1024)
+            .build()
+            .unwrap();
+        let port = server.port().unwrap();
+        thread::spawn(move || server.run());
+
+        let mut client = Client::connect(format!("127.0.0.1:{port}"));
+        assert_eq!(client.get("abc").unwrap(), Response::Get(None));
+        let thread_name = THREAD_NAME.lock().unwrap().clone().unwrap();
+        assert!(thread_name.starts_with("zcached-conn-"), "{thread_name}");
+    }
+
+    #[test]
+    fn test_panic_while_handling_connection_is_caught() {
+        let db = HookedDb {
+            db: DB::new(),
+            before_get: |key| assert_ne!(key, "panic", "backend failure"),
+        };
+        let dbs = [db];
+        // A Get request for the key `panic`
+        let raw_data = vec![1, 0, 0, 0, 5, 112, 97, 110, 105, 99];
         let mut stream = Cursor::new(raw_data);
+        let result = handle_connection_catching_panic(
+            &mut stream,
+            &dbs,
+            test_config(),
+            ConnectionHandles::default(),
+            &ConnectionStats::default(),
+        );
         assert!(matches!(
-            handle_connection(
-                &mut stream,
-                db,
-                InitialBufferSize(INITIAL_BUFFER_SIZE),
-                MaxBufferSize(MAX_BUFFER_SIZE),
-            )
-            .err(),
-            Some(Error::Server(ServerError::TooMuchData))
+            result,
+            Err(Error::Server(ServerError::Panic(message))) if message.contains("backend failure")
         ));
+
+        // Following connections are still served
+        let raw_data = vec![1, 0, 0, 0, 3, 97, 98, 99];
+        let n_request_bytes = raw_data.len();
+        let mut stream = Cursor::new(raw_data);
+        handle_connection_catching_panic(
+            &mut stream,
+            &dbs,
+            test_config(),
+            ConnectionHandles::default(),
+            &ConnectionStats::default(),
+        )
+        .unwrap();
+        let written = &stream.get_ref()[n_request_bytes..];
+        let (response, _) = parse_response(written).unwrap().unwrap();
+        assert_eq!(response, Response::Get(None));
+    }
+
+    #[test]
+    fn test_accept_loop_backs_off_on_resource_exhaustion() {
+        let accept_failures = AtomicUsize::new(0);
+        // EMFILE: too many open files
+        let incoming = (0..4).map(|_| Err::<(), _>(io::Error::from_raw_os_error(24)));
+        let now = std::time::Instant::now();
+        accept_loop(incoming, &accept_failures, |_| {});
+        let elapsed = now.elapsed();
+        assert_eq!(accept_failures.load(Ordering::Relaxed), 4);
+        // 10ms + 20ms + 40ms + 80ms
+        assert!(
+            elapsed >= Duration::from_millis(150),
+            "elapsed: {elapsed:?}"
+        );
+    }
+
+    #[test]
+    fn test_accept_loop_does_not_back_off_on_other_errors() {
+        let accept_failures = AtomicUsize::new(0);
+        let incoming =
+            (0..100).map(|_| Err::<(), _>(io::Error::from(io::ErrorKind::ConnectionAborted)));
+        let now = std::time::Instant::now();
+        accept_loop(incoming, &accept_failures, |_| {});
+        assert_eq!(accept_failures.load(Ordering::Relaxed), 100);
+        assert!(now.elapsed() < ACCEPT_BACKOFF_INITIAL * 100);
+    }
+
+    #[test]
+    fn test_accept_loop_handles_streams_between_failures() {
+        let accept_failures = AtomicUsize::new(0);
+        let incoming = vec![
+            Ok(1),
+            Err(io::Error::from(io::ErrorKind::ConnectionAborted)),
+            Ok(2),
+        ];
+        let mut handled = vec![];
+        accept_loop(incoming.into_iter(), &accept_failures, |stream| {
+            handled.push(stream)
+        });
+        assert_eq!(handled, vec![1, 2]);
+        assert_eq!(accept_failures.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_watcher_reports_lost_invalidations() {
+        let watcher = Watcher::new(Arc::default());
+        watcher.push("a".to_string());
+        watcher.push("b".to_string());
+        let mut pending = Vec::new();
+        assert!(watcher.drain(&mut pending));
+        let mut expected = Response::Invalidate("a".to_string()).serialize();
+        expected.extend(Response::Invalidate("b".to_string()).serialize());
+        assert_eq!(pending, expected);
+
+        for i in 0..=MAX_QUEUED_INVALIDATIONS {
+            watcher.push(i.to_string());
+        }
+        pending.clear();
+        assert!(!watcher.drain(&mut pending));
+    }
+
+    #[test]
+    fn test_flusher_writes_invalidations_only_while_the_connection_waits() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (socket, _) = listener.accept().unwrap();
+        let stats = Arc::new(ConnectionStats::default());
+        let watcher = Arc::new(Watcher::new(Arc::clone(&stats)));
+        watcher.spawn_flusher(Arc::new(socket)).unwrap();
+
+        // The connection is busy, so it writes the invalidation itself
+        watcher.push("a".to_string());
+        client
+            .set_read_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+        assert!(client.read(&mut [0; 16]).is_err());
+
+        watcher.start_waiting();
+        let expected = Response::Invalidate("a".to_string()).serialize();
+        let mut received = vec![0; expected.len()];
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        client.read_exact(&mut received).unwrap();
+        assert_eq!(received, expected);
+        watcher.stop_waiting();
+        assert_eq!(
+            stats.bytes_written.load(Ordering::Relaxed),
+            expected.len() as u64
+        );
+        watcher.close();
+    }
+
+    #[test]
+    fn test_admin_password_must_match_exactly() {
+        assert!(is_admin_password("secret", "secret"));
+        assert!(!is_admin_password("secret", "secreT"));
+        assert!(!is_admin_password("secret", "secret2"));
+        assert!(!is_admin_password("secret", "secre"));
+        assert!(!is_admin_password("secret", ""));
+    }
+
+    #[test]
+    fn test_transaction_waits_for_the_requests_being_applied() {
+        let locks = Arc::new(TransactionLocks::default());
+        let connection_lock = locks.register();
+        let applying = connection_lock.lock().unwrap();
+        let executed = Arc::new(AtomicBool::new(false));
+        let transaction = thread::spawn({
+            let locks = Arc::clone(&locks);
+            let executed = Arc::clone(&executed);
+            move || locks.exclusively(|| executed.store(true, Ordering::Relaxed))
+        });
+        thread::sleep(Duration::from_millis(50));
+        assert!(!executed.load(Ordering::Relaxed));
+        drop(applying);
+        transaction.join().unwrap();
+        assert!(executed.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_locks_of_closed_connections_are_removed() {
+        let locks = TransactionLocks::default();
+        let open = locks.register();
+        for _ in 0..10 {
+            drop(locks.register());
+        }
+        locks.exclusively(|| {});
+        assert_eq!(locks.locks.lock().unwrap().len(), 1);
+        drop(open);
     }
 }
@@ -0,0 +1,74 @@
+//! A stable mapping of keys to shards, e.g. for routing keys to one of several servers.
+
+/// The FNV-1a 64 bit offset basis.
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+/// The FNV-1a 64 bit prime.
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+/// Returns the index of the shard in `0..shard_count` that owns `key`.
+///
+/// The shard is the 64 bit FNV-1a hash of the key's UTF-8 bytes modulo `shard_count`.
+/// All arithmetic is done on `u64`, so the result is the same on every platform and endianness
+/// and is part of the protocol contract, i.e. it will not change between versions.
+///
+/// # Panics
+/// Panics if `shard_count` is `0`.
+pub fn key_shard(
+    key: &str,
+    shard_count: usize,
+) -> usize {
+    assert!(shard_count > 0, "shard_count must not be 0");
+    let hash = fnv1a(key.as_bytes());
+    // The remainder is lower than `shard_count`, so it always fits into `usize`
+    (hash % shard_count as u64) as usize
+}
+
+/// Computes the 64 bit FNV-1a hash of `bytes`.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(FNV_OFFSET_BASIS, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(FNV_PRIME)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fnv1a_reference_values() {
+        assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(fnv1a(b"foobar"), 0x8594_4171_f739_67e8);
+    }
+
+    #[test]
+    fn test_key_shard_is_stable() {
+        // Part of the protocol contract, client-side routing relies on these shards
+        let cases = [
+            ("", 16, 5),
+            ("a", 16, 12),
+            ("foobar", 16, 8),
+            ("user:42", 16, 2),
+            ("session:abc", 16, 9),
+            ("", 3, 2),
+            ("a", 3, 1),
+            ("foobar", 3, 0),
+            ("user:42", 3, 2),
+            ("session:abc", 3, 1),
+        ];
+        for (key, shard_count, shard) in cases {
+            assert_eq!(
+                key_shard(key, shard_count),
+                shard,
+                "{key:?} in {shard_count}"
+            );
+        }
+        assert_eq!(key_shard("anything", 1), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "shard_count must not be 0")]
+    fn test_key_shard_rejects_zero_shards() {
+        key_shard("a", 0);
+    }
+}
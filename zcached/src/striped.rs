@@ -0,0 +1,53 @@
+//! Locks for single keys, so that read-modify-write operations on different keys do not wait for each other.
+
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+
+use crate::shard::key_shard;
+
+/// A fixed number of locks, each guarding all keys hashing to it.
+///
+/// Two keys share a lock only if they hash to the same stripe, so the memory used
+/// does not grow with the number of keys like a lock per key would.
+#[derive(Debug)]
+pub(crate) struct StripedLock {
+    stripes: Box<[Mutex<()>]>,
+}
+
+impl StripedLock {
+    /// Creates `stripes` locks.
+    ///
+    /// # Panics
+    /// Panics if `stripes` is `0`.
+    pub(crate) fn new(stripes: usize) -> Self {
+        assert!(stripes > 0, "stripes must not be 0");
+        Self {
+            stripes: (0..stripes).map(|_| Mutex::new(())).collect(),
+        }
+    }
+
+    /// Locks the stripe of `key`, blocking until no other thread holds it.
+    pub(crate) fn lock(
+        &self,
+        key: &str,
+    ) -> MutexGuard<'_, ()> {
+        let stripe = &self.stripes[key_shard(key, self.stripes.len())];
+        // The guarded data is `()`, so a panic while holding the lock cannot leave it inconsistent.
+        stripe.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_keys_of_different_stripes_are_locked_independently() {
+        let locks = StripedLock::new(16);
+        // "a" and "foobar" hash to different stripes, see `shard::test::test_key_shard_is_stable`
+        let _a = locks.lock("a");
+        let foobar = locks.stripes[key_shard("foobar", 16)].try_lock();
+        assert!(foobar.is_ok());
+        assert!(locks.stripes[key_shard("a", 16)].try_lock().is_err());
+    }
+}
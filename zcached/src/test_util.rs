@@ -0,0 +1,241 @@
+use std::collections::VecDeque;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::net::Ipv4Addr;
+use std::net::SocketAddr;
+use std::net::ToSocketAddrs;
+use std::ops::Deref;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::thread;
+use std::thread::JoinHandle;
+
+use crate::server::ServerBuilder;
+use crate::Client;
+use crate::Database;
+use crate::Server;
+use crate::DB;
+
+/// Creates a connected pair of in-memory streams.
+/// Bytes written to one end can be read from the other, so a [`Client`](crate::Client)
+/// and a server started with [`serve_on`](crate::serve_on) can talk without opening a port.
+pub fn duplex() -> (DuplexStream, DuplexStream) {
+    let a = Arc::new(Pipe::default());
+    let b = Arc::new(Pipe::default());
+    (
+        DuplexStream {
+            read: a.clone(),
+            write: b.clone(),
+        },
+        DuplexStream { read: b, write: a },
+    )
+}
+
+/// One end of an in-memory pipe created with [`duplex`].
+/// Reads block until the other end writes or is dropped, in which case the end of the stream is reached.
+/// Writes fail with [`io::ErrorKind::BrokenPipe`] once the other end was dropped.
+#[derive(Debug)]
+pub struct DuplexStream {
+    read: Arc<Pipe>,
+    write: Arc<Pipe>,
+}
+
+/// The bytes flowing in one direction between the two ends of a [`DuplexStream`].
+#[derive(Debug, Default)]
+struct Pipe {
+    state: Mutex<PipeState>,
+    readable: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct PipeState {
+    bytes: VecDeque<u8>,
+    // Set once either end was dropped.
+    closed: bool,
+}
+
+impl Pipe {
+    fn close(&self) {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).closed = true;
+        self.readable.notify_all();
+    }
+}
+
+impl Read for DuplexStream {
+    fn read(
+        &mut self,
+        buf: &mut [u8],
+    ) -> io::Result<usize> {
+        let mut state = self.read.state.lock().unwrap_or_else(|e| e.into_inner());
+        while state.bytes.is_empty() && !state.closed && !buf.is_empty() {
+            state = self
+                .read
+                .readable
+                .wait(state)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+        let n = buf.len().min(state.bytes.len());
+        for (dst, src) in buf.iter_mut().zip(state.bytes.drain(..n)) {
+            *dst = src;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for DuplexStream {
+    fn write(
+        &mut self,
+        buf: &[u8],
+    ) -> io::Result<usize> {
+        let mut state = self.write.state.lock().unwrap_or_else(|e| e.into_inner());
+        if state.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        state.bytes.extend(buf);
+        self.write.readable.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for DuplexStream {
+    fn drop(&mut self) {
+        self.read.close();
+        self.write.close();
+    }
+}
+
+/// Starts a [`Server`] with the default settings on a background thread
+/// and connects a [`Client`] to it, see [`spawn_server_with`].
+pub fn spawn_server() -> (ServerHandle, Client) {
+    spawn_server_with(Server::builder())
+}
+
+/// Starts the [`Server`] built from `builder` on a background thread and connects a [`Client`] to it.
+/// The server listens on a port of localhost chosen by the OS instead of the address of `builder`,
+/// so tests running in parallel never compete for a port.
+/// It runs until the returned [`ServerHandle`] is dropped.
+///
+/// # Panics
+/// Panics if the server cannot be built.
+pub fn spawn_server_with<A, D>(builder: ServerBuilder<A, D>) -> (ServerHandle<D>, Client)
+where
+    A: ToSocketAddrs,
+    D: Database,
+    D: Clone,
+    D: 'static,
+{
+    let server = builder
+        .address((Ipv4Addr::LOCALHOST, 0))
+        .build()
+        .expect("to be able to build the server");
+    let port = server.port().expect("to know the port of the server");
+    let server = Arc::new(server);
+    let thread = thread::spawn({
+        let server = Arc::clone(&server);
+        move || server.run()
+    });
+    let handle = ServerHandle {
+        server,
+        addr: (Ipv4Addr::LOCALHOST, port).into(),
+        thread: Some(thread),
+    };
+    let client = handle.connect();
+    (handle, client)
+}
+
+/// A [`Server`] running on a background thread, see [`spawn_server`].
+/// Dereferences to the server, e.g. to list its [`connections`](Server::connections).
+/// Dropping it [stops](Server::stop) the server and waits until it stopped.
+pub struct ServerHandle<D = DB> {
+    server: Arc<Server<D>>,
+    addr: SocketAddr,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl<D> ServerHandle<D> {
+    /// Returns the address the server is listening on.
+    pub fn address(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Connects another [`Client`] to the server.
+    pub fn connect(&self) -> Client {
+        Client::connect(self.addr)
+    }
+}
+
+impl<D> Deref for ServerHandle<D> {
+    type Target = Server<D>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.server
+    }
+}
+
+impl<D> Drop for ServerHandle<D> {
+    fn drop(&mut self) {
+        self.server.stop();
+        if let Some(thread) = self.thread.take() {
+            // Do not panic while a failing test is already unwinding.
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::TcpStream;
+
+    use super::*;
+    use crate::Response;
+
+    #[test]
+    fn test_bytes_flow_in_both_directions() {
+        let (mut a, mut b) = duplex();
+        a.write_all(b"ping").unwrap();
+        let mut buf = [0; 4];
+        b.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+
+        b.write_all(b"pong").unwrap();
+        a.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"pong");
+    }
+
+    #[test]
+    fn test_dropping_one_end_closes_the_other() {
+        let (mut a, b) = duplex();
+        let reader = thread::spawn(move || {
+            let mut b = b;
+            let mut bytes = Vec::new();
+            b.read_to_end(&mut bytes).unwrap();
+            bytes
+        });
+        a.write_all(b"abc").unwrap();
+        drop(a);
+        assert_eq!(reader.join().unwrap(), b"abc");
+
+        let (mut a, b) = duplex();
+        drop(b);
+        assert_eq!(
+            a.write_all(b"abc").unwrap_err().kind(),
+            io::ErrorKind::BrokenPipe
+        );
+    }
+
+    #[test]
+    fn test_dropping_the_server_handle_stops_the_server() {
+        let (server, mut client) = spawn_server();
+        assert_eq!(client.set("abc", "1").unwrap(), Response::Set);
+        let addr = server.address();
+        drop(server);
+        assert!(client.get("abc").is_err());
+        assert!(TcpStream::connect(addr).is_err());
+    }
+}
@@ -0,0 +1,435 @@
+//! A textual command format for humans, e.g. for a REPL or debugging.
+//!
+//! Commands like `SET foo "bar baz"` are parsed into an [`OwnedRequest`], which can be serialized
+//! into the binary wire protocol with [`OwnedRequest::as_request`], and a [`Response`] is
+//! rendered back to text with its `Display` implementation.
+//! This is not part of the wire protocol.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::str::FromStr;
+
+use crate::protocol::GetModified;
+use crate::Request;
+use crate::Response;
+
+/// An owned request parsed from a textual command.
+///
+/// | Command           | Request           |
+/// |-------------------|-------------------|
+/// | `GET key`         | `Request::Get`    |
+/// | `SET key value`   | `Request::Set`    |
+/// | `DEL key`         | `Request::Delete` |
+/// | `FLUSH`           | `Request::Flush`  |
+///
+/// Commands are case-insensitive. Arguments are separated by whitespace and can be put in
+/// double quotes to contain whitespace, where `\"` and `\\` escape a quote and a backslash.
+///
+/// ```
+/// use zcached::OwnedRequest;
+///
+/// let request: OwnedRequest = r#"SET greeting "hello world""#.parse().unwrap();
+/// assert_eq!(
+///     request,
+///     OwnedRequest::Set {
+///         key: "greeting".to_string(),
+///         value: "hello world".to_string(),
+///     }
+/// );
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OwnedRequest {
+    Get(String),
+    Set { key: String, value: String },
+    Delete(String),
+    Flush,
+}
+
+impl OwnedRequest {
+    /// Borrows the request, e.g. to [`serialize`](crate::Serialize::serialize) it.
+    pub fn as_request(&self) -> Request<'_> {
+        match self {
+            OwnedRequest::Get(key) => Request::Get(key),
+            OwnedRequest::Set { key, value } => Request::Set { key, value },
+            OwnedRequest::Delete(key) => Request::Delete(key),
+            OwnedRequest::Flush => Request::Flush,
+        }
+    }
+}
+
+/// An error while parsing a textual command, see [`OwnedRequest`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TextCommandError {
+    /// The input contains no command.
+    Empty,
+    /// The command is not known.
+    UnknownCommand(String),
+    /// The command was given the wrong number of arguments.
+    WrongArguments {
+        command: &'static str,
+        expected: usize,
+    },
+    /// A quoted argument is missing its closing quote.
+    UnterminatedQuote,
+}
+
+impl fmt::Display for TextCommandError {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        match self {
+            TextCommandError::Empty => write!(f, "no command given"),
+            TextCommandError::UnknownCommand(command) => write!(f, "unknown command {command}"),
+            TextCommandError::WrongArguments { command, expected } => {
+                write!(f, "{command} takes {expected} argument(s)")
+            }
+            TextCommandError::UnterminatedQuote => write!(f, "unterminated quote"),
+        }
+    }
+}
+
+impl core::error::Error for TextCommandError {}
+
+impl FromStr for OwnedRequest {
+    type Err = TextCommandError;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let mut words = split_words(input)?.into_iter();
+        let command = words.next().ok_or(TextCommandError::Empty)?;
+        let arguments: Vec<String> = words.collect();
+        let (name, expected) = match command.to_ascii_uppercase().as_str() {
+            "GET" => ("GET", 1),
+            "SET" => ("SET", 2),
+            "DEL" => ("DEL", 1),
+            "FLUSH" => ("FLUSH", 0),
+            _ => return Err(TextCommandError::UnknownCommand(command)),
+        };
+        if arguments.len() != expected {
+            return Err(TextCommandError::WrongArguments {
+                command: name,
+                expected,
+            });
+        }
+        let mut arguments = arguments.into_iter();
+        let mut next = || arguments.next().unwrap_or_default();
+        Ok(match name {
+            "GET" => OwnedRequest::Get(next()),
+            "SET" => OwnedRequest::Set {
+                key: next(),
+                value: next(),
+            },
+            "DEL" => OwnedRequest::Delete(next()),
+            _ => OwnedRequest::Flush,
+        })
+    }
+}
+
+/// Splits `input` at whitespace outside of double quotes and removes the quotes and escapes.
+fn split_words(input: &str) -> Result<Vec<String>, TextCommandError> {
+    let mut words = Vec::new();
+    let mut chars = input.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let Some(first) = chars.next() else {
+            return Ok(words);
+        };
+        let mut word = String::new();
+        if first == '"' {
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some(escaped) => word.push(escaped),
+                        None => return Err(TextCommandError::UnterminatedQuote),
+                    },
+                    Some(c) => word.push(c),
+                    None => return Err(TextCommandError::UnterminatedQuote),
+                }
+            }
+        } else {
+            word.push(first);
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                word.push(c);
+            }
+        }
+        words.push(word);
+    }
+}
+
+/// Writes `s` in double quotes, escaping quotes and backslashes, so that it can be typed back in.
+fn write_quoted(
+    f: &mut fmt::Formatter<'_>,
+    s: &str,
+) -> fmt::Result {
+    f.write_str("\"")?;
+    for c in s.chars() {
+        if matches!(c, '"' | '\\') {
+            f.write_str("\\")?;
+        }
+        write!(f, "{c}")?;
+    }
+    f.write_str("\"")
+}
+
+/// Writes `items` separated by commas in square brackets.
+fn write_list<T>(
+    f: &mut fmt::Formatter<'_>,
+    items: &[T],
+    mut write_item: impl FnMut(&mut fmt::Formatter<'_>, &T) -> fmt::Result,
+) -> fmt::Result {
+    f.write_str("[")?;
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+            f.write_str(", ")?;
+        }
+        write_item(f, item)?;
+    }
+    f.write_str("]")
+}
+
+fn write_optional(
+    f: &mut fmt::Formatter<'_>,
+    value: Option<&str>,
+) -> fmt::Result {
+    match value {
+        Some(value) => write_quoted(f, value),
+        None => f.write_str("(nil)"),
+    }
+}
+
+/// Renders the response as human readable text, with strings in double quotes
+/// and missing values as `(nil)`.
+impl fmt::Display for Response {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        match self {
+            Response::Get(value) => write_optional(f, value.as_deref()),
+            Response::Set
+            | Response::Delete
+            | Response::Flush
+            | Response::Select
+            | Response::FlushAll
+            | Response::MSet
+            | Response::ReplaceAll
+            | Response::Multi
+            | Response::Watch
+            | Response::Auth
+            | Response::Hello => f.write_str("OK"),
+            Response::Scan { cursor, keys } => {
+                write!(f, "cursor {cursor} ")?;
+                write_list(f, keys, |f, key| write_quoted(f, key))
+            }
+            Response::MGet(values) => {
+                write_list(f, values, |f, value| write_optional(f, value.as_deref()))
+            }
+            Response::Incr(value) => write!(f, "(integer) {value}"),
+            Response::MExists(exists) => write_list(f, exists, |f, exists| write!(f, "{exists}")),
+            Response::MTouch(count) => write!(f, "(integer) {count}"),
+            Response::CountPrefix(count) => write!(f, "(integer) {count}"),
+            Response::Cas(done)
+            | Response::SetNx(done)
+            | Response::DeleteIfOlderThan(done)
+            | Response::DeleteIf(done) => {
+                write!(f, "{done}")
+            }
+            Response::Info(info) => {
+                write!(
+                    f,
+                    "protocol {}, version {}, uptime {}ms, features ",
+                    info.protocol_version,
+                    info.version,
+                    info.uptime.as_millis()
+                )?;
+                write_list(f, &info.features, |f, feature| f.write_str(feature))
+            }
+            Response::GetMeta(None) => f.write_str("(nil)"),
+            Response::GetMeta(Some((value, meta))) => {
+                write_quoted(f, value)?;
+                write!(f, " (size {}", meta.size)?;
+                match meta.ttl {
+                    Some(ttl) => write!(f, ", ttl {}ms)", ttl.as_millis()),
+                    None => f.write_str(")"),
+                }
+            }
+            Response::Pong => f.write_str("PONG"),
+            Response::Queued => f.write_str("QUEUED"),
+            Response::Invalidate(key) => {
+                f.write_str("invalidate ")?;
+                write_quoted(f, key)
+            }
+            Response::Exec(responses) => {
+                write_list(f, responses, |f, response| write!(f, "{response}"))
+            }
+            Response::GetPrefix(pairs) => write_list(f, pairs, |f, (key, value)| {
+                write_quoted(f, key)?;
+                f.write_str(": ")?;
+                write_quoted(f, value)
+            }),
+            Response::GetRange(range) => write_optional(f, range.as_deref()),
+            Response::SetRange(len) => write!(f, "(integer) {len}"),
+            Response::StrLen(Some(len)) => write!(f, "(integer) {len}"),
+            Response::StrLen(None) => f.write_str("(nil)"),
+            Response::GetIfModifiedSince(GetModified::NotModified) => f.write_str("(not modified)"),
+            Response::GetIfModifiedSince(GetModified::Value(value)) => write_quoted(f, value),
+            Response::GetIfModifiedSince(GetModified::Missing) => f.write_str("(nil)"),
+            Response::Error { code, message } => {
+                write!(f, "(error) {code:?}")?;
+                match message {
+                    Some(message) => write!(f, ": {message}"),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use core::time::Duration;
+
+    use super::*;
+    use crate::serialization::Serialize;
+    use crate::ErrorCode;
+    use crate::KeyMeta;
+
+    fn parse(input: &str) -> Result<OwnedRequest, TextCommandError> {
+        input.parse()
+    }
+
+    #[test]
+    fn test_every_command_is_parsed() {
+        assert_eq!(parse("GET abc"), Ok(OwnedRequest::Get("abc".to_string())));
+        assert_eq!(
+            parse("SET abc def"),
+            Ok(OwnedRequest::Set {
+                key: "abc".to_string(),
+                value: "def".to_string(),
+            })
+        );
+        assert_eq!(
+            parse("DEL abc"),
+            Ok(OwnedRequest::Delete("abc".to_string()))
+        );
+        assert_eq!(parse("FLUSH"), Ok(OwnedRequest::Flush));
+        // Commands are case-insensitive and surrounding whitespace is ignored
+        assert_eq!(
+            parse("  get\tAbc \n"),
+            Ok(OwnedRequest::Get("Abc".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_quoted_arguments_keep_whitespace() {
+        assert_eq!(
+            parse(r#"SET "a key" "hello  world""#),
+            Ok(OwnedRequest::Set {
+                key: "a key".to_string(),
+                value: "hello  world".to_string(),
+            })
+        );
+        assert_eq!(
+            parse(r#"SET abc "say \"hi\" \\o/""#),
+            Ok(OwnedRequest::Set {
+                key: "abc".to_string(),
+                value: r#"say "hi" \o/"#.to_string(),
+            })
+        );
+        assert_eq!(parse(r#"GET """#), Ok(OwnedRequest::Get(String::new())));
+    }
+
+    #[test]
+    fn test_invalid_commands_are_rejected() {
+        assert_eq!(parse(" "), Err(TextCommandError::Empty));
+        assert_eq!(
+            parse("PUT abc def"),
+            Err(TextCommandError::UnknownCommand("PUT".to_string()))
+        );
+        assert_eq!(
+            parse("SET abc"),
+            Err(TextCommandError::WrongArguments {
+                command: "SET",
+                expected: 2,
+            })
+        );
+        assert_eq!(
+            parse("FLUSH now"),
+            Err(TextCommandError::WrongArguments {
+                command: "FLUSH",
+                expected: 0,
+            })
+        );
+        assert_eq!(
+            parse(r#"SET abc "def"#),
+            Err(TextCommandError::UnterminatedQuote)
+        );
+    }
+
+    #[test]
+    fn test_parsed_request_serializes_like_the_request() {
+        let request = parse(r#"SET abc "d e f""#).unwrap();
+        assert_eq!(
+            request.as_request().serialize(),
+            Request::Set {
+                key: "abc",
+                value: "d e f",
+            }
+            .serialize()
+        );
+    }
+
+    #[test]
+    fn test_responses_are_rendered_as_text() {
+        let cases = [
+            (Response::Get(Some("a \"b\"".to_string())), r#""a \"b\"""#),
+            (Response::Get(None), "(nil)"),
+            (Response::Set, "OK"),
+            (
+                Response::Scan {
+                    cursor: 3,
+                    keys: vec!["a".to_string(), "b".to_string()],
+                },
+                r#"cursor 3 ["a", "b"]"#,
+            ),
+            (
+                Response::MGet(vec![Some("1".to_string()), None]),
+                r#"["1", (nil)]"#,
+            ),
+            (Response::Incr(-2), "(integer) -2"),
+            (Response::MExists(vec![true, false]), "[true, false]"),
+            (
+                Response::GetPrefix(vec![("a:1".to_string(), "x".to_string())]),
+                r#"["a:1": "x"]"#,
+            ),
+            (Response::Cas(false), "false"),
+            (
+                Response::GetMeta(Some((
+                    "v".to_string(),
+                    KeyMeta {
+                        ttl: Some(Duration::from_secs(2)),
+                        size: 1,
+                    },
+                ))),
+                r#""v" (size 1, ttl 2000ms)"#,
+            ),
+            (Response::Pong, "PONG"),
+            (
+                Response::GetIfModifiedSince(GetModified::NotModified),
+                "(not modified)",
+            ),
+            (
+                Response::Error {
+                    code: ErrorCode::NotAnInteger,
+                    message: Some("value is not an integer".to_string()),
+                },
+                "(error) NotAnInteger: value is not an integer",
+            ),
+        ];
+        for (response, expected) in cases {
+            assert_eq!(response.to_string(), expected);
+        }
+    }
+}
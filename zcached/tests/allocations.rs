@@ -0,0 +1,39 @@
+#![cfg(feature = "std")]
+
+use zcached::Database;
+use zcached::DB;
+
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+/// Returns the number of allocations made while running `f`.
+fn count_allocations(f: impl FnOnce()) -> u64 {
+    let before = dhat::HeapStats::get().total_blocks;
+    f();
+    dhat::HeapStats::get().total_blocks - before
+}
+
+// dhat only supports a single profiler per process, so all checks live in one test.
+#[test]
+fn overwriting_a_string_reuses_its_allocations() {
+    let _profiler = dhat::Profiler::builder().testing().build();
+    let db = DB::new();
+    let value = "a".repeat(64);
+    db.insert_str("key", &value).unwrap();
+
+    // Allocating a new key and value for every write
+    let allocations = count_allocations(|| {
+        db.insert("key".to_string(), value.as_str().into()).unwrap();
+    });
+    assert_eq!(allocations, 2);
+
+    let allocations = count_allocations(|| db.insert_str("key", &value).unwrap());
+    assert_eq!(allocations, 0);
+    let allocations = count_allocations(|| db.insert_str("key", "shorter").unwrap());
+    assert_eq!(allocations, 0);
+    assert_eq!(db.get("key").unwrap(), Some("shorter".into()));
+
+    // A new key still needs its own allocations
+    let allocations = count_allocations(|| db.insert_str("other", &value).unwrap());
+    assert!(allocations >= 2);
+}
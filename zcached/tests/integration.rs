@@ -1,29 +1,51 @@
+use std::collections::HashSet;
+use std::io::Read;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
 use std::thread;
 use std::thread::JoinHandle;
 use std::time::Duration;
 use std::time::Instant;
+use std::time::SystemTime;
 
+use zcached::spawn_server;
+use zcached::spawn_server_with;
+use zcached::Capacity;
 use zcached::Client;
+use zcached::ClientError;
+use zcached::ClientIdPolicy;
+use zcached::ConnectionInfo;
 use zcached::Database;
+use zcached::Error;
+use zcached::ErrorCode;
+use zcached::EvictionPolicy;
+use zcached::GetModified;
+use zcached::Health;
+use zcached::KeyMeta;
+use zcached::MeteredDb;
+use zcached::ReadThroughDb;
+use zcached::Request;
 use zcached::Response;
+use zcached::Serialize;
 use zcached::Server;
+use zcached::ServerError;
+use zcached::Value;
 use zcached::DB;
+use zcached::PROTOCOL_VERSION;
 
 #[test]
 fn setting_and_getting_a_key_works() {
-    let host = "127.0.0.1";
-    let server = Server::builder()
-        .address(format!("{host}:0"))
-        .initial_buffer_size(256)
-        .max_buffer_size(1024)
-        .build()
-        .unwrap();
-    let port = server.port().unwrap();
-    thread::spawn(move || {
-        server.run();
-    });
-
-    let mut client = Client::connect(format!("{host}:{port}"));
+    let (_server, mut client) = spawn_server_with(
+        Server::builder()
+            .initial_buffer_size(256)
+            .max_buffer_size(1024),
+    );
     let key = "abc";
     let value = "123".to_string();
     assert_eq!(client.get(key).unwrap(), Response::Get(None));
@@ -33,19 +55,11 @@ fn setting_and_getting_a_key_works() {
 
 #[test]
 fn deleting_a_key_works() {
-    let host = "127.0.0.1";
-    let server = Server::builder()
-        .address(format!("{host}:0"))
-        .initial_buffer_size(256)
-        .max_buffer_size(1024)
-        .build()
-        .unwrap();
-    let port = server.port().unwrap();
-    thread::spawn(move || {
-        server.run();
-    });
-
-    let mut client = Client::connect(format!("{host}:{port}"));
+    let (_server, mut client) = spawn_server_with(
+        Server::builder()
+            .initial_buffer_size(256)
+            .max_buffer_size(1024),
+    );
     let key = "abc";
     let value = "123".to_string();
     assert_eq!(client.set(key, &value).unwrap(), Response::Set);
@@ -56,19 +70,11 @@ fn deleting_a_key_works() {
 
 #[test]
 fn flushing_works() {
-    let host = "127.0.0.1";
-    let server = Server::builder()
-        .address(format!("{host}:0"))
-        .initial_buffer_size(256)
-        .max_buffer_size(1024)
-        .build()
-        .unwrap();
-    let port = server.port().unwrap();
-    thread::spawn(move || {
-        server.run();
-    });
-
-    let mut client = Client::connect(format!("{host}:{port}"));
+    let (_server, mut client) = spawn_server_with(
+        Server::builder()
+            .initial_buffer_size(256)
+            .max_buffer_size(1024),
+    );
     let key_1 = "abc";
     let key_2 = "def";
     let value = "123".to_string();
@@ -84,15 +90,1498 @@ fn flushing_works() {
     assert_eq!(client.get(key_2).unwrap(), Response::Get(None));
 }
 
+#[test]
+fn flushing_in_the_background_works() {
+    let (_server, mut client) = spawn_server_with(Server::builder().background_clear(true));
+    for i in 0..100 {
+        assert_eq!(client.set(&i.to_string(), "value").unwrap(), Response::Set);
+    }
+    assert_eq!(client.flush().unwrap(), Response::Flush);
+    assert_eq!(client.get("0").unwrap(), Response::Get(None));
+    assert_eq!(client.set("0", "new").unwrap(), Response::Set);
+    assert_eq!(
+        client.get("0").unwrap(),
+        Response::Get(Some("new".to_string()))
+    );
+}
+
+#[test]
+fn getting_and_setting_many_keys_works() {
+    let (_server, mut client) = spawn_server_with(
+        Server::builder()
+            .initial_buffer_size(256)
+            .max_buffer_size(1024),
+    );
+    assert_eq!(client.get_many(&[]).unwrap(), Response::MGet(vec![]));
+    assert_eq!(client.set_many(&[]).unwrap(), Response::MSet);
+    assert_eq!(
+        client.set_many(&[("abc", "123"), ("def", "456")]).unwrap(),
+        Response::MSet
+    );
+    assert_eq!(
+        client.get_many(&["def", "xyz", "abc"]).unwrap(),
+        Response::MGet(vec![Some("456".to_string()), None, Some("123".to_string())])
+    );
+}
+
+#[test]
+fn databases_are_isolated() {
+    let (_server, mut client) = spawn_server_with(Server::builder().num_databases(2));
+    let key = "abc";
+    let value = "123".to_string();
+    assert_eq!(client.set(key, &value).unwrap(), Response::Set);
+    assert_eq!(client.select(1).unwrap(), Response::Select);
+    assert_eq!(client.get(key).unwrap(), Response::Get(None));
+    assert_eq!(client.set(key, "456").unwrap(), Response::Set);
+    assert_eq!(client.flush().unwrap(), Response::Flush);
+    assert_eq!(client.get(key).unwrap(), Response::Get(None));
+    // Flushing database 1 left database 0 intact
+    assert_eq!(client.select(0).unwrap(), Response::Select);
+    assert_eq!(client.get(key).unwrap(), Response::Get(Some(value)));
+}
+
+#[test]
+fn flushing_all_databases_works() {
+    let (_server, mut client) = spawn_server_with(Server::builder().num_databases(2));
+    let key = "abc";
+    assert_eq!(client.set(key, "123").unwrap(), Response::Set);
+    assert_eq!(client.select(1).unwrap(), Response::Select);
+    assert_eq!(client.set(key, "456").unwrap(), Response::Set);
+    assert_eq!(client.flush_all().unwrap(), Response::FlushAll);
+    assert_eq!(client.get(key).unwrap(), Response::Get(None));
+    assert_eq!(client.select(0).unwrap(), Response::Select);
+    assert_eq!(client.get(key).unwrap(), Response::Get(None));
+}
+
+#[test]
+fn selecting_a_nonexistent_database_fails() {
+    let (_server, mut client) = spawn_server_with(Server::builder().num_databases(2));
+    assert!(matches!(
+        client.select(2),
+        Err(Error::Client(ClientError::Server {
+            code: ErrorCode::InvalidDatabase,
+            ..
+        }))
+    ));
+    // The connection is still usable
+    assert_eq!(client.get("abc").unwrap(), Response::Get(None));
+}
+
+#[test]
+fn writing_to_a_full_database_fails() {
+    let (_server, mut client) = spawn_server_with(
+        Server::builder()
+            .eviction_policy(EvictionPolicy::NoEviction)
+            .capacity(Capacity {
+                max_entries: Some(1),
+                ..Capacity::default()
+            }),
+    );
+    assert_eq!(client.set("a", "1").unwrap(), Response::Set);
+    assert!(matches!(
+        client.set("b", "2"),
+        Err(Error::Client(ClientError::Server {
+            code: ErrorCode::OutOfMemory,
+            ..
+        }))
+    ));
+    assert_eq!(client.set("a", "2").unwrap(), Response::Set);
+}
+
+#[test]
+fn evicting_servers_accept_writes_once_full() {
+    let (_server, mut client) = spawn_server_with(
+        Server::builder()
+            .eviction_policy(EvictionPolicy::Lru)
+            .capacity(Capacity {
+                max_entries: Some(2),
+                ..Capacity::default()
+            }),
+    );
+    for key in ["a", "b", "c"] {
+        assert_eq!(client.set(key, "1").unwrap(), Response::Set);
+    }
+    assert_eq!(client.get("a").unwrap(), Response::Get(None));
+    assert_eq!(client.get("c").unwrap(), Response::Get(Some("1".into())));
+}
+
+#[test]
+fn scanning_returns_every_key_exactly_once() {
+    let (_server, mut client) = spawn_server_with(
+        Server::builder()
+            .initial_buffer_size(256)
+            .max_buffer_size(1024),
+    );
+    assert_eq!(client.scan_iter().count(), 0);
+    let mut keys: Vec<String> = (0..1000).map(|i| format!("key-{i}")).collect();
+    for key in &keys {
+        assert_eq!(client.set(key, "value").unwrap(), Response::Set);
+    }
+    let mut scanned: Vec<String> = client.scan_iter().collect::<Result<_, _>>().unwrap();
+    scanned.sort();
+    keys.sort();
+    assert_eq!(scanned, keys);
+}
+
+#[test]
+fn setting_a_key_with_expiry_works() {
+    let (_server, mut client) = spawn_server_with(
+        Server::builder()
+            .initial_buffer_size(256)
+            .max_buffer_size(1024),
+    );
+    let key = "abc";
+    let value = "123".to_string();
+    assert_eq!(
+        client
+            .set_ex(key, &value, Duration::from_millis(100))
+            .unwrap(),
+        Response::Set
+    );
+    assert_eq!(client.get(key).unwrap(), Response::Get(Some(value)));
+    thread::sleep(Duration::from_millis(150));
+    assert_eq!(client.get(key).unwrap(), Response::Get(None));
+    assert_eq!(client.scan_iter().count(), 0);
+}
+
+#[test]
+fn incrementing_a_key_keeps_its_expiry_unless_configured_otherwise() {
+    for (reset_ttl_on_update, expected_ttl) in [(false, true), (true, false)] {
+        let (_server, mut client) =
+            spawn_server_with(Server::builder().reset_ttl_on_update(reset_ttl_on_update));
+        assert_eq!(client.incr("counter", 1).unwrap(), Response::Incr(1));
+        client
+            .touch_many(&["counter"], Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(client.incr("counter", 1).unwrap(), Response::Incr(2));
+        let (value, meta) = client.get_with_meta("counter").unwrap().unwrap();
+        assert_eq!(value, "2");
+        assert_eq!(meta.ttl.is_some(), expected_ttl);
+    }
+}
+
+#[test]
+fn client_operation_times_out_if_server_does_not_respond() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    // A server that reads requests but never responds
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut buffer = [0; 1024];
+        while stream.read(&mut buffer).unwrap_or(0) > 0 {}
+    });
+
+    let timeout = Duration::from_millis(100);
+    let mut client = Client::builder()
+        .address(addr)
+        .operation_timeout(timeout)
+        .build()
+        .unwrap();
+    let now = Instant::now();
+    assert!(matches!(
+        client.get("abc"),
+        Err(Error::Client(ClientError::Timeout))
+    ));
+    let elapsed = now.elapsed();
+    assert!(elapsed >= timeout);
+    assert!(elapsed < Duration::from_secs(1), "elapsed: {elapsed:?}");
+}
+
+#[test]
+fn building_a_client_without_address_fails() {
+    assert!(matches!(
+        Client::builder::<SocketAddr>().build(),
+        Err(Error::Client(ClientError::NoAddress))
+    ));
+}
+
+#[test]
+fn binding_a_port_twice_returns_an_error() {
+    let server = Server::builder().address("127.0.0.1:0").build().unwrap();
+    let addr = format!("127.0.0.1:{}", server.port().unwrap());
+    assert!(matches!(
+        Server::builder().address(addr.as_str()).build(),
+        Err(Error::Server(ServerError::Bind(_)))
+    ));
+    assert!(matches!(
+        Server::try_bind(addr.as_str()),
+        Err(Error::Server(ServerError::Bind(_)))
+    ));
+}
+
+#[test]
+fn fresh_server_is_healthy() {
+    let server = Server::builder().address("127.0.0.1:0").build().unwrap();
+    assert_eq!(
+        server.health(),
+        Health {
+            accepting: true,
+            active_connections: 0,
+            db_healthy: true,
+        }
+    );
+    server.stop();
+    assert!(!server.health().accepting);
+}
+
+#[test]
+fn servers_spawned_in_parallel_do_not_share_a_port() {
+    let spawned: Vec<JoinHandle<_>> = (0..8).map(|_| thread::spawn(spawn_server)).collect();
+    let mut servers: Vec<_> = spawned
+        .into_iter()
+        .map(|spawned| spawned.join().unwrap())
+        .collect();
+    let ports: HashSet<_> = servers
+        .iter()
+        .map(|(server, _)| server.port().unwrap())
+        .collect();
+    assert_eq!(ports.len(), servers.len());
+
+    for (i, (_, client)) in servers.iter_mut().enumerate() {
+        assert_eq!(client.set("id", &i.to_string()).unwrap(), Response::Set);
+    }
+    for (i, (_, client)) in servers.iter_mut().enumerate() {
+        assert_eq!(
+            client.get("id").unwrap(),
+            Response::Get(Some(i.to_string()))
+        );
+    }
+}
+
+#[test]
+fn health_tracks_active_connections() {
+    let (server, mut client) = spawn_server();
+    // Make sure the connection is handled by the server
+    assert_eq!(client.get("abc").unwrap(), Response::Get(None));
+    assert_eq!(server.health().active_connections, 1);
+    drop(client);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while server.health().active_connections != 0 {
+        assert!(Instant::now() < deadline, "connection was not closed");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn connections_lists_open_connections() {
+    let host = "127.0.0.1";
+    let server = Arc::new(
+        Server::builder()
+            .address(format!("{host}:0"))
+            .build()
+            .unwrap(),
+    );
+    let port = server.port().unwrap();
+    let server_clone = Arc::clone(&server);
+    thread::spawn(move || {
+        server_clone.run();
+    });
+    assert!(server.connections().is_empty());
+
+    let mut first = TcpStream::connect(format!("{host}:{port}")).unwrap();
+    let second = TcpStream::connect(format!("{host}:{port}")).unwrap();
+    let wait_for = |done: &dyn Fn(&[ConnectionInfo]) -> bool| {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let connections = server.connections();
+            if done(&connections) {
+                return connections;
+            }
+            assert!(Instant::now() < deadline, "{connections:?}");
+            thread::sleep(Duration::from_millis(10));
+        }
+    };
+    let connections = wait_for(&|connections| connections.len() == 2);
+    let mut peers: Vec<_> = connections.iter().map(|c| c.peer).collect();
+    peers.sort();
+    let mut expected = vec![first.local_addr().unwrap(), second.local_addr().unwrap()];
+    expected.sort();
+    assert_eq!(peers, expected);
+    assert!(connections.iter().all(|c| c.requests == 0));
+
+    // The counters are updated while the connection stays open
+    let request = Request::Ping.serialize();
+    first.write_all(&request).unwrap();
+    let mut response = [0; 1];
+    first.read_exact(&mut response).unwrap();
+    let first_peer = first.local_addr().unwrap();
+    // The response can arrive before the server counted it as written
+    let connections = wait_for(&|connections| {
+        connections
+            .iter()
+            .any(|c| c.peer == first_peer && c.bytes_written == response.len() as u64)
+    });
+    let info = connections.iter().find(|c| c.peer == first_peer).unwrap();
+    assert_eq!(info.bytes_read, request.len() as u64);
+    assert_eq!(info.requests, 1);
+
+    drop(first);
+    let connections = wait_for(&|connections| connections.len() == 1);
+    assert_eq!(connections[0].peer, second.local_addr().unwrap());
+}
+
+#[test]
+fn killing_a_connection_closes_it() {
+    let (server, mut client) = spawn_server();
+    let mut other = server.connect();
+    // Make sure both connections are handled by the server
+    assert_eq!(client.get("abc").unwrap(), Response::Get(None));
+    assert_eq!(other.get("abc").unwrap(), Response::Get(None));
+    let connections = server.connections();
+    assert_eq!(connections.len(), 2);
+    let id = connections[0].id;
+
+    assert!(server.kill_connection(id));
+    assert!(client.get("abc").is_err());
+    assert_eq!(other.get("abc").unwrap(), Response::Get(None));
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while server.connections().iter().any(|c| c.id == id) {
+        assert!(Instant::now() < deadline, "connection was not closed");
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(!server.kill_connection(id));
+}
+
+#[test]
+fn requests_exceeding_the_max_buffer_size_are_rejected_with_an_error() {
+    let (server, mut client) = spawn_server_with(
+        Server::builder()
+            .initial_buffer_size(256)
+            .max_buffer_size(1024),
+    );
+
+    // A single value larger than the buffer
+    assert!(matches!(
+        client.set("abc", &"a".repeat(2000)),
+        Err(Error::Client(ClientError::TooMuchData))
+    ));
+
+    // Many small pairs filling up the buffer
+    let mut client = server.connect();
+    let keys: Vec<_> = (0..200).map(|i| format!("key:{i}")).collect();
+    let pairs: Vec<_> = keys.iter().map(|key| (key.as_str(), "value")).collect();
+    assert!(matches!(
+        client.set_many(&pairs),
+        Err(Error::Client(ClientError::TooMuchData))
+    ));
+}
+
+#[test]
+fn custom_opcodes_are_handled_by_their_registered_handler() {
+    let (_server, mut client) =
+        spawn_server_with(Server::builder().register_handler(200, |body, db| {
+            // Gets the value of the key in the body reversed
+            let value = std::str::from_utf8(body)
+                .ok()
+                .and_then(|key| db.get(key).ok().flatten())
+                .and_then(|value| String::try_from(value).ok())
+                .map(|value| value.chars().rev().collect());
+            Response::Get(value).serialize()
+        }));
+    client.set("abc", "hello").unwrap();
+    let mut request = vec![200];
+    request.extend(3u32.to_be_bytes());
+    request.extend(b"abc");
+    assert_eq!(
+        client.send_raw(&request).unwrap(),
+        Response::Get(Some("olleh".to_string()))
+    );
+    request.truncate(1);
+    request.extend(7u32.to_be_bytes());
+    request.extend(b"missing");
+    assert_eq!(client.send_raw(&request).unwrap(), Response::Get(None));
+    // The connection stays usable for built-in requests
+    assert_eq!(
+        client.get("abc").unwrap(),
+        Response::Get(Some("hello".to_string()))
+    );
+}
+
+#[test]
+fn changing_the_max_buffer_size_applies_to_open_connections() {
+    let (server, mut client) = spawn_server_with(
+        Server::builder()
+            .initial_buffer_size(256)
+            .max_buffer_size(1024),
+    );
+    let value = "a".repeat(2000);
+    let mut other = server.connect();
+    assert_eq!(other.set("abc", "1").unwrap(), Response::Set);
+    assert!(client.set("abc", &value).is_err());
+
+    server.set_max_buffer_size(4096);
+    assert_eq!(server.max_buffer_size(), 4096);
+    // The connection was opened before the change
+    assert_eq!(other.set("abc", &value).unwrap(), Response::Set);
+
+    server.set_max_buffer_size(1024);
+    assert!(other.set("abc", &value).is_err());
+}
+
+#[test]
+fn checking_and_touching_many_keys_works() {
+    let (_server, mut client) = spawn_server_with(
+        Server::builder()
+            .initial_buffer_size(256)
+            .max_buffer_size(1024),
+    );
+    assert_eq!(
+        client.set_many(&[("a", "1"), ("c", "3")]).unwrap(),
+        Response::MSet
+    );
+    let keys = ["a", "b", "c", "b"];
+    assert_eq!(
+        client.exists_many(&keys).unwrap(),
+        Response::MExists(vec![true, false, true, false])
+    );
+    assert_eq!(client.exists_many(&[]).unwrap(), Response::MExists(vec![]));
+
+    assert_eq!(
+        client.touch_many(&keys, Duration::from_millis(50)).unwrap(),
+        Response::MTouch(2)
+    );
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(
+        client.exists_many(&keys).unwrap(),
+        Response::MExists(vec![false; 4])
+    );
+    assert_eq!(
+        client.touch_many(&keys, Duration::from_millis(50)).unwrap(),
+        Response::MTouch(0)
+    );
+}
+
+#[test]
+fn counting_keys_by_prefix_works() {
+    let (_server, mut client) = spawn_server_with(
+        Server::builder()
+            .initial_buffer_size(256)
+            .max_buffer_size(1024),
+    );
+    for (tenant, n_keys) in [(1, 3), (2, 5), (10, 2)] {
+        for i in 0..n_keys {
+            let key = format!("tenant:{tenant}:{i}");
+            assert_eq!(client.set(&key, "value").unwrap(), Response::Set);
+        }
+    }
+    assert_eq!(client.set("other", "value").unwrap(), Response::Set);
+
+    assert_eq!(
+        client.count_prefix("tenant:1:").unwrap(),
+        Response::CountPrefix(3)
+    );
+    assert_eq!(
+        client.count_prefix("tenant:2:").unwrap(),
+        Response::CountPrefix(5)
+    );
+    assert_eq!(
+        client.count_prefix("tenant:10:").unwrap(),
+        Response::CountPrefix(2)
+    );
+    assert_eq!(
+        client.count_prefix("tenant:3:").unwrap(),
+        Response::CountPrefix(0)
+    );
+    assert_eq!(
+        client.count_prefix("tenant:").unwrap(),
+        Response::CountPrefix(10)
+    );
+    assert_eq!(client.count_prefix("").unwrap(), Response::CountPrefix(11));
+}
+
+#[test]
+fn getting_keys_by_prefix_works() {
+    let (_server, mut client) = spawn_server();
+    client.set("config:b", "2").unwrap();
+    client.set("config:a", "1").unwrap();
+    client.set("other", "3").unwrap();
+    client.incr("config:c", 3).unwrap();
+
+    assert_eq!(
+        client.get_prefix("config:").unwrap(),
+        Response::GetPrefix(vec![
+            ("config:a".to_string(), "1".to_string()),
+            ("config:b".to_string(), "2".to_string()),
+            ("config:c".to_string(), "3".to_string()),
+        ])
+    );
+    assert_eq!(
+        client.get_prefix("missing:").unwrap(),
+        Response::GetPrefix(vec![])
+    );
+    let Response::GetPrefix(all) = client.get_prefix("").unwrap() else {
+        panic!("expected a GetPrefix response");
+    };
+    assert_eq!(all.len(), 4);
+}
+
+#[test]
+fn conditional_writes_work() {
+    let (_server, mut client) = spawn_server_with(
+        Server::builder()
+            .initial_buffer_size(256)
+            .max_buffer_size(1024),
+    );
+    let key = "abc";
+    assert!(!client.set_if_equal(key, "1", "2").unwrap());
+    assert_eq!(client.get(key).unwrap(), Response::Get(None));
+
+    assert!(client.set_if_absent(key, "1").unwrap());
+    assert!(!client.set_if_absent(key, "3").unwrap());
+    assert_eq!(
+        client.get(key).unwrap(),
+        Response::Get(Some("1".to_string()))
+    );
+
+    // Mismatch
+    assert!(!client.set_if_equal(key, "3", "4").unwrap());
+    assert_eq!(
+        client.get(key).unwrap(),
+        Response::Get(Some("1".to_string()))
+    );
+    // Match
+    assert!(client.set_if_equal(key, "1", "2").unwrap());
+    assert_eq!(
+        client.get(key).unwrap(),
+        Response::Get(Some("2".to_string()))
+    );
+    // Counters are compared by their digits
+    assert_eq!(client.incr("counter", 5).unwrap(), Response::Incr(5));
+    assert!(client.set_if_equal("counter", "5", "7").unwrap());
+    assert_eq!(
+        client.get("counter").unwrap(),
+        Response::Get(Some("7".to_string()))
+    );
+}
+
+#[test]
+fn seeded_keys_can_be_read_immediately() {
+    let (_server, mut client) = spawn_server_with(
+        Server::builder()
+            .seed([
+                ("abc".to_string(), "123".to_string()),
+                ("def".to_string(), "456".to_string()),
+            ])
+            .seed([("abc".to_string(), "789".to_string())]),
+    );
+    assert_eq!(
+        client.get("abc").unwrap(),
+        Response::Get(Some("789".to_string()))
+    );
+    assert_eq!(
+        client.get("def").unwrap(),
+        Response::Get(Some("456".to_string()))
+    );
+    assert_eq!(client.get("ghi").unwrap(), Response::Get(None));
+}
+
+#[test]
+fn server_info_reports_version_and_uptime() {
+    let (_server, mut client) = spawn_server();
+    thread::sleep(Duration::from_millis(20));
+    let info = client.info().unwrap();
+    assert_eq!(info.protocol_version, PROTOCOL_VERSION);
+    assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+    assert!(info.uptime >= Duration::from_millis(20));
+    assert!(info.features.iter().any(|feature| feature == "ttl"));
+    assert!(!info.features.iter().any(|feature| feature == "auth"));
+}
+
+#[test]
+fn sliding_ttl_keeps_read_keys_alive() {
+    let (_server, mut client) =
+        spawn_server_with(Server::builder().sliding_ttl(Duration::from_millis(200)));
+    let value = "123".to_string();
+    assert_eq!(
+        client
+            .set_ex("read", &value, Duration::from_millis(200))
+            .unwrap(),
+        Response::Set
+    );
+    assert_eq!(
+        client
+            .set_ex("unread", &value, Duration::from_millis(200))
+            .unwrap(),
+        Response::Set
+    );
+    let original_deadline = Instant::now() + Duration::from_millis(200);
+    while Instant::now() < original_deadline + Duration::from_millis(200) {
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(
+            client.get("read").unwrap(),
+            Response::Get(Some(value.clone()))
+        );
+    }
+    assert_eq!(client.get("unread").unwrap(), Response::Get(None));
+}
+
+#[test]
+fn getting_a_key_and_refreshing_its_expiry_works() {
+    let (_server, mut client) = spawn_server();
+    let ttl = Duration::from_secs(60);
+    assert_eq!(client.get_touch("abc", ttl).unwrap(), None);
+    assert_eq!(client.get_with_meta("abc").unwrap(), None);
+    assert_eq!(client.set("abc", "123").unwrap(), Response::Set);
+    assert_eq!(
+        client.get_touch("abc", ttl).unwrap(),
+        Some("123".to_string())
+    );
+    let (_, meta) = client.get_with_meta("abc").unwrap().unwrap();
+    assert!(meta
+        .ttl
+        .is_some_and(|remaining| remaining > Duration::from_secs(50)));
+
+    // A short ttl lets the key expire
+    client.get_touch("abc", Duration::from_millis(50)).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(client.get("abc").unwrap(), Response::Get(None));
+}
+
+#[test]
+fn sending_raw_requests_works() {
+    let (_server, mut client) = spawn_server_with(
+        Server::builder()
+            .initial_buffer_size(256)
+            .max_buffer_size(1024),
+    );
+    // Set abc to ghi
+    let set = [2, 0, 0, 0, 3, 97, 98, 99, 0, 0, 0, 3, 103, 104, 105];
+    assert_eq!(client.send_raw(&set).unwrap(), Response::Set);
+    let get = Request::Get("abc").serialize();
+    assert_eq!(
+        client.send_raw(&get).unwrap(),
+        Response::Get(Some("ghi".to_string()))
+    );
+}
+
+#[test]
+fn writes_are_rejected_above_the_memory_watermark() {
+    let (_server, mut client) = spawn_server_with(Server::builder().memory_watermarks(2048, 1024));
+    let value = "x".repeat(256);
+    let is_out_of_memory = |result: Result<Response, Error>| {
+        matches!(
+            result,
+            Err(Error::Client(ClientError::Server {
+                code: ErrorCode::OutOfMemory,
+                ..
+            }))
+        )
+    };
+    let mut n_keys = 0;
+    loop {
+        let result = client.set(&format!("key{n_keys}"), &value);
+        if is_out_of_memory(result) {
+            break;
+        }
+        n_keys += 1;
+        assert!(n_keys < 100, "sets were never rejected");
+    }
+    assert!(n_keys > 0);
+
+    // Reads are still served while writes are rejected
+    assert_eq!(
+        client.get("key0").unwrap(),
+        Response::Get(Some(value.clone()))
+    );
+    assert!(is_out_of_memory(client.set("key0", "small")));
+    assert!(is_out_of_memory(client.incr("counter", 1)));
+
+    // Dropping below the high watermark is not enough to accept writes again
+    assert_eq!(client.delete("key1").unwrap(), Response::Delete);
+    assert!(is_out_of_memory(client.set("other", "1")));
+
+    // Writes are accepted again once the usage dropped below the low watermark
+    for i in 2..n_keys {
+        assert_eq!(client.delete(&format!("key{i}")).unwrap(), Response::Delete);
+    }
+    assert_eq!(client.set("other", "1").unwrap(), Response::Set);
+}
+
+#[test]
+fn reconnecting_recovers_from_a_desynced_connection() {
+    let (_server, mut client) = spawn_server();
+    assert_eq!(client.set("abc", "1").unwrap(), Response::Set);
+
+    // Two requests sent as one leave the second response unread
+    let mut requests = Request::Get("abc").serialize();
+    requests.extend(Request::Ping.serialize());
+    assert_eq!(
+        client.send_raw(&requests).unwrap(),
+        Response::Get(Some("1".to_string()))
+    );
+    assert_eq!(client.get("abc").unwrap(), Response::Pong);
+
+    client.reconnect().unwrap();
+    assert_eq!(
+        client.get("abc").unwrap(),
+        Response::Get(Some("1".to_string()))
+    );
+    assert_eq!(client.set("abc", "2").unwrap(), Response::Set);
+    assert_eq!(
+        client.get("abc").unwrap(),
+        Response::Get(Some("2".to_string()))
+    );
+}
+
+#[test]
+fn reconnecting_closes_the_old_connection() {
+    let (server, _client) = spawn_server();
+    let mut client = Client::builder()
+        .address(server.address())
+        .keepalive_interval(Duration::from_secs(60))
+        .build()
+        .unwrap();
+    assert_eq!(client.get("abc").unwrap(), Response::Get(None));
+    client.reconnect().unwrap();
+    assert_eq!(client.get("abc").unwrap(), Response::Get(None));
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while server.health().active_connections != 2 {
+        assert!(Instant::now() < deadline, "old connection was not closed");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn cloned_clients_can_be_used_concurrently() {
+    let (server, _client) = spawn_server();
+
+    let client = Client::builder()
+        .address(server.address())
+        .operation_timeout(Duration::from_secs(5))
+        .build()
+        .unwrap();
+    let handles: Vec<JoinHandle<()>> = (0..4)
+        .map(|thread| {
+            let mut client = client.try_clone().unwrap();
+            thread::spawn(move || {
+                for i in 0..100 {
+                    let key = format!("key-{thread}-{i}");
+                    assert_eq!(client.set(&key, &key).unwrap(), Response::Set);
+                    assert_eq!(client.get(&key).unwrap(), Response::Get(Some(key)));
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+#[test]
+fn getting_a_key_or_a_default_works() {
+    let (_server, mut client) = spawn_server();
+    assert_eq!(client.set("abc", "stored").unwrap(), Response::Set);
+    assert_eq!(client.get_or_default("abc", "default").unwrap(), "stored");
+    assert_eq!(
+        client.get_or_default("missing", "default").unwrap(),
+        "default"
+    );
+}
+
+#[test]
+fn connections_rejected_by_the_accept_filter_are_closed() {
+    // Loopback clients are rejected until they are allowed
+    let loopback_allowed = Arc::new(AtomicBool::new(false));
+    let (server, _client) = spawn_server_with(Server::builder().accept_filter({
+        let loopback_allowed = Arc::clone(&loopback_allowed);
+        move |peer: &SocketAddr| {
+            !peer.ip().is_loopback() || loopback_allowed.load(Ordering::Relaxed)
+        }
+    }));
+    let mut rejected = server.connect();
+    assert!(rejected.ping().is_err());
+    assert!(server.connections().is_empty());
+
+    loopback_allowed.store(true, Ordering::Relaxed);
+    server.connect().ping().unwrap();
+}
+
+#[test]
+fn deleting_keys_older_than_a_cutoff_works() {
+    let (_server, mut client) = spawn_server();
+    assert_eq!(client.set("stale", "1").unwrap(), Response::Set);
+    // The cutoff is sent with millisecond precision
+    thread::sleep(Duration::from_millis(5));
+    let cutoff = SystemTime::now();
+    thread::sleep(Duration::from_millis(5));
+    assert_eq!(client.set("fresh", "2").unwrap(), Response::Set);
+
+    assert!(client.delete_if_older_than("stale", cutoff).unwrap());
+    assert!(!client.delete_if_older_than("fresh", cutoff).unwrap());
+    assert!(!client.delete_if_older_than("missing", cutoff).unwrap());
+    assert_eq!(client.get("stale").unwrap(), Response::Get(None));
+    assert_eq!(
+        client.get("fresh").unwrap(),
+        Response::Get(Some("2".to_string()))
+    );
+}
+
+#[test]
+fn deleting_a_key_only_if_it_holds_the_expected_value_works() {
+    let (_server, mut client) = spawn_server();
+    assert_eq!(client.set("abc", "1").unwrap(), Response::Set);
+
+    // Another writer refreshed the value, so it is kept
+    assert!(!client.delete_if("abc", "0").unwrap());
+    assert_eq!(
+        client.get("abc").unwrap(),
+        Response::Get(Some("1".to_string()))
+    );
+    assert!(client.delete_if("abc", "1").unwrap());
+    assert_eq!(client.get("abc").unwrap(), Response::Get(None));
+    assert!(!client.delete_if("missing", "1").unwrap());
+
+    // Counters are compared by their digits
+    assert_eq!(client.incr("counter", 5).unwrap(), Response::Incr(5));
+    assert!(client.delete_if("counter", "5").unwrap());
+    assert_eq!(client.get("counter").unwrap(), Response::Get(None));
+}
+
+#[test]
+fn streaming_a_large_value_works() {
+    let value = "v".repeat(4 * 1024 * 1024);
+    let (server, _client) = spawn_server_with(Server::builder().max_buffer_size(4 * value.len()));
+
+    // The value does not fit into the client's buffer
+    let mut client = Client::builder()
+        .address(server.address())
+        .max_buffer_size(1024)
+        .build()
+        .unwrap();
+    assert_eq!(client.set("large", &value).unwrap(), Response::Set);
+    let mut sink = Vec::new();
+    assert!(client.get_streaming("large", &mut sink).unwrap());
+    assert_eq!(sink, value.as_bytes());
+    assert!(!client.get_streaming("missing", &mut sink).unwrap());
+}
+
+#[test]
+fn flushing_requires_the_admin_password() {
+    let (server, mut client) = spawn_server_with(Server::builder().admin_password("secret"));
+    assert!(client
+        .info()
+        .unwrap()
+        .features
+        .iter()
+        .any(|feature| feature == "auth"));
+
+    let is_unauthorized = |result: Result<Response, Error>| {
+        matches!(
+            result,
+            Err(Error::Client(ClientError::Server {
+                code: ErrorCode::Unauthorized,
+                ..
+            }))
+        )
+    };
+    assert_eq!(client.set("abc", "1").unwrap(), Response::Set);
+    assert!(is_unauthorized(client.flush()));
+    assert!(is_unauthorized(client.flush_all()));
+    assert!(is_unauthorized(client.auth("wrong")));
+    assert_eq!(
+        client.get("abc").unwrap(),
+        Response::Get(Some("1".to_string()))
+    );
+
+    // Other connections do not gain the privilege
+    let mut admin = server.connect();
+    assert_eq!(admin.auth("secret").unwrap(), Response::Auth);
+    assert!(is_unauthorized(client.flush()));
+    assert_eq!(admin.flush().unwrap(), Response::Flush);
+    assert_eq!(admin.flush_all().unwrap(), Response::FlushAll);
+    assert_eq!(client.get("abc").unwrap(), Response::Get(None));
+}
+
+#[test]
+fn replacing_all_keys_requires_the_admin_password() {
+    let (server, mut client) = spawn_server_with(Server::builder().admin_password("secret"));
+    assert_eq!(client.set("abc", "1").unwrap(), Response::Set);
+    assert!(matches!(
+        client.replace_all(&[("def", "2")]),
+        Err(Error::Client(ClientError::Server {
+            code: ErrorCode::Unauthorized,
+            ..
+        }))
+    ));
+    assert_eq!(
+        client.get_many(&["abc", "def"]).unwrap(),
+        Response::MGet(vec![Some("1".to_string()), None])
+    );
+
+    let mut admin = server.connect();
+    assert_eq!(admin.auth("secret").unwrap(), Response::Auth);
+    assert_eq!(
+        admin.replace_all(&[("def", "2")]).unwrap(),
+        Response::ReplaceAll
+    );
+    assert_eq!(
+        client.get_many(&["abc", "def"]).unwrap(),
+        Response::MGet(vec![None, Some("2".to_string())])
+    );
+}
+
+#[test]
+fn getting_a_key_with_its_metadata_works() {
+    let (_server, mut client) = spawn_server();
+    let ttl = Duration::from_secs(60);
+    assert_eq!(client.set_ex("abc", "value", ttl).unwrap(), Response::Set);
+    let (value, meta) = client.get_with_meta("abc").unwrap().unwrap();
+    assert_eq!(value, "value");
+    assert_eq!(meta.size, 5);
+    let remaining = meta.ttl.unwrap();
+    assert!(remaining <= ttl && remaining > ttl - Duration::from_secs(5));
+
+    assert_eq!(client.set("def", "1").unwrap(), Response::Set);
+    let (value, meta) = client.get_with_meta("def").unwrap().unwrap();
+    assert_eq!(value, "1");
+    assert_eq!(meta, KeyMeta { ttl: None, size: 1 });
+    assert_eq!(client.get_with_meta("missing").unwrap(), None);
+}
+
+#[test]
+fn idle_client_with_keepalive_keeps_working() {
+    let (server, _client) = spawn_server();
+
+    let mut client = Client::builder()
+        .address(server.address())
+        .keepalive_interval(Duration::from_millis(20))
+        .build()
+        .unwrap();
+    assert_eq!(client.set("abc", "1").unwrap(), Response::Set);
+    // Several keepalive pings are sent meanwhile, their pongs must not be mistaken for responses.
+    thread::sleep(Duration::from_millis(150));
+    assert_eq!(
+        client.get("abc").unwrap(),
+        Response::Get(Some("1".to_string()))
+    );
+    for i in 0..100 {
+        assert_eq!(client.set("abc", &i.to_string()).unwrap(), Response::Set);
+    }
+    client.ping().unwrap();
+    assert_eq!(
+        client.get("abc").unwrap(),
+        Response::Get(Some("99".to_string()))
+    );
+}
+
+#[test]
+fn server_serves_a_metered_database() {
+    let dbs = Arc::new(Mutex::new(Vec::new()));
+    let builder = Server::builder().num_databases(2).database({
+        let dbs = dbs.clone();
+        move |db| {
+            let db = MeteredDb::new(db);
+            dbs.lock().unwrap().push(db.clone());
+            db
+        }
+    });
+    let (_server, mut client) = spawn_server_with(builder);
+    assert_eq!(client.set("abc", "1").unwrap(), Response::Set);
+    assert_eq!(
+        client.get("abc").unwrap(),
+        Response::Get(Some("1".to_string()))
+    );
+    assert_eq!(client.delete("abc").unwrap(), Response::Delete);
+    // The second database is metered separately.
+    assert_eq!(client.select(1).unwrap(), Response::Select);
+    assert_eq!(client.set("abc", "1").unwrap(), Response::Set);
+
+    let dbs = dbs.lock().unwrap();
+    assert_eq!(dbs.len(), 2);
+    let metrics = dbs[0].metrics();
+    assert_eq!((metrics.reads, metrics.writes, metrics.removals), (1, 1, 1));
+    assert_eq!(dbs[1].metrics().writes, 1);
+}
+
+#[test]
+fn databases_of_a_decorated_server_are_configured_by_the_builder() {
+    let builder = Server::builder()
+        .capacity(Capacity {
+            max_entries: Some(1),
+            ..Capacity::default()
+        })
+        .database(MeteredDb::new);
+    let (_server, mut client) = spawn_server_with(builder);
+    assert_eq!(client.set("a", "1").unwrap(), Response::Set);
+    assert!(matches!(
+        client.set("b", "1"),
+        Err(Error::Client(ClientError::Server {
+            code: ErrorCode::OutOfMemory,
+            ..
+        }))
+    ));
+}
+
+#[test]
+fn server_serves_stacked_database_decorators() {
+    let loader = |key: &str| (key == "backed").then(|| Value::from("loaded"));
+    let db = MeteredDb::new(ReadThroughDb::new(DB::new(), loader));
+    let (_server, mut client) = spawn_server_with(Server::builder().database({
+        let db = db.clone();
+        move |_| db.clone()
+    }));
+    assert_eq!(
+        client.get("backed").unwrap(),
+        Response::Get(Some("loaded".to_string()))
+    );
+    assert_eq!(client.get("missing").unwrap(), Response::Get(None));
+    assert_eq!(db.metrics().reads, 2);
+    assert_eq!(
+        db.inner().inner().get("backed").unwrap(),
+        Some("loaded".into())
+    );
+}
+
+#[test]
+fn getting_a_key_if_modified_since_works() {
+    let (_server, mut client) = spawn_server();
+    let before_write = SystemTime::now() - Duration::from_secs(1);
+    assert_eq!(client.set("abc", "value").unwrap(), Response::Set);
+    let after_write = SystemTime::now() + Duration::from_secs(1);
+
+    assert_eq!(
+        client.get_if_modified_since("abc", before_write).unwrap(),
+        GetModified::Value("value".to_string())
+    );
+    assert_eq!(
+        client.get_if_modified_since("abc", after_write).unwrap(),
+        GetModified::NotModified
+    );
+    assert_eq!(
+        client
+            .get_if_modified_since("missing", before_write)
+            .unwrap(),
+        GetModified::Missing
+    );
+}
+
+#[test]
+fn concurrent_sets_of_the_same_key_leave_one_written_value() {
+    let (server, mut client) = spawn_server();
+    let address = server.address();
+
+    let key = "contended";
+    // Values of different lengths, so that a mix of two writes would be noticed
+    let value_of = |id: usize| id.to_string().repeat(id + 1);
+    let n_threads = 16;
+    let join_handles: Vec<JoinHandle<_>> = (0..n_threads)
+        .map(|id| {
+            thread::spawn(move || {
+                let mut client = Client::connect(address);
+                let value = value_of(id);
+                for _ in 0..100 {
+                    assert_eq!(client.set(key, &value).unwrap(), Response::Set);
+                }
+            })
+        })
+        .collect();
+    for join_handle in join_handles {
+        join_handle.join().unwrap();
+    }
+
+    let Response::Get(Some(value)) = client.get(key).unwrap() else {
+        panic!("the key should exist");
+    };
+    assert!((0..n_threads).any(|id| value == value_of(id)));
+}
+
+#[test]
+fn all_batched_writes_land() {
+    let (server, mut client) = spawn_server();
+
+    let mut batching_client = Client::builder()
+        .address(server.address())
+        .batch_max_bytes(256)
+        .build_batching()
+        .unwrap();
+    for i in 0..100 {
+        batching_client
+            .queue_set(&format!("key-{i}"), &i.to_string())
+            .unwrap();
+    }
+    // The batches sent automatically are not lost either
+    assert!(batching_client.queued() < 100);
+    let responses = batching_client.flush().unwrap();
+    assert_eq!(responses.len(), 100);
+    assert!(responses.iter().all(|response| *response == Response::Set));
+
+    for i in 0..100 {
+        assert_eq!(
+            client.get(&format!("key-{i}")).unwrap(),
+            Response::Get(Some(i.to_string()))
+        );
+    }
+}
+
+#[test]
+fn duplicate_client_ids_are_rejected() {
+    let (server, mut first) =
+        spawn_server_with(Server::builder().unique_client_ids(ClientIdPolicy::RejectNew));
+    assert_eq!(first.hello("worker-1").unwrap(), Response::Hello);
+    // Repeating the own id is fine
+    assert_eq!(first.hello("worker-1").unwrap(), Response::Hello);
+
+    let mut second = server.connect();
+    assert!(matches!(
+        second.hello("worker-1"),
+        Err(Error::Client(ClientError::Server {
+            code: ErrorCode::DuplicateClientId,
+            ..
+        }))
+    ));
+    assert_eq!(second.hello("worker-2").unwrap(), Response::Hello);
+    assert_eq!(first.set("abc", "1").unwrap(), Response::Set);
+
+    // The id is released once the first connection is closed
+    drop(first);
+    let started_at = Instant::now();
+    while second.hello("worker-1").is_err() {
+        assert!(started_at.elapsed() < Duration::from_secs(1));
+        thread::sleep(Duration::from_millis(5));
+    }
+}
+
+#[test]
+fn duplicate_client_ids_evict_the_old_connection() {
+    let (server, mut first) =
+        spawn_server_with(Server::builder().unique_client_ids(ClientIdPolicy::EvictOld));
+    assert_eq!(first.hello("worker-1").unwrap(), Response::Hello);
+    let mut second = server.connect();
+    assert_eq!(second.hello("worker-1").unwrap(), Response::Hello);
+
+    assert!(first.get("abc").is_err());
+    assert_eq!(second.get("abc").unwrap(), Response::Get(None));
+}
+
+#[test]
+fn getting_and_setting_ranges_works() {
+    let (_server, mut client) = spawn_server();
+    assert_eq!(client.get_range("abc", 0, 10).unwrap(), None);
+    assert_eq!(client.set("abc", "hello world").unwrap(), Response::Set);
+    assert_eq!(
+        client.get_range("abc", 0, 5).unwrap(),
+        Some("hello".to_string())
+    );
+    assert_eq!(
+        client.get_range("abc", -5, 100).unwrap(),
+        Some("world".to_string())
+    );
+
+    assert_eq!(client.set_range("abc", 6, "there").unwrap(), 11);
+    assert_eq!(client.set_range("abc", 11, "!").unwrap(), 12);
+    assert_eq!(
+        client.get("abc").unwrap(),
+        Response::Get(Some("hello there!".to_string()))
+    );
+
+    // `ä` takes two bytes, ranges splitting it are rejected
+    assert_eq!(client.set("def", "aäb").unwrap(), Response::Set);
+    assert!(matches!(
+        client.get_range("def", 0, 2),
+        Err(Error::Client(ClientError::Server {
+            code: ErrorCode::WrongType,
+            ..
+        }))
+    ));
+    assert!(matches!(
+        client.set_range("def", 2, "x"),
+        Err(Error::Client(ClientError::Server {
+            code: ErrorCode::WrongType,
+            ..
+        }))
+    ));
+    assert_eq!(
+        client.get("def").unwrap(),
+        Response::Get(Some("aäb".to_string()))
+    );
+}
+
+#[test]
+fn getting_the_length_of_a_value_works() {
+    let (_server, mut client) = spawn_server();
+    assert_eq!(client.strlen("abc").unwrap(), None);
+    assert_eq!(client.set("abc", "hello").unwrap(), Response::Set);
+    assert_eq!(client.strlen("abc").unwrap(), Some(5));
+    // The length is in bytes, `ä` takes two
+    assert_eq!(client.set("abc", "hällo").unwrap(), Response::Set);
+    assert_eq!(client.strlen("abc").unwrap(), Some(6));
+    assert_eq!(client.incr("num", 1234).unwrap(), Response::Incr(1234));
+    assert_eq!(client.strlen("num").unwrap(), Some(4));
+    assert_eq!(client.delete("abc").unwrap(), Response::Delete);
+    assert_eq!(client.strlen("abc").unwrap(), None);
+}
+
+#[test]
+fn replacing_all_keys_works() {
+    let (_server, mut client) = spawn_server();
+    assert_eq!(
+        client.set_many(&[("a", "1"), ("b", "2")]).unwrap(),
+        Response::MSet
+    );
+    assert_eq!(
+        client.replace_all(&[("b", "3"), ("c", "4")]).unwrap(),
+        Response::ReplaceAll
+    );
+    assert_eq!(
+        client.get_many(&["a", "b", "c"]).unwrap(),
+        Response::MGet(vec![None, Some("3".to_string()), Some("4".to_string())])
+    );
+}
+
+#[test]
+fn transactions_are_not_interleaved_with_other_connections() {
+    let (server, mut client) = spawn_server();
+    let mut other = server.connect();
+    // Queued requests are only applied on `Exec`, so they see the writes made in the meantime
+    assert_eq!(
+        client.send_raw(&Request::Multi.serialize()).unwrap(),
+        Response::Multi
+    );
+    let incr = Request::Incr {
+        key: "counter",
+        delta: 1,
+    };
+    assert_eq!(
+        client.send_raw(&incr.serialize()).unwrap(),
+        Response::Queued
+    );
+    other.incr("counter", 10).unwrap();
+    assert_eq!(
+        client.send_raw(&Request::Exec.serialize()).unwrap(),
+        Response::Exec(vec![Response::Incr(11)])
+    );
+
+    let writer = thread::spawn(move || {
+        for _ in 0..1000 {
+            other.incr("counter", 1).unwrap();
+        }
+    });
+    for _ in 0..200 {
+        let responses = client
+            .multi_exec(&[
+                Request::Get("counter"),
+                Request::Incr {
+                    key: "counter",
+                    delta: 1,
+                },
+                Request::Get("counter"),
+            ])
+            .unwrap();
+        // The other connection's increments never land between the requests of the transaction
+        let [Response::Get(Some(before)), Response::Incr(incremented), Response::Get(Some(after))] =
+            responses.as_slice()
+        else {
+            panic!("unexpected responses {responses:?}");
+        };
+        assert_eq!(before.parse::<i64>().unwrap() + 1, *incremented);
+        assert_eq!(*after, incremented.to_string());
+    }
+    writer.join().unwrap();
+    assert_eq!(
+        client.get("counter").unwrap(),
+        Response::Get(Some((11 + 1000 + 200).to_string()))
+    );
+}
+
+#[test]
+fn writes_of_other_connections_to_watched_keys_are_pushed() {
+    let (server, mut client) = spawn_server();
+    let mut other = server.connect();
+    assert_eq!(client.watch(&["abc"]).unwrap(), Response::Watch);
+
+    // The invalidation arrives before the response and is kept for later
+    other.set("abc", "1").unwrap();
+    assert_eq!(
+        client.get("abc").unwrap(),
+        Response::Get(Some("1".to_string()))
+    );
+    assert_eq!(client.take_invalidations(), ["abc"]);
+
+    // Neither the connection's own writes nor writes to other keys are pushed
+    client.set("abc", "2").unwrap();
+    other.set("def", "1").unwrap();
+    client.ping().unwrap();
+    assert!(client.take_invalidations().is_empty());
+
+    other.delete("abc").unwrap();
+    assert_eq!(client.next_invalidation().unwrap(), "abc");
+}
+
+#[test]
+fn incrementing_a_key_works() {
+    let (_server, mut client) = spawn_server_with(
+        Server::builder()
+            .initial_buffer_size(256)
+            .max_buffer_size(1024),
+    );
+    let key = "counter";
+    assert_eq!(client.incr(key, 5).unwrap(), Response::Incr(5));
+    assert_eq!(client.incr(key, -7).unwrap(), Response::Incr(-2));
+    assert_eq!(
+        client.get(key).unwrap(),
+        Response::Get(Some("-2".to_string()))
+    );
+    assert!(matches!(
+        client.incr(key, i64::MAX),
+        Ok(Response::Incr(value)) if value == i64::MAX - 2
+    ));
+    assert!(matches!(
+        client.incr(key, 3),
+        Err(Error::Client(ClientError::Server {
+            code: ErrorCode::IntegerOverflow,
+            ..
+        }))
+    ));
+}
+
+#[test]
+fn incrementing_a_string_fails_with_a_type_error() {
+    let (_server, mut client) = spawn_server_with(
+        Server::builder()
+            .initial_buffer_size(256)
+            .max_buffer_size(1024),
+    );
+    let key = "abc";
+    assert_eq!(client.set(key, "123").unwrap(), Response::Set);
+    assert!(matches!(
+        client.incr(key, 1),
+        Err(Error::Client(ClientError::Server {
+            code: ErrorCode::NotAnInteger,
+            ..
+        }))
+    ));
+    // The connection stays usable after the type error
+    assert_eq!(
+        client.get(key).unwrap(),
+        Response::Get(Some("123".to_string()))
+    );
+}
+
+#[test]
+fn getting_into_a_reused_string_works() {
+    let (_server, mut client) = spawn_server_with(
+        Server::builder()
+            .initial_buffer_size(256)
+            .max_buffer_size(1024),
+    );
+    for i in 0..10 {
+        assert_eq!(
+            client
+                .set(&format!("key-{i}"), &format!("value-{i}"))
+                .unwrap(),
+            Response::Set
+        );
+    }
+
+    let mut out = String::with_capacity(64);
+    let capacity = out.capacity();
+    let ptr = out.as_ptr();
+    for _ in 0..10 {
+        for i in 0..10 {
+            assert!(client.get_into(&format!("key-{i}"), &mut out).unwrap());
+            assert_eq!(out, format!("value-{i}"));
+        }
+        assert!(!client.get_into("missing", &mut out).unwrap());
+        assert!(out.is_empty());
+    }
+    assert_eq!(out.capacity(), capacity);
+    assert_eq!(out.as_ptr(), ptr);
+}
+
+#[test]
+fn dual_stack_server_accepts_ipv4_and_ipv6_clients() {
+    // Skip on platforms without IPv6 support
+    if TcpListener::bind("[::1]:0").is_err() {
+        return;
+    }
+    let server = Server::builder()
+        .address("0.0.0.0:0")
+        .dual_stack(true)
+        .build()
+        .unwrap();
+    let port = server.port().unwrap();
+    thread::spawn(move || {
+        server.run();
+    });
+
+    let mut ipv4_client = Client::connect(format!("127.0.0.1:{port}"));
+    assert_eq!(ipv4_client.set("abc", "123").unwrap(), Response::Set);
+    let mut ipv6_client = Client::connect(format!("[::1]:{port}"));
+    assert_eq!(
+        ipv6_client.get("abc").unwrap(),
+        Response::Get(Some("123".to_string()))
+    );
+}
+
+#[test]
+fn pipelined_requests_are_applied_in_order() {
+    let (server, mut client) = spawn_server_with(
+        Server::builder()
+            .initial_buffer_size(256)
+            .max_buffer_size(1024),
+    );
+
+    // set a 1, flush, set b 2 sent in a single write without waiting for responses
+    let mut stream = TcpStream::connect(server.address()).unwrap();
+    let mut requests = vec![2, 0, 0, 0, 1, b'a', 0, 0, 0, 1, b'1'];
+    requests.push(4);
+    requests.extend([2, 0, 0, 0, 1, b'b', 0, 0, 0, 1, b'2']);
+    stream.write_all(&requests).unwrap();
+    let mut responses = [0; 3];
+    stream.read_exact(&mut responses).unwrap();
+    // Set, Flush, Set
+    assert_eq!(responses, [2, 4, 2]);
+
+    assert_eq!(client.get("a").unwrap(), Response::Get(None));
+    assert_eq!(
+        client.get("b").unwrap(),
+        Response::Get(Some("2".to_string()))
+    );
+}
+
+#[test]
+fn serving_from_an_already_bound_listener_works() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = Server::builder()
+        .from_listener(listener)
+        .initial_buffer_size(256)
+        .max_buffer_size(1024)
+        .build()
+        .unwrap();
+    assert_eq!(server.port().unwrap(), addr.port());
+    thread::spawn(move || {
+        server.run();
+    });
+
+    let mut client = Client::connect(addr);
+    let key = "abc";
+    let value = "123".to_string();
+    assert_eq!(client.set(key, &value).unwrap(), Response::Set);
+    assert_eq!(client.get(key).unwrap(), Response::Get(Some(value)));
+}
+
+#[test]
+fn server_from_listener_uses_the_listener() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = Server::from_listener(listener);
+    assert_eq!(server.port().unwrap(), addr.port());
+    thread::spawn(move || {
+        server.run();
+    });
+
+    let mut client = Client::connect(addr);
+    assert_eq!(client.get("abc").unwrap(), Response::Get(None));
+}
+
 #[test]
 fn test_basic_contention() {
     let db = DB::new();
     let keys: Vec<_> = (0..10).map(|i| i.to_string()).collect();
-    let mut lock = db.write().unwrap();
     for key in &keys {
-        lock.insert(key.clone(), "value".to_string());
+        db.insert(key.clone(), "value".into()).unwrap();
     }
-    drop(lock);
     let iterations = 100_000;
     let n_threads = 4;
     let join_handles: Vec<JoinHandle<_>> = (0..n_threads)
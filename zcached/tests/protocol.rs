@@ -0,0 +1,65 @@
+//! Round trips through the public wire protocol, which is also available without the `std` feature:
+//! `cargo test -p zcached --no-default-features --test protocol`.
+//! The tests themselves link std, so `just c-no-std` checks that the protocol builds without it.
+
+use zcached::parse_request;
+use zcached::parse_response;
+use zcached::ErrorCode;
+use zcached::ParsingError;
+use zcached::Request;
+use zcached::Response;
+use zcached::Serialize;
+
+#[test]
+fn requests_round_trip() {
+    let requests = [
+        Request::Get("abc"),
+        Request::Set {
+            key: "abc",
+            value: "def",
+        },
+        Request::MSet(vec![("a", "1"), ("b", "2")]),
+        Request::Incr {
+            key: "counter",
+            delta: -3,
+        },
+        Request::Info,
+    ];
+    for request in requests {
+        let bytes = request.serialize();
+        let (parsed, n_parsed_bytes) = parse_request(&bytes).unwrap().unwrap();
+        assert_eq!(n_parsed_bytes, bytes.len());
+        assert_eq!(parsed.opcode(), request.opcode());
+        assert_eq!(parsed.serialize(), bytes);
+        // An incomplete request is not parsed yet
+        assert!(parse_request(&bytes[..bytes.len() - 1]).unwrap().is_none());
+    }
+}
+
+#[test]
+fn responses_round_trip() {
+    let responses = [
+        Response::Get(Some("def".to_string())),
+        Response::Get(None),
+        Response::MGet(vec![Some("1".to_string()), None]),
+        Response::Cas(true),
+        Response::Error {
+            code: ErrorCode::WrongType,
+            message: Some("not a string".to_string()),
+        },
+    ];
+    for response in responses {
+        let bytes = response.serialize();
+        let (parsed, n_parsed_bytes) = parse_response(&bytes).unwrap().unwrap();
+        assert_eq!(n_parsed_bytes, bytes.len());
+        assert_eq!(parsed, response);
+    }
+}
+
+#[test]
+fn invalid_utf8_is_rejected() {
+    assert!(matches!(
+        parse_request(&[1, 0, 0, 0, 1, 0xff]),
+        Err(ParsingError::Utf8Error(_))
+    ));
+}
@@ -0,0 +1,25 @@
+use std::thread;
+
+use zcached::duplex;
+use zcached::serve_on;
+use zcached::Client;
+use zcached::Response;
+use zcached::DB;
+
+#[test]
+fn client_and_server_talk_over_an_in_memory_pipe() {
+    let (client_end, server_end) = duplex();
+    let server = thread::spawn(move || serve_on(server_end, DB::new()));
+
+    let mut client = Client::from_stream(client_end);
+    assert_eq!(client.set("abc", "1").unwrap(), Response::Set);
+    assert_eq!(
+        client.get("abc").unwrap(),
+        Response::Get(Some("1".to_string()))
+    );
+    assert_eq!(client.incr("def", 2).unwrap(), Response::Incr(2));
+
+    // Dropping the client closes the pipe, which ends the connection cleanly.
+    drop(client);
+    server.join().unwrap().unwrap();
+}